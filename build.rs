@@ -1,3 +1,15 @@
 fn main() {
     built::write_built_file().expect("Failed to acquire build-time information");
+
+    // The protoc of `PROTOC`, e.g. of Nix, is preferred over the vendored one.
+    let mut config = prost_build::Config::new();
+    if std::env::var_os("PROTOC").is_none() {
+        config.protoc_executable(
+            protoc_bin_vendored::protoc_bin_path().expect("Failed to find the vendored protoc"),
+        );
+    }
+    tonic_prost_build::configure()
+        .build_client(false)
+        .compile_with_config(config, &["proto/ccproxy/admin/v1/admin.proto"], &["proto"])
+        .expect("Failed to compile the protos");
 }
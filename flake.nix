@@ -43,11 +43,17 @@
       nix2containerPkgs = nix2container.packages.${system};
       craneLib = crane.mkLib pkgs;
 
-      src = craneLib.cleanCargoSource ./.;
+      # The protos of the gRPC control plane are compiled by the build script.
+      src = pkgs.lib.cleanSourceWith {
+        src = ./.;
+        filter = path: type: (pkgs.lib.hasSuffix ".proto" path) || (craneLib.filterCargoSources path type);
+        name = "source";
+      };
 
       commonArgs = {
         inherit src;
         strictDeps = true;
+        PROTOC = "${pkgs.protobuf}/bin/protoc";
       };
 
       cargoArtifacts = craneLib.buildDepsOnly commonArgs;
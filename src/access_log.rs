@@ -0,0 +1,89 @@
+use crate::config::DATA_PATH;
+use crate::error::CCProxyResult;
+use crate::geoip::GeoInfo;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// The directory which the access log files are written to.
+///
+/// One NDJSON file is written per UTC day, like the session history, so the retention
+/// policy can remove old ones file by file.
+pub static ACCESS_LOG_PATH: LazyLock<PathBuf> = LazyLock::new(|| DATA_PATH.join("access"));
+
+/// A single line in the access log, written when a session ends.
+#[derive(Clone, Debug, Serialize)]
+pub struct AccessRecord {
+    pub session_id: String,
+
+    /// Unix timestamp in seconds.
+    pub start: u64,
+
+    /// Unix timestamp in seconds.
+    pub end: u64,
+
+    pub client_ip: IpAddr,
+
+    pub client_port: u16,
+
+    /// Where the client address is located, if the GeoIP databases are configured.
+    #[serde(flatten)]
+    pub geo: GeoInfo,
+
+    pub name: Option<String>,
+
+    pub xuid: Option<String>,
+
+    pub upstream_address: SocketAddr,
+
+    /// The bytes forwarded from the client to the upstream.
+    pub bytes_in: u64,
+
+    /// The bytes forwarded from the upstream to the client.
+    pub bytes_out: u64,
+
+    /// Why the session ended, e.g. `client_left`, `upstream_lost`, or `kicked`.
+    pub reason: &'static str,
+
+    /// The message which the proxy kicked the client with, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kick_message: Option<String>,
+}
+
+/// Appends [`AccessRecord`]s to the daily access log file.
+#[derive(Default)]
+pub struct AccessLog {
+    lock: Mutex<()>,
+}
+
+impl AccessLog {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub async fn append(&self, record: &AccessRecord) -> CCProxyResult<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let date = chrono::DateTime::from_timestamp(record.end as i64, 0)
+            .unwrap_or_default()
+            .format("%Y-%m-%d");
+        let path = ACCESS_LOG_PATH.join(format!("access-{date}.ndjson"));
+
+        // Serialize writers so lines from concurrent sessions never interleave.
+        let _lock = self.lock.lock().await;
+        tokio::fs::create_dir_all(&*ACCESS_LOG_PATH).await?;
+        tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?
+            .write_all(&line)
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1235 @@
+use crate::audit::{Actor, AuditLog, AuditRecord};
+use crate::ban::{Ban, BanStore, BanTarget};
+use crate::built_info;
+use crate::config::{
+    AdminConfig, AdminRole, AdminToken, CCProxyConfig, ControlConfig, DisconnectMessagesConfig,
+    LogFilterHandle, TransferConfig, UpstreamAddress,
+};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::geoip::GeoInfo;
+use crate::metrics::packets::{PACKET_STATS, PacketIdStats};
+use crate::metrics::{FORWARDED_BYTES_TOTAL, METRICS, SESSIONS_EVICTED_TOTAL};
+use crate::network::circuit::CircuitBreaker;
+use crate::network::dns::UpstreamAddresses;
+use crate::network::health::UpstreamHealth;
+use crate::network::latency::LatencyProbes;
+use crate::network::session::{Eviction, Session, SessionRegistry};
+use crate::scheduler;
+use crate::state::ProxyState;
+use events::{EVENTS, Event};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+
+pub mod events;
+pub mod grpc;
+pub mod http;
+
+/// A command line longer than this closes the connection.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+/// The runtime of a listener which the admin commands act on.
+#[derive(Clone)]
+pub struct ListenerHandle {
+    pub address: SocketAddr,
+
+    pub state: Arc<ProxyState>,
+
+    pub sessions: Arc<SessionRegistry>,
+
+    pub disconnect_messages: DisconnectMessagesConfig,
+
+    /// The upstream pool which the listener is started with.
+    pub upstreams: Vec<UpstreamAddress>,
+
+    pub upstream_addresses: Arc<UpstreamAddresses>,
+
+    pub health: Arc<UpstreamHealth>,
+
+    pub breaker: Arc<CircuitBreaker>,
+
+    pub probes: Arc<LatencyProbes>,
+
+    /// The commands which the listener applies on its own subsystems.
+    pub commands: mpsc::UnboundedSender<ListenerCommand>,
+}
+
+/// A change which a listener applies itself, as it may start a maintenance countdown or
+/// an upstream drain.
+pub enum ListenerCommand {
+    /// Reload the config, answering whether it could be loaded.
+    Reload(oneshot::Sender<CCProxyResult<()>>),
+
+    /// Enter or leave the maintenance mode.
+    Maintenance(bool),
+}
+
+/// The control plane of the process, which is only served on the admin listener and
+/// never on the game ports.
+pub struct Admin {
+    start_time: Instant,
+
+    /// The listeners by name, where the main listener has none.
+    listeners: RwLock<BTreeMap<Option<String>, ListenerHandle>>,
+
+    bans: Arc<BanStore>,
+
+    /// The bearer tokens, where every command is allowed if empty.
+    tokens: Vec<AdminToken>,
+
+    /// Where the commands which change anything are recorded.
+    audit: AuditLog,
+
+    /// The log filters of the process, which only a running proxy has.
+    log_filter: Option<LogFilterHandle>,
+}
+
+/// A command, sent as a JSON object per line such as `{"command":"status"}`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum AdminRequest {
+    /// The version, the uptime, and the state of every listener.
+    Status,
+
+    /// Ban an IP, and an XUID with it, and kick the online sessions of either.
+    Ban {
+        ip: IpAddr,
+
+        #[serde(default)]
+        xuid: Option<String>,
+
+        #[serde(default)]
+        reason: String,
+
+        /// The ban is permanent if unset.
+        #[serde(default)]
+        duration_secs: Option<u64>,
+    },
+
+    /// Lift the bans of an IP or an XUID.
+    Unban { target: String },
+
+    /// The bans in effect.
+    ListBans,
+
+    /// The live sessions of every listener.
+    Sessions,
+
+    /// The upstreams of every listener, with their health and circuits.
+    Upstreams,
+
+    /// Enter or leave the maintenance mode of a listener, or of every listener if unset.
+    Maintenance {
+        enabled: bool,
+
+        #[serde(default)]
+        listener: Option<String>,
+    },
+
+    /// Reload the config of every listener, as SIGHUP does.
+    Reload,
+
+    /// Kick the online sessions of a client address, an IP, a session ID, an XUID, or a
+    /// gamertag.
+    Kick {
+        target: String,
+
+        /// The kick message of the listener is shown if unset.
+        #[serde(default)]
+        message: Option<String>,
+    },
+
+    /// Move the online sessions of a listener, or of every listener if unset, to another
+    /// server with the Transfer packet. Encrypted sessions cannot be moved and stay.
+    Transfer {
+        host: String,
+
+        port: u16,
+
+        #[serde(default)]
+        listener: Option<String>,
+    },
+
+    /// The game packet bytes forwarded since the start, by direction.
+    Traffic,
+
+    /// The recent events, the newest first.
+    Events,
+
+    /// The forwarded game packets by packet ID in each direction, the most bytes first.
+    PacketStats,
+
+    /// Replace the stdout or the file log filter, e.g. `info,ccproxy=debug`, and return
+    /// the filters in effect. Nothing is replaced if both are unset.
+    LogFilter {
+        #[serde(default)]
+        stdout: Option<String>,
+
+        #[serde(default)]
+        file: Option<String>,
+    },
+}
+
+impl AdminRequest {
+    /// The name of the command, as in the `command` field.
+    pub fn command(&self) -> &'static str {
+        match self {
+            Self::Status => "status",
+            Self::Ban { .. } => "ban",
+            Self::Unban { .. } => "unban",
+            Self::ListBans => "list_bans",
+            Self::Sessions => "sessions",
+            Self::Upstreams => "upstreams",
+            Self::Maintenance { .. } => "maintenance",
+            Self::Reload => "reload",
+            Self::Kick { .. } => "kick",
+            Self::Transfer { .. } => "transfer",
+            Self::Traffic => "traffic",
+            Self::Events => "events",
+            Self::PacketStats => "packet_stats",
+            Self::LogFilter { .. } => "log_filter",
+        }
+    }
+
+    /// The role which may run the command.
+    pub fn role(&self) -> AdminRole {
+        match self {
+            Self::Status
+            | Self::ListBans
+            | Self::Sessions
+            | Self::Upstreams
+            | Self::Traffic
+            | Self::Events
+            | Self::PacketStats
+            | Self::LogFilter {
+                stdout: None,
+                file: None,
+            } => AdminRole::ReadOnly,
+            Self::Ban { .. }
+            | Self::Unban { .. }
+            | Self::Maintenance { .. }
+            | Self::Reload
+            | Self::Kick { .. }
+            | Self::Transfer { .. }
+            | Self::LogFilter { .. } => AdminRole::Operator,
+        }
+    }
+}
+
+/// What the sessions to kick are found by.
+enum KickTarget {
+    Address(SocketAddr),
+
+    Ip(IpAddr),
+
+    /// A session ID, an XUID, or a gamertag.
+    Id(String),
+}
+
+impl KickTarget {
+    fn parse(target: &str) -> Self {
+        if let Ok(address) = target.parse() {
+            Self::Address(address)
+        } else if let Ok(ip) = target.parse() {
+            Self::Ip(ip)
+        } else {
+            Self::Id(target.to_owned())
+        }
+    }
+
+    fn matches(&self, session: &Session) -> bool {
+        // A dual-stack socket reports IPv4 clients as IPv4-mapped IPv6 addresses.
+        let ip = session.client_address.ip().to_canonical();
+        match self {
+            Self::Address(address) => {
+                ip == address.ip().to_canonical() && session.client_address.port() == address.port()
+            }
+            Self::Ip(target) => ip == target.to_canonical(),
+            Self::Id(id) => {
+                if session.id.eq_ignore_ascii_case(id) {
+                    return true;
+                }
+
+                let handshake = session.handshake.lock().unwrap();
+                handshake.identity.as_ref().is_some_and(|i| {
+                    i.xuid.as_deref() == Some(id)
+                        || i.display_name
+                            .as_deref()
+                            .is_some_and(|n| n.eq_ignore_ascii_case(id))
+                })
+            }
+        }
+    }
+}
+
+/// The version, the uptime, and the state of every listener.
+#[derive(Serialize)]
+pub struct Status {
+    pub version: &'static str,
+
+    pub uptime_secs: u64,
+
+    pub listeners: Vec<ListenerStatus>,
+}
+
+#[derive(Serialize)]
+pub struct ListenerStatus {
+    pub name: Option<String>,
+
+    pub address: SocketAddr,
+
+    pub sessions: usize,
+
+    pub draining: bool,
+
+    pub maintenance: bool,
+}
+
+/// A live session of a listener.
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub listener: Option<String>,
+
+    pub id: String,
+
+    pub client_address: SocketAddr,
+
+    pub upstream_address: SocketAddr,
+
+    /// Unix timestamp in seconds.
+    pub connected_at: u64,
+
+    /// Unix timestamp in seconds when a packet was last forwarded either way.
+    pub last_activity: u64,
+
+    pub name: Option<String>,
+
+    pub xuid: Option<String>,
+
+    pub geo: GeoInfo,
+
+    /// The RTT of the upstream server, as the latency prober measured it last.
+    pub upstream_rtt_ms: Option<f64>,
+
+    pub bytes_c2s: u64,
+
+    pub bytes_s2c: u64,
+}
+
+/// The game packet bytes forwarded since the start, summed over every listener.
+#[derive(Serialize)]
+pub struct Traffic {
+    pub bytes_c2s: u64,
+
+    pub bytes_s2c: u64,
+}
+
+/// An upstream of the pool of a listener.
+#[derive(Serialize)]
+pub struct UpstreamInfo {
+    pub listener: Option<String>,
+
+    pub upstream: UpstreamAddress,
+
+    /// Whether it is removed by a reload and takes no new sessions.
+    pub drained: bool,
+
+    /// The servers which the upstream address resolves to.
+    pub targets: Vec<UpstreamTargetInfo>,
+}
+
+#[derive(Serialize)]
+pub struct UpstreamTargetInfo {
+    pub address: SocketAddr,
+
+    pub healthy: bool,
+
+    pub circuit: &'static str,
+
+    pub sessions: usize,
+
+    /// The RTT of the last answered latency probe.
+    pub rtt_ms: Option<f64>,
+}
+
+impl Admin {
+    pub fn new(bans: Arc<BanStore>, tokens: Vec<AdminToken>) -> Self {
+        Self {
+            start_time: Instant::now(),
+            listeners: Default::default(),
+            bans,
+            tokens,
+            audit: AuditLog::new(),
+            log_filter: None,
+        }
+    }
+
+    pub fn with_log_filter(mut self, log_filter: LogFilterHandle) -> Self {
+        self.log_filter = Some(log_filter);
+        self
+    }
+
+    /// Check that the bearer `token` may run `request`, and return the name of the token
+    /// for the audit log.
+    pub fn authorize(
+        &self,
+        token: Option<&str>,
+        request: &AdminRequest,
+    ) -> CCProxyResult<Option<String>> {
+        self.authorize_command(token, request.command(), request.role())
+    }
+
+    /// Check that the bearer `token` may run `command`, which needs `role`, and return the
+    /// name of the token, or its role if unnamed.
+    pub fn authorize_command(
+        &self,
+        token: Option<&str>,
+        command: &str,
+        role: AdminRole,
+    ) -> CCProxyResult<Option<String>> {
+        if self.tokens.is_empty() {
+            return Ok(None);
+        }
+
+        let granted = token
+            .and_then(|token| {
+                self.tokens
+                    .iter()
+                    .find(|t| constant_time_eq(t.token.as_bytes(), token.as_bytes()))
+            })
+            .ok_or(CCProxyError::AdminUnauthorized)?;
+        if granted.role < role {
+            return Err(CCProxyError::AdminForbidden {
+                command: command.to_owned(),
+            });
+        }
+
+        Ok(Some(
+            granted
+                .name
+                .clone()
+                .unwrap_or_else(|| granted.role.as_str().to_owned()),
+        ))
+    }
+
+    /// Authorize `request` with the bearer `token` and run it, recording it in the audit
+    /// log if it changes anything, whether it succeeds or not.
+    pub async fn run(
+        &self,
+        mut actor: Actor,
+        token: Option<&str>,
+        request: AdminRequest,
+    ) -> CCProxyResult<serde_json::Value> {
+        let command = request.command();
+        let audited = request.role() == AdminRole::Operator;
+        let parameters = if audited {
+            parameters(&request)?
+        } else {
+            serde_json::Value::Null
+        };
+
+        let result = match self.authorize(token, &request) {
+            Ok(name) => {
+                actor.token = name;
+                self.handle(request).await
+            }
+            Err(err) => Err(err),
+        };
+
+        if audited {
+            self.audit(&actor, command, parameters, result.as_ref().err())
+                .await;
+        }
+
+        result
+    }
+
+    /// Record an admin action in the audit log, with `error` if it failed.
+    pub async fn audit(
+        &self,
+        actor: &Actor,
+        command: &'static str,
+        parameters: serde_json::Value,
+        error: Option<&CCProxyError>,
+    ) {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let record = AuditRecord {
+            timestamp,
+            actor,
+            command,
+            parameters,
+            error: error.map(ToString::to_string),
+        };
+
+        if let Err(err) = self.audit.append(&record).await {
+            tracing::error!("Cannot write the audit log: {err}");
+        }
+    }
+
+    pub fn register(&self, name: Option<String>, handle: ListenerHandle) {
+        self.listeners.write().unwrap().insert(name, handle);
+    }
+
+    pub fn listeners(&self) -> Vec<(Option<String>, ListenerHandle)> {
+        self.listeners
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, handle)| (name.clone(), handle.clone()))
+            .collect()
+    }
+
+    /// The listener named `name`, or every listener if unset.
+    fn select(&self, name: Option<&str>) -> CCProxyResult<Vec<(Option<String>, ListenerHandle)>> {
+        let listeners = self.listeners();
+        let Some(name) = name else {
+            return Ok(listeners);
+        };
+
+        let selected = listeners
+            .into_iter()
+            .filter(|(n, _)| n.as_deref() == Some(name))
+            .collect::<Vec<_>>();
+        if selected.is_empty() {
+            return Err(CCProxyError::ListenerNotFound {
+                name: name.to_owned(),
+            });
+        }
+
+        Ok(selected)
+    }
+
+    pub async fn status(&self) -> Status {
+        let mut listeners = vec![];
+        for (name, handle) in self.listeners() {
+            listeners.push(ListenerStatus {
+                name,
+                address: handle.address,
+                sessions: handle.sessions.sessions().await.len(),
+                draining: handle.state.is_draining(),
+                maintenance: handle.state.is_maintenance(),
+            });
+        }
+
+        Status {
+            version: built_info::PKG_VERSION,
+            uptime_secs: self.start_time.elapsed().as_secs(),
+            listeners,
+        }
+    }
+
+    pub async fn sessions(&self) -> Vec<SessionInfo> {
+        let mut sessions = vec![];
+        for (name, handle) in self.listeners() {
+            for session in handle.sessions.sessions().await {
+                let identity = session.handshake.lock().unwrap().identity.clone();
+                let connected_at = session
+                    .connected_at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                sessions.push(SessionInfo {
+                    listener: name.clone(),
+                    id: session.id.clone(),
+                    client_address: session.client_address,
+                    upstream_address: session.upstream_address,
+                    connected_at: connected_at.as_secs(),
+                    last_activity: session.last_activity.load(Ordering::Relaxed),
+                    name: identity.as_ref().and_then(|i| i.display_name.clone()),
+                    xuid: identity.and_then(|i| i.xuid),
+                    geo: session.geo.clone(),
+                    upstream_rtt_ms: handle
+                        .probes
+                        .stats(&session.upstream_address)
+                        .and_then(|s| s.rtt_ms),
+                    bytes_c2s: session.bytes_c2s.load(Ordering::Relaxed),
+                    bytes_s2c: session.bytes_s2c.load(Ordering::Relaxed),
+                });
+            }
+        }
+
+        sessions
+    }
+
+    pub fn upstreams(&self) -> Vec<UpstreamInfo> {
+        let mut upstreams = vec![];
+        for (name, handle) in self.listeners() {
+            let drained = handle.state.drained_upstreams();
+            for upstream in &handle.upstreams {
+                let targets = handle
+                    .upstream_addresses
+                    .targets(upstream)
+                    .into_iter()
+                    .map(|address| UpstreamTargetInfo {
+                        address,
+                        healthy: handle.health.is_healthy(&address),
+                        circuit: handle.breaker.state(&address),
+                        sessions: handle.sessions.upstream_load(&address),
+                        rtt_ms: handle.probes.stats(&address).and_then(|s| s.rtt_ms),
+                    })
+                    .collect();
+                upstreams.push(UpstreamInfo {
+                    listener: name.clone(),
+                    upstream: upstream.clone(),
+                    drained: drained.contains(upstream),
+                    targets,
+                });
+            }
+        }
+
+        upstreams
+    }
+
+    pub fn traffic(&self) -> Traffic {
+        let mut traffic = Traffic {
+            bytes_c2s: 0,
+            bytes_s2c: 0,
+        };
+        for sample in METRICS.snapshot() {
+            if sample.desc.name != FORWARDED_BYTES_TOTAL.name {
+                continue;
+            }
+
+            match sample.labels.iter().find(|(k, _)| *k == "direction") {
+                Some((_, d)) if d == "c2s" => traffic.bytes_c2s += sample.value as u64,
+                Some((_, d)) if d == "s2c" => traffic.bytes_s2c += sample.value as u64,
+                _ => (),
+            }
+        }
+
+        traffic
+    }
+
+    /// Replace the log filters which are given, and return the stdout and the file
+    /// filters in effect.
+    pub fn set_log_filter(
+        &self,
+        stdout: Option<&str>,
+        file: Option<&str>,
+    ) -> CCProxyResult<(String, String)> {
+        let Some(log_filter) = &self.log_filter else {
+            return Err(CCProxyError::LogFilterUnavailable);
+        };
+
+        if stdout.is_some() || file.is_some() {
+            log_filter.set(stdout, file)?;
+            let (stdout, file) = log_filter.filters();
+            tracing::info!(
+                "The log filters are changed to `{stdout}` on stdout and `{file}` in the file."
+            );
+            EVENTS.push(
+                "log_filter",
+                format!("The log filters are changed to `{stdout}` and `{file}`."),
+            );
+        }
+
+        Ok(log_filter.filters())
+    }
+
+    pub fn events(&self) -> Vec<Event> {
+        EVENTS.recent(usize::MAX)
+    }
+
+    pub fn packet_stats(&self) -> CCProxyResult<Vec<PacketIdStats>> {
+        if !PACKET_STATS.is_enabled() {
+            return Err(CCProxyError::PacketStatsDisabled);
+        }
+
+        Ok(PACKET_STATS.top(usize::MAX))
+    }
+
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.bans()
+    }
+
+    /// Add `ban`, and kick the online sessions which it applies to. Return how many are
+    /// kicked.
+    pub async fn ban(&self, ban: &Ban) -> CCProxyResult<usize> {
+        self.bans.add(ban.clone()).await?;
+        let kicked = self.kick_banned(ban).await;
+        tracing::info!("{} is banned, and {kicked} sessions are kicked.", ban.ip);
+        EVENTS.push(
+            "ban",
+            format!("{} is banned, and {kicked} sessions are kicked.", ban.ip),
+        );
+
+        Ok(kicked)
+    }
+
+    /// Lift the bans of an IP or an XUID, and return them.
+    pub async fn unban(&self, target: &str) -> CCProxyResult<Vec<Ban>> {
+        let removed = self.bans.remove(&BanTarget::parse(target)).await?;
+        if !removed.is_empty() {
+            tracing::info!("{target} is unbanned.");
+            EVENTS.push("unban", format!("{target} is unbanned."));
+        }
+
+        Ok(removed)
+    }
+
+    /// Kick the online sessions of `target` with `message`, or the kick message of each
+    /// listener if unset. Return how many are kicked.
+    pub async fn kick(&self, target: &str, message: Option<&str>) -> usize {
+        let kick_target = KickTarget::parse(target);
+
+        let mut kicked = 0;
+        for (_, handle) in self.listeners() {
+            let message = message.unwrap_or(&handle.disconnect_messages.kick);
+            for session in handle.sessions.sessions().await {
+                if kick_target.matches(&session) {
+                    session.evict(Eviction::Kick(message.to_owned()));
+                    kicked += 1;
+                }
+            }
+        }
+
+        if kicked > 0 {
+            METRICS.counter_add(
+                &SESSIONS_EVICTED_TOTAL,
+                &[("reason", "kick")],
+                kicked as f64,
+            );
+        }
+        tracing::info!("{kicked} sessions of {target} are kicked.");
+        EVENTS.push("kick", format!("{kicked} sessions of {target} are kicked."));
+
+        kicked
+    }
+
+    /// Move the online sessions of the listener named `listener`, or of every listener if
+    /// unset, to `host:port`. Return how many are moved.
+    pub async fn transfer(
+        &self,
+        host: &str,
+        port: u16,
+        listener: Option<&str>,
+    ) -> CCProxyResult<usize> {
+        let transfer = TransferConfig {
+            host: host.to_owned(),
+            port,
+        };
+
+        let mut transferred = 0;
+        for (_, handle) in self.select(listener)? {
+            transferred += scheduler::transfer_sessions(&handle.sessions, &transfer).await;
+        }
+        EVENTS.push(
+            "transfer",
+            format!("{transferred} sessions are transferred to {host}:{port}."),
+        );
+
+        Ok(transferred)
+    }
+
+    /// Enter or leave the maintenance mode of the listener named `listener`, or of every
+    /// listener if unset. Return how many listeners are affected.
+    pub fn set_maintenance(&self, enabled: bool, listener: Option<&str>) -> CCProxyResult<usize> {
+        let listeners = self.select(listener)?;
+        for (_, handle) in &listeners {
+            handle
+                .commands
+                .send(ListenerCommand::Maintenance(enabled))
+                .ok();
+        }
+        let state = if enabled { "on" } else { "off" };
+        EVENTS.push(
+            "maintenance",
+            format!(
+                "The maintenance mode is {state} on {} listeners.",
+                listeners.len()
+            ),
+        );
+
+        Ok(listeners.len())
+    }
+
+    /// Reload the config of every listener, and return how many are reloaded.
+    pub async fn reload(&self) -> CCProxyResult<usize> {
+        let mut replies = vec![];
+        for (_, handle) in self.listeners() {
+            let (reply, receiver) = oneshot::channel();
+            if handle.commands.send(ListenerCommand::Reload(reply)).is_ok() {
+                replies.push(receiver);
+            }
+        }
+
+        let reloaded = replies.len();
+        for reply in replies {
+            // A listener which is stopping does not answer.
+            if let Ok(result) = reply.await {
+                result?;
+            }
+        }
+        EVENTS.push(
+            "reload",
+            format!("The config of {reloaded} listeners is reloaded."),
+        );
+
+        Ok(reloaded)
+    }
+
+    pub async fn handle(&self, request: AdminRequest) -> CCProxyResult<serde_json::Value> {
+        match request {
+            AdminRequest::Status => Ok(serde_json::to_value(self.status().await)?),
+            AdminRequest::Ban {
+                ip,
+                xuid,
+                reason,
+                duration_secs,
+            } => {
+                let ban = Ban::new(ip, xuid, reason, duration_secs.map(Duration::from_secs))?;
+                let kicked = self.ban(&ban).await?;
+
+                Ok(serde_json::json!({ "ban": ban, "kicked": kicked }))
+            }
+            AdminRequest::Unban { target } => {
+                let removed = self.unban(&target).await?;
+
+                Ok(serde_json::json!({ "removed": removed }))
+            }
+            AdminRequest::ListBans => Ok(serde_json::json!({ "bans": self.bans() })),
+            AdminRequest::Sessions => Ok(serde_json::json!({ "sessions": self.sessions().await })),
+            AdminRequest::Upstreams => Ok(serde_json::json!({ "upstreams": self.upstreams() })),
+            AdminRequest::Maintenance { enabled, listener } => {
+                let listeners = self.set_maintenance(enabled, listener.as_deref())?;
+
+                Ok(serde_json::json!({ "maintenance": enabled, "listeners": listeners }))
+            }
+            AdminRequest::Reload => {
+                let reloaded = self.reload().await?;
+
+                Ok(serde_json::json!({ "reloaded": reloaded }))
+            }
+            AdminRequest::Kick { target, message } => {
+                let kicked = self.kick(&target, message.as_deref()).await;
+
+                Ok(serde_json::json!({ "kicked": kicked }))
+            }
+            AdminRequest::Transfer {
+                host,
+                port,
+                listener,
+            } => {
+                let transferred = self.transfer(&host, port, listener.as_deref()).await?;
+
+                Ok(serde_json::json!({ "transferred": transferred }))
+            }
+            AdminRequest::Traffic => Ok(serde_json::to_value(self.traffic())?),
+            AdminRequest::Events => Ok(serde_json::json!({ "events": self.events() })),
+            AdminRequest::PacketStats => Ok(serde_json::json!({ "packets": self.packet_stats()? })),
+            AdminRequest::LogFilter { stdout, file } => {
+                let (stdout, file) = self.set_log_filter(stdout.as_deref(), file.as_deref())?;
+
+                Ok(serde_json::json!({ "stdout": stdout, "file": file }))
+            }
+        }
+    }
+
+    /// Kick the online sessions which `ban` applies to, and return how many.
+    async fn kick_banned(&self, ban: &Ban) -> usize {
+        let mut kicked = 0;
+        for (_, handle) in self.listeners() {
+            for session in handle.sessions.sessions().await {
+                let xuid = session
+                    .handshake
+                    .lock()
+                    .unwrap()
+                    .identity
+                    .as_ref()
+                    .and_then(|i| i.xuid.clone());
+                if ban.matches(session.client_address.ip(), xuid.as_deref()) {
+                    session.evict(Eviction::Kick(handle.disconnect_messages.ban.clone()));
+                    kicked += 1;
+                }
+            }
+        }
+
+        kicked
+    }
+
+    /// Answer the commands of a connection of `actor`, one JSON object per line each way.
+    async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        sub_sys: &SubsystemHandle<CCProxyError>,
+        stream: S,
+        actor: Actor,
+    ) -> CCProxyResult<()> {
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut reader = BufReader::new(reader);
+
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let mut limited = (&mut reader).take(MAX_REQUEST_LEN as u64);
+            let len = tokio::select! {
+                len = limited.read_line(&mut line) => len?,
+                // Shutdown handler
+                _ = sub_sys.on_shutdown_requested() => break,
+            };
+            if len == 0 || !line.ends_with('\n') {
+                break;
+            }
+
+            let response = match parse_request(&line) {
+                Ok((token, request)) => {
+                    match self.run(actor.clone(), token.as_deref(), request).await {
+                        Ok(result) => serde_json::json!({ "ok": true, "result": result }),
+                        Err(err) => serde_json::json!({ "ok": false, "error": err.to_string() }),
+                    }
+                }
+                Err(err) => serde_json::json!({ "ok": false, "error": err.to_string() }),
+            };
+
+            let mut response = response.to_string();
+            response.push('\n');
+            writer.write_all(response.as_bytes()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Send `request` to the admin listener of a running proxy and return its result,
+/// preferring the Unix socket of `config` if any.
+pub async fn send(
+    config: &AdminConfig,
+    request: &AdminRequest,
+) -> CCProxyResult<serde_json::Value> {
+    let line = request_line(request, config.client_token()?)?;
+
+    #[cfg(unix)]
+    if let Some(path) = &config.unix_socket {
+        let stream = tokio::net::UnixStream::connect(path).await?;
+        return exchange(stream, &line).await;
+    }
+
+    let Some(address) = config.address else {
+        return Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into());
+    };
+    let stream = tokio::net::TcpStream::connect(address).await?;
+
+    exchange(stream, &line).await
+}
+
+/// Send `request` to the control socket of a running proxy and return its result, with
+/// the admin token of `config` if any.
+pub async fn send_control(
+    config: &CCProxyConfig,
+    request: &AdminRequest,
+) -> CCProxyResult<serde_json::Value> {
+    let token = match &config.admin {
+        Some(admin) => admin.client_token()?,
+        None => None,
+    };
+    let line = request_line(request, token)?;
+
+    #[cfg(unix)]
+    {
+        let stream = tokio::net::UnixStream::connect(&config.control.path).await?;
+        exchange(stream, &line).await
+    }
+
+    #[cfg(windows)]
+    {
+        let stream =
+            tokio::net::windows::named_pipe::ClientOptions::new().open(&config.control.path)?;
+        exchange(stream, &line).await
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (config, line);
+        Err(CCProxyError::UnixSocketUnsupported)
+    }
+}
+
+fn request_line(request: &AdminRequest, token: Option<String>) -> CCProxyResult<String> {
+    let mut value = serde_json::to_value(request)?;
+    if let Some(token) = token {
+        value["token"] = token.into();
+    }
+
+    let mut line = value.to_string();
+    line.push('\n');
+
+    Ok(line)
+}
+
+/// Parse a command line, which carries its bearer token in the `token` field.
+fn parse_request(line: &str) -> CCProxyResult<(Option<String>, AdminRequest)> {
+    let value = serde_json::from_str::<serde_json::Value>(line)?;
+    let token = value["token"].as_str().map(ToOwned::to_owned);
+    let request = serde_json::from_value::<AdminRequest>(value)?;
+
+    Ok((token, request))
+}
+
+/// The fields of `request` without its name, as written to the audit log.
+fn parameters(request: &AdminRequest) -> CCProxyResult<serde_json::Value> {
+    let mut parameters = serde_json::to_value(request)?;
+    if let Some(fields) = parameters.as_object_mut() {
+        fields.remove("command");
+    }
+
+    Ok(parameters)
+}
+
+/// Compare the tokens in a time which does not leak how long their common prefix is.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    line: &str,
+) -> CCProxyResult<serde_json::Value> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    writer.write_all(line.as_bytes()).await?;
+
+    let mut response = String::new();
+    BufReader::new(reader)
+        .take(MAX_REQUEST_LEN as u64)
+        .read_line(&mut response)
+        .await?;
+    let mut response = serde_json::from_str::<serde_json::Value>(&response)?;
+
+    if response["ok"].as_bool() == Some(true) {
+        Ok(response["result"].take())
+    } else {
+        Err(CCProxyError::AdminCommandFailed {
+            reason: response["error"].as_str().unwrap_or_default().to_owned(),
+        })
+    }
+}
+
+/// Serve the admin commands on the loopback address, the Unix socket, the REST API, and
+/// the gRPC API of `config`, which are separate from the game listeners.
+pub async fn run_admin_listener(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: AdminConfig,
+    admin: Arc<Admin>,
+) -> CCProxyResult<()> {
+    if let Some(address) = config.address {
+        config.check_address(address)?;
+
+        let listener = TcpListener::bind(address).await?;
+        tracing::info!("The admin listener is started on {address}.");
+
+        let tcp_admin = admin.clone();
+        sub_sys.start(SubsystemBuilder::new("AdminListener_Tcp", move |sub| async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let (stream, client_address) = match accepted {
+                            Ok(accepted) => accepted,
+                            Err(err) => {
+                                tracing::debug!("Cannot accept an admin connection: {err}");
+                                continue;
+                            }
+                        };
+
+                        let conn_admin = tcp_admin.clone();
+                        sub.start(SubsystemBuilder::new(format!("Admin_{client_address}"), move |sub| async move {
+                            let actor = Actor::new("tcp", Some(client_address));
+                            if let Err(err) = conn_admin.serve(&sub, stream, actor).await {
+                                tracing::debug!("The admin connection ({client_address}) error is occurred: {err}");
+                            }
+
+                            Ok::<_, CCProxyError>(())
+                        }));
+                    },
+                    // Shutdown handler
+                    _ = sub.on_shutdown_requested() => {
+                        break;
+                    }
+                }
+            }
+
+            Ok::<_, CCProxyError>(())
+        }));
+    }
+
+    if let Some(address) = config.http_address {
+        config.check_address(address)?;
+
+        http::start(&sub_sys, address, admin.clone()).await?;
+    }
+
+    if let Some(address) = config.grpc_address {
+        config.check_address(address)?;
+
+        grpc::start(&sub_sys, address, admin.clone()).await?;
+    }
+
+    if let Some(path) = config.unix_socket {
+        #[cfg(unix)]
+        unix::start(&sub_sys, path, admin, "unix")?;
+
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            return Err(CCProxyError::UnixSocketUnsupported);
+        }
+    }
+
+    sub_sys.on_shutdown_requested().await;
+
+    Ok(())
+}
+
+/// Serve the admin commands on the control socket of `config`, which `ccproxy ctl` talks
+/// to.
+pub async fn run_control_socket(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: ControlConfig,
+    admin: Arc<Admin>,
+) -> CCProxyResult<()> {
+    #[cfg(unix)]
+    unix::start(&sub_sys, config.path, admin, "control")?;
+
+    #[cfg(windows)]
+    windows::start(&sub_sys, config.path, admin)?;
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (config, admin);
+        return Err(CCProxyError::UnixSocketUnsupported);
+    }
+
+    sub_sys.on_shutdown_requested().await;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+mod unix {
+    use super::Admin;
+    use crate::audit::Actor;
+    use crate::error::{CCProxyError, CCProxyResult};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::net::UnixListener;
+    use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+
+    /// Serve the admin commands on a Unix socket which only the owner can connect to, as
+    /// `via` in the audit log.
+    pub fn start(
+        sub_sys: &SubsystemHandle<CCProxyError>,
+        path: PathBuf,
+        admin: Arc<Admin>,
+        via: &'static str,
+    ) -> CCProxyResult<()> {
+        // The socket of a previous process is left behind if it did not stop cleanly.
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+        tracing::info!("The admin listener is started on {}.", path.display());
+
+        sub_sys.start(SubsystemBuilder::new("AdminListener_Unix", move |sub| async move {
+            loop {
+                tokio::select! {
+                    accepted = listener.accept() => {
+                        let stream = match accepted {
+                            Ok((stream, _)) => stream,
+                            Err(err) => {
+                                tracing::debug!("Cannot accept an admin connection: {err}");
+                                continue;
+                            }
+                        };
+
+                        let conn_admin = admin.clone();
+                        sub.start(SubsystemBuilder::new("Admin_Unix", move |sub| async move {
+                            if let Err(err) = conn_admin.serve(&sub, stream, Actor::new(via, None)).await {
+                                tracing::debug!("The admin connection error is occurred: {err}");
+                            }
+
+                            Ok::<_, CCProxyError>(())
+                        }));
+                    },
+                    // Shutdown handler
+                    _ = sub.on_shutdown_requested() => {
+                        break;
+                    }
+                }
+            }
+
+            std::fs::remove_file(&path).ok();
+
+            Ok::<_, CCProxyError>(())
+        }));
+
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use super::Admin;
+    use crate::audit::Actor;
+    use crate::error::{CCProxyError, CCProxyResult};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::net::windows::named_pipe::ServerOptions;
+    use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+
+    /// Serve the admin commands on a named pipe which remote clients cannot connect to.
+    pub fn start(
+        sub_sys: &SubsystemHandle<CCProxyError>,
+        path: PathBuf,
+        admin: Arc<Admin>,
+    ) -> CCProxyResult<()> {
+        // Fails if another process serves the pipe already.
+        let mut server = ServerOptions::new()
+            .first_pipe_instance(true)
+            .create(&path)?;
+        tracing::info!("The admin listener is started on {}.", path.display());
+
+        sub_sys.start(SubsystemBuilder::new("AdminListener_Pipe", move |sub| async move {
+            loop {
+                tokio::select! {
+                    connected = server.connect() => {
+                        if let Err(err) = connected {
+                            tracing::debug!("Cannot accept an admin connection: {err}");
+                            continue;
+                        }
+
+                        // The next client connects to a new instance of the pipe.
+                        let stream = std::mem::replace(&mut server, ServerOptions::new().create(&path)?);
+                        let conn_admin = admin.clone();
+                        sub.start(SubsystemBuilder::new("Admin_Pipe", move |sub| async move {
+                            if let Err(err) = conn_admin.serve(&sub, stream, Actor::new("control", None)).await {
+                                tracing::debug!("The admin connection error is occurred: {err}");
+                            }
+
+                            Ok::<_, CCProxyError>(())
+                        }));
+                    },
+                    // Shutdown handler
+                    _ = sub.on_shutdown_requested() => {
+                        break;
+                    }
+                }
+            }
+
+            Ok::<_, CCProxyError>(())
+        }));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{LazyLock, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many events are kept for the dashboard; older ones are dropped.
+const MAX_EVENTS: usize = 200;
+
+/// The recent events of the process, shown on the dashboard.
+pub static EVENTS: LazyLock<EventLog> = LazyLock::new(EventLog::default);
+
+/// A notable change, e.g. a session start or an upstream going down.
+#[derive(Clone, Debug, Serialize)]
+pub struct Event {
+    /// Unix timestamp in seconds.
+    pub timestamp: u64,
+
+    /// e.g. `session_start`, `upstream_down`, or `ban`.
+    pub kind: &'static str,
+
+    pub message: String,
+}
+
+/// A bounded log of the latest [`Event`]s, which is kept in memory only.
+#[derive(Default)]
+pub struct EventLog {
+    events: Mutex<VecDeque<Event>>,
+}
+
+impl EventLog {
+    pub fn push(&self, kind: &'static str, message: impl Into<String>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() >= MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(Event {
+            timestamp,
+            kind,
+            message: message.into(),
+        });
+    }
+
+    /// The latest `limit` events, the newest first.
+    pub fn recent(&self, limit: usize) -> Vec<Event> {
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,317 @@
+use super::{Admin, AdminRequest, SessionInfo, UpstreamInfo};
+use crate::audit::Actor;
+use crate::ban::Ban;
+use crate::config::AdminRole;
+use crate::error::{CCProxyError, CCProxyResult};
+use proto::admin_service_server::{AdminService, AdminServiceServer};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+use tonic::transport::Server;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Request, Response, Status};
+
+/// The messages and the service of `proto/ccproxy/admin/v1/admin.proto`.
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("ccproxy.admin.v1");
+}
+
+type GrpcResult<T> = Result<Response<T>, Status>;
+
+/// Serve the admin commands as a gRPC API on `address`.
+pub async fn start(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    address: SocketAddr,
+    admin: Arc<Admin>,
+) -> CCProxyResult<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("The admin gRPC API is started on {address}.");
+
+    let service = AdminServiceServer::new(GrpcAdmin { admin });
+    sub_sys.start(SubsystemBuilder::new(
+        "AdminListener_Grpc",
+        move |sub| async move {
+            Server::builder()
+                .add_service(service)
+                .serve_with_incoming_shutdown(
+                    TcpIncoming::from(listener),
+                    sub.create_cancellation_token().cancelled_owned(),
+                )
+                .await?;
+
+            Ok::<_, CCProxyError>(())
+        },
+    ));
+
+    Ok(())
+}
+
+struct GrpcAdmin {
+    admin: Arc<Admin>,
+}
+
+impl GrpcAdmin {
+    /// Check the bearer token in the `authorization` metadata of `request`.
+    fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        command: &str,
+        role: AdminRole,
+    ) -> Result<(), Status> {
+        self.admin
+            .authorize_command(bearer_token(request), command, role)
+            .map(|_| ())
+            .map_err(status)
+    }
+
+    /// Run a command which changes anything as the other APIs do, so it is written to
+    /// the audit log.
+    async fn run<T>(
+        &self,
+        request: &Request<T>,
+        admin_request: AdminRequest,
+    ) -> Result<serde_json::Value, Status> {
+        let actor = Actor::new("grpc", request.remote_addr());
+
+        self.admin
+            .run(actor, bearer_token(request), admin_request)
+            .await
+            .map_err(status)
+    }
+}
+
+fn bearer_token<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+fn status(err: CCProxyError) -> Status {
+    match err {
+        CCProxyError::ListenerNotFound { .. } => Status::not_found(err.to_string()),
+        CCProxyError::AdminUnauthorized => Status::unauthenticated(err.to_string()),
+        CCProxyError::AdminForbidden { .. } => Status::permission_denied(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    }
+}
+
+impl From<Ban> for proto::Ban {
+    fn from(ban: Ban) -> Self {
+        Self {
+            ip: ban.ip.to_string(),
+            xuid: ban.xuid,
+            reason: ban.reason,
+            created_at: ban.created_at,
+            expires_at: ban.expires_at,
+        }
+    }
+}
+
+impl From<SessionInfo> for proto::Session {
+    fn from(session: SessionInfo) -> Self {
+        Self {
+            listener: session.listener,
+            id: session.id,
+            client_address: session.client_address.to_string(),
+            upstream_address: session.upstream_address.to_string(),
+            connected_at: session.connected_at,
+            last_activity: session.last_activity,
+            name: session.name,
+            xuid: session.xuid,
+            country: session.geo.country,
+            asn: session.geo.asn,
+            as_org: session.geo.as_org,
+            upstream_rtt_ms: session.upstream_rtt_ms,
+            bytes_c2s: session.bytes_c2s,
+            bytes_s2c: session.bytes_s2c,
+        }
+    }
+}
+
+impl From<UpstreamInfo> for proto::Upstream {
+    fn from(upstream: UpstreamInfo) -> Self {
+        Self {
+            listener: upstream.listener,
+            upstream: upstream.upstream.to_string(),
+            drained: upstream.drained,
+            targets: upstream
+                .targets
+                .into_iter()
+                .map(|t| proto::UpstreamTarget {
+                    address: t.address.to_string(),
+                    healthy: t.healthy,
+                    circuit: t.circuit.to_owned(),
+                    sessions: t.sessions as u64,
+                    rtt_ms: t.rtt_ms,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AdminService for GrpcAdmin {
+    async fn get_status(
+        &self,
+        request: Request<proto::GetStatusRequest>,
+    ) -> GrpcResult<proto::GetStatusResponse> {
+        self.authorize(&request, "GetStatus", AdminRole::ReadOnly)?;
+
+        let status = self.admin.status().await;
+
+        Ok(Response::new(proto::GetStatusResponse {
+            version: status.version.to_owned(),
+            uptime_secs: status.uptime_secs,
+            listeners: status
+                .listeners
+                .into_iter()
+                .map(|l| proto::ListenerStatus {
+                    name: l.name,
+                    address: l.address.to_string(),
+                    sessions: l.sessions as u64,
+                    draining: l.draining,
+                    maintenance: l.maintenance,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<proto::ListSessionsRequest>,
+    ) -> GrpcResult<proto::ListSessionsResponse> {
+        self.authorize(&request, "ListSessions", AdminRole::ReadOnly)?;
+
+        let sessions = self.admin.sessions().await;
+
+        Ok(Response::new(proto::ListSessionsResponse {
+            sessions: sessions.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn list_upstreams(
+        &self,
+        request: Request<proto::ListUpstreamsRequest>,
+    ) -> GrpcResult<proto::ListUpstreamsResponse> {
+        self.authorize(&request, "ListUpstreams", AdminRole::ReadOnly)?;
+
+        Ok(Response::new(proto::ListUpstreamsResponse {
+            upstreams: self.admin.upstreams().into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn list_bans(
+        &self,
+        request: Request<proto::ListBansRequest>,
+    ) -> GrpcResult<proto::ListBansResponse> {
+        self.authorize(&request, "ListBans", AdminRole::ReadOnly)?;
+
+        Ok(Response::new(proto::ListBansResponse {
+            bans: self.admin.bans().into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn ban(&self, request: Request<proto::BanRequest>) -> GrpcResult<proto::BanResponse> {
+        let message = request.get_ref();
+        let ip = message
+            .ip
+            .parse()
+            .map_err(|_| Status::invalid_argument(format!("{} is not an IP.", message.ip)))?;
+        let admin_request = AdminRequest::Ban {
+            ip,
+            xuid: message.xuid.clone(),
+            reason: message.reason.clone(),
+            duration_secs: message.duration_secs,
+        };
+
+        let mut result = self.run(&request, admin_request).await?;
+        let ban = serde_json::from_value::<Ban>(result["ban"].take())
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(proto::BanResponse {
+            ban: Some(ban.into()),
+            kicked: result["kicked"].as_u64().unwrap_or_default(),
+        }))
+    }
+
+    async fn unban(
+        &self,
+        request: Request<proto::UnbanRequest>,
+    ) -> GrpcResult<proto::UnbanResponse> {
+        let admin_request = AdminRequest::Unban {
+            target: request.get_ref().target.clone(),
+        };
+
+        let mut result = self.run(&request, admin_request).await?;
+        let removed = serde_json::from_value::<Vec<Ban>>(result["removed"].take())
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(proto::UnbanResponse {
+            removed: removed.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn kick(&self, request: Request<proto::KickRequest>) -> GrpcResult<proto::KickResponse> {
+        let admin_request = AdminRequest::Kick {
+            target: request.get_ref().target.clone(),
+            message: request.get_ref().message.clone(),
+        };
+
+        let result = self.run(&request, admin_request).await?;
+
+        Ok(Response::new(proto::KickResponse {
+            kicked: result["kicked"].as_u64().unwrap_or_default(),
+        }))
+    }
+
+    async fn transfer(
+        &self,
+        request: Request<proto::TransferRequest>,
+    ) -> GrpcResult<proto::TransferResponse> {
+        let message = request.get_ref();
+        let port = u16::try_from(message.port)
+            .map_err(|_| Status::invalid_argument(format!("{} is not a port.", message.port)))?;
+        let admin_request = AdminRequest::Transfer {
+            host: message.host.clone(),
+            port,
+            listener: message.listener.clone(),
+        };
+
+        let result = self.run(&request, admin_request).await?;
+
+        Ok(Response::new(proto::TransferResponse {
+            transferred: result["transferred"].as_u64().unwrap_or_default(),
+        }))
+    }
+
+    async fn set_maintenance(
+        &self,
+        request: Request<proto::SetMaintenanceRequest>,
+    ) -> GrpcResult<proto::SetMaintenanceResponse> {
+        let admin_request = AdminRequest::Maintenance {
+            enabled: request.get_ref().enabled,
+            listener: request.get_ref().listener.clone(),
+        };
+
+        let result = self.run(&request, admin_request).await?;
+
+        Ok(Response::new(proto::SetMaintenanceResponse {
+            listeners: result["listeners"].as_u64().unwrap_or_default(),
+        }))
+    }
+
+    async fn reload(
+        &self,
+        request: Request<proto::ReloadRequest>,
+    ) -> GrpcResult<proto::ReloadResponse> {
+        let result = self.run(&request, AdminRequest::Reload).await?;
+
+        Ok(Response::new(proto::ReloadResponse {
+            reloaded: result["reloaded"].as_u64().unwrap_or_default(),
+        }))
+    }
+}
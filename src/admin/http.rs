@@ -0,0 +1,343 @@
+use super::{Admin, AdminRequest};
+use crate::audit::Actor;
+use crate::error::{CCProxyError, CCProxyResult};
+use axum::extract::{ConnectInfo, Path, Request, State};
+use axum::http::header::{AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use axum::http::{HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{Html, IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Json, Router};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+
+type ApiResult = Result<Json<serde_json::Value>, ApiError>;
+
+/// The single page which polls the API below, served at `/`.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// An error of an admin command, answered as `{"code":..,"error":..}`.
+struct ApiError(CCProxyError);
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            CCProxyError::TracingSubscriberParse { .. } | CCProxyError::Json { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            CCProxyError::ListenerNotFound { .. }
+            | CCProxyError::PacketStatsDisabled
+            | CCProxyError::LogFilterUnavailable => StatusCode::NOT_FOUND,
+            // The config on disk is refused, so the running one is kept.
+            CCProxyError::Config { .. }
+            | CCProxyError::ConfigProfileNotFound { .. }
+            | CCProxyError::Yaml { .. } => StatusCode::CONFLICT,
+            CCProxyError::AdminUnauthorized => StatusCode::UNAUTHORIZED,
+            CCProxyError::AdminForbidden { .. } | CCProxyError::AdminCrossSiteRequest => {
+                StatusCode::FORBIDDEN
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        let body = serde_json::json!({ "code": self.0.code(), "error": self.0.to_string() });
+
+        if status == StatusCode::UNAUTHORIZED {
+            return (status, [(WWW_AUTHENTICATE, "Bearer")], Json(body)).into_response();
+        }
+        (status, Json(body)).into_response()
+    }
+}
+
+/// The body of `POST /v1/bans`.
+#[derive(Deserialize)]
+struct BanBody {
+    ip: IpAddr,
+
+    #[serde(default)]
+    xuid: Option<String>,
+
+    #[serde(default)]
+    reason: String,
+
+    #[serde(default)]
+    duration_secs: Option<u64>,
+}
+
+/// The body of `PUT /v1/maintenance`.
+#[derive(Deserialize)]
+struct MaintenanceBody {
+    enabled: bool,
+
+    #[serde(default)]
+    listener: Option<String>,
+}
+
+/// The body of `POST /v1/kick`.
+#[derive(Deserialize)]
+struct KickBody {
+    target: String,
+
+    #[serde(default)]
+    message: Option<String>,
+}
+
+/// The body of `POST /v1/transfer`.
+#[derive(Deserialize)]
+struct TransferBody {
+    host: String,
+
+    port: u16,
+
+    #[serde(default)]
+    listener: Option<String>,
+}
+
+/// The body of `PUT /v1/log-filter`.
+#[derive(Deserialize)]
+struct LogFilterBody {
+    #[serde(default)]
+    stdout: Option<String>,
+
+    #[serde(default)]
+    file: Option<String>,
+}
+
+/// Serve the admin commands as a REST API on `address`.
+pub async fn start(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    address: SocketAddr,
+    admin: Arc<Admin>,
+) -> CCProxyResult<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("The admin REST API and the dashboard are started on http://{address}.");
+
+    let router = Router::new()
+        .route("/", get(dashboard))
+        .route("/v1/status", get(status))
+        .route("/v1/sessions", get(sessions))
+        .route("/v1/upstreams", get(upstreams))
+        .route("/v1/bans", get(list_bans).post(ban))
+        .route("/v1/bans/{target}", delete(unban))
+        .route("/v1/maintenance", put(maintenance))
+        .route("/v1/reload", post(reload))
+        .route("/v1/kick", post(kick))
+        .route("/v1/transfer", post(transfer))
+        .route("/v1/traffic", get(traffic))
+        .route("/v1/events", get(events))
+        .route("/v1/packet-stats", get(packet_stats))
+        .route("/v1/log-filter", get(log_filter).put(set_log_filter))
+        .layer(middleware::from_fn(refuse_cross_site))
+        .with_state(admin);
+
+    sub_sys.start(SubsystemBuilder::new(
+        "AdminListener_Http",
+        move |sub| async move {
+            let service = router.into_make_service_with_connect_info::<SocketAddr>();
+            axum::serve(listener, service)
+                .with_graceful_shutdown(sub.create_cancellation_token().cancelled_owned())
+                .await?;
+
+            Ok::<_, CCProxyError>(())
+        },
+    ));
+
+    Ok(())
+}
+
+/// Refuse a state-changing request which a browser may send from another site without
+/// a preflight, i.e. one with neither the Authorization header nor a JSON body.
+async fn refuse_cross_site(request: Request, next: Next) -> Response {
+    let headers = request.headers();
+    let is_json = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("application/json"));
+    if !request.method().is_safe() && !headers.contains_key(AUTHORIZATION) && !is_json {
+        return ApiError(CCProxyError::AdminCrossSiteRequest).into_response();
+    }
+
+    next.run(request).await
+}
+
+async fn dashboard() -> Html<&'static str> {
+    Html(DASHBOARD)
+}
+
+async fn command(
+    admin: &Admin,
+    peer: SocketAddr,
+    headers: &HeaderMap,
+    request: AdminRequest,
+) -> ApiResult {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    admin
+        .run(Actor::new("http", Some(peer)), token, request)
+        .await
+        .map(Json)
+        .map_err(ApiError)
+}
+
+async fn status(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> ApiResult {
+    command(&admin, peer, &headers, AdminRequest::Status).await
+}
+
+async fn sessions(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> ApiResult {
+    command(&admin, peer, &headers, AdminRequest::Sessions).await
+}
+
+async fn upstreams(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> ApiResult {
+    command(&admin, peer, &headers, AdminRequest::Upstreams).await
+}
+
+async fn list_bans(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> ApiResult {
+    command(&admin, peer, &headers, AdminRequest::ListBans).await
+}
+
+async fn ban(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<BanBody>,
+) -> ApiResult {
+    let request = AdminRequest::Ban {
+        ip: body.ip,
+        xuid: body.xuid,
+        reason: body.reason,
+        duration_secs: body.duration_secs,
+    };
+
+    command(&admin, peer, &headers, request).await
+}
+
+async fn unban(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(target): Path<String>,
+) -> ApiResult {
+    command(&admin, peer, &headers, AdminRequest::Unban { target }).await
+}
+
+async fn maintenance(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<MaintenanceBody>,
+) -> ApiResult {
+    let request = AdminRequest::Maintenance {
+        enabled: body.enabled,
+        listener: body.listener,
+    };
+
+    command(&admin, peer, &headers, request).await
+}
+
+async fn reload(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> ApiResult {
+    command(&admin, peer, &headers, AdminRequest::Reload).await
+}
+
+async fn traffic(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> ApiResult {
+    command(&admin, peer, &headers, AdminRequest::Traffic).await
+}
+
+async fn packet_stats(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> ApiResult {
+    command(&admin, peer, &headers, AdminRequest::PacketStats).await
+}
+
+async fn events(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> ApiResult {
+    command(&admin, peer, &headers, AdminRequest::Events).await
+}
+
+async fn log_filter(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> ApiResult {
+    let request = AdminRequest::LogFilter {
+        stdout: None,
+        file: None,
+    };
+
+    command(&admin, peer, &headers, request).await
+}
+
+async fn set_log_filter(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<LogFilterBody>,
+) -> ApiResult {
+    let request = AdminRequest::LogFilter {
+        stdout: body.stdout,
+        file: body.file,
+    };
+
+    command(&admin, peer, &headers, request).await
+}
+
+async fn kick(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<KickBody>,
+) -> ApiResult {
+    let request = AdminRequest::Kick {
+        target: body.target,
+        message: body.message,
+    };
+
+    command(&admin, peer, &headers, request).await
+}
+
+async fn transfer(
+    State(admin): State<Arc<Admin>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(body): Json<TransferBody>,
+) -> ApiResult {
+    let request = AdminRequest::Transfer {
+        host: body.host,
+        port: body.port,
+        listener: body.listener,
+    };
+
+    command(&admin, peer, &headers, request).await
+}
@@ -0,0 +1,84 @@
+use crate::config::DATA_PATH;
+use crate::error::CCProxyResult;
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// The file which the admin actions are appended to, one JSON object per line.
+pub static AUDIT_PATH: LazyLock<PathBuf> = LazyLock::new(|| DATA_PATH.join("audit.ndjson"));
+
+/// Who runs an admin command.
+#[derive(Clone, Debug, Serialize)]
+pub struct Actor {
+    /// How the command came in: `tcp`, `unix`, `control`, `http`, `grpc`, `signal`, or
+    /// `cli`.
+    pub via: &'static str,
+
+    /// The address of the client, if it came over the network.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<SocketAddr>,
+
+    /// The name of the token, or its role if unnamed. Unset if no tokens are configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl Actor {
+    pub fn new(via: &'static str, peer: Option<SocketAddr>) -> Self {
+        Self {
+            via,
+            peer,
+            token: None,
+        }
+    }
+}
+
+/// A single line in the audit log.
+#[derive(Debug, Serialize)]
+pub struct AuditRecord<'a> {
+    /// Unix timestamp in seconds.
+    pub timestamp: u64,
+
+    pub actor: &'a Actor,
+
+    pub command: &'static str,
+
+    /// The fields of the command, e.g. the target and the message of a kick.
+    pub parameters: serde_json::Value,
+
+    /// Why the command failed or was refused, if it did.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Appends [`AuditRecord`]s to the audit log, which is never rewritten.
+#[derive(Default)]
+pub struct AuditLog {
+    lock: Mutex<()>,
+}
+
+impl AuditLog {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub async fn append(&self, record: &AuditRecord<'_>) -> CCProxyResult<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        // Serialize writers so lines from concurrent commands never interleave.
+        let _lock = self.lock.lock().await;
+        tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&*AUDIT_PATH)
+            .await?
+            .write_all(&line)
+            .await?;
+
+        Ok(())
+    }
+}
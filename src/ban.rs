@@ -0,0 +1,319 @@
+use crate::config::DATA_PATH;
+use crate::error::{CCProxyError, CCProxyResult};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// The file which the bans are kept in, shared by every listener.
+pub static BANS_PATH: LazyLock<PathBuf> = LazyLock::new(|| DATA_PATH.join("bans.yaml"));
+
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+
+    /// The Xbox user ID of the banned player, which is banned from any IP once its
+    /// client sends the Login packet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xuid: Option<String>,
+
+    #[serde(default)]
+    pub reason: String,
+
+    /// Unix timestamp in seconds.
+    pub created_at: u64,
+
+    /// Unix timestamp in seconds after which the ban is lifted, or never if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    /// A ban from now, which is lifted after `duration` if set.
+    pub fn new(
+        ip: IpAddr,
+        xuid: Option<String>,
+        reason: String,
+        duration: Option<Duration>,
+    ) -> CCProxyResult<Self> {
+        let now = unix_now();
+        let expires_at = duration
+            .map(|d| {
+                now.checked_add(d.as_secs())
+                    .ok_or(CCProxyError::BanDurationTooLong { secs: d.as_secs() })
+            })
+            .transpose()?;
+
+        Ok(Self {
+            ip: ip.to_canonical(),
+            xuid,
+            reason,
+            created_at: now,
+            expires_at,
+        })
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Whether a client from `ip` with `xuid` is banned by this.
+    pub fn matches(&self, ip: IpAddr, xuid: Option<&str>) -> bool {
+        // A dual-stack socket reports IPv4 clients as IPv4-mapped IPv6 addresses.
+        self.ip.to_canonical() == ip.to_canonical()
+            || xuid.is_some_and(|xuid| self.xuid.as_deref() == Some(xuid))
+    }
+}
+
+/// What bans are lifted by.
+pub enum BanTarget {
+    Ip(IpAddr),
+
+    Xuid(String),
+}
+
+impl BanTarget {
+    pub fn parse(target: &str) -> Self {
+        match target.parse() {
+            Ok(ip) => Self::Ip(ip),
+            Err(_) => Self::Xuid(target.to_owned()),
+        }
+    }
+
+    fn matches(&self, ban: &Ban) -> bool {
+        match self {
+            Self::Ip(ip) => ban.ip.to_canonical() == ip.to_canonical(),
+            Self::Xuid(xuid) => ban.xuid.as_ref() == Some(xuid),
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct BanFile {
+    #[serde(default)]
+    bans: Vec<Ban>,
+}
+
+/// The bans of the process, persisted to a YAML file so they survive restarts.
+pub struct BanStore {
+    path: PathBuf,
+
+    bans: RwLock<Vec<Ban>>,
+
+    /// Serializes the writes to the file.
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+impl BanStore {
+    /// Load the bans from `path`, which has none if it does not exist yet.
+    pub async fn load(path: &Path) -> CCProxyResult<Self> {
+        let file = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => serde_yaml::from_str::<BanFile>(&contents)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Default::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let now = unix_now();
+        let bans = file
+            .bans
+            .into_iter()
+            .filter(|ban| !ban.is_expired(now))
+            .collect::<Vec<_>>();
+        tracing::info!("{} bans are loaded from {}.", bans.len(), path.display());
+
+        Ok(Self {
+            path: path.to_owned(),
+            bans: RwLock::new(bans),
+            save_lock: Default::default(),
+        })
+    }
+
+    /// The ban of a client from `ip` with `xuid`, if any is in effect.
+    pub fn find(&self, ip: IpAddr, xuid: Option<&str>) -> Option<Ban> {
+        let now = unix_now();
+        self.bans
+            .read()
+            .unwrap()
+            .iter()
+            .find(|ban| !ban.is_expired(now) && ban.matches(ip, xuid))
+            .cloned()
+    }
+
+    /// The bans in effect.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = unix_now();
+        self.bans
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|ban| !ban.is_expired(now))
+            .cloned()
+            .collect()
+    }
+
+    /// Add `ban`, replacing the ban of the same IP and XUID.
+    pub async fn add(&self, mut ban: Ban) -> CCProxyResult<()> {
+        ban.ip = ban.ip.to_canonical();
+        {
+            let mut bans = self.bans.write().unwrap();
+            // An IP may have bans of multiple XUIDs, which each replace only their own.
+            bans.retain(|b| b.ip.to_canonical() != ban.ip || b.xuid != ban.xuid);
+            bans.push(ban);
+        }
+
+        self.save().await
+    }
+
+    /// Remove the bans of `target`, and return them.
+    pub async fn remove(&self, target: &BanTarget) -> CCProxyResult<Vec<Ban>> {
+        let removed = {
+            let mut bans = self.bans.write().unwrap();
+            let (removed, kept) = std::mem::take(&mut *bans)
+                .into_iter()
+                .partition::<Vec<_>, _>(|ban| target.matches(ban));
+            *bans = kept;
+            removed
+        };
+
+        if !removed.is_empty() {
+            self.save().await?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove the expired bans, and return how many were.
+    async fn expire(&self) -> CCProxyResult<usize> {
+        let now = unix_now();
+        let expired = {
+            let mut bans = self.bans.write().unwrap();
+            let len = bans.len();
+            bans.retain(|ban| !ban.is_expired(now));
+            len - bans.len()
+        };
+
+        if expired > 0 {
+            self.save().await?;
+        }
+
+        Ok(expired)
+    }
+
+    async fn save(&self) -> CCProxyResult<()> {
+        let _guard = self.save_lock.lock().await;
+
+        let file = BanFile {
+            bans: self.bans.read().unwrap().clone(),
+        };
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        // Written aside and renamed, so a crash never leaves a truncated file behind.
+        let temp_path = self.path.with_extension("yaml.tmp");
+        tokio::fs::write(&temp_path, serde_yaml::to_string(&file)?).await?;
+        tokio::fs::rename(&temp_path, &self.path).await?;
+
+        Ok(())
+    }
+}
+
+/// Remove the expired bans from the store and its file on an interval.
+pub async fn run_ban_expirer(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    bans: Arc<BanStore>,
+) -> CCProxyResult<()> {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                match bans.expire().await {
+                    Ok(0) => {}
+                    Ok(expired) => tracing::info!("{expired} bans are expired."),
+                    Err(err) => tracing::error!("Cannot save the bans: {err}"),
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a ban duration such as `90s`, `30m`, `12h`, or `7d`.
+pub fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (value, unit) = duration
+        .char_indices()
+        .last()
+        .map(|(index, _)| duration.split_at(index))
+        .unwrap_or_default();
+    let secs_per_unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err("the unit must be one of s, m, h, and d".to_owned()),
+    };
+    let value = value.parse::<u64>().map_err(|err| err.to_string())?;
+    let secs = value
+        .checked_mul(secs_per_unit)
+        .ok_or_else(|| "the duration is too long".to_owned())?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_units() {
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Ok(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn parse_duration_rejects_malformed() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("10").is_err());
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("-1d").is_err());
+        assert!(parse_duration("10é").is_err());
+        assert!(parse_duration("é").is_err());
+    }
+
+    #[test]
+    fn parse_duration_rejects_overflow() {
+        assert!(parse_duration(&format!("{}d", u64::MAX)).is_err());
+        assert!(parse_duration(&format!("{}s", u64::MAX)).is_ok());
+    }
+
+    #[test]
+    fn new_rejects_overflowing_expiry() {
+        let ip = IpAddr::from([127, 0, 0, 1]);
+
+        assert!(Ban::new(ip, None, String::new(), Some(Duration::from_secs(u64::MAX))).is_err());
+        let ban = Ban::new(ip, None, String::new(), Some(Duration::from_secs(60))).unwrap();
+        assert_eq!(ban.expires_at, Some(ban.created_at + 60));
+    }
+}
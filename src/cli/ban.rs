@@ -0,0 +1,200 @@
+use crate::admin::{self, Admin, AdminRequest};
+use crate::audit::Actor;
+use crate::ban::{BANS_PATH, Ban, BanStore};
+use crate::cli::probe::OutputFormat;
+use crate::config::CCProxyConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use serde::Deserialize;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Deserialize)]
+struct BanOutput {
+    ban: Ban,
+
+    kicked: usize,
+}
+
+#[derive(Deserialize)]
+struct UnbanOutput {
+    removed: Vec<Ban>,
+}
+
+#[derive(Deserialize)]
+struct BansOutput {
+    bans: Vec<Ban>,
+}
+
+#[derive(Deserialize)]
+struct KickOutput {
+    kicked: usize,
+}
+
+pub async fn ban(
+    config: &CCProxyConfig,
+    ip: IpAddr,
+    xuid: Option<String>,
+    reason: String,
+    duration: Option<Duration>,
+    output: OutputFormat,
+) -> CCProxyResult<()> {
+    let request = AdminRequest::Ban {
+        ip,
+        xuid,
+        reason,
+        duration_secs: duration.map(|d| d.as_secs()),
+    };
+    let result = request_or_edit(config, request).await?;
+
+    match output {
+        OutputFormat::Text => {
+            let out = serde_json::from_value::<BanOutput>(result)?;
+            println!("Banned: {}", describe(&out.ban));
+            println!("Kicked: {} sessions", out.kicked);
+        }
+        OutputFormat::Json => println!("{result}"),
+    }
+
+    Ok(())
+}
+
+pub async fn unban(
+    config: &CCProxyConfig,
+    target: &str,
+    output: OutputFormat,
+) -> CCProxyResult<()> {
+    let request = AdminRequest::Unban {
+        target: target.to_owned(),
+    };
+    let result = request_or_edit(config, request).await?;
+
+    match output {
+        OutputFormat::Text => {
+            let out = serde_json::from_value::<UnbanOutput>(result)?;
+            if out.removed.is_empty() {
+                println!("No ban is found for {target}.");
+            }
+            for ban in &out.removed {
+                println!("Unbanned: {}", describe(ban));
+            }
+        }
+        OutputFormat::Json => println!("{result}"),
+    }
+
+    Ok(())
+}
+
+pub async fn list_bans(config: &CCProxyConfig, output: OutputFormat) -> CCProxyResult<()> {
+    let result = request_or_edit(config, AdminRequest::ListBans).await?;
+
+    match output {
+        OutputFormat::Text => {
+            let out = serde_json::from_value::<BansOutput>(result)?;
+            println!("Bans: {}", out.bans.len());
+            for ban in &out.bans {
+                println!("  {}", describe(ban));
+            }
+        }
+        OutputFormat::Json => println!("{result}"),
+    }
+
+    Ok(())
+}
+
+/// Kick the online sessions of a client address, an IP, a session ID, an XUID, or a
+/// gamertag from the running proxy.
+pub async fn kick(
+    config: &CCProxyConfig,
+    target: &str,
+    message: Option<String>,
+    output: OutputFormat,
+) -> CCProxyResult<()> {
+    let request = AdminRequest::Kick {
+        target: target.to_owned(),
+        message,
+    };
+    let Some(result) = request_running(config, &request).await? else {
+        return Err(CCProxyError::ProxyUnreachable);
+    };
+
+    match output {
+        OutputFormat::Text => {
+            let out = serde_json::from_value::<KickOutput>(result)?;
+            println!("Kicked: {} sessions", out.kicked);
+        }
+        OutputFormat::Json => println!("{result}"),
+    }
+
+    Ok(())
+}
+
+/// Send `request` to the running proxy on the control socket or the admin listener, or
+/// return `None` if neither is reachable.
+async fn request_running(
+    config: &CCProxyConfig,
+    request: &AdminRequest,
+) -> CCProxyResult<Option<serde_json::Value>> {
+    if config.control.enabled {
+        match admin::send_control(config, request).await {
+            Err(err) if is_unreachable(&err) => {}
+            result => return result.map(Some),
+        }
+    }
+
+    if let Some(admin_config) = &config.admin {
+        match admin::send(admin_config, request).await {
+            Err(err) if is_unreachable(&err) => {}
+            result => return result.map(Some),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Send `request` to the running proxy, so online sessions are kicked at once, or act
+/// on the ban file directly if no proxy is reachable on the control socket or the admin
+/// listener.
+async fn request_or_edit(
+    config: &CCProxyConfig,
+    request: AdminRequest,
+) -> CCProxyResult<serde_json::Value> {
+    if let Some(result) = request_running(config, &request).await? {
+        return Ok(result);
+    }
+
+    // A proxy which runs without the admin listener applies the file on its next start.
+    let bans = Arc::new(BanStore::load(&BANS_PATH).await?);
+    Admin::new(bans, vec![])
+        .run(Actor::new("cli", None), None, request)
+        .await
+}
+
+fn is_unreachable(err: &CCProxyError) -> bool {
+    matches!(
+        err,
+        CCProxyError::IO { err } if matches!(
+            err.kind(),
+            ErrorKind::ConnectionRefused | ErrorKind::NotFound | ErrorKind::NotConnected
+        )
+    )
+}
+
+fn describe(ban: &Ban) -> String {
+    let until = ban
+        .expires_at
+        .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0))
+        .map(|t| format!("until {}", t.to_rfc3339()))
+        .unwrap_or_else(|| "permanently".to_owned());
+
+    let mut description = format!("{} {until}", ban.ip);
+    if let Some(xuid) = &ban.xuid {
+        description.push_str(&format!(" (XUID: {xuid})"));
+    }
+    if !ban.reason.is_empty() {
+        description.push_str(&format!(": {}", ban.reason));
+    }
+
+    description
+}
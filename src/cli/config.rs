@@ -0,0 +1,139 @@
+use crate::config::{CCProxyConfig, CONFIG_FILE_PATH, UpstreamAddress};
+use crate::error::CCProxyResult;
+use dialoguer::{Confirm, Input};
+use std::net::SocketAddr;
+
+/// Comments which are written above each key of the generated config, by key path.
+const CONFIG_COMMENTS: &[(&str, &str)] = &[
+    (
+        "log",
+        "Log filters use the tracing EnvFilter syntax, e.g. `info,ccproxy=debug`.",
+    ),
+    ("proxy", "The listener which players join."),
+    (
+        "proxy.address",
+        "The address and port which players connect to.",
+    ),
+    (
+        "proxy.fallback_motd",
+        "The server list entry shown while the upstream does not answer pings.",
+    ),
+    (
+        "proxy.fallback_query",
+        "The Query answer while the upstream does not answer Query.",
+    ),
+    ("upstream", "The Bedrock server behind the proxy."),
+    (
+        "upstream.address",
+        "The `host:port` of the upstream server. It must differ from `proxy.address`.",
+    ),
+    (
+        "upstream.query_address",
+        "The Query address of the upstream (`enable-query` in server.properties), or null.",
+    ),
+    (
+        "upstream.proxy_protocol",
+        "Send the real client address with the PROXY protocol (off, v1, or v2). The upstream must support it, and only v2 works over UDP.",
+    ),
+];
+
+/// Ask for the essential settings and write a commented config file.
+///
+/// It runs before the config is loaded, so it works while the current config is broken.
+pub fn init(force: bool) -> CCProxyResult<()> {
+    let path = &*CONFIG_FILE_PATH;
+
+    // The default config is written on every start, so only a customized file is protected.
+    if path.exists() && !force {
+        let current = std::fs::read_to_string(path)?;
+        if current != serde_yaml::to_string(&CCProxyConfig::default())?
+            && !Confirm::new()
+                .with_prompt(format!("{} already exists. Overwrite it?", path.display()))
+                .default(false)
+                .interact()?
+        {
+            return Ok(());
+        }
+    }
+
+    let mut config = CCProxyConfig::default();
+
+    config.proxy.address = Input::<SocketAddr>::new()
+        .with_prompt("Listener address")
+        .default(config.proxy.address)
+        .interact_text()?;
+    config.upstream.address = Input::<UpstreamAddress>::new()
+        .with_prompt("Upstream server address")
+        .default(config.upstream.address)
+        .validate_with(|address: &UpstreamAddress| {
+            (address.socket_addr() != Some(config.proxy.address))
+                .then_some(())
+                .ok_or("The upstream address must differ from the listener address.")
+        })
+        .interact_text()?;
+    // The Query address must be a socket address, so it is only offered for an IP.
+    config.upstream.query_address = match config.upstream.address.socket_addr() {
+        Some(address) => Confirm::new()
+            .with_prompt("Does the upstream have Query enabled on the same address?")
+            .default(true)
+            .interact()?
+            .then_some(address),
+        None => None,
+    };
+
+    let server_name: String = Input::new()
+        .with_prompt("Server name")
+        .default(config.proxy.fallback_motd.server_name.clone())
+        .interact_text()?;
+    let max_players: u32 = Input::new()
+        .with_prompt("Player cap")
+        .default(config.proxy.fallback_motd.max_players as u32)
+        .interact_text()?;
+
+    config.proxy.fallback_motd.server_name = server_name.clone();
+    config.proxy.fallback_motd.max_players = max_players as i32;
+    config.proxy.fallback_motd.ipv4_port = Some(config.proxy.address.port());
+    config.proxy.fallback_query.motd = server_name;
+    config.proxy.fallback_query.max_players = max_players as u64;
+    config.proxy.fallback_query.host_port = config.proxy.address.port();
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(
+        path,
+        annotate(&serde_yaml::to_string(&config)?, CONFIG_COMMENTS),
+    )?;
+
+    println!("The config is written to {}.", path.display());
+
+    Ok(())
+}
+
+/// Insert `# comment` lines above the YAML keys listed in `comments`.
+///
+/// Only block mappings are followed, which is all `serde_yaml` emits for the config.
+fn annotate(yaml: &str, comments: &[(&str, &str)]) -> String {
+    let mut out = String::new();
+    let mut path: Vec<(usize, &str)> = vec![];
+
+    for line in yaml.lines() {
+        let indent = line.len() - line.trim_start().len();
+        if let Some((key, _)) = line.trim_start().split_once(':')
+            && !key.starts_with('-')
+        {
+            path.retain(|(i, _)| *i < indent);
+            path.push((indent, key));
+
+            let key_path = path.iter().map(|(_, k)| *k).collect::<Vec<_>>().join(".");
+            if let Some((_, comment)) = comments.iter().find(|(k, _)| *k == key_path) {
+                out.push_str(&format!("{}# {comment}\n", " ".repeat(indent)));
+            }
+        }
+
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    out
+}
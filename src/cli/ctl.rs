@@ -0,0 +1,350 @@
+use crate::admin::{self, AdminRequest};
+use crate::ban::parse_duration;
+use crate::cli::ban;
+use crate::cli::probe::OutputFormat;
+use crate::config::CCProxyConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use clap::builder::BoolishValueParser;
+use clap::{ArgAction, Subcommand};
+use serde::Deserialize;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// A command of `ccproxy ctl`, sent to the control socket of the running proxy.
+#[derive(Debug, Subcommand)]
+pub enum CtlCommands {
+    /// Show the version, the uptime, and the state of every listener.
+    Status,
+
+    /// List the live sessions.
+    Sessions,
+
+    /// Show the upstreams with their health and circuits.
+    Upstreams,
+
+    /// Reload the config of every listener.
+    Reload,
+
+    /// Kick the sessions of a client address, an IP, a session ID, an XUID, or a gamertag.
+    Kick {
+        target: String,
+
+        /// Show this on the disconnect screen instead of the kick message.
+        #[arg(long)]
+        message: Option<String>,
+    },
+
+    /// Move the sessions to another server, e.g. to migrate the upstream to new hardware.
+    /// Encrypted sessions cannot be moved and stay.
+    Transfer {
+        host: String,
+
+        port: u16,
+
+        /// Only this listener instead of every listener.
+        #[arg(long)]
+        listener: Option<String>,
+    },
+
+    /// Ban an IP from every listener and kick its sessions.
+    Ban {
+        ip: IpAddr,
+
+        /// Also ban this XUID from any IP.
+        #[arg(long)]
+        xuid: Option<String>,
+
+        #[arg(long, default_value = "")]
+        reason: String,
+
+        /// Lift the ban after this long, e.g. `30m` or `7d`. The ban is permanent if unset.
+        #[arg(long, value_parser = parse_duration)]
+        duration: Option<Duration>,
+    },
+
+    /// Lift the bans of an IP or an XUID.
+    Unban { target: String },
+
+    /// List the bans in effect.
+    Bans,
+
+    /// Enter (`on`) or leave (`off`) the maintenance mode.
+    Maintenance {
+        #[arg(action = ArgAction::Set, value_parser = BoolishValueParser::new())]
+        enabled: bool,
+
+        /// Only this listener instead of every listener.
+        #[arg(long)]
+        listener: Option<String>,
+    },
+
+    /// Show the log filters, or replace them until the next restart.
+    LogFilter {
+        /// The stdout filter, e.g. `info,ccproxy=debug`.
+        #[arg(long)]
+        stdout: Option<String>,
+
+        /// The file filter.
+        #[arg(long)]
+        file: Option<String>,
+    },
+}
+
+#[derive(Deserialize)]
+struct StatusOutput {
+    version: String,
+
+    uptime_secs: u64,
+
+    listeners: Vec<ListenerOutput>,
+}
+
+#[derive(Deserialize)]
+struct ListenerOutput {
+    name: Option<String>,
+
+    address: SocketAddr,
+
+    sessions: usize,
+
+    draining: bool,
+
+    maintenance: bool,
+}
+
+#[derive(Deserialize)]
+struct SessionsOutput {
+    sessions: Vec<SessionOutput>,
+}
+
+#[derive(Deserialize)]
+struct SessionOutput {
+    listener: Option<String>,
+
+    id: String,
+
+    client_address: SocketAddr,
+
+    upstream_address: SocketAddr,
+
+    connected_at: u64,
+
+    last_activity: u64,
+
+    name: Option<String>,
+
+    bytes_c2s: u64,
+
+    bytes_s2c: u64,
+}
+
+#[derive(Deserialize)]
+struct UpstreamsOutput {
+    upstreams: Vec<UpstreamOutput>,
+}
+
+#[derive(Deserialize)]
+struct UpstreamOutput {
+    listener: Option<String>,
+
+    upstream: String,
+
+    drained: bool,
+
+    targets: Vec<UpstreamTargetOutput>,
+}
+
+#[derive(Deserialize)]
+struct UpstreamTargetOutput {
+    address: SocketAddr,
+
+    healthy: bool,
+
+    circuit: String,
+
+    sessions: usize,
+}
+
+#[derive(Deserialize)]
+struct ReloadOutput {
+    reloaded: usize,
+}
+
+#[derive(Deserialize)]
+struct KickOutput {
+    kicked: usize,
+}
+
+#[derive(Deserialize)]
+struct TransferOutput {
+    transferred: usize,
+}
+
+#[derive(Deserialize)]
+struct LogFilterOutput {
+    stdout: String,
+
+    file: String,
+}
+
+#[derive(Deserialize)]
+struct MaintenanceOutput {
+    maintenance: bool,
+
+    listeners: usize,
+}
+
+pub async fn ctl(
+    config: &CCProxyConfig,
+    cmd: &CtlCommands,
+    output: OutputFormat,
+) -> CCProxyResult<()> {
+    let request = match cmd {
+        CtlCommands::Status => AdminRequest::Status,
+        CtlCommands::Sessions => AdminRequest::Sessions,
+        CtlCommands::Upstreams => AdminRequest::Upstreams,
+        CtlCommands::Reload => AdminRequest::Reload,
+        CtlCommands::Kick { target, message } => AdminRequest::Kick {
+            target: target.clone(),
+            message: message.clone(),
+        },
+        CtlCommands::Transfer {
+            host,
+            port,
+            listener,
+        } => AdminRequest::Transfer {
+            host: host.clone(),
+            port: *port,
+            listener: listener.clone(),
+        },
+        CtlCommands::Maintenance { enabled, listener } => AdminRequest::Maintenance {
+            enabled: *enabled,
+            listener: listener.clone(),
+        },
+        CtlCommands::LogFilter { stdout, file } => AdminRequest::LogFilter {
+            stdout: stdout.clone(),
+            file: file.clone(),
+        },
+        // The bans are also kept while the proxy is not running.
+        CtlCommands::Ban {
+            ip,
+            xuid,
+            reason,
+            duration,
+        } => return ban::ban(config, *ip, xuid.clone(), reason.clone(), *duration, output).await,
+        CtlCommands::Unban { target } => return ban::unban(config, target, output).await,
+        CtlCommands::Bans => return ban::list_bans(config, output).await,
+    };
+
+    if !config.control.enabled {
+        return Err(CCProxyError::ControlDisabled);
+    }
+    let result = admin::send_control(config, &request).await?;
+
+    if let OutputFormat::Json = output {
+        println!("{result}");
+        return Ok(());
+    }
+
+    match cmd {
+        CtlCommands::Status => print_status(&serde_json::from_value(result)?),
+        CtlCommands::Sessions => print_sessions(&serde_json::from_value(result)?),
+        CtlCommands::Upstreams => print_upstreams(&serde_json::from_value(result)?),
+        CtlCommands::Reload => {
+            let out = serde_json::from_value::<ReloadOutput>(result)?;
+            println!("Reloaded: {} listeners", out.reloaded);
+        }
+        CtlCommands::Kick { .. } => {
+            let out = serde_json::from_value::<KickOutput>(result)?;
+            println!("Kicked: {} sessions", out.kicked);
+        }
+        CtlCommands::Transfer { .. } => {
+            let out = serde_json::from_value::<TransferOutput>(result)?;
+            println!("Transferred: {} sessions", out.transferred);
+        }
+        CtlCommands::Maintenance { .. } => {
+            let out = serde_json::from_value::<MaintenanceOutput>(result)?;
+            let state = if out.maintenance { "on" } else { "off" };
+            println!("Maintenance: {state} on {} listeners", out.listeners);
+        }
+        CtlCommands::LogFilter { .. } => {
+            let out = serde_json::from_value::<LogFilterOutput>(result)?;
+            println!("Stdout: {}", out.stdout);
+            println!("File:   {}", out.file);
+        }
+        CtlCommands::Ban { .. } | CtlCommands::Unban { .. } | CtlCommands::Bans => (),
+    }
+
+    Ok(())
+}
+
+fn print_status(out: &StatusOutput) {
+    println!("Version:  {}", out.version);
+    println!("Uptime:   {}s", out.uptime_secs);
+    for listener in &out.listeners {
+        let mut flags = String::new();
+        if listener.maintenance {
+            flags.push_str(" (maintenance)");
+        }
+        if listener.draining {
+            flags.push_str(" (draining)");
+        }
+
+        println!(
+            "  {:<16} {} with {} sessions{flags}",
+            listener_name(&listener.name),
+            listener.address,
+            listener.sessions
+        );
+    }
+}
+
+fn print_sessions(out: &SessionsOutput) {
+    let now = chrono::Utc::now().timestamp() as u64;
+
+    println!("Sessions: {}", out.sessions.len());
+    for session in &out.sessions {
+        let connected_at = chrono::DateTime::from_timestamp(session.connected_at as i64, 0)
+            .map(|t| t.to_rfc3339())
+            .unwrap_or_default();
+
+        println!(
+            "  {:<16} {} {} ({}) -> {} since {connected_at}, {}/{} bytes in/out, idle for {}s",
+            listener_name(&session.listener),
+            session.id,
+            session.client_address,
+            session.name.as_deref().unwrap_or("-"),
+            session.upstream_address,
+            session.bytes_c2s,
+            session.bytes_s2c,
+            now.saturating_sub(session.last_activity)
+        );
+    }
+}
+
+fn print_upstreams(out: &UpstreamsOutput) {
+    for upstream in &out.upstreams {
+        let drained = if upstream.drained { " (drained)" } else { "" };
+        println!(
+            "{} {}{drained}",
+            listener_name(&upstream.listener),
+            upstream.upstream
+        );
+
+        for target in &upstream.targets {
+            let health = if target.healthy {
+                "healthy"
+            } else {
+                "unhealthy"
+            };
+            println!(
+                "  {} is {health}, circuit {}, with {} sessions",
+                target.address, target.circuit, target.sessions
+            );
+        }
+    }
+}
+
+fn listener_name(name: &Option<String>) -> &str {
+    name.as_deref().unwrap_or("(main)")
+}
@@ -0,0 +1,114 @@
+use crate::error::{CCProxyError, CCProxyResult};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Detach from the terminal and continue in the background.
+///
+/// This must be called before the async runtime starts because only the calling
+/// thread survives the fork. The working directory is kept so a relative data
+/// path still resolves the same way.
+#[cfg(unix)]
+pub fn daemonize() -> CCProxyResult<()> {
+    nix::unistd::daemon(true, false).map_err(std::io::Error::from)?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> CCProxyResult<()> {
+    Err(CCProxyError::DaemonUnsupported)
+}
+
+/// A file holding the PID of the running proxy, removed when dropped.
+///
+/// The file is locked while the proxy runs, so two processes cannot both take it, and
+/// one left behind by a crashed process is stale and can be taken over.
+pub struct Pidfile {
+    path: PathBuf,
+
+    /// Unlocked when dropped, after the file is removed.
+    _file: File,
+}
+
+impl Pidfile {
+    /// Fail if the pidfile is locked by a process which is still running.
+    pub fn check(path: &Path) -> CCProxyResult<()> {
+        let Ok(mut file) = File::open(path) else {
+            return Ok(());
+        };
+
+        lock(&mut file)
+    }
+
+    /// Lock `path` and write the PID of this process to it.
+    pub fn acquire(path: &Path) -> CCProxyResult<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        // The file is only truncated once it is locked. A process which exits meanwhile
+        // removes the file which is locked, so it is opened again.
+        let mut file = loop {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            lock(&mut file)?;
+
+            if is_at(&file, path) {
+                break file;
+            }
+        };
+
+        file.set_len(0)?;
+        file.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            _file: file,
+        })
+    }
+}
+
+impl Drop for Pidfile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Whether `file` is still the one at `path`.
+#[cfg(unix)]
+fn is_at(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_at(_file: &File, path: &Path) -> bool {
+    // There is no portable file identity, so only the existence is checked.
+    path.exists()
+}
+
+/// Lock the pidfile, failing with the PID in it if another process holds the lock.
+fn lock(file: &mut File) -> CCProxyResult<()> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => {
+            // The PID cannot be read while the file is locked on some platforms.
+            let mut content = String::new();
+            file.read_to_string(&mut content).ok();
+
+            Err(CCProxyError::AlreadyRunning {
+                pid: content.trim().parse().unwrap_or_default(),
+            })
+        }
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
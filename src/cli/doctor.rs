@@ -0,0 +1,494 @@
+use crate::config::{CCProxyConfig, DATA_PATH, TunnelRole};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::network::dns::DnsResolver;
+use crate::network::query::QueryHandler;
+use crate::network::raknet;
+use rust_raknet::RaknetSocket;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// The UDP buffer size below which bursts of players may cause dropped datagrams.
+#[cfg(target_os = "linux")]
+const RECOMMENDED_UDP_BUFFER_SIZE: u64 = 4 * 1024 * 1024;
+
+/// The MTU sizes which Bedrock clients try, from the largest.
+const MTU_SIZES: [u16; 3] = [1492, 1200, 576];
+
+pub(crate) enum Finding {
+    Ok(String),
+
+    Warn(String, String),
+
+    Fail(String, String),
+}
+
+impl Finding {
+    fn print(&self) {
+        match self {
+            Self::Ok(message) => println!("[ OK ] {message}"),
+            Self::Warn(message, hint) => println!("[WARN] {message}\n       -> {hint}"),
+            Self::Fail(message, hint) => println!("[FAIL] {message}\n       -> {hint}"),
+        }
+    }
+}
+
+/// Check the environment and the config, printing actionable findings. Fails if any
+/// check fails, so it can gate deployments.
+pub async fn doctor(config: &CCProxyConfig) -> CCProxyResult<()> {
+    let mut findings = vec![];
+
+    findings.extend(check_config(config));
+    findings.push(check_data_path().await);
+    findings.push(check_listener(config).await);
+    #[cfg(target_os = "linux")]
+    findings.extend(check_udp_buffers().await);
+    findings.push(check_upstream_ping(config).await);
+    if let Some(finding) = check_upstream_query(config).await {
+        findings.push(finding);
+    }
+    findings.push(check_upstream_mtu(config).await);
+
+    match report(&findings) {
+        0 => Ok(()),
+        failures => Err(CCProxyError::DoctorFailed { failures }),
+    }
+}
+
+/// Print the findings and a summary line, returning the number of failures.
+pub(crate) fn report(findings: &[Finding]) -> usize {
+    for finding in findings {
+        finding.print();
+    }
+
+    let failures = findings
+        .iter()
+        .filter(|f| matches!(f, Finding::Fail(..)))
+        .count();
+    let warnings = findings
+        .iter()
+        .filter(|f| matches!(f, Finding::Warn(..)))
+        .count();
+    println!("\n{failures} failures, {warnings} warnings.");
+
+    failures
+}
+
+pub(crate) fn check_config(config: &CCProxyConfig) -> Vec<Finding> {
+    let mut findings = vec![];
+
+    if config
+        .upstream
+        .pool()
+        .iter()
+        .any(|s| s.address.socket_addr() == Some(config.proxy.address))
+    {
+        findings.push(Finding::Fail(
+            format!(
+                "The proxy and an upstream have the same address ({}).",
+                config.proxy.address
+            ),
+            "Move the upstream server to another port, e.g. 19133.".to_owned(),
+        ));
+    }
+
+    if let Some(address) = config.proxy.ipv6_address
+        && !address.is_ipv6()
+    {
+        findings.push(Finding::Fail(
+            format!("The IPv6 listener address ({address}) is not an IPv6 address."),
+            "Use an IPv6 address such as `[::]:19133`.".to_owned(),
+        ));
+    }
+
+    if config.proxy.passthrough.is_some()
+        && (config.proxy.ipv6_address.is_some() || config.upstream.proxy_protocol.is_enabled())
+    {
+        findings.push(Finding::Warn(
+            "The passthrough mode ignores `ipv6_address` and the PROXY protocol.".to_owned(),
+            "Remove them, or disable `passthrough` to terminate RakNet.".to_owned(),
+        ));
+    }
+
+    if config.proxy.rate_limit.pings_per_ip.is_some()
+        && config.proxy.passthrough.is_none()
+        && !config
+            .proxy
+            .tunnel
+            .as_ref()
+            .is_some_and(|t| t.role == TunnelRole::Edge)
+    {
+        findings.push(Finding::Warn(
+            "The RakNet transport answers pings itself, so `pings_per_ip` is ignored.".to_owned(),
+            "Enable `passthrough` or a tunnel edge to limit pings, or remove `pings_per_ip`."
+                .to_owned(),
+        ));
+    }
+
+    if config
+        .proxy
+        .limbo
+        .as_ref()
+        .is_some_and(|l| l.hold_on_upstream_loss)
+    {
+        findings.push(Finding::Warn(
+            "Encrypted sessions cannot be transferred, so `hold_on_upstream_loss` only holds the players of an upstream without encryption."
+                .to_owned(),
+            "Disable encryption on the upstream, or remove `hold_on_upstream_loss`.".to_owned(),
+        ));
+    }
+
+    if (config.proxy.handshake_gate.is_some() || config.proxy.idle_kick.is_some())
+        && (config.proxy.passthrough.is_some() || config.proxy.tunnel.is_some())
+    {
+        findings.push(Finding::Warn(
+            "The raw datagram relays never see game packets, so `handshake_gate` and `idle_kick` are ignored."
+                .to_owned(),
+            "Remove them, or disable `passthrough` and `tunnel` to terminate RakNet.".to_owned(),
+        ));
+    }
+
+    if config
+        .proxy
+        .under_attack
+        .as_ref()
+        .is_some_and(|u| u.max_attempts_per_sec.is_none() && u.max_drops_per_sec.is_none())
+    {
+        findings.push(Finding::Warn(
+            "`under_attack` has no threshold, so it is never switched on.".to_owned(),
+            "Set `max_attempts_per_sec` or `max_drops_per_sec`.".to_owned(),
+        ));
+    }
+
+    if (!config.proxy.identity.allow.is_empty()
+        || !config.proxy.identity.deny.is_empty()
+        || config.proxy.duplicate_identity.is_some())
+        && (config.proxy.passthrough.is_some() || config.proxy.tunnel.is_some())
+    {
+        findings.push(Finding::Warn(
+            "The raw datagram relays never see the Login packet, so `identity` and `duplicate_identity` are ignored."
+                .to_owned(),
+            "Remove them, or disable `passthrough` and `tunnel` to terminate RakNet.".to_owned(),
+        ));
+    }
+
+    if config.proxy.workers > 1 && config.proxy.passthrough.is_none() {
+        findings.push(Finding::Warn(
+            "The RakNet transport binds its own socket, so `workers` is ignored.".to_owned(),
+            "Enable `passthrough` to relay with multiple workers, or remove `workers`.".to_owned(),
+        ));
+    }
+
+    if let Some(tunnel) = &config.proxy.tunnel {
+        if config.proxy.passthrough.is_some() {
+            findings.push(Finding::Warn(
+                "The tunnel mode replaces the passthrough mode, so `passthrough` is ignored."
+                    .to_owned(),
+                "Remove `passthrough`, as the origin already relays raw datagrams.".to_owned(),
+            ));
+        }
+
+        // The origin generates the certificate, which the edges need a copy of.
+        if tunnel.role == TunnelRole::Edge
+            && let Some(path) = [&tunnel.certificate, &tunnel.key]
+                .into_iter()
+                .find(|p| !p.exists())
+        {
+            findings.push(Finding::Fail(
+                format!("The tunnel certificate file ({}) does not exist.", path.display()),
+                "Copy the certificate and the key from the origin, which generates them on its first start.".to_owned(),
+            ));
+        }
+    }
+
+    if let Some(admin) = &config.admin
+        && let Err(err) = admin.load_tokens()
+    {
+        findings.push(Finding::Fail(
+            format!("The admin tokens cannot be loaded: {err}"),
+            "Give every admin token a `token` or a readable `token_file`.".to_owned(),
+        ));
+    }
+
+    if let Some(admin) = &config.admin {
+        for address in [admin.address, admin.http_address, admin.grpc_address]
+            .into_iter()
+            .flatten()
+        {
+            match admin.check_address(address) {
+                Ok(()) => (),
+                Err(CCProxyError::AdminTokensRequired { .. }) => findings.push(Finding::Fail(
+                    format!("The admin listener address ({address}) allows remote clients without tokens."),
+                    "Add `tokens` to the admin config so only their holders can run commands.".to_owned(),
+                )),
+                Err(_) => findings.push(Finding::Fail(
+                    format!("The admin listener address ({address}) is not a loopback address."),
+                    "Bind it to `127.0.0.1`, or set `allow_remote` behind a firewall.".to_owned(),
+                )),
+            }
+        }
+    }
+
+    for (name, range) in &config.port_ranges {
+        if range.upstream.is_srv()
+            || range
+                .upstream
+                .port
+                .checked_add(range.ports.end - range.ports.start)
+                .is_none()
+        {
+            findings.push(Finding::Fail(
+                format!(
+                    "The port range ({name}) cannot be mapped to the upstream ({}).",
+                    range.upstream
+                ),
+                "Use a `host:port` upstream with enough ports after it.".to_owned(),
+            ));
+        }
+    }
+
+    if let Err(err) = config.check_listener_addresses() {
+        findings.push(Finding::Fail(
+            err.to_string(),
+            "Give every listener and port range its own ports, apart from `proxy.address`."
+                .to_owned(),
+        ));
+    }
+
+    if let Err(err) = config.check_intervals() {
+        findings.push(Finding::Fail(
+            err.to_string(),
+            "Set the value to at least 1.".to_owned(),
+        ));
+    }
+
+    if let Err(err) = config.check_proxy_protocol() {
+        findings.push(Finding::Fail(
+            err.to_string(),
+            "Set `proxy_protocol` to `v2`, which the upstream must accept, or relay through a tunnel origin."
+                .to_owned(),
+        ));
+    }
+
+    if let Err(err) = config.timezone() {
+        findings.push(Finding::Fail(
+            err.to_string(),
+            "Use an IANA timezone name such as `Asia/Seoul`, or remove `timezone`.".to_owned(),
+        ));
+    }
+
+    for schedule in &config.schedules {
+        if let Err(err) = cron::Schedule::from_str(&schedule.cron) {
+            findings.push(Finding::Fail(
+                format!("The schedule ({}) is invalid: {err}", schedule.name),
+                "Use a cron expression with seconds, e.g. `0 0 4 * * *`.".to_owned(),
+            ));
+        }
+
+        if let Some(profile) = &schedule.motd_profile
+            && !profile.is_empty()
+            && !config.proxy.motd_profiles.contains_key(profile)
+        {
+            findings.push(Finding::Fail(
+                format!(
+                    "The schedule ({}) refers to an unknown MOTD profile ({profile}).",
+                    schedule.name
+                ),
+                "Add the profile to `proxy.motd_profiles`.".to_owned(),
+            ));
+        }
+    }
+
+    if (!config.geoip.allowed_countries.is_empty() || !config.geoip.blocked_countries.is_empty())
+        && config.geoip.country_database.is_none()
+    {
+        findings.push(Finding::Warn(
+            "The country filters are set without a country database, so they are ignored."
+                .to_owned(),
+            "Set `geoip.country_database` to a GeoLite2-Country `.mmdb` file.".to_owned(),
+        ));
+    }
+
+    if (!config.geoip.blocked_asns.is_empty() || !config.geoip.allowed_asns.is_empty())
+        && config.geoip.asn_database.is_none()
+    {
+        findings.push(Finding::Warn(
+            "The ASN filters are set without an ASN database, so they are ignored.".to_owned(),
+            "Set `geoip.asn_database` to a GeoLite2-ASN `.mmdb` file.".to_owned(),
+        ));
+    }
+
+    if config.proxy.advertise_max_sessions && config.proxy.max_sessions.is_none() {
+        findings.push(Finding::Warn(
+            "`advertise_max_sessions` is set without `max_sessions`.".to_owned(),
+            "Set `proxy.max_sessions` to the player cap of the proxy.".to_owned(),
+        ));
+    }
+
+    if config.proxy.fallback_motd.max_players <= 0 {
+        findings.push(Finding::Warn(
+            "The fallback MOTD allows no players.".to_owned(),
+            "Set `proxy.fallback_motd.max_players` to the player cap.".to_owned(),
+        ));
+    }
+
+    if findings.is_empty() {
+        findings.push(Finding::Ok("The config is valid.".to_owned()));
+    }
+
+    findings
+}
+
+async fn check_data_path() -> Finding {
+    let path = DATA_PATH.join(".doctor");
+    let result = async {
+        tokio::fs::create_dir_all(&*DATA_PATH).await?;
+        tokio::fs::write(&path, b"").await?;
+        tokio::fs::remove_file(&path).await
+    }
+    .await;
+
+    match result {
+        Ok(()) => Finding::Ok(format!(
+            "The data directory ({}) is writable.",
+            DATA_PATH.display()
+        )),
+        Err(err) => Finding::Fail(
+            format!(
+                "The data directory ({}) is not writable: {err}",
+                DATA_PATH.display()
+            ),
+            "Fix the permissions or set CCPROXY__DATA_PATH to a writable directory.".to_owned(),
+        ),
+    }
+}
+
+pub(crate) async fn check_listener(config: &CCProxyConfig) -> Finding {
+    match tokio::net::UdpSocket::bind(config.proxy.address).await {
+        Ok(_) => Finding::Ok(format!(
+            "The listener address ({}) can be bound.",
+            config.proxy.address
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::AddrInUse => Finding::Fail(
+            format!("The listener address ({}) is in use.", config.proxy.address),
+            "Stop the other process (or a running ccproxy) or change `proxy.address`.".to_owned(),
+        ),
+        Err(err) => Finding::Fail(
+            format!(
+                "The listener address ({}) cannot be bound: {err}",
+                config.proxy.address
+            ),
+            "Check that the address belongs to this host and ports below 1024 are allowed."
+                .to_owned(),
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn check_udp_buffers() -> Vec<Finding> {
+    let mut findings = vec![];
+
+    for name in ["rmem_max", "wmem_max"] {
+        let path = format!("/proc/sys/net/core/{name}");
+        let Some(size) = tokio::fs::read_to_string(&path)
+            .await
+            .ok()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+        else {
+            continue;
+        };
+
+        findings.push(if size < RECOMMENDED_UDP_BUFFER_SIZE {
+            Finding::Warn(
+                format!("net.core.{name} is {size} bytes, which may drop datagrams under load."),
+                format!("Run `sysctl -w net.core.{name}={RECOMMENDED_UDP_BUFFER_SIZE}`."),
+            )
+        } else {
+            Finding::Ok(format!("net.core.{name} is {size} bytes."))
+        });
+    }
+
+    findings
+}
+
+/// Resolve the upstream, or get the finding which reports why it cannot be.
+async fn resolve_upstream(config: &CCProxyConfig) -> Result<SocketAddr, Finding> {
+    let resolved = match DnsResolver::new(&config.dns) {
+        Ok(resolver) => resolver.resolve_upstream(&config.upstream.address).await,
+        Err(err) => Err(err),
+    };
+
+    resolved.map_err(|err| {
+        Finding::Fail(
+            format!(
+                "The upstream ({}) cannot be resolved: {err}",
+                config.upstream.address
+            ),
+            "Check the hostname in `upstream.address` and the DNS of this host.".to_owned(),
+        )
+    })
+}
+
+pub(crate) async fn check_upstream_ping(config: &CCProxyConfig) -> Finding {
+    let address = match resolve_upstream(config).await {
+        Ok(address) => address,
+        Err(finding) => return finding,
+    };
+    match RaknetSocket::ping_with(
+        &address,
+        Duration::from_secs(5),
+        3,
+        config.upstream.proxy_protocol.is_enabled(),
+    )
+    .await
+    {
+        Ok((latency, _)) => Finding::Ok(format!(
+            "The upstream ({address}) answers pings in {latency}ms."
+        )),
+        Err(err) => Finding::Fail(
+            format!("The upstream ({address}) does not answer pings: {err}"),
+            "Check that the server is running and the firewall allows UDP from this host."
+                .to_owned(),
+        ),
+    }
+}
+
+async fn check_upstream_query(config: &CCProxyConfig) -> Option<Finding> {
+    let address = config.upstream.query_address?;
+
+    Some(
+        match QueryHandler::query(&address, Duration::from_secs(5), 3, true).await {
+            Ok(_) => Finding::Ok(format!("The upstream Query ({address}) answers.")),
+            Err(err) => Finding::Warn(
+                format!("The upstream Query ({address}) does not answer: {err}"),
+                "Set `enable-query=true` in server.properties or remove `upstream.query_address`."
+                    .to_owned(),
+            ),
+        },
+    )
+}
+
+async fn check_upstream_mtu(config: &CCProxyConfig) -> Finding {
+    let address = match resolve_upstream(config).await {
+        Ok(address) => address,
+        Err(finding) => return finding,
+    };
+    match raknet::probe_mtu(&address, &MTU_SIZES, Duration::from_secs(2)).await {
+        Ok(Some(mtu)) if mtu == MTU_SIZES[0] => {
+            Finding::Ok(format!("The MTU to the upstream ({address}) is {mtu}."))
+        }
+        Ok(Some(mtu)) => Finding::Warn(
+            format!("The MTU to the upstream ({address}) is only {mtu}."),
+            "Check for tunnels or VPNs between the proxy and the upstream which lower the MTU."
+                .to_owned(),
+        ),
+        Ok(None) => Finding::Fail(
+            format!("No MTU probe reached the upstream ({address})."),
+            "Check that the upstream is a RakNet server and UDP is not filtered.".to_owned(),
+        ),
+        Err(err) => Finding::Fail(
+            format!("Cannot probe the MTU to the upstream ({address}): {err}"),
+            "Check the network configuration of this host.".to_owned(),
+        ),
+    }
+}
@@ -1,29 +1,328 @@
+use crate::ban::parse_duration;
 use crate::built_info;
-use crate::config::CCProxyConfig;
+use crate::config::{CCProxyConfig, LogFilterHandle};
 use crate::error::CCProxyResult;
-use clap::{Parser, Subcommand};
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
+use ctl::CtlCommands;
+use daemon::Pidfile;
+use probe::OutputFormat;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 
+pub mod ban;
+pub mod config;
+pub mod ctl;
+pub mod daemon;
+pub mod doctor;
+pub mod probe;
 pub mod run;
+pub mod self_update;
+pub mod top;
+pub mod whois;
 
 #[derive(Debug, Parser)]
 #[command(about = built_info::PKG_DESCRIPTION, long_about = None, version = built_info::PKG_VERSION)]
-struct CCProxyCli {
+pub struct CCProxyCli {
     #[command(subcommand)]
     cmd: Commands,
+
+    /// Log more to stdout for this run: -v for debug, -vv for trace.
+    #[arg(short, long, action = ArgAction::Count, global = true, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log only errors to stdout for this run.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Merge `config.<PROFILE>.yaml` over the base config. Defaults to `CCPROXY__PROFILE`.
+    #[arg(long, global = true)]
+    profile: Option<String>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Run the proxy server.
-    Run,
+    Run(RunArgs),
+
+    /// Look up a player by IP, name, or XUID in the session history.
+    Whois {
+        /// The IP address, gamertag, or XUID to look up.
+        target: String,
+    },
+
+    /// Ping a Bedrock server, the upstream by default.
+    Ping {
+        address: Option<SocketAddr>,
+
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
+    /// Send a full Query to a server, the upstream by default.
+    Query {
+        address: Option<SocketAddr>,
+
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
+    /// Show whether the local proxy and the upstream are online.
+    Status {
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
+    /// List the players on the local proxy.
+    Players {
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
+    /// Ban an IP from every listener and kick its sessions.
+    Ban {
+        ip: IpAddr,
+
+        /// Also ban this XUID from any IP.
+        #[arg(long)]
+        xuid: Option<String>,
+
+        #[arg(long, default_value = "")]
+        reason: String,
+
+        /// Lift the ban after this long, e.g. `30m` or `7d`. The ban is permanent if unset.
+        #[arg(long, value_parser = parse_duration)]
+        duration: Option<Duration>,
+
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
+    /// Lift the bans of an IP or an XUID.
+    Unban {
+        target: String,
+
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
+    /// List the bans in effect.
+    Bans {
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
+    /// Kick the sessions of a client address, an IP, a session ID, an XUID, or a gamertag.
+    Kick {
+        target: String,
+
+        /// Show this on the disconnect screen instead of the kick message.
+        #[arg(long)]
+        message: Option<String>,
+
+        #[arg(long, value_enum, default_value_t)]
+        output: OutputFormat,
+    },
+
+    /// Control the running proxy over its local control socket.
+    Ctl {
+        #[command(subcommand)]
+        cmd: CtlCommands,
+
+        #[arg(long, value_enum, default_value_t, global = true)]
+        output: OutputFormat,
+    },
+
+    /// Watch the sessions, the upstreams, and the throughput of the running proxy live.
+    Top,
+
+    /// Manage the config file.
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCommands,
+    },
+
+    /// Diagnose the environment, the upstream, and the config.
+    Doctor,
+
+    /// Print a shell completion script to stdout.
+    Completions {
+        /// The shell to generate the script for.
+        shell: clap_complete::Shell,
+    },
+
+    /// Print a man page in roff to stdout.
+    Manpage,
+
+    /// Replace this binary with the latest release.
+    SelfUpdate {
+        /// Download the latest release even if this binary is up to date.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommands {
+    /// Create the config file interactively.
+    Init {
+        /// Overwrite an existing config file without asking.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
+    /// Fork into the background (Unix only).
+    #[arg(long)]
+    daemon: bool,
+
+    /// Write the PID to this file, refusing to start if another process holds it.
+    #[arg(long)]
+    pidfile: Option<PathBuf>,
+
+    /// Check the config, the listener, and the upstream, then exit without serving.
+    #[arg(long, conflicts_with = "daemon")]
+    dry_run: bool,
 }
 
-pub async fn execute(config: CCProxyConfig) -> CCProxyResult<()> {
-    let cli = CCProxyCli::parse();
+impl CCProxyCli {
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// The stdout log filter which the verbosity flags override the config with, if any.
+    pub fn stdout_filter(&self) -> Option<&'static str> {
+        match (self.verbose, self.quiet) {
+            (_, true) => Some("error"),
+            (0, _) => None,
+            (1, _) => Some("debug"),
+            _ => Some("trace"),
+        }
+    }
+
+    /// Daemonize and take the pidfile if requested.
+    ///
+    /// This must run before the async runtime starts. The returned pidfile must be
+    /// kept until the process exits.
+    pub fn prepare_process(&self) -> CCProxyResult<Option<Pidfile>> {
+        let Commands::Run(args) = &self.cmd else {
+            return Ok(None);
+        };
+        if args.dry_run {
+            return Ok(None);
+        }
+
+        if args.daemon {
+            // Check before detaching so the error still reaches the terminal.
+            if let Some(pidfile) = &args.pidfile {
+                Pidfile::check(pidfile)?;
+            }
+
+            daemon::daemonize()?;
+        }
 
+        args.pidfile.as_deref().map(Pidfile::acquire).transpose()
+    }
+
+    /// Run the command if it needs neither the config nor the logs, so it works without
+    /// a valid config and logs nothing into its output. Returns whether it is run.
+    pub fn execute_standalone(&self) -> CCProxyResult<bool> {
+        match &self.cmd {
+            Commands::Completions { shell } => {
+                clap_complete::generate(
+                    *shell,
+                    &mut CCProxyCli::command(),
+                    built_info::PKG_NAME,
+                    &mut std::io::stdout(),
+                );
+            }
+            Commands::Manpage => {
+                clap_mangen::Man::new(CCProxyCli::command()).render(&mut std::io::stdout())?;
+            }
+            Commands::Config {
+                cmd: ConfigCommands::Init { force },
+            } => {
+                config::init(*force)?;
+            }
+            _ => return Ok(false),
+        }
+
+        Ok(true)
+    }
+}
+
+pub async fn execute(
+    cli: CCProxyCli,
+    config: CCProxyConfig,
+    log_filter: LogFilterHandle,
+) -> CCProxyResult<()> {
     match &cli.cmd {
-        Commands::Run => {
-            run::run(config).await?;
+        Commands::Run(RunArgs { dry_run: true, .. }) => {
+            run::dry_run(&config).await?;
+        }
+        Commands::Run(_) => {
+            run::run(config, cli.profile().map(ToOwned::to_owned), log_filter).await?;
+        }
+        Commands::Whois { target } => {
+            whois::whois(target).await?;
+        }
+        Commands::Ping { address, output } => {
+            probe::ping(&config, *address, *output).await?;
+        }
+        Commands::Query { address, output } => {
+            probe::query(&config, *address, *output).await?;
+        }
+        Commands::Status { output } => {
+            probe::status(&config, *output).await?;
+        }
+        Commands::Players { output } => {
+            probe::players(&config, *output).await?;
+        }
+        Commands::Ban {
+            ip,
+            xuid,
+            reason,
+            duration,
+            output,
+        } => {
+            ban::ban(
+                &config,
+                *ip,
+                xuid.clone(),
+                reason.clone(),
+                *duration,
+                *output,
+            )
+            .await?;
+        }
+        Commands::Unban { target, output } => {
+            ban::unban(&config, target, *output).await?;
+        }
+        Commands::Bans { output } => {
+            ban::list_bans(&config, *output).await?;
+        }
+        Commands::Kick {
+            target,
+            message,
+            output,
+        } => {
+            ban::kick(&config, target, message.clone(), *output).await?;
+        }
+        Commands::Ctl { cmd, output } => {
+            ctl::ctl(&config, cmd, *output).await?;
+        }
+        Commands::Top => {
+            top::top(&config).await?;
+        }
+        Commands::Doctor => {
+            doctor::doctor(&config).await?;
+        }
+        // Run by `execute_standalone` before the config is loaded.
+        Commands::Completions { .. } | Commands::Manpage | Commands::Config { .. } => (),
+        Commands::SelfUpdate { force } => {
+            self_update::self_update(&config, *force).await?;
         }
     };
 
@@ -0,0 +1,249 @@
+use crate::config::{CCProxyConfig, ProxyQueryConfig};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::network::bedrock::BedrockMotd;
+use crate::network::dns::DnsResolver;
+use crate::network::query::{QueryHandler, QueryResponsePacketPayload};
+use clap::ValueEnum;
+use rust_raknet::RaknetSocket;
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+
+    /// One JSON object with stable field names.
+    Json,
+}
+
+#[derive(Serialize)]
+struct PingOutput {
+    address: SocketAddr,
+
+    latency_ms: i64,
+
+    motd: BedrockMotd,
+}
+
+#[derive(Serialize)]
+struct QueryOutput {
+    address: SocketAddr,
+
+    query: ProxyQueryConfig,
+}
+
+#[derive(Serialize)]
+struct StatusOutput {
+    proxy: EndpointStatus,
+
+    upstream: EndpointStatus,
+}
+
+#[derive(Serialize)]
+struct EndpointStatus {
+    address: SocketAddr,
+
+    online: bool,
+
+    latency_ms: Option<i64>,
+
+    num_players: Option<i32>,
+
+    max_players: Option<i32>,
+
+    version: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PlayersOutput {
+    address: SocketAddr,
+
+    num_players: u64,
+
+    max_players: u64,
+
+    players: Vec<String>,
+}
+
+/// Resolve the upstream, which may be configured by hostname.
+async fn upstream_address(config: &CCProxyConfig) -> CCProxyResult<SocketAddr> {
+    DnsResolver::new(&config.dns)?
+        .resolve_upstream(&config.upstream.address)
+        .await
+}
+
+/// Ping a Bedrock server, the upstream by default.
+pub async fn ping(
+    config: &CCProxyConfig,
+    address: Option<SocketAddr>,
+    output: OutputFormat,
+) -> CCProxyResult<()> {
+    let address = match address {
+        Some(address) => address,
+        None => upstream_address(config).await?,
+    };
+    let (latency_ms, motd) = ping_motd(&address).await?;
+    let out = PingOutput {
+        address,
+        latency_ms,
+        motd,
+    };
+
+    match output {
+        OutputFormat::Text => {
+            println!("Address:  {}", out.address);
+            println!("Latency:  {}ms", out.latency_ms);
+            println!("Name:     {}", out.motd.server_name);
+            println!("Version:  {}", out.motd.version);
+            println!(
+                "Players:  {}/{}",
+                out.motd.num_players, out.motd.max_players
+            );
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string(&out)?),
+    }
+
+    Ok(())
+}
+
+/// Send a full Query to a server, the upstream by default.
+pub async fn query(
+    config: &CCProxyConfig,
+    address: Option<SocketAddr>,
+    output: OutputFormat,
+) -> CCProxyResult<()> {
+    let address = match address.or(config.upstream.query_address) {
+        Some(address) => address,
+        None => upstream_address(config).await?,
+    };
+    let out = QueryOutput {
+        address,
+        query: full_query(&address).await?,
+    };
+
+    match output {
+        OutputFormat::Text => {
+            println!("Address:  {}", out.address);
+            println!("MOTD:     {}", out.query.motd);
+            println!("Map:      {}", out.query.map);
+            println!("Version:  {}", out.query.version);
+            println!(
+                "Players:  {}/{}",
+                out.query.num_players, out.query.max_players
+            );
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string(&out)?),
+    }
+
+    Ok(())
+}
+
+/// Report whether the local proxy and the upstream answer pings.
+pub async fn status(config: &CCProxyConfig, output: OutputFormat) -> CCProxyResult<()> {
+    let upstream_address = upstream_address(config).await?;
+    let (proxy, upstream) = tokio::join!(
+        endpoint_status(local_address(config.proxy.address)),
+        endpoint_status(upstream_address)
+    );
+    let out = StatusOutput { proxy, upstream };
+
+    match output {
+        OutputFormat::Text => {
+            for (name, status) in [("Proxy", &out.proxy), ("Upstream", &out.upstream)] {
+                match status.latency_ms {
+                    Some(latency_ms) => println!(
+                        "{name:<9} {} is online ({latency_ms}ms, {}/{} players).",
+                        status.address,
+                        status.num_players.unwrap_or_default(),
+                        status.max_players.unwrap_or_default()
+                    ),
+                    None => println!("{name:<9} {} is offline.", status.address),
+                }
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string(&out)?),
+    }
+
+    Ok(())
+}
+
+/// List the players on the local proxy using its Query answer.
+pub async fn players(config: &CCProxyConfig, output: OutputFormat) -> CCProxyResult<()> {
+    let address = local_address(config.proxy.address);
+    let query = full_query(&address).await?;
+    let out = PlayersOutput {
+        address,
+        num_players: query.num_players,
+        max_players: query.max_players,
+        players: query.players,
+    };
+
+    match output {
+        OutputFormat::Text => {
+            println!("Players: {}/{}", out.num_players, out.max_players);
+            for player in &out.players {
+                println!("  {player}");
+            }
+        }
+        OutputFormat::Json => println!("{}", serde_json::to_string(&out)?),
+    }
+
+    Ok(())
+}
+
+async fn ping_motd(address: &SocketAddr) -> CCProxyResult<(i64, BedrockMotd)> {
+    let (latency_ms, motd) = RaknetSocket::ping_with(address, PROBE_TIMEOUT, 3, false).await?;
+
+    Ok((latency_ms, BedrockMotd::decode(motd, None, None, None)?))
+}
+
+async fn full_query(address: &SocketAddr) -> CCProxyResult<ProxyQueryConfig> {
+    match QueryHandler::query(address, PROBE_TIMEOUT, 3, true)
+        .await?
+        .payload
+    {
+        QueryResponsePacketPayload::FullStat {
+            k_v_section,
+            players,
+        } => ProxyQueryConfig::from_kv_and_players(k_v_section, players),
+        _ => Err(CCProxyError::QueryInvalid),
+    }
+}
+
+async fn endpoint_status(address: SocketAddr) -> EndpointStatus {
+    match ping_motd(&address).await {
+        Ok((latency_ms, motd)) => EndpointStatus {
+            address,
+            online: true,
+            latency_ms: Some(latency_ms),
+            num_players: Some(motd.num_players),
+            max_players: Some(motd.max_players),
+            version: Some(motd.version),
+        },
+        Err(_) => EndpointStatus {
+            address,
+            online: false,
+            latency_ms: None,
+            num_players: None,
+            max_players: None,
+            version: None,
+        },
+    }
+}
+
+/// The address to reach a listener on this host, replacing a wildcard with loopback.
+fn local_address(address: SocketAddr) -> SocketAddr {
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv4Addr::LOCALHOST.into(), address.port())
+        }
+        IpAddr::V6(ip) if ip.is_unspecified() => {
+            SocketAddr::new(Ipv6Addr::LOCALHOST.into(), address.port())
+        }
+        _ => address,
+    }
+}
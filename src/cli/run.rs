@@ -1,28 +1,240 @@
+use crate::access_log::AccessLog;
+use crate::admin::{self, Admin, ListenerHandle};
+use crate::ban::{BANS_PATH, BanStore, run_ban_expirer};
 use crate::built_info;
-use crate::config::CCProxyConfig;
+use crate::cli::doctor;
+use crate::config::{
+    BackupConfig, CCProxyConfig, ConnectRetryConfig, DisconnectMessagesConfig, LimboConfig,
+    ListenerConfig, LogFilterHandle, ProxyProtocolVersion, ProxyQueryConfig, TunnelRole,
+    UpstreamConfig,
+};
 use crate::error::{CCProxyError, CCProxyResult, sub_sys_err_to_ccproxy_err};
+use crate::geoip::GeoIp;
+use crate::history::SessionHistory;
+use crate::maintenance;
+use crate::metrics::packets::PACKET_STATS;
+use crate::metrics::per_ip::run_per_ip_monitor;
+use crate::metrics::{
+    BACKUP_TRANSFERS_TOTAL, CounterHandle, FORWARDED_BYTES_TOTAL, FORWARDED_PACKETS_TOTAL,
+    HANDSHAKE_FAILURES_TOTAL, LIMBO_SESSIONS_ACTIVE, METRICS, MOTD_UPDATES_TOTAL,
+    NETWORK_SETTINGS_TOTAL, QUERY_REQUESTS_TOTAL, SESSIONS_ACTIVE, SESSIONS_BY_COUNTRY_TOTAL,
+    SESSIONS_EVICTED_TOTAL, SESSIONS_REFUSED_TOTAL, SESSIONS_REPLACED_TOTAL, SESSIONS_TOTAL,
+    UPSTREAM_CONNECT_FAILURES_TOTAL, UPSTREAM_CONNECT_RETRIES_TOTAL, UPSTREAM_RESTARTS_TOTAL,
+    UPSTREAM_SESSIONS_ACTIVE, influx, packets, prometheus, push, statsd,
+};
+use crate::network::admission::{Admission, AdmissionSlot, Refusal};
+use crate::network::balancer::Balancer;
 use crate::network::bedrock::BedrockMotd;
+use crate::network::blocklist::{AccessList, run_blocklist_refresher};
+use crate::network::circuit::CircuitBreaker;
+use crate::network::datagram_filter::DatagramFilter;
+use crate::network::dns::{DnsResolver, UpstreamAddresses, run_dns_refresher};
+use crate::network::error_summary::{ErrorSummary, run_error_summarizer};
+use crate::network::game::{self, GAME_PACKET_ID, HandshakeState};
+use crate::network::health::{HealthCheckTarget, UpstreamHealth, run_health_checker};
+use crate::network::java;
+use crate::network::lan::run_lan_announcer;
+use crate::network::latency::{LatencyProbes, run_latency_prober};
+use crate::network::limbo::{Limbo, LimboExit};
+use crate::network::outbound::Outbound;
+use crate::network::passthrough::run_passthrough;
+use crate::network::ping_guard::PingGuard;
+use crate::network::port_mapping::run_port_mapper;
+use crate::network::proxy_protocol::InboundProxyProtocol;
 use crate::network::query::QueryHandler;
+use crate::network::rate_limit::{Direction, SharedBandwidth, run_bucket_pruner};
+use crate::network::session::{
+    Eviction, Session, SessionRegistry, new_session_id, session_snapshot_path, session_span,
+};
+use crate::network::tproxy;
+use crate::network::tunnel::{run_tunnel_edge, run_tunnel_origin};
+use crate::network::under_attack::{AttackCounters, run_under_attack_monitor};
+use crate::reload;
+use crate::retention;
+use crate::scheduler;
+use crate::state::ProxyState;
+use crate::update;
 use rust_raknet::error::RaknetError;
 use rust_raknet::{RaknetListener, RaknetSocket, Reliability};
 use std::io::Cursor;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use tokio::net::UdpSocket;
+use tokio::sync::{RwLock, mpsc};
 use tokio::time::Instant;
 use tokio_graceful_shutdown::{ErrorAction, SubsystemBuilder, SubsystemHandle, Toplevel};
+use tracing::Instrument;
 
-const RAKNET_GAME_PACKET_ID: u8 = 0xfe;
+/// How long to wait for a disconnect message to be delivered before closing the connection.
+const DISCONNECT_GRACE: std::time::Duration = std::time::Duration::from_millis(200);
 
-pub async fn run(config: CCProxyConfig) -> CCProxyResult<()> {
+const MOTD_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// How often and how many times the MOTD is refreshed after the upstream restarts.
+const RESTART_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+const RESTART_REFRESH_COUNT: u32 = 10;
+
+const STATS_QUERY_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
+// The counters of every forwarded packet.
+static FORWARDED_PACKETS_C2S: LazyLock<CounterHandle> =
+    LazyLock::new(|| METRICS.counter_handle(&FORWARDED_PACKETS_TOTAL, &[("direction", "c2s")]));
+static FORWARDED_PACKETS_S2C: LazyLock<CounterHandle> =
+    LazyLock::new(|| METRICS.counter_handle(&FORWARDED_PACKETS_TOTAL, &[("direction", "s2c")]));
+static FORWARDED_BYTES_C2S: LazyLock<CounterHandle> =
+    LazyLock::new(|| METRICS.counter_handle(&FORWARDED_BYTES_TOTAL, &[("direction", "c2s")]));
+static FORWARDED_BYTES_S2C: LazyLock<CounterHandle> =
+    LazyLock::new(|| METRICS.counter_handle(&FORWARDED_BYTES_TOTAL, &[("direction", "s2c")]));
+
+pub async fn run(
+    config: CCProxyConfig,
+    profile: Option<String>,
+    log_filter: LogFilterHandle,
+) -> CCProxyResult<()> {
     tracing::info!(
         "The proxy server (v{}) is starting...",
         built_info::PKG_VERSION
     );
 
+    // The main listener is started next to the named ones and the port ranges.
+    config.check_listener_addresses()?;
+
+    let state = Arc::new(ProxyState::new());
+    let bans = Arc::new(BanStore::load(&BANS_PATH).await?);
+    let tokens = match &config.admin {
+        Some(admin) => admin.load_tokens()?,
+        None => vec![],
+    };
+    let admin = Arc::new(Admin::new(bans.clone(), tokens).with_log_filter(log_filter));
+    let bandwidth = Arc::new(SharedBandwidth::new(&config.bandwidth));
+
     Toplevel::<CCProxyError>::new(move |s| async move {
+        let expirer_bans = bans.clone();
+        s.start(SubsystemBuilder::new("BanExpirer", move |s| {
+            run_ban_expirer(s, expirer_bans)
+        }));
+
+        if let Some(admin_config) = config.admin.clone() {
+            let listener_admin = admin.clone();
+            s.start(SubsystemBuilder::new("AdminListener", move |s| {
+                admin::run_admin_listener(s, admin_config, listener_admin)
+            }));
+        }
+
+        if config.control.enabled {
+            let control_config = config.control.clone();
+            let control_admin = admin.clone();
+            s.start(SubsystemBuilder::new("ControlSocket", move |s| {
+                admin::run_control_socket(s, control_config, control_admin)
+            }));
+        }
+
+        #[cfg(unix)]
+        {
+            let hangup_admin = admin.clone();
+            s.start(SubsystemBuilder::new("HangupHandler", move |s| {
+                reload::run_hangup_handler(s, hangup_admin)
+            }));
+        }
+
+        if let Some(metrics_address) = config.metrics.address {
+            s.start(SubsystemBuilder::new("MetricsExporter", move |s| {
+                prometheus::run_prometheus_exporter(s, metrics_address)
+            }));
+        }
+
+        if let Some(push_config) = config.metrics.push.clone() {
+            s.start(SubsystemBuilder::new("MetricsPusher", move |s| {
+                push::run_metrics_pusher(s, push_config)
+            }));
+        }
+
+        if let Some(influx_config) = config.metrics.influxdb.clone() {
+            s.start(SubsystemBuilder::new("MetricsInfluxExporter", move |s| {
+                influx::run_influx_exporter(s, influx_config)
+            }));
+        }
+
+        if let Some(statsd_config) = config.metrics.statsd.clone() {
+            s.start(SubsystemBuilder::new("MetricsStatsdExporter", move |s| {
+                statsd::run_statsd_exporter(s, statsd_config)
+            }));
+        }
+
+        if config.metrics.packets.enabled {
+            PACKET_STATS.enable();
+            let packets_config = config.metrics.packets.clone();
+            s.start(SubsystemBuilder::new("PacketStatsExporter", move |s| {
+                packets::run_packet_stats_exporter(s, packets_config)
+            }));
+        }
+
+        if config.update_check.enabled {
+            let update_check_config = config.update_check.clone();
+            s.start(SubsystemBuilder::new("UpdateChecker", move |s| {
+                update::run_update_checker(s, update_check_config)
+            }));
+        }
+
+        let retention_config = config.retention.clone();
+        s.start(SubsystemBuilder::new("Retention", move |s| {
+            retention::run_retention(s, retention_config)
+        }));
+
+        // Every named listener is an independent proxy with its own runtime state.
+        for (name, listener) in config.listeners.clone() {
+            let listener_config = config.with_listener(&listener);
+            let listener_profile = profile.clone();
+            let listener_name = name.clone();
+            let listener_admin = admin.clone();
+            let listener_bans = bans.clone();
+            let listener_bandwidth = bandwidth.clone();
+            s.start(SubsystemBuilder::new(
+                format!("ProxyServer_{name}"),
+                move |s| {
+                    listen(
+                        s,
+                        listener_config,
+                        Arc::new(ProxyState::new()),
+                        listener_admin,
+                        listener_bans,
+                        listener_bandwidth,
+                        listener_profile,
+                        Some(listener_name),
+                        None,
+                    )
+                },
+            ));
+        }
+
+        for (name, range) in config.port_ranges.clone() {
+            let range_listeners = config.port_range_listeners(&name, &range);
+            let range_config = config.clone();
+            let range_profile = profile.clone();
+            let range_admin = admin.clone();
+            let range_bans = bans.clone();
+            let range_bandwidth = bandwidth.clone();
+            s.start(SubsystemBuilder::new(
+                format!("PortRange_{name}"),
+                move |s| {
+                    run_port_range(
+                        s,
+                        range_config,
+                        range_listeners,
+                        range_admin,
+                        range_bans,
+                        range_bandwidth,
+                        range_profile,
+                    )
+                },
+            ));
+        }
+
         s.start(SubsystemBuilder::new("ProxyServer", move |s| {
-            listen(s, config)
+            listen(
+                s, config, state, admin, bans, bandwidth, profile, None, None,
+            )
         }));
     })
     .catch_signals()
@@ -34,62 +246,670 @@ pub async fn run(config: CCProxyConfig) -> CCProxyResult<()> {
     Ok(())
 }
 
-async fn listen(
+/// Run every port of a range as a listener, with the upstream monitors shared by all.
+async fn run_port_range(
     sub_sys: SubsystemHandle<CCProxyError>,
     config: CCProxyConfig,
+    listeners: Vec<(String, ListenerConfig)>,
+    admin: Arc<Admin>,
+    bans: Arc<BanStore>,
+    bandwidth: Arc<SharedBandwidth>,
+    profile: Option<String>,
+) -> CCProxyResult<()> {
+    let upstreams = listeners.iter().map(|(_, l)| l.upstream.clone()).collect();
+    let monitors = start_upstream_monitors(&sub_sys, &config, upstreams).await?;
+
+    for (name, listener) in listeners {
+        let listener_config = config.with_listener(&listener);
+        let listener_profile = profile.clone();
+        let listener_name = name.clone();
+        let listener_admin = admin.clone();
+        let listener_bans = bans.clone();
+        let listener_bandwidth = bandwidth.clone();
+        let listener_monitors = monitors.clone();
+        sub_sys.start(SubsystemBuilder::new(
+            format!("ProxyServer_{name}"),
+            move |s| {
+                listen(
+                    s,
+                    listener_config,
+                    Arc::new(ProxyState::new()),
+                    listener_admin,
+                    listener_bans,
+                    listener_bandwidth,
+                    listener_profile,
+                    Some(listener_name),
+                    Some(listener_monitors),
+                )
+            },
+        ));
+    }
+
+    sub_sys.on_shutdown_requested().await;
+
+    Ok(())
+}
+
+/// The DNS resolution, the latency probes, and the health checks of the upstreams of a
+/// listener. The ports of a range share them, as their upstreams only differ in the port.
+#[derive(Clone)]
+struct UpstreamMonitors {
+    upstream_addresses: Arc<UpstreamAddresses>,
+
+    probes: Arc<LatencyProbes>,
+
+    health: Arc<UpstreamHealth>,
+}
+
+/// Resolve the hostnames of `upstreams` and start following them, probing their latency,
+/// and checking their health.
+async fn start_upstream_monitors(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    config: &CCProxyConfig,
+    upstreams: Vec<UpstreamConfig>,
+) -> CCProxyResult<UpstreamMonitors> {
+    // Upstream hostnames are resolved before serving and followed afterwards.
+    let pool = upstreams
+        .iter()
+        .flat_map(|u| u.pool())
+        .map(|s| s.address)
+        .collect::<Vec<_>>();
+    let upstream_addresses =
+        Arc::new(UpstreamAddresses::resolve(DnsResolver::new(&config.dns)?, pool).await?);
+    let refresher_upstream_addresses = upstream_addresses.clone();
+    let refresh_interval = std::time::Duration::from_secs(config.dns.refresh_interval_secs);
+    sub_sys.start(SubsystemBuilder::new("DnsRefresher", move |sub| {
+        run_dns_refresher(sub, refresh_interval, refresher_upstream_addresses)
+    }));
+
+    let upstreams = Arc::new(upstreams);
+
+    // Upstream latency prober
+    let probes = Arc::new(LatencyProbes::new(config.upstream.latency_probe.window));
+    let probe_upstreams = upstreams.clone();
+    let probe_upstream_addresses = upstream_addresses.clone();
+    let probe_targets = move || {
+        probe_upstreams
+            .iter()
+            .flat_map(|upstream| {
+                let proxy_protocol = upstream.proxy_protocol.is_enabled();
+                let mut targets = upstream
+                    .pool()
+                    .iter()
+                    .flat_map(|s| {
+                        let proxy_protocol = upstream.proxy_protocol_of(s).is_enabled();
+                        probe_upstream_addresses
+                            .targets(&s.address)
+                            .into_iter()
+                            .map(move |address| (address, proxy_protocol))
+                    })
+                    .collect::<Vec<_>>();
+                targets.extend(upstream.regions.iter().map(|r| (r.address, proxy_protocol)));
+                if let Some(backup) = &upstream.backup {
+                    targets.push((backup.address, proxy_protocol));
+                }
+                targets
+            })
+            .collect::<Vec<_>>()
+    };
+    let probe_config = config.upstream.latency_probe.clone();
+    let prober_probes = probes.clone();
+    sub_sys.start(SubsystemBuilder::new("LatencyProber", move |sub| {
+        run_latency_prober(sub, probe_config, probe_targets, prober_probes)
+    }));
+
+    // Upstream health checker
+    let health = Arc::new(UpstreamHealth::new(&config.upstream.health_check));
+    let health_upstreams = upstreams.clone();
+    let health_upstream_addresses = upstream_addresses.clone();
+    let health_targets = move || {
+        health_upstreams
+            .iter()
+            .flat_map(|upstream| {
+                let mut targets = upstream
+                    .pool()
+                    .iter()
+                    .flat_map(|s| {
+                        // The Query address is of a single server, so it is not used for SRV.
+                        let query_address = s.query_address.filter(|_| !s.address.is_srv());
+                        let proxy_protocol = upstream.proxy_protocol_of(s).is_enabled();
+                        health_upstream_addresses
+                            .targets(&s.address)
+                            .into_iter()
+                            .map(move |address| HealthCheckTarget {
+                                address,
+                                query_address,
+                                proxy_protocol,
+                            })
+                    })
+                    .collect::<Vec<_>>();
+                targets.extend(upstream.regions.iter().map(|r| HealthCheckTarget {
+                    address: r.address,
+                    query_address: None,
+                    proxy_protocol: upstream.proxy_protocol.is_enabled(),
+                }));
+                targets
+            })
+            .collect::<Vec<_>>()
+    };
+    let health_config = config.upstream.health_check.clone();
+    let checker_health = health.clone();
+    sub_sys.start(SubsystemBuilder::new("HealthChecker", move |sub| {
+        run_health_checker(sub, health_config, health_targets, checker_health)
+    }));
+
+    Ok(UpstreamMonitors {
+        upstream_addresses,
+        probes,
+        health,
+    })
+}
+
+/// Check that the proxy server could start, without serving any player.
+///
+/// The listener is bound and released, and the upstream is pinged once, so this can
+/// gate deployments in CI/CD pipelines.
+pub async fn dry_run(config: &CCProxyConfig) -> CCProxyResult<()> {
+    let mut findings = doctor::check_config(config);
+    findings.push(doctor::check_listener(config).await);
+    findings.push(doctor::check_upstream_ping(config).await);
+
+    match doctor::report(&findings) {
+        0 => {
+            println!("The proxy server is ready to start.");
+            Ok(())
+        }
+        failures => Err(CCProxyError::NotReady { failures }),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn listen(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    mut config: CCProxyConfig,
+    state: Arc<ProxyState>,
+    admin: Arc<Admin>,
+    bans: Arc<BanStore>,
+    bandwidth: Arc<SharedBandwidth>,
+    profile: Option<String>,
+    listener: Option<String>,
+    monitors: Option<UpstreamMonitors>,
 ) -> CCProxyResult<()> {
     let start_time = Instant::now();
+    let snapshot_path = session_snapshot_path(listener.as_deref());
+
+    config.check_proxy_protocol()?;
+    config.validate()?;
+
+    // The IPv6 socket of a dual-stack listener is advertised unless a port is set.
+    if let Some(address) = config.proxy.ipv6_address {
+        config
+            .proxy
+            .fallback_motd
+            .ipv6_port
+            .get_or_insert(address.port());
+    }
 
-    let mut server = RaknetListener::bind_with(&config.proxy.address, true, Some(15_000)).await?;
+    let config = Arc::new(config);
+    let disconnect_messages = &config.proxy.disconnect_messages;
+
+    let sessions = Arc::new(SessionRegistry::new(
+        std::time::Duration::from_secs(config.proxy.session_state.affinity_ttl_secs),
+        std::time::Duration::from_secs(config.proxy.session_state.sticky_ttl_secs),
+        config.history.enabled.then(SessionHistory::new),
+        config.access_log.enabled.then(AccessLog::new),
+        GeoIp::open(&config.geoip)?,
+        config.proxy.session_bandwidth,
+        bandwidth,
+    ));
+    if config.proxy.session_state.persist {
+        match sessions.load_snapshot(&snapshot_path).await {
+            Ok(0) => (),
+            Ok(restored) => {
+                tracing::info!("{restored} session affinities are restored from the snapshot.")
+            }
+            Err(err) => tracing::error!("Cannot restore the session snapshot: {err}"),
+        }
+    }
+
+    let access = Arc::new(AccessList::new(&config.proxy.access));
+    if let Some(blocklist_config) = config.proxy.access.blocklists.clone() {
+        let refresher_access = access.clone();
+        sub_sys.start(SubsystemBuilder::new("BlocklistRefresher", move |sub| {
+            run_blocklist_refresher(sub, blocklist_config, refresher_access)
+        }));
+    }
+
+    // Measured by the under-attack monitor, if any.
+    let attack_counters = Arc::new(AttackCounters::default());
+    let admission = Arc::new(Admission::new(
+        &config.proxy,
+        &config.geoip,
+        state.clone(),
+        access,
+        bans.clone(),
+        sessions.clone(),
+        attack_counters.clone(),
+    ));
+    let inbound = Arc::new(InboundProxyProtocol::new(
+        &config.proxy.inbound_proxy_protocol,
+    ));
+    let outbound = Arc::new(Outbound::new(config.upstream.outbound.clone()));
+    // Java Edition players are admitted by the same rules as the Bedrock ones.
+    if let Some(java_config) = config.proxy.java.clone() {
+        let java_disconnect_messages = config.proxy.disconnect_messages.clone();
+        let java_admission = admission.clone();
+        let java_outbound = outbound.clone();
+        sub_sys.start(SubsystemBuilder::new("JavaListener", move |sub| {
+            java::run_java_listener(
+                sub,
+                java_config,
+                java_disconnect_messages,
+                java_admission,
+                java_outbound,
+            )
+        }));
+    }
+    if let Some(tproxy_config) = config.proxy.tproxy.clone() {
+        let tproxy_mtu = config.proxy.mtu.clone();
+        let tproxy_inbound = inbound.clone();
+        let tproxy_sessions = sessions.clone();
+        let tproxy_admission = admission.clone();
+        sub_sys.start(SubsystemBuilder::new("TproxyGateway", move |sub| {
+            tproxy::run_tproxy_gateway(
+                sub,
+                tproxy_config,
+                tproxy_mtu,
+                tproxy_inbound,
+                tproxy_sessions,
+                tproxy_admission,
+            )
+        }));
+    }
+
+    let datagram_filter = Arc::new(DatagramFilter::new(
+        config.proxy.datagram_filter.clone(),
+        bans,
+        sessions.clone(),
+        config.proxy.disconnect_messages.ban.clone(),
+        attack_counters.clone(),
+    ));
+    let pings = Arc::new(PingGuard::new(
+        config.proxy.rate_limit.pings_per_ip,
+        state.clone(),
+        attack_counters.clone(),
+        config.proxy.randomize_guid,
+    ));
+
+    let pruner_admission = admission.clone();
+    let pruner_pings = pings.clone();
+    sub_sys.start(SubsystemBuilder::new("BucketPruner", move |sub| {
+        run_bucket_pruner(sub, move || {
+            pruner_admission.prune();
+            pruner_pings.prune();
+        })
+    }));
+
+    if let Some(under_attack_config) = config.proxy.under_attack.clone() {
+        let monitor_state = state.clone();
+        let monitor_listener = listener.clone();
+        sub_sys.start(SubsystemBuilder::new("UnderAttackMonitor", move |sub| {
+            run_under_attack_monitor(
+                sub,
+                under_attack_config,
+                attack_counters,
+                monitor_state,
+                monitor_listener,
+            )
+        }));
+    }
+
+    // Repeated transport errors are summarized instead of flooding the log.
+    let error_summary = Arc::new(ErrorSummary::new(config.log.error_summary.clone()));
+    let summarizer_error_summary = error_summary.clone();
+    sub_sys.start(SubsystemBuilder::new("ErrorSummarizer", move |sub| {
+        run_error_summarizer(sub, summarizer_error_summary)
+    }));
+
+    if config.proxy.maintenance.enabled {
+        maintenance::set_maintenance(&sub_sys, &config.proxy, &state, &sessions, true);
+    }
+
+    // The scheduler acts on the live sessions, so it starts with the listener.
+    if !config.schedules.is_empty() {
+        let schedules = config.schedules.clone();
+        let scheduler_proxy = config.proxy.clone();
+        let timezone = config.timezone();
+        let scheduler_state = state.clone();
+        let scheduler_sessions = sessions.clone();
+        sub_sys.start(SubsystemBuilder::new("Scheduler", move |s| async move {
+            scheduler::run_scheduler(
+                s,
+                schedules,
+                scheduler_proxy,
+                timezone?,
+                scheduler_state,
+                scheduler_sessions,
+            )
+            .await
+        }));
+    }
+
+    let UpstreamMonitors {
+        upstream_addresses,
+        probes,
+        health,
+    } = match monitors {
+        Some(monitors) => monitors,
+        None => start_upstream_monitors(&sub_sys, &config, vec![config.upstream.clone()]).await?,
+    };
+
+    let per_ip_config = config.metrics.per_ip.clone();
+    let per_ip_sessions = sessions.clone();
+    sub_sys.start(SubsystemBuilder::new("PerIpMonitor", move |sub| {
+        run_per_ip_monitor(sub, per_ip_config, per_ip_sessions)
+    }));
+
+    if config.proxy.stats_query.is_some() {
+        let stats_config = config.clone();
+        let stats_sessions = sessions.clone();
+        sub_sys.start(SubsystemBuilder::new("StatsQueryResponder", move |sub| {
+            run_stats_query_responder(sub, stats_config, stats_sessions)
+        }));
+    }
+
+    let breaker = Arc::new(CircuitBreaker::new(config.upstream.circuit_breaker.clone()));
+
+    let (commands, command_receiver) = mpsc::unbounded_channel();
+    admin.register(
+        listener.clone(),
+        ListenerHandle {
+            address: config.proxy.address,
+            state: state.clone(),
+            sessions: sessions.clone(),
+            disconnect_messages: config.proxy.disconnect_messages.clone(),
+            upstreams: config
+                .upstream
+                .pool()
+                .into_iter()
+                .map(|s| s.address)
+                .collect(),
+            upstream_addresses: upstream_addresses.clone(),
+            health: health.clone(),
+            breaker: breaker.clone(),
+            probes: probes.clone(),
+            commands,
+        },
+    );
 
-    server
-        .set_full_motd(
-            config
-                .proxy
-                .fallback_motd
-                .clone()
-                .encode(Some(server.guid())),
+    // The upstream pool and the maintenance mode follow config reloads.
+    let reloader_config = config.clone();
+    let reloader_state = state.clone();
+    let reloader_sessions = sessions.clone();
+    let reloader_upstream_addresses = upstream_addresses.clone();
+    sub_sys.start(SubsystemBuilder::new("ConfigReloader", move |sub| {
+        reload::run_config_reloader(
+            sub,
+            profile,
+            listener,
+            reloader_config,
+            reloader_state,
+            reloader_sessions,
+            reloader_upstream_addresses,
+            command_receiver,
         )
-        .await?;
+    }));
+    let balancer = Arc::new(Balancer::new(
+        config.upstream.balancer,
+        config.upstream.pool(),
+    ));
+
+    if let Some(port_mapping_config) = config.proxy.port_mapping.clone() {
+        let address = config.proxy.address;
+        sub_sys.start(SubsystemBuilder::new("PortMapper", move |sub| {
+            run_port_mapper(sub, port_mapping_config, address)
+        }));
+    }
+
+    // The passthrough mode and the origin of a tunnel relay raw datagrams instead of
+    // serving RakNet.
+    let select_config = config.clone();
+    let select_state = state.clone();
+    let select_sessions = sessions.clone();
+    let select_probes = probes.clone();
+    let select_health = health.clone();
+    let select_breaker = breaker.clone();
+    let select_balancer = balancer.clone();
+    let select_upstream_addresses = upstream_addresses.clone();
+    let select_admission = admission.clone();
+    // Every passthrough worker and every edge of a tunnel selects upstreams for its own
+    // clients.
+    let select = move |client_address: SocketAddr| {
+        let config = select_config.clone();
+        let state = select_state.clone();
+        let sessions = select_sessions.clone();
+        let probes = select_probes.clone();
+        let health = select_health.clone();
+        let breaker = select_breaker.clone();
+        let balancer = select_balancer.clone();
+        let upstream_addresses = select_upstream_addresses.clone();
+        let admission = select_admission.clone();
+        async move {
+            let drained = state
+                .drained_upstreams()
+                .iter()
+                .flat_map(|u| upstream_addresses.targets(u))
+                .collect::<Vec<_>>();
+            let is_available = |a: &SocketAddr| {
+                !drained.contains(a) && health.is_healthy(a) && breaker.is_available(a)
+            };
+            let upstream_address = select_upstream(
+                &config,
+                &sessions,
+                &probes,
+                &is_available,
+                &balancer,
+                &upstream_addresses,
+                &client_address,
+            )
+            .await;
+
+            let admitted = admission
+                .check(&client_address)
+                .and_then(|slot| acquire_upstream(&breaker, upstream_address, slot));
+            match admitted {
+                Ok(admitted) => Some(admitted),
+                Err(refusal) => {
+                    METRICS.counter_add(
+                        &SESSIONS_REFUSED_TOTAL,
+                        &[("reason", refusal.reason())],
+                        1.0,
+                    );
+                    None
+                }
+            }
+        }
+    };
+
+    if let Some(tunnel_config) = config.proxy.tunnel.clone() {
+        let result = match tunnel_config.role {
+            TunnelRole::Edge => {
+                let admit_admission = admission.clone();
+                // The origin selects the upstream, so only the listener is checked here.
+                let admit = move |client_address: SocketAddr| {
+                    admit_admission
+                        .check(&client_address)
+                        .inspect_err(|refusal| {
+                            METRICS.counter_add(
+                                &SESSIONS_REFUSED_TOTAL,
+                                &[("reason", refusal.reason())],
+                                1.0,
+                            );
+                        })
+                        .ok()
+                };
+
+                run_tunnel_edge(
+                    sub_sys,
+                    tunnel_config,
+                    config.proxy.address,
+                    sessions.clone(),
+                    datagram_filter,
+                    pings,
+                    config.proxy.mtu.clone(),
+                    inbound,
+                    admit,
+                )
+                .await
+            }
+            TunnelRole::Origin => {
+                let proxy_protocol_config = config.clone();
+                let proxy_protocol_upstream_addresses = upstream_addresses.clone();
+                let proxy_protocol = move |upstream_address: &SocketAddr| {
+                    upstream_proxy_protocol(
+                        &proxy_protocol_config,
+                        &proxy_protocol_upstream_addresses,
+                        upstream_address,
+                    )
+                };
+
+                run_tunnel_origin(
+                    sub_sys,
+                    tunnel_config,
+                    sessions.clone(),
+                    outbound,
+                    select,
+                    proxy_protocol,
+                )
+                .await
+            }
+        };
+
+        if config.proxy.session_state.persist {
+            save_session_snapshot(&sessions, &snapshot_path).await;
+        }
+
+        return result;
+    }
+
+    if let Some(passthrough_config) = config.proxy.passthrough.clone() {
+        let pong_config = config.clone();
+        let pong_upstream_addresses = upstream_addresses.clone();
+        let pong_upstream = move || pong_upstream_addresses.get(&pong_config.upstream.address);
+        let result = run_passthrough(
+            sub_sys,
+            passthrough_config,
+            config.proxy.address,
+            config.proxy.workers,
+            sessions.clone(),
+            datagram_filter,
+            pings,
+            config.proxy.mtu.clone(),
+            inbound,
+            outbound,
+            pong_upstream,
+            select,
+        )
+        .await;
+
+        if config.proxy.session_state.persist {
+            save_session_snapshot(&sessions, &snapshot_path).await;
+        }
+
+        return result;
+    }
+
+    let mut server = RaknetListener::bind_with(&config.proxy.address, true, Some(15_000)).await?;
+
+    // The second socket of a dual-stack listener, whose port is advertised in the MOTD.
+    let mut ipv6_server = match config.proxy.ipv6_address {
+        Some(address) => Some(RaknetListener::bind_with(&address, true, Some(15_000)).await?),
+        None => None,
+    };
+    let initial_motd = config
+        .proxy
+        .fallback_motd
+        .clone()
+        .encode(Some(server.guid()));
+    server.set_full_motd(initial_motd.clone()).await?;
+    if let Some(ipv6_server) = &mut ipv6_server {
+        ipv6_server.set_full_motd(initial_motd).await?;
+    }
 
     // MOTD updater
-    let motd = server.motd().await;
+    let mut motds = vec![server.motd().await];
+    if let Some(ipv6_server) = &ipv6_server {
+        motds.push(ipv6_server.motd().await);
+    }
 
     let updater_config = config.clone();
     let guid = server.guid();
+    let updater_state = state.clone();
+    let updater_probes = probes.clone();
+    let updater_upstream_addresses = upstream_addresses.clone();
     sub_sys.start(SubsystemBuilder::new("ProxyMotdUpdater", move |sub| {
-        run_motd_updater(sub, updater_config, motd, guid)
+        run_motd_updater(
+            sub,
+            updater_config,
+            updater_state,
+            updater_probes,
+            updater_upstream_addresses,
+            motds,
+            guid,
+        )
     }));
 
+    if let Some(lan_config) = config.proxy.lan_discovery.clone() {
+        let lan_motd = server.motd().await;
+        sub_sys.start(SubsystemBuilder::new("LanAnnouncer", move |sub| {
+            run_lan_announcer(sub, lan_config, lan_motd, guid)
+        }));
+    }
+
     server.listen().await;
+    if let Some(ipv6_server) = &mut ipv6_server {
+        ipv6_server.listen().await;
+    }
     tracing::debug!("RaknetListener(GUID: {guid}) is started.");
 
     // Query Protocol handler
     if let Some(query_address) = config.upstream.query_address {
-        let query_recv = server.get_recv_query()?;
-        let query_socket = server.get_raw_socket().unwrap();
-        sub_sys.start(SubsystemBuilder::new(
-            "QueryHandler",
-            move |sub| async move {
-                let query_handler = QueryHandler::new(query_address, &config.proxy.fallback_query);
-                query_handler.init(&sub).await;
+        let mut queries = vec![(server.get_recv_query()?, server.get_raw_socket().unwrap())];
+        if let Some(ipv6_server) = &mut ipv6_server {
+            queries.push((
+                ipv6_server.get_recv_query()?,
+                ipv6_server.get_raw_socket().unwrap(),
+            ));
+        }
 
-                loop {
-                    tokio::select! {
-                        Some((address, packet)) = async { query_recv.lock().await.recv().await } => {
-                            if let Err(err) = query_handler.handle_packet(&query_socket, &address, &mut Cursor::new(packet)).await {
-                                tracing::debug!("Failed to handle a Query packet from the client ({address}): {err}");
-                            }
-                        },
-                        _ = sub.on_shutdown_requested() => {
-                            break;
-                        },
+        for (query_recv, query_socket) in queries {
+            let fallback_query = config.proxy.fallback_query.clone();
+            sub_sys.start(SubsystemBuilder::new(
+                "QueryHandler",
+                move |sub| async move {
+                    let query_handler = QueryHandler::new(query_address, &fallback_query);
+                    query_handler.init(&sub).await;
+
+                    loop {
+                        tokio::select! {
+                            Some((address, packet)) = async { query_recv.lock().await.recv().await } => {
+                                if let Err(err) = query_handler.handle_packet(&query_socket, &address, &mut Cursor::new(packet)).await {
+                                    METRICS.counter_add(&QUERY_REQUESTS_TOTAL, &[("result", "failure")], 1.0);
+                                    tracing::debug!("Failed to handle a Query packet from the client ({address}): {err}");
+                                } else {
+                                    METRICS.counter_add(&QUERY_REQUESTS_TOTAL, &[("result", "success")], 1.0);
+                                }
+                            },
+                            _ = sub.on_shutdown_requested() => {
+                                break;
+                            },
+                        }
                     }
-                }
 
-                Ok::<_, CCProxyError>(())
-            },
-        ));
+                    Ok::<_, CCProxyError>(())
+                },
+            ));
+        }
     }
 
     tracing::info!(
@@ -100,14 +920,54 @@ async fn listen(
 
     loop {
         tokio::select! {
-            conn = server.accept() => {
+            conn = accept(&mut server, ipv6_server.as_mut()) => {
                 let conn = conn?;
                 let client_address = conn.peer_addr().unwrap();
-                let upstream_address = config.upstream.address;
-                let upstream_proxy_protocol = config.upstream.proxy_protocol;
 
+                let drained = state
+                    .drained_upstreams()
+                    .iter()
+                    .flat_map(|u| upstream_addresses.targets(u))
+                    .collect::<Vec<_>>();
+                let is_available = |a: &SocketAddr| !drained.contains(a) && health.is_healthy(a) && breaker.is_available(a);
+                let upstream_address = select_upstream(&config, &sessions, &probes, &is_available, &balancer, &upstream_addresses, &client_address).await;
+
+                let admitted = admission
+                    .check(&client_address)
+                    .and_then(|slot| acquire_upstream(&breaker, upstream_address, slot));
+                let (upstream_address, slot) = match admitted {
+                    Ok(admitted) => admitted,
+                    Err(refusal) => {
+                        METRICS.counter_add(&SESSIONS_REFUSED_TOTAL, &[("reason", refusal.reason())], 1.0);
+                        tracing::info!("The client ({client_address}) is refused because {}.", refusal.description());
+
+                        let message = (!admission.is_silent(refusal)).then(|| refusal.message(disconnect_messages).to_owned());
+                        sub_sys.start(SubsystemBuilder::new(
+                            format!("ClientRefuse_{client_address}"),
+                            move |_| async move { refuse_client(conn, message.as_deref()).await },
+                        ));
+                        continue;
+                    }
+                };
+                let proxy_protocol = upstream_proxy_protocol(&config, &upstream_addresses, &upstream_address);
+
+                let conn_config = config.clone();
+                let conn_sessions = sessions.clone();
+                let conn_breaker = breaker.clone();
+                let conn_admission = admission.clone();
+                let conn_error_summary = error_summary.clone();
+
+                // Every span of the session is under this one, which starts at the accept.
+                let session_id = new_session_id();
+                let conn_span = session_span(&session_id, client_address, upstream_address);
                 let conn_task = SubsystemBuilder::new(
-                    format!("Client_{client_address}"), move |sub| handle_connection(sub, upstream_address, upstream_proxy_protocol, conn)
+                    format!("Client_{client_address}"), move |sub| async move {
+                        // The client counts towards `max_connections_per_ip` until it is disconnected.
+                        let _slot = slot;
+                        handle_connection(sub, conn_config, conn_sessions, conn_breaker, conn_admission, session_id, upstream_address, proxy_protocol, conn)
+                            .instrument(conn_span)
+                            .await
+                    }
                 )
                     .on_failure(ErrorAction::CatchAndLocalShutdown);
                 let conn_task_start = sub_sys.start(conn_task);
@@ -118,9 +978,13 @@ async fn listen(
                         err = conn_task_start.join() => {
                             if let Err(err) = err && let Some(err) = sub_sys_err_to_ccproxy_err(&err) {
                                 match err {
+                                    // Already logged when the client was given up on.
+                                    CCProxyError::UpstreamConnectExhausted { .. } => (),
                                     CCProxyError::RakNet { err: err_raknet } => match err_raknet {
                                         rust_raknet::error::RaknetError::ConnectionClosed => (),
-                                        _ => tracing::error!("The client ({client_address}) error is occurred: {err}")
+                                        _ => if conn_error_summary.record(&format!("{err_raknet:?}"), client_address.ip()) {
+                                            tracing::error!("The client ({client_address}) error is occurred: {err}")
+                                        }
                                     },
                                     _ => tracing::error!("The client ({client_address}) error is occurred: {err}")
                                 }
@@ -139,6 +1003,13 @@ async fn listen(
                 tracing::info!("The proxy server is stopping...");
 
                 server.close().await.ok();
+                if let Some(ipv6_server) = &mut ipv6_server {
+                    ipv6_server.close().await.ok();
+                }
+
+                if config.proxy.session_state.persist {
+                    save_session_snapshot(&sessions, &snapshot_path).await;
+                }
 
                 break;
             },
@@ -148,43 +1019,316 @@ async fn listen(
     Ok(())
 }
 
+async fn save_session_snapshot(sessions: &SessionRegistry, path: &std::path::Path) {
+    match sessions.save_snapshot(path).await {
+        Ok(saved) => tracing::info!("{saved} session affinities are saved to the snapshot."),
+        Err(err) => tracing::error!("Cannot save the session snapshot: {err}"),
+    }
+}
+
+/// Accept a connection on either socket of a dual-stack listener.
+async fn accept(
+    server: &mut RaknetListener,
+    ipv6_server: Option<&mut RaknetListener>,
+) -> Result<RaknetSocket, RaknetError> {
+    match ipv6_server {
+        Some(ipv6_server) => tokio::select! {
+            conn = server.accept() => conn,
+            conn = ipv6_server.accept() => conn,
+        },
+        None => server.accept().await,
+    }
+}
+
+/// Take the circuit of the selected upstream for an admitted client, refusing it if the
+/// circuit is open or the upstream is not resolved yet.
+fn acquire_upstream(
+    breaker: &CircuitBreaker,
+    upstream_address: Option<SocketAddr>,
+    slot: AdmissionSlot,
+) -> Result<(SocketAddr, AdmissionSlot), Refusal> {
+    match upstream_address {
+        Some(address) if breaker.try_acquire(&address) => Ok((address, slot)),
+        Some(_) => Err(Refusal::CircuitOpen),
+        None => Err(Refusal::UpstreamUnresolved),
+    }
+}
+
+/// Select the upstream for a new client, resuming to the previous upstream of its
+/// endpoint or IP while it is still configured and available, i.e. healthy and with a
+/// closed circuit. Clients without a region are distributed over the available
+/// upstreams by the balancer. Returns `None` if the upstream it picks is not resolved yet.
+async fn select_upstream(
+    config: &CCProxyConfig,
+    sessions: &SessionRegistry,
+    probes: &LatencyProbes,
+    is_available: &(dyn Fn(&SocketAddr) -> bool + Sync),
+    balancer: &Balancer,
+    upstream_addresses: &UpstreamAddresses,
+    client_address: &SocketAddr,
+) -> Option<SocketAddr> {
+    let mut upstreams = config
+        .upstream
+        .pool()
+        .iter()
+        .flat_map(|s| upstream_addresses.targets(&s.address))
+        .collect::<Vec<_>>();
+    upstreams.extend(config.upstream.regions.iter().map(|r| r.address));
+
+    let previous = match sessions.affinity(client_address).await {
+        Some(address) => Some(address),
+        None => sessions.sticky(client_address.ip()),
+    };
+    if let Some(address) = previous
+        && upstreams.contains(&address)
+        && is_available(&address)
+    {
+        return Some(address);
+    }
+
+    if let Some(address) = select_region(config, sessions, probes, is_available, client_address) {
+        return Some(address);
+    }
+
+    // When every upstream is down, the connection fails over to the backup or the limbo.
+    let upstream = balancer.select(
+        client_address,
+        |a| upstream_addresses.targets(a).iter().any(is_available),
+        |a| {
+            upstream_addresses
+                .targets(a)
+                .iter()
+                .map(|t| sessions.upstream_load(t))
+                .sum()
+        },
+    );
+    upstream_addresses.pick(upstream, is_available)
+}
+
+/// The PROXY protocol of the pool member which `upstream_address` belongs to. Regions
+/// and the backup follow the upstream-level setting.
+fn upstream_proxy_protocol(
+    config: &CCProxyConfig,
+    upstream_addresses: &UpstreamAddresses,
+    upstream_address: &SocketAddr,
+) -> ProxyProtocolVersion {
+    config
+        .upstream
+        .pool()
+        .iter()
+        .find(|s| {
+            upstream_addresses
+                .targets(&s.address)
+                .contains(upstream_address)
+        })
+        .map_or(config.upstream.proxy_protocol, |s| {
+            config.upstream.proxy_protocol_of(s)
+        })
+}
+
+/// Select the available region upstream which serves the country of the client with the
+/// lowest average RTT. Regions which lost every recent probe or were never probed are
+/// skipped.
+fn select_region(
+    config: &CCProxyConfig,
+    sessions: &SessionRegistry,
+    probes: &LatencyProbes,
+    is_available: &(dyn Fn(&SocketAddr) -> bool + Sync),
+    client_address: &SocketAddr,
+) -> Option<SocketAddr> {
+    let regions = config
+        .upstream
+        .regions
+        .iter()
+        .filter(|r| is_available(&r.address))
+        .collect::<Vec<_>>();
+    if regions.is_empty() {
+        return None;
+    }
+
+    let country = sessions.geo(client_address.ip()).country;
+    let serves = |countries: &[String]| {
+        countries.is_empty()
+            || country
+                .as_ref()
+                .is_some_and(|c| countries.iter().any(|r| r.eq_ignore_ascii_case(c)))
+    };
+
+    let mut candidates = regions
+        .iter()
+        .copied()
+        .filter(|r| serves(&r.countries))
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        candidates = regions;
+    }
+
+    candidates
+        .into_iter()
+        .filter_map(|r| Some((r, probes.stats(&r.address)?.avg_rtt_ms?)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(region, rtt)| {
+            tracing::debug!(
+                "The region ({}) is selected for the client ({client_address}) with {rtt:.0}ms.",
+                region.name
+            );
+            region.address
+        })
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_connection(
     sub_sys: SubsystemHandle<CCProxyError>,
+    config: Arc<CCProxyConfig>,
+    sessions: Arc<SessionRegistry>,
+    breaker: Arc<CircuitBreaker>,
+    admission: Arc<Admission>,
+    session_id: String,
     upstream_address: SocketAddr,
-    upstream_proxy_protocol: bool,
+    upstream_proxy_protocol: ProxyProtocolVersion,
     client: RaknetSocket,
 ) -> CCProxyResult<()> {
-    let client_address = client.peer_addr()?;
+    let client_address = client
+        .peer_addr()
+        .inspect_err(|_| breaker.release(&upstream_address))?;
+    let disconnect_messages = &config.proxy.disconnect_messages;
+    let upstream_proxy_protocol = upstream_proxy_protocol.is_enabled();
+    let backup = config.upstream.backup.clone();
 
     tracing::info!("A new client ({client_address}) is connected to the proxy server.");
 
+    let first_packet = match admission.handshake_gate() {
+        Some(gate) => match wait_for_game_packet(&sub_sys, &client, gate.timeout_secs).await {
+            Some(packet) => Some(packet),
+            None => {
+                METRICS.counter_add(
+                    &SESSIONS_REFUSED_TOTAL,
+                    &[("reason", "handshake_timeout")],
+                    1.0,
+                );
+                METRICS.counter_add(&HANDSHAKE_FAILURES_TOTAL, &[("reason", "timeout")], 1.0);
+                tracing::info!(
+                    "The client ({client_address}) sent no game packet in time, so it is closed."
+                );
+                // The upstream was never tried, so the trial of its circuit is not used up.
+                breaker.release(&upstream_address);
+                client.close().await?;
+
+                return Ok(());
+            }
+        },
+        None => None,
+    };
+
+    // The client reconnected (e.g. after an app crash or a NAT rebinding) while its old
+    // session is still alive, so the old one is torn down before the new upstream leg.
+    if let Some(stale) = sessions.terminate(&client_address).await {
+        METRICS.counter_add(&SESSIONS_REPLACED_TOTAL, &[], 1.0);
+        tracing::info!(
+            "The stale session ({}) of the client ({client_address}) is replaced by the new connection.",
+            stale.id
+        );
+    }
+
     // Try to connect to he upstream server for the new client.
-    let server = match tokio::time::timeout(
-        std::time::Duration::from_secs(10),
-        RaknetSocket::connect_with(
-            &upstream_address,
-            11,
-            Some(15_000),
-            upstream_proxy_protocol.then_some(&client_address),
-        ),
+    let server = match connect_upstream(
+        &sub_sys,
+        &config.upstream.connect_retry,
+        upstream_address,
+        client_address,
+        upstream_proxy_protocol,
     )
+    .instrument(tracing::info_span!("upstream_connect", upstream = %upstream_address))
     .await
     {
         Ok(server) => {
+            breaker.record(upstream_address, true);
             tracing::info!(
                 "The client ({client_address}) is connected to the upstream server ({upstream_address})."
             );
 
-            server?
+            server
         }
-        Err(_) => {
+        Err(err) => {
+            breaker.record(upstream_address, false);
+            METRICS.counter_add(&UPSTREAM_CONNECT_FAILURES_TOTAL, &[], 1.0);
+            METRICS.counter_add(
+                &HANDSHAKE_FAILURES_TOTAL,
+                &[("reason", "upstream_unreachable")],
+                1.0,
+            );
             tracing::error!(
                 "Cannot connect to upstream server ({upstream_address}). Closing the client ({client_address})."
             );
 
+            let transferred = match &backup {
+                Some(backup) => {
+                    transfer_to_backup(&client, &HandshakeState::default(), backup).await
+                }
+                None => false,
+            };
+            if !transferred {
+                match &config.proxy.limbo {
+                    Some(limbo_config) => {
+                        tracing::info!(
+                            "The client ({client_address}) is held in the limbo until the upstream server recovers."
+                        );
+
+                        METRICS.gauge_add(&LIMBO_SESSIONS_ACTIVE, &[], 1.0);
+                        let mut limbo = Limbo::new(&client, limbo_config);
+                        let exit = async {
+                            // Read by the handshake gate before the upstream was connected
+                            if let Some(packet) = &first_packet {
+                                limbo.handle_packet(packet).await?;
+                            }
+                            limbo
+                                .hold(&sub_sys, upstream_address, upstream_proxy_protocol)
+                                .await
+                        }
+                        .await;
+                        METRICS.gauge_add(&LIMBO_SESSIONS_ACTIVE, &[], -1.0);
+
+                        match exit {
+                            Ok(LimboExit::Transferred) => {
+                                tracing::info!(
+                                    "The client ({client_address}) is transferred back from the limbo."
+                                );
+                                tokio::time::sleep(DISCONNECT_GRACE).await;
+                            }
+                            Ok(LimboExit::TimedOut) => {
+                                disconnect_client(
+                                    &client,
+                                    limbo.handshake(),
+                                    &disconnect_messages.upstream_unavailable,
+                                )
+                                .await;
+                            }
+                            Ok(LimboExit::Shutdown) => {
+                                disconnect_client(
+                                    &client,
+                                    limbo.handshake(),
+                                    &disconnect_messages.shutdown,
+                                )
+                                .await;
+                            }
+                            Err(err) => tracing::debug!(
+                                "The client ({client_address}) left the limbo: {err}"
+                            ),
+                        }
+                    }
+                    None => {
+                        disconnect_client(
+                            &client,
+                            &HandshakeState::default(),
+                            &disconnect_messages.upstream_unavailable,
+                        )
+                        .await;
+                    }
+                }
+            }
             client.close().await?;
 
-            Err(RaknetError::ConnectionClosed)?
+            return Err(err);
         }
     };
 
@@ -195,33 +1339,193 @@ async fn handle_connection(
     let c2s_server = server_clone.clone();
     let s2c_server = server_clone.clone();
 
+    let session = sessions
+        .register(session_id, client_address, upstream_address)
+        .await;
+    let c2s_session = session.clone();
+    let s2c_session = session.clone();
+    let c2s_disconnect_messages = disconnect_messages.clone();
+    let idle_timeout = config
+        .proxy
+        .idle_kick
+        .as_ref()
+        .map(|c| std::time::Duration::from_secs(c.timeout_secs));
+
+    // The forwarding loops run as their own subsystems, so their spans are made here to
+    // stay under the span of the session.
+    let c2s_span = tracing::debug_span!("forward", direction = "c2s");
+    let s2c_span = tracing::debug_span!("forward", direction = "s2c");
+
     let c2s = SubsystemBuilder::new(format!("Client_{client_address}_c2s"), move |sub| {
-        handle_c2s(sub, c2s_client.clone(), c2s_server.clone())
+        handle_c2s(
+            sub,
+            c2s_client.clone(),
+            c2s_server.clone(),
+            c2s_session,
+            admission,
+            c2s_disconnect_messages,
+            idle_timeout,
+            first_packet,
+        )
+        .instrument(c2s_span)
     });
+    let s2c_limbo = config
+        .proxy
+        .limbo
+        .clone()
+        .filter(|l| l.hold_on_upstream_loss);
     let s2c = SubsystemBuilder::new(format!("Client_{client_address}_s2c"), move |sub| {
-        handle_s2c(sub, s2c_client.clone(), s2c_server.clone())
+        handle_s2c(
+            sub,
+            s2c_client.clone(),
+            s2c_server.clone(),
+            s2c_session,
+            backup,
+            s2c_limbo,
+        )
+        .instrument(s2c_span)
     });
 
+    let upstream_label = upstream_address.to_string();
+    METRICS.counter_add(&SESSIONS_TOTAL, &[], 1.0);
+    METRICS.counter_add(
+        &SESSIONS_BY_COUNTRY_TOTAL,
+        &[("country", session.geo.country_label())],
+        1.0,
+    );
+    METRICS.gauge_add(&SESSIONS_ACTIVE, &[], 1.0);
+    METRICS.gauge_add(
+        &UPSTREAM_SESSIONS_ACTIVE,
+        &[("upstream", &upstream_label)],
+        1.0,
+    );
+
     sub_sys.start(c2s);
     sub_sys.start(s2c);
 
     sub_sys.wait_for_children().await;
 
-    let _ = tokio::join!(client_clone.close(), server_clone.close());
+    METRICS.gauge_add(&SESSIONS_ACTIVE, &[], -1.0);
+    METRICS.gauge_add(
+        &UPSTREAM_SESSIONS_ACTIVE,
+        &[("upstream", &upstream_label)],
+        -1.0,
+    );
+    async {
+        sessions.unregister(&session).await;
+
+        if session.terminate.is_cancelled() {
+            server_clone.close().await.ok();
+        } else {
+            let _ = tokio::join!(client_clone.close(), server_clone.close());
+        }
+    }
+    .instrument(tracing::info_span!("close"))
+    .await;
 
     Ok(())
 }
 
+/// Wait for the first game packet of the client, or `None` if it sends none within
+/// `timeout_secs` or leaves.
+async fn wait_for_game_packet(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    client: &RaknetSocket,
+    timeout_secs: u64,
+) -> Option<Vec<u8>> {
+    let recv = async {
+        loop {
+            match client.recv().await {
+                Ok(packet) if packet.first() == Some(&GAME_PACKET_ID) => break Some(packet),
+                Ok(_) => continue,
+                Err(_) => break None,
+            }
+        }
+    };
+
+    tokio::select! {
+        packet = tokio::time::timeout(std::time::Duration::from_secs(timeout_secs), recv) => packet.ok().flatten(),
+        // Shutdown handler
+        _ = sub_sys.on_shutdown_requested() => None,
+    }
+}
+
+/// Connect to the upstream, retrying with an exponential backoff and jitter.
+async fn connect_upstream(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    config: &ConnectRetryConfig,
+    upstream_address: SocketAddr,
+    client_address: SocketAddr,
+    proxy_protocol: bool,
+) -> CCProxyResult<RaknetSocket> {
+    let mut backoff = std::time::Duration::from_millis(config.initial_backoff_ms);
+
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let result = tokio::time::timeout(
+            std::time::Duration::from_secs(config.timeout_secs),
+            RaknetSocket::connect_with(
+                &upstream_address,
+                11,
+                Some(15_000),
+                proxy_protocol.then_some(&client_address),
+            ),
+        )
+        .await;
+        match result {
+            Ok(Ok(server)) => return Ok(server),
+            Ok(Err(err)) => tracing::debug!(
+                "The attempt {attempt} to connect to the upstream server ({upstream_address}) for the client ({client_address}) failed: {err:?}"
+            ),
+            Err(_) => tracing::debug!(
+                "The attempt {attempt} to connect to the upstream server ({upstream_address}) for the client ({client_address}) timed out."
+            ),
+        }
+        if attempt > config.retries {
+            break;
+        }
+
+        // Half of the backoff is random, so clients which failed together spread out.
+        let sleep = backoff / 2 + backoff.mul_f64(rand::random::<f64>() / 2.0);
+        tokio::select! {
+            _ = tokio::time::sleep(sleep) => (),
+            _ = sub_sys.on_shutdown_requested() => break,
+        }
+        METRICS.counter_add(&UPSTREAM_CONNECT_RETRIES_TOTAL, &[], 1.0);
+        backoff = (backoff * 2).min(std::time::Duration::from_millis(config.max_backoff_ms));
+    }
+
+    Err(CCProxyError::UpstreamConnectExhausted {
+        address: upstream_address,
+        attempts: attempt,
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn handle_c2s(
     sub_sys: SubsystemHandle<CCProxyError>,
     client: Arc<RaknetSocket>,
     server: Arc<RaknetSocket>,
+    session: Arc<Session>,
+    admission: Arc<Admission>,
+    disconnect_messages: DisconnectMessagesConfig,
+    idle_timeout: Option<std::time::Duration>,
+    first_packet: Option<Vec<u8>>,
 ) -> CCProxyResult<()> {
-    let client_address = client.peer_addr()?;
+    // Read by the handshake gate before the upstream was connected
+    if let Some(packet) = first_packet {
+        handle_c2s_packet(packet, &server, &session, &admission, &disconnect_messages).await?;
+    }
+
+    // Reset by every game packet of the client, so RakNet keepalives do not count.
+    let idle = tokio::time::sleep(idle_timeout.unwrap_or(std::time::Duration::MAX));
+    tokio::pin!(idle);
 
     loop {
         // Check the s2c connection is closed.
         if server.is_closed() {
+            session.set_end_reason("upstream_lost");
             client.close().await?;
             break;
         }
@@ -229,10 +1533,54 @@ async fn handle_c2s(
         tokio::select! {
             // Client -> Server
             packet = client.recv() => {
-                handle_c2s_packet(packet?, &server, &client_address).await?;
+                let packet = packet.inspect_err(|_| session.set_end_reason("client_left"))?;
+                if let Some(timeout) = idle_timeout
+                    && packet.first() == Some(&GAME_PACKET_ID)
+                {
+                    idle.as_mut().reset(tokio::time::Instant::now() + timeout);
+                }
+
+                handle_c2s_packet(packet, &server, &session, &admission, &disconnect_messages).await?;
+            }
+            // Idle for too long, which ends the session through the eviction below
+            _ = &mut idle, if idle_timeout.is_some() && !session.evicted.is_cancelled() => {
+                tracing::info!("The client ({}) is kicked for being idle.", session.client_address);
+                METRICS.counter_add(&SESSIONS_EVICTED_TOTAL, &[("reason", "idle")], 1.0);
+                session.evict(Eviction::Kick(disconnect_messages.idle.clone()));
+            }
+            // Replaced by a new connection from the same endpoint. The client leg is
+            // left alone because the endpoint now belongs to the new connection.
+            _ = session.terminate.cancelled() => {
+                break;
+            }
+            // Ended by the proxy, e.g. because its upstream is drained
+            _ = session.evicted.cancelled() => {
+                let handshake = session.handshake.lock().unwrap().clone();
+                match session.eviction() {
+                    Some(Eviction::Kick(message)) => {
+                        disconnect_client(&client, &handshake, &message).await;
+                    }
+                    Some(Eviction::Transfer { host, port }) => {
+                        let transferred = transfer_client(&client, &handshake, &host, port).await;
+                        if transferred {
+                            tracing::info!(
+                                "The client ({}) is transferred to {host}:{port}.",
+                                session.client_address
+                            );
+                        }
+                    }
+                    None => (),
+                }
+
+                client.close().await?;
+                break;
             }
             // Shutdown handler
             _ = sub_sys.on_shutdown_requested() => {
+                session.set_end_reason("shutdown");
+                let handshake = session.handshake.lock().unwrap().clone();
+                disconnect_client(&client, &handshake, &disconnect_messages.shutdown).await;
+
                 client.close().await?;
                 break;
             }
@@ -246,12 +1594,14 @@ async fn handle_s2c(
     sub_sys: SubsystemHandle<CCProxyError>,
     client: Arc<RaknetSocket>,
     server: Arc<RaknetSocket>,
+    session: Arc<Session>,
+    backup: Option<BackupConfig>,
+    limbo: Option<LimboConfig>,
 ) -> CCProxyResult<()> {
-    let client_address = client.peer_addr()?;
-
     loop {
         // Check the c2s connection is closed.
         if client.is_closed() {
+            session.set_end_reason("client_left");
             server.close().await?;
             break;
         }
@@ -259,7 +1609,50 @@ async fn handle_s2c(
         tokio::select! {
             // Server -> Client
             packet = server.recv() => {
-                handle_s2c_packet(packet?, &client, &client_address).await?;
+                let packet = match packet {
+                    Ok(packet) => packet,
+                    Err(err) => {
+                        // The upstream leg died, so move the client to the backup if possible.
+                        session.set_end_reason("upstream_lost");
+                        let handshake = session.handshake.lock().unwrap().clone();
+                        if let Some(backup) = &backup
+                            && transfer_to_backup(&client, &handshake, backup).await
+                        {
+                            client.close().await?;
+                            break;
+                        }
+
+                        // Otherwise, send it back to the proxy to wait in the limbo.
+                        if let Some(limbo) = &limbo {
+                            if !handshake.can_inject() {
+                                tracing::warn!(
+                                    "The upstream connection of the client ({}) is lost, but the session is encrypted, so it cannot be sent to the limbo.",
+                                    session.client_address
+                                );
+                            } else if transfer_client(&client, &handshake, &limbo.transfer_host, limbo.transfer_port).await {
+                                tracing::info!(
+                                    "The upstream connection of the client ({}) is lost, so it is sent to the limbo.",
+                                    session.client_address
+                                );
+                                client.close().await?;
+                                break;
+                            }
+                        }
+
+                        Err(err)?
+                    }
+                };
+
+                handle_s2c_packet(packet, &client, &session).await?;
+            }
+            // Replaced by a new connection from the same endpoint, or ended by the proxy
+            _ = session.terminate.cancelled() => {
+                server.close().await?;
+                break;
+            }
+            _ = session.evicted.cancelled() => {
+                server.close().await?;
+                break;
             }
             // Shutdown handler
             _ = sub_sys.on_shutdown_requested() => {
@@ -276,74 +1669,409 @@ async fn handle_s2c(
 async fn handle_c2s_packet(
     packet: Vec<u8>,
     server: &RaknetSocket,
-    #[allow(unused_variables)] client_address: &SocketAddr,
+    session: &Session,
+    admission: &Admission,
+    disconnect_messages: &DisconnectMessagesConfig,
 ) -> CCProxyResult<()> {
     #[cfg(debug_assertions)]
-    tracing::trace!("The client ({client_address}) got a packet: {packet:?}");
+    tracing::trace!(
+        "The client ({}) got a packet: {packet:?}",
+        session.client_address
+    );
 
-    if packet[0] != RAKNET_GAME_PACKET_ID {
+    if packet[0] != GAME_PACKET_ID {
         return Ok(());
     }
 
+    let observed = {
+        let mut handshake = session.handshake.lock().unwrap();
+        PACKET_STATS.record(Direction::C2s, &handshake, &packet);
+        handshake.observe_c2s(&packet)
+    };
+    match observed {
+        Ok(Some(identity)) => {
+            tracing::info!(
+                "The client ({}) logs in as {} (XUID: {}).",
+                session.client_address,
+                identity.display_name.as_deref().unwrap_or("unknown"),
+                identity.xuid.as_deref().unwrap_or("none"),
+            );
+
+            // The Login packet of a refused player never reaches the upstream.
+            if let Err(refusal) = admission.check_identity(session, &identity).await {
+                METRICS.counter_add(
+                    &SESSIONS_REFUSED_TOTAL,
+                    &[("reason", refusal.reason())],
+                    1.0,
+                );
+                tracing::info!(
+                    "The client ({}) is refused because {}.",
+                    session.client_address,
+                    refusal.description()
+                );
+                session.evict(Eviction::Kick(
+                    refusal.message(disconnect_messages).to_owned(),
+                ));
+
+                return Ok(());
+            }
+        }
+        Ok(None) => (),
+        Err(err) => {
+            METRICS.counter_add(
+                &HANDSHAKE_FAILURES_TOTAL,
+                &[("reason", "login_invalid")],
+                1.0,
+            );
+            tracing::debug!(
+                "Cannot read the login sequence of the client ({}): {err}",
+                session.client_address
+            );
+        }
+    }
+
+    session
+        .bandwidth
+        .throttle(Direction::C2s, packet.len())
+        .await;
     server.send(&packet, Reliability::ReliableOrdered).await?;
 
+    session.add_c2s(packet.len());
+
+    FORWARDED_PACKETS_C2S.add(1);
+    FORWARDED_BYTES_C2S.add(packet.len() as u64);
+
     Ok(())
 }
 
 async fn handle_s2c_packet(
     packet: Vec<u8>,
     client: &RaknetSocket,
-    #[allow(unused_variables)] client_address: &SocketAddr,
+    session: &Session,
 ) -> CCProxyResult<()> {
     #[cfg(debug_assertions)]
-    tracing::trace!("The server from the client ({client_address}) got a packet: {packet:?}");
+    tracing::trace!(
+        "The server from the client ({}) got a packet: {packet:?}",
+        session.client_address
+    );
 
-    if packet[0] != RAKNET_GAME_PACKET_ID {
+    if packet[0] != GAME_PACKET_ID {
         return Ok(());
     }
 
+    let observed = {
+        let mut handshake = session.handshake.lock().unwrap();
+        PACKET_STATS.record(Direction::S2c, &handshake, &packet);
+        handshake
+            .observe_s2c(&packet)
+            .map(|settings| settings.map(|s| (s, handshake.protocol_version)))
+    };
+    match observed {
+        Ok(Some((settings, protocol_version))) => {
+            let algorithm = settings.compression_algorithm.as_str();
+            let threshold = settings.compression_threshold.to_string();
+            tracing::info!(
+                "The upstream negotiated the {algorithm} compression over {threshold} bytes with the client ({}, protocol {}).",
+                session.client_address,
+                protocol_version.map_or("unknown".to_owned(), |v| v.to_string()),
+            );
+            METRICS.counter_add(
+                &NETWORK_SETTINGS_TOTAL,
+                &[("algorithm", algorithm), ("threshold", &threshold)],
+                1.0,
+            );
+        }
+        Ok(None) => (),
+        Err(err) => tracing::debug!(
+            "Cannot follow the login sequence of the client ({}): {err}",
+            session.client_address
+        ),
+    }
+
+    session
+        .bandwidth
+        .throttle(Direction::S2c, packet.len())
+        .await;
     client.send(&packet, Reliability::ReliableOrdered).await?;
 
+    session.add_s2c(packet.len());
+
+    FORWARDED_PACKETS_S2C.add(1);
+    FORWARDED_BYTES_S2C.add(packet.len() as u64);
+
     Ok(())
 }
 
+/// Close a client which is not allowed to start a session, showing `message` to it if
+/// there is one.
+async fn refuse_client(client: RaknetSocket, message: Option<&str>) -> CCProxyResult<()> {
+    if let Some(message) = message {
+        disconnect_client(&client, &HandshakeState::default(), message).await;
+    }
+    client.close().await?;
+
+    Ok(())
+}
+
+/// Show `message` on the client's disconnect screen if the login sequence still allows
+/// the proxy to inject packets. The connection itself is not closed.
+async fn disconnect_client(client: &RaknetSocket, handshake: &HandshakeState, message: &str) {
+    if !handshake.can_inject() {
+        return;
+    }
+
+    let frame = handshake.encode_batch(&[game::encode_disconnect(message)]);
+    if client
+        .send(&frame, Reliability::ReliableOrdered)
+        .await
+        .is_ok()
+    {
+        tokio::time::sleep(DISCONNECT_GRACE).await;
+    }
+}
+
+/// Transfer the client to the backup server if it answers a ping and the login sequence
+/// still allows the proxy to inject packets. Returns whether the client is transferred.
+async fn transfer_to_backup(
+    client: &RaknetSocket,
+    handshake: &HandshakeState,
+    backup: &BackupConfig,
+) -> bool {
+    if !handshake.can_inject() {
+        return false;
+    }
+
+    let Ok(client_address) = client.peer_addr() else {
+        return false;
+    };
+    if let Err(err) =
+        RaknetSocket::ping_with(&backup.address, std::time::Duration::from_secs(2), 1, false).await
+    {
+        tracing::error!(
+            "Cannot transfer the client ({client_address}) because the backup server ({}) is not available: {err}",
+            backup.address
+        );
+        return false;
+    }
+
+    if !transfer_client(
+        client,
+        handshake,
+        &backup.transfer_host,
+        backup.transfer_port,
+    )
+    .await
+    {
+        return false;
+    }
+
+    METRICS.counter_add(&BACKUP_TRANSFERS_TOTAL, &[], 1.0);
+    tracing::info!(
+        "The client ({client_address}) is transferred to the backup server ({}:{}).",
+        backup.transfer_host,
+        backup.transfer_port
+    );
+
+    true
+}
+
+/// Send the Transfer packet to `host:port` if the login sequence still allows the proxy
+/// to inject packets. Returns whether the packet is sent. The connection is not closed.
+async fn transfer_client(
+    client: &RaknetSocket,
+    handshake: &HandshakeState,
+    host: &str,
+    port: u16,
+) -> bool {
+    if !handshake.can_inject() {
+        return false;
+    }
+
+    let frame = handshake.encode_batch(&[game::encode_transfer(host, port)]);
+    if client
+        .send(&frame, Reliability::ReliableOrdered)
+        .await
+        .is_err()
+    {
+        return false;
+    }
+    tokio::time::sleep(DISCONNECT_GRACE).await;
+
+    true
+}
+
+/// Answer Query Protocol requests with the proxy's own statistics.
+async fn run_stats_query_responder(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: Arc<CCProxyConfig>,
+    sessions: Arc<SessionRegistry>,
+) -> CCProxyResult<()> {
+    let Some(stats_config) = &config.proxy.stats_query else {
+        return Ok(());
+    };
+
+    let socket = UdpSocket::bind(stats_config.address).await?;
+    tracing::info!(
+        "The stats Query responder is started on {}.",
+        stats_config.address
+    );
+
+    let query = Arc::new(RwLock::new(stats_query(&config, 0)));
+    let query_handler = QueryHandler::with_query(query.clone());
+    query_handler.init(&sub_sys).await;
+
+    let mut buf = vec![0u8; 1500];
+    let mut interval = tokio::time::interval(STATS_QUERY_UPDATE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let active_sessions = sessions.sessions().await.len();
+                *query.write().await = stats_query(&config, active_sessions as u64);
+            },
+            received = socket.recv_from(&mut buf) => {
+                let (len, address) = received?;
+                if let Err(err) = query_handler.handle_packet(&socket, &address, &mut Cursor::new(buf[..len].to_vec())).await {
+                    tracing::debug!("Failed to handle a stats Query packet from ({address}): {err}");
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn stats_query(config: &CCProxyConfig, active_sessions: u64) -> ProxyQueryConfig {
+    let fallback_query = &config.proxy.fallback_query;
+    let address = config
+        .proxy
+        .stats_query
+        .as_ref()
+        .map_or(config.proxy.address, |c| c.address);
+
+    ProxyQueryConfig {
+        motd: fallback_query.motd.clone(),
+        game_type: fallback_query.game_type.clone(),
+        map: config.upstream.address.to_string(),
+        num_players: active_sessions,
+        max_players: fallback_query.max_players,
+        host_port: address.port(),
+        host_ip: address.ip(),
+        version: built_info::PKG_VERSION.to_owned(),
+        plugins: Some(format!(
+            "{} {}",
+            built_info::PKG_NAME,
+            built_info::PKG_VERSION
+        )),
+        players: vec![],
+    }
+}
+
 async fn run_motd_updater(
     sub_sys: SubsystemHandle<CCProxyError>,
-    config: CCProxyConfig,
-    motd: Arc<RwLock<String>>,
+    config: Arc<CCProxyConfig>,
+    state: Arc<ProxyState>,
+    probes: Arc<LatencyProbes>,
+    upstream_addresses: Arc<UpstreamAddresses>,
+    motds: Vec<Arc<RwLock<String>>>,
     guid: u64,
 ) -> CCProxyResult<()> {
-    let upstream_address = config.upstream.address;
     let fallback_motd = config.proxy.fallback_motd.clone();
-    let proxy_protocol = config.upstream.proxy_protocol;
-
-    let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+    let proxy_protocol = config.upstream.proxy_protocol.is_enabled();
+    let max_players = config
+        .proxy
+        .max_sessions
+        .filter(|_| config.proxy.advertise_max_sessions)
+        .map(|max| max.min(i32::MAX as usize) as i32);
+
+    // The GUID advertised by the upstream, or 0 before the first pong.
+    let upstream_guid = Arc::new(AtomicU64::new(0));
+    let mut refresh_remaining = 0;
+
+    let mut interval = tokio::time::interval(MOTD_UPDATE_INTERVAL);
     loop {
-        let fallback_motd_clone = fallback_motd.clone();
-        let motd_clone = motd.clone();
+        let motds_clone = motds.clone();
+        let upstream_guid_clone = upstream_guid.clone();
 
         tokio::select! {
             // Update MOTD from the upstream server every 5 seconds.
             _ = interval.tick() => {
-                let ping_task = SubsystemBuilder::new("ProxyMotdUpdater_Ping", move |sub| async move {
-                    let motd_clone = motd_clone.clone();
+                if refresh_remaining > 0 {
+                    refresh_remaining -= 1;
+                    if refresh_remaining == 0 {
+                        interval = tokio::time::interval(MOTD_UPDATE_INTERVAL);
+                        interval.reset();
+                    }
+                }
+
+                let upstream_address = upstream_addresses.get(&config.upstream.address);
+
+                // rust-raknet answers pings from the shared MOTD by itself, so the GUID is
+                // re-rolled whenever the MOTD is rewritten rather than for every pong.
+                let guid = if config.proxy.randomize_guid { rand::random() } else { guid };
+
+                let upstream_ping = upstream_address
+                    .and_then(|a| probes.stats(&a))
+                    .and_then(|s| s.rtt_ms)
+                    .map_or("-".to_owned(), |rtt| format!("{rtt:.0}"));
+                let placeholders = [("upstream_ping", upstream_ping.as_str())];
+
+                // The maintenance MOTD replaces any other.
+                if state.is_maintenance() {
+                    let maintenance_motd = config.proxy.maintenance.motd.as_ref().unwrap_or(&fallback_motd);
+                    set_motd(&motds, maintenance_motd.with_placeholders(&placeholders).encode(Some(guid))).await;
+                    continue;
+                }
 
-                    update_motd(sub, upstream_address, motd_clone.clone(), fallback_motd_clone, guid, proxy_protocol).await
+                // An active MOTD profile replaces the upstream MOTD.
+                if let Some(profile) = state.motd_profile()
+                    && let Some(profile_motd) = config.proxy.motd_profiles.get(&profile)
+                {
+                    set_motd(&motds, profile_motd.with_placeholders(&placeholders).encode(Some(guid))).await;
+                    continue;
+                }
+
+                // The fallback MOTD is served until the upstream is resolved.
+                let Some(upstream_address) = upstream_address else {
+                    set_motd(&motds, fallback_motd.with_placeholders(&placeholders).encode(Some(guid))).await;
+                    continue;
+                };
+
+                let fallback_motd_clone = fallback_motd.with_placeholders(&placeholders);
+                let ping_task = SubsystemBuilder::new("ProxyMotdUpdater_Ping", move |sub| async move {
+                    update_motd(sub, upstream_address, motds_clone, fallback_motd_clone, guid, upstream_guid_clone, proxy_protocol, max_players).await
                 })
                     .on_failure(ErrorAction::CatchAndLocalShutdown);
 
+                let previous_upstream_guid = upstream_guid.load(Ordering::Relaxed);
+
                 if let Err(err) = sub_sys.start(ping_task).join().await {
+                    METRICS.counter_add(&MOTD_UPDATES_TOTAL, &[("result", "failure")], 1.0);
+
                     if let Some(err) = sub_sys_err_to_ccproxy_err(&err) {
                         tracing::error!("Cannot update the MOTD from the upstream server: {err}");
                     } else {
                         tracing::error!("Cannot update the MOTD from the upstream server: {err}");
                     }
 
-                    let fallback_motd = fallback_motd.clone().encode(Some(guid));
-
-                    {
-                        let mut motd = motd.write().await;
-                        *motd = fallback_motd;
+                    let fallback_motd = fallback_motd.with_placeholders(&placeholders).encode(Some(guid));
+                    set_motd(&motds, fallback_motd).await;
+                } else {
+                    METRICS.counter_add(&MOTD_UPDATES_TOTAL, &[("result", "success")], 1.0);
+
+                    let current_upstream_guid = upstream_guid.load(Ordering::Relaxed);
+                    if previous_upstream_guid != 0 && current_upstream_guid != previous_upstream_guid {
+                        METRICS.counter_add(&UPSTREAM_RESTARTS_TOTAL, &[], 1.0);
+                        tracing::warn!("The upstream server ({upstream_address}) is restarted. The GUID is changed from {previous_upstream_guid} to {current_upstream_guid}.");
+
+                        if config.upstream.restart_refresh {
+                            refresh_remaining = RESTART_REFRESH_COUNT;
+                            interval = tokio::time::interval(RESTART_REFRESH_INTERVAL);
+                            interval.reset();
+                        }
                     }
                 };
             },
@@ -357,27 +2085,37 @@ async fn run_motd_updater(
     Ok(())
 }
 
+/// Serve `motd` on every socket of the listener.
+async fn set_motd(motds: &[Arc<RwLock<String>>], motd: String) {
+    for shared in motds {
+        *shared.write().await = motd.clone();
+    }
+}
+
+/// Copy the MOTD of the upstream, advertising `max_players` instead of its own if set.
+#[allow(clippy::too_many_arguments)]
 async fn update_motd(
     sub_sys: SubsystemHandle<CCProxyError>,
     upstream_address: SocketAddr,
-    motd: Arc<RwLock<String>>,
+    motds: Vec<Arc<RwLock<String>>>,
     fallback_motd: BedrockMotd,
     guid: u64,
+    upstream_guid: Arc<AtomicU64>,
     proxy_protocol: bool,
+    max_players: Option<i32>,
 ) -> CCProxyResult<()> {
     tokio::select! {
         pong = RaknetSocket::ping_with(&upstream_address, std::time::Duration::from_secs(5), 1, proxy_protocol) => {
             let (pong_latency, pong_motd) = pong?;
 
             // Preserve server GUID, IPv4 port, and IPv6 port.
-            let new_motd = BedrockMotd::decode(pong_motd, None, fallback_motd.ipv4_port, fallback_motd.ipv6_port)
-                .map_err(|_| CCProxyError::UpstreamMotdInvalid)?
-                .encode(Some(guid));
-
-            {
-                let mut motd = motd.write().await;
-                *motd = new_motd;
+            let mut upstream_motd = BedrockMotd::decode(pong_motd, None, fallback_motd.ipv4_port, fallback_motd.ipv6_port)
+                .map_err(|_| CCProxyError::UpstreamMotdInvalid)?;
+            if let Some(max_players) = max_players {
+                upstream_motd.max_players = max_players;
             }
+            upstream_guid.store(upstream_motd.guid, Ordering::Relaxed);
+            set_motd(&motds, upstream_motd.encode(Some(guid))).await;
 
             tracing::debug!("The proxy server MOTD is updated from the upstream server ({upstream_address}). The latency is {pong_latency}ms.");
         },
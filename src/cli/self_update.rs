@@ -0,0 +1,197 @@
+use crate::built_info;
+use crate::config::CCProxyConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::update::{self, Release};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ring::signature::{ED25519, UnparsedPublicKey};
+use sha2::{Digest, Sha256};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+/// The Ed25519 public key which the release archives are signed with, as base64. It is
+/// embedded from `CCPROXY_RELEASE_PUBLIC_KEY` at build time, and builds without it
+/// cannot update themselves.
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("CCPROXY_RELEASE_PUBLIC_KEY");
+
+/// The largest release asset which is downloaded, well above the size of an archive.
+const MAX_ASSET_LEN: usize = 128 * 1024 * 1024;
+
+/// Replace the running binary with the latest release for the current platform.
+///
+/// The archive is verified against the SHA-256 checksum published next to it, and its
+/// detached signature against the key embedded in this binary, so a compromised release
+/// page cannot replace it. The running process is not restarted, so the new binary takes
+/// effect on the next start.
+pub async fn self_update(config: &CCProxyConfig, force: bool) -> CCProxyResult<()> {
+    let public_key = release_public_key()?;
+
+    let release = update::fetch_latest_release(&config.update_check.url).await?;
+    if !force && !release.is_newer()? {
+        println!("ccproxy v{} is up to date.", built_info::PKG_VERSION);
+        return Ok(());
+    }
+
+    let asset_name = asset_name();
+    println!("Downloading {asset_name} ({})...", release.tag_name);
+
+    let archive = download(&release, &asset_name).await?;
+    let checksum = download(&release, &format!("{asset_name}.sha256")).await?;
+    let signature = download(&release, &format!("{asset_name}.sig")).await?;
+    verify_checksum(&archive, &checksum)?;
+    public_key
+        .verify(&archive, &signature)
+        .map_err(|_| CCProxyError::UpdateSignatureInvalid)?;
+
+    let binary = extract_binary(&archive, &binary_name())?;
+
+    let current_exe = std::env::current_exe()?;
+    replace_binary(&current_exe, &binary)?;
+
+    println!(
+        "ccproxy is updated from v{} to {}. Restart the proxy server to apply it. See the changelog: {}",
+        built_info::PKG_VERSION,
+        release.tag_name,
+        release.html_url
+    );
+
+    Ok(())
+}
+
+/// The release asset name for the current platform, as packaged by the release workflow.
+fn asset_name() -> String {
+    if cfg!(windows) {
+        format!("ccproxy-{}.exe.zip", built_info::TARGET)
+    } else {
+        format!("ccproxy-{}.tar.gz", built_info::TARGET)
+    }
+}
+
+/// The name of the binary in the release archive.
+fn binary_name() -> String {
+    if cfg!(windows) {
+        format!("ccproxy-{}.exe", built_info::TARGET)
+    } else {
+        format!("ccproxy-{}", built_info::TARGET)
+    }
+}
+
+fn release_public_key() -> CCProxyResult<UnparsedPublicKey<Vec<u8>>> {
+    let key = RELEASE_PUBLIC_KEY
+        .and_then(|key| STANDARD.decode(key.trim()).ok())
+        .ok_or(CCProxyError::UpdateSigningKeyMissing)?;
+
+    Ok(UnparsedPublicKey::new(&ED25519, key))
+}
+
+async fn download(release: &Release, name: &str) -> CCProxyResult<Vec<u8>> {
+    let asset = release
+        .assets
+        .iter()
+        .find(|a| a.name == name)
+        .ok_or_else(|| CCProxyError::UpdateAssetNotFound {
+            name: name.to_owned(),
+        })?;
+
+    let too_large = || CCProxyError::UpdateAssetTooLarge {
+        name: name.to_owned(),
+        limit: MAX_ASSET_LEN,
+    };
+
+    let mut response = reqwest::get(&asset.browser_download_url)
+        .await?
+        .error_for_status()?;
+    if response
+        .content_length()
+        .is_some_and(|len| len > MAX_ASSET_LEN as u64)
+    {
+        return Err(too_large());
+    }
+
+    // The length may be missing or wrong, so the body is limited as it arrives.
+    let mut body = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_ASSET_LEN {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+/// Compare the archive with a `sha256sum`-style checksum file.
+fn verify_checksum(archive: &[u8], checksum: &[u8]) -> CCProxyResult<()> {
+    let expected = String::from_utf8_lossy(checksum)
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    let actual = Sha256::digest(archive)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+
+    if expected != actual {
+        return Err(CCProxyError::UpdateChecksumMismatch);
+    }
+
+    Ok(())
+}
+
+/// Get the binary `name` from the release archive.
+fn extract_binary(archive: &[u8], name: &str) -> CCProxyResult<Vec<u8>> {
+    let not_found = || CCProxyError::UpdateAssetNotFound {
+        name: name.to_owned(),
+    };
+    let mut binary = vec![];
+
+    if cfg!(windows) {
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive))?;
+        let mut file = zip.by_name(name).map_err(|_| not_found())?;
+        if !file.is_file() {
+            return Err(not_found());
+        }
+        file.read_to_end(&mut binary)?;
+    } else {
+        let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+        let mut entry = tar
+            .entries()?
+            .filter_map(Result::ok)
+            .find(|e| {
+                e.header().entry_type().is_file()
+                    && e.path().is_ok_and(|path| path == Path::new(name))
+            })
+            .ok_or_else(not_found)?;
+        entry.read_to_end(&mut binary)?;
+    }
+
+    Ok(binary)
+}
+
+/// Write the new binary next to the current one and rename it over, so the swap is atomic.
+fn replace_binary(current_exe: &Path, binary: &[u8]) -> CCProxyResult<()> {
+    let new_exe = with_suffix(current_exe, ".new");
+    std::fs::write(&new_exe, binary)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&new_exe, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    // A running executable cannot be overwritten on Windows, but it can be renamed.
+    if cfg!(windows) {
+        std::fs::rename(current_exe, with_suffix(current_exe, ".old"))?;
+    }
+
+    std::fs::rename(new_exe, current_exe)?;
+
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
@@ -0,0 +1,393 @@
+use crate::admin::{self, AdminRequest};
+use crate::config::CCProxyConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crossterm::event::{Event, EventStream, KeyCode, KeyEventKind, KeyModifiers};
+use futures_util::StreamExt;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Cell, Paragraph, Row, Sparkline, Table};
+use ratatui::{DefaultTerminal, Frame};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many throughput samples the graphs keep, one per refresh.
+const THROUGHPUT_SAMPLES: usize = 120;
+
+#[derive(Deserialize)]
+struct StatusOutput {
+    version: String,
+
+    uptime_secs: u64,
+
+    listeners: Vec<ListenerOutput>,
+}
+
+#[derive(Deserialize)]
+struct ListenerOutput {
+    sessions: usize,
+
+    draining: bool,
+
+    maintenance: bool,
+}
+
+#[derive(Deserialize)]
+struct SessionsOutput {
+    sessions: Vec<SessionOutput>,
+}
+
+#[derive(Deserialize)]
+struct SessionOutput {
+    client_address: SocketAddr,
+
+    upstream_address: SocketAddr,
+
+    connected_at: u64,
+
+    name: Option<String>,
+
+    upstream_rtt_ms: Option<f64>,
+
+    bytes_c2s: u64,
+
+    bytes_s2c: u64,
+}
+
+#[derive(Deserialize)]
+struct UpstreamsOutput {
+    upstreams: Vec<UpstreamOutput>,
+}
+
+#[derive(Deserialize)]
+struct UpstreamOutput {
+    upstream: String,
+
+    drained: bool,
+
+    targets: Vec<UpstreamTargetOutput>,
+}
+
+#[derive(Deserialize)]
+struct UpstreamTargetOutput {
+    address: SocketAddr,
+
+    healthy: bool,
+
+    circuit: String,
+
+    sessions: usize,
+
+    rtt_ms: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct TrafficOutput {
+    bytes_c2s: u64,
+
+    bytes_s2c: u64,
+}
+
+/// What the screen shows, refreshed from the control socket.
+#[derive(Default)]
+struct Top {
+    status: Option<StatusOutput>,
+
+    sessions: Vec<SessionOutput>,
+
+    upstreams: Vec<UpstreamOutput>,
+
+    /// The traffic counters of the last refresh, to take the throughput from.
+    last_traffic: Option<(Instant, TrafficOutput)>,
+
+    /// Bytes per second, the newest last.
+    throughput_in: VecDeque<u64>,
+
+    throughput_out: VecDeque<u64>,
+
+    /// Why the last refresh failed, e.g. as the proxy is stopped.
+    error: Option<String>,
+}
+
+/// Show the sessions, the upstreams, and the throughput of the running proxy until `q`
+/// is pressed.
+pub async fn top(config: &CCProxyConfig) -> CCProxyResult<()> {
+    if !config.control.enabled {
+        return Err(CCProxyError::ControlDisabled);
+    }
+
+    // Fail before taking over the terminal if the proxy cannot be reached.
+    let mut top = Top::default();
+    top.refresh(config).await?;
+
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, config, top).await;
+    ratatui::restore();
+
+    result
+}
+
+async fn run(
+    terminal: &mut DefaultTerminal,
+    config: &CCProxyConfig,
+    mut top: Top,
+) -> CCProxyResult<()> {
+    let mut events = EventStream::new();
+    let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        terminal.draw(|frame| top.draw(frame))?;
+
+        tokio::select! {
+            _ = interval.tick() => {
+                top.error = top.refresh(config).await.err().map(|err| err.to_string());
+            },
+            event = events.next() => {
+                match event {
+                    Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                        let ctrl_c = key.modifiers.contains(KeyModifiers::CONTROL)
+                            && key.code == KeyCode::Char('c');
+                        if ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                            break;
+                        }
+                    }
+                    Some(Ok(_)) => (),
+                    Some(Err(err)) => return Err(err.into()),
+                    None => break,
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+impl Top {
+    async fn refresh(&mut self, config: &CCProxyConfig) -> CCProxyResult<()> {
+        let status = admin::send_control(config, &AdminRequest::Status).await?;
+        let sessions = admin::send_control(config, &AdminRequest::Sessions).await?;
+        let upstreams = admin::send_control(config, &AdminRequest::Upstreams).await?;
+        let traffic = admin::send_control(config, &AdminRequest::Traffic).await?;
+
+        self.status = Some(serde_json::from_value(status)?);
+        self.sessions = serde_json::from_value::<SessionsOutput>(sessions)?.sessions;
+        self.sessions.sort_by_key(|s| s.connected_at);
+        self.upstreams = serde_json::from_value::<UpstreamsOutput>(upstreams)?.upstreams;
+
+        let traffic = serde_json::from_value::<TrafficOutput>(traffic)?;
+        let now = Instant::now();
+        if let Some((at, last)) = &self.last_traffic {
+            let secs = now.duration_since(*at).as_secs_f64().max(0.001);
+            let rate = |now: u64, last: u64| (now.saturating_sub(last) as f64 / secs) as u64;
+            push_sample(
+                &mut self.throughput_in,
+                rate(traffic.bytes_c2s, last.bytes_c2s),
+            );
+            push_sample(
+                &mut self.throughput_out,
+                rate(traffic.bytes_s2c, last.bytes_s2c),
+            );
+        }
+        self.last_traffic = Some((now, traffic));
+
+        Ok(())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, graphs, sessions, upstreams, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Length(6),
+            Constraint::Fill(2),
+            Constraint::Fill(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        self.draw_header(frame, header);
+        self.draw_throughput(frame, graphs);
+        self.draw_sessions(frame, sessions);
+        self.draw_upstreams(frame, upstreams);
+
+        let footer_line = match &self.error {
+            Some(err) => Line::from(err.as_str()).fg(Color::Red),
+            None => Line::from("q: quit").dim(),
+        };
+        frame.render_widget(Paragraph::new(footer_line), footer);
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let Some(status) = &self.status else {
+            return;
+        };
+
+        let players = status.listeners.iter().map(|l| l.sessions).sum::<usize>();
+        let mut text = format!(
+            "ccproxy v{}  up {}  {players} players",
+            status.version,
+            format_duration(status.uptime_secs)
+        );
+        if status.listeners.iter().any(|l| l.maintenance) {
+            text.push_str("  (maintenance)");
+        }
+        if status.listeners.iter().any(|l| l.draining) {
+            text.push_str("  (draining)");
+        }
+
+        frame.render_widget(Paragraph::new(Line::from(text).bold()), area);
+    }
+
+    fn draw_throughput(&mut self, frame: &mut Frame, area: Rect) {
+        let [left, right] =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).areas(area);
+
+        for (samples, label, color, area) in [
+            (&mut self.throughput_in, "In", Color::Green, left),
+            (&mut self.throughput_out, "Out", Color::Yellow, right),
+        ] {
+            let current = samples.back().copied().unwrap_or_default();
+            // The newest samples are drawn on the right, as many as fit.
+            let width = area.width.saturating_sub(2) as usize;
+            let data = samples.make_contiguous();
+            let data = &data[data.len().saturating_sub(width)..];
+
+            let sparkline = Sparkline::default()
+                .block(Block::bordered().title(format!("{label} {}/s", format_bytes(current))))
+                .data(data)
+                .style(Style::new().fg(color));
+            frame.render_widget(sparkline, area);
+        }
+    }
+
+    fn draw_sessions(&self, frame: &mut Frame, area: Rect) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let rows = self.sessions.iter().map(|s| {
+            Row::new([
+                Cell::from(s.client_address.to_string()),
+                Cell::from(s.name.clone().unwrap_or_else(|| "-".to_owned())),
+                Cell::from(s.upstream_address.to_string()),
+                Cell::from(format_rtt(s.upstream_rtt_ms)),
+                Cell::from(format_bytes(s.bytes_c2s)),
+                Cell::from(format_bytes(s.bytes_s2c)),
+                Cell::from(format_duration(now.saturating_sub(s.connected_at))),
+            ])
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Min(22),
+                Constraint::Min(16),
+                Constraint::Min(22),
+                Constraint::Length(9),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Length(10),
+            ],
+        )
+        .header(
+            Row::new([
+                "Client", "Gamertag", "Upstream", "Ping", "In", "Out", "Time",
+            ])
+            .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::bordered().title(format!("Sessions ({})", self.sessions.len())));
+
+        frame.render_widget(table, area);
+    }
+
+    fn draw_upstreams(&self, frame: &mut Frame, area: Rect) {
+        let rows = self.upstreams.iter().flat_map(|u| {
+            u.targets.iter().map(move |t| {
+                let upstream = if u.drained {
+                    format!("{} (drained)", u.upstream)
+                } else {
+                    u.upstream.clone()
+                };
+                let health = if t.healthy {
+                    Cell::from("healthy").fg(Color::Green)
+                } else {
+                    Cell::from("unhealthy").fg(Color::Red)
+                };
+                let circuit = if t.circuit == "closed" {
+                    Cell::from(t.circuit.clone())
+                } else {
+                    Cell::from(t.circuit.clone()).fg(Color::Red)
+                };
+
+                Row::new([
+                    Cell::from(upstream),
+                    Cell::from(t.address.to_string()),
+                    health,
+                    circuit,
+                    Cell::from(format_rtt(t.rtt_ms)),
+                    Cell::from(t.sessions.to_string()),
+                ])
+            })
+        });
+        let table = Table::new(
+            rows,
+            [
+                Constraint::Min(24),
+                Constraint::Min(22),
+                Constraint::Length(10),
+                Constraint::Length(10),
+                Constraint::Length(9),
+                Constraint::Length(9),
+            ],
+        )
+        .header(
+            Row::new(["Upstream", "Server", "Health", "Circuit", "RTT", "Sessions"])
+                .style(Style::new().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::bordered().title("Upstreams"));
+
+        frame.render_widget(table, area);
+    }
+}
+
+fn push_sample(samples: &mut VecDeque<u64>, sample: u64) {
+    if samples.len() >= THROUGHPUT_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+fn format_rtt(rtt_ms: Option<f64>) -> String {
+    rtt_ms.map_or_else(|| "-".to_owned(), |rtt| format!("{rtt:.0} ms"))
+}
+
+fn format_duration(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86_400, secs % 86_400 / 3_600, secs % 3_600 / 60);
+    if days > 0 {
+        format!("{days}d {hours}h {minutes}m")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{minutes}m {}s", secs % 60)
+    }
+}
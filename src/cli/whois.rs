@@ -0,0 +1,202 @@
+use crate::error::CCProxyResult;
+use crate::history::{HistoryEvent, HistoryRecord, SessionHistory};
+use std::collections::{BTreeSet, HashMap};
+use std::net::IpAddr;
+
+/// What a `whois` target is matched against.
+enum WhoisTarget {
+    Ip(IpAddr),
+
+    Xuid(String),
+
+    Name(String),
+}
+
+impl WhoisTarget {
+    fn parse(target: &str) -> Self {
+        if let Ok(ip) = target.parse() {
+            Self::Ip(ip)
+        } else if !target.is_empty() && target.chars().all(|c| c.is_ascii_digit()) {
+            // Gamertags cannot be all digits, so this must be an XUID.
+            Self::Xuid(target.to_owned())
+        } else {
+            Self::Name(target.to_lowercase())
+        }
+    }
+
+    fn matches(&self, record: &HistoryRecord) -> bool {
+        match self {
+            Self::Ip(ip) => record.client_address.ip() == *ip,
+            Self::Xuid(xuid) => record.xuid.as_ref() == Some(xuid),
+            Self::Name(name) => record
+                .name
+                .as_ref()
+                .is_some_and(|n| n.to_lowercase() == *name),
+        }
+    }
+}
+
+#[derive(Default)]
+struct WhoisSummary {
+    sessions: usize,
+
+    online_sessions: usize,
+
+    last_seen: Option<u64>,
+
+    bytes_c2s: u64,
+
+    bytes_s2c: u64,
+
+    addresses: BTreeSet<IpAddr>,
+
+    names: BTreeSet<String>,
+
+    xuids: BTreeSet<String>,
+}
+
+pub async fn whois(target: &str) -> CCProxyResult<()> {
+    let summary = summarize(
+        &WhoisTarget::parse(target),
+        SessionHistory::read_all().await?,
+    );
+
+    if summary.sessions == 0 {
+        println!("No session history is found for {target}.");
+        return Ok(());
+    }
+
+    let last_seen = summary
+        .last_seen
+        .and_then(|t| chrono::DateTime::from_timestamp(t as i64, 0))
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default();
+
+    println!("Target:         {target}");
+    println!(
+        "Status:         {}",
+        if summary.online_sessions > 0 {
+            format!("online ({} sessions)", summary.online_sessions)
+        } else {
+            "offline".to_owned()
+        }
+    );
+    println!("Last seen:      {last_seen}");
+    println!("Total sessions: {}", summary.sessions);
+    println!(
+        "Total bytes:    {} (client -> server: {}, server -> client: {})",
+        summary.bytes_c2s + summary.bytes_s2c,
+        summary.bytes_c2s,
+        summary.bytes_s2c
+    );
+    println!("Addresses:      {}", join(&summary.addresses));
+    println!("Names:          {}", join(&summary.names));
+    println!("XUIDs:          {}", join(&summary.xuids));
+
+    Ok(())
+}
+
+fn summarize(target: &WhoisTarget, records: Vec<HistoryRecord>) -> WhoisSummary {
+    // Names and XUIDs are only known on some records, so match by session first.
+    let mut sessions: HashMap<String, Vec<HistoryRecord>> = HashMap::new();
+    for record in records {
+        sessions
+            .entry(record.session_id.clone())
+            .or_default()
+            .push(record);
+    }
+
+    let matched = sessions
+        .values()
+        .filter(|records| records.iter().any(|r| target.matches(r)))
+        .collect::<Vec<_>>();
+    let latest_start = matched
+        .iter()
+        .filter_map(|records| records.iter().map(|r| r.timestamp).min())
+        .max();
+
+    let mut summary = WhoisSummary::default();
+    for records in matched {
+        summary.sessions += 1;
+
+        let end = records.iter().find(|r| r.event == HistoryEvent::End);
+        match end {
+            Some(end) => {
+                summary.bytes_c2s += end.bytes_c2s;
+                summary.bytes_s2c += end.bytes_s2c;
+            }
+            // A session without an end record is still in progress, unless a later one
+            // started, e.g. after the proxy crashed before writing the end record.
+            None if records.iter().map(|r| r.timestamp).min() >= latest_start => {
+                summary.online_sessions += 1
+            }
+            None => (),
+        }
+
+        for record in records {
+            summary.last_seen = summary.last_seen.max(Some(record.timestamp));
+            summary.addresses.insert(record.client_address.ip());
+            summary.names.extend(record.name.clone());
+            summary.xuids.extend(record.xuid.clone());
+        }
+    }
+
+    summary
+}
+
+fn join<T: ToString>(values: &BTreeSet<T>) -> String {
+    if values.is_empty() {
+        return "-".to_owned();
+    }
+
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(event: HistoryEvent, session_id: &str, timestamp: u64) -> HistoryRecord {
+        HistoryRecord {
+            event,
+            session_id: session_id.to_owned(),
+            client_address: "1.2.3.4:5000".parse().unwrap(),
+            upstream_address: "10.0.0.1:19132".parse().unwrap(),
+            timestamp,
+            name: None,
+            xuid: None,
+            bytes_c2s: 0,
+            bytes_s2c: 0,
+            geo: Default::default(),
+            protocol_version: None,
+            compression: None,
+            compression_threshold: None,
+            kick_message: None,
+        }
+    }
+
+    #[test]
+    fn later_start_closes_session_without_end() {
+        let target = WhoisTarget::parse("1.2.3.4");
+
+        let summary = summarize(&target, vec![record(HistoryEvent::Start, "a", 10)]);
+        assert_eq!(summary.online_sessions, 1);
+
+        // The proxy crashed before writing the end of `a`.
+        let summary = summarize(
+            &target,
+            vec![
+                record(HistoryEvent::Start, "a", 10),
+                record(HistoryEvent::Start, "b", 20),
+                record(HistoryEvent::End, "b", 30),
+            ],
+        );
+        assert_eq!(summary.sessions, 2);
+        assert_eq!(summary.online_sessions, 0);
+        assert_eq!(summary.last_seen, Some(30));
+    }
+}
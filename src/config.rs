@@ -1,16 +1,30 @@
 use crate::error::{CCProxyError, CCProxyResult};
+use crate::geoip::GeoInfo;
 use crate::network::bedrock::BedrockMotd;
+use crate::network::game::LoginIdentity;
+use crate::network::java::JavaMotd;
+use crate::network::rate_limit::TokenBucketConfig;
+use chrono::{SecondsFormat, Utc};
+use chrono_tz::Tz;
 use figment::Figment;
 use figment::providers::{Env, Format, Yaml};
+use ipnet::IpNet;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::SdkTracerProvider;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
-use std::sync::LazyLock;
+use std::str::FromStr;
+use std::sync::{LazyLock, Mutex};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_appender::rolling::RollingFileAppender;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::FormatTime;
 use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::{EnvFilter, Layer};
+use tracing_subscriber::{EnvFilter, Layer, reload};
 
 pub const CCPROXY_ENV_PREFIX: &str = "CCPROXY__";
 
@@ -40,6 +54,13 @@ pub static DATA_PATH: LazyLock<PathBuf> =
             .join("data/"),
     });
 
+/// The config file which is loaded at startup.
+pub static CONFIG_FILE_PATH: LazyLock<PathBuf> =
+    LazyLock::new(|| DATA_PATH.join("config").join("config.yaml"));
+
+/// The directory which the daily rolling log files are written to.
+pub static LOG_PATH: LazyLock<PathBuf> = LazyLock::new(|| DATA_PATH.join("logs"));
+
 #[derive(Clone, Default, Deserialize, Serialize)]
 pub struct CCProxyConfig {
     #[serde(default)]
@@ -48,15 +69,286 @@ pub struct CCProxyConfig {
     pub proxy: ProxyConfig,
 
     pub upstream: UpstreamConfig,
+
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+
+    #[serde(default)]
+    pub history: HistoryConfig,
+
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+
+    #[serde(default)]
+    pub retention: RetentionConfig,
+
+    #[serde(default)]
+    pub schedules: Vec<ScheduleConfig>,
+
+    /// The IANA timezone for schedules and optionally log timestamps, e.g. `Asia/Seoul`.
+    /// UTC is used if it is not set.
+    #[serde(default)]
+    pub timezone: Option<String>,
+
+    #[serde(default)]
+    pub update_check: UpdateCheckConfig,
+
+    #[serde(default)]
+    pub dns: DnsConfig,
+
+    #[serde(default)]
+    pub geoip: GeoIpConfig,
+
+    /// More proxies in the same process by name, each fronting its own upstream on its
+    /// own address. The schedules apply to every listener.
+    ///
+    /// The main listener of `proxy` still runs, so no address may overlap with it.
+    #[serde(default)]
+    pub listeners: HashMap<String, ListenerConfig>,
+
+    /// Ranges of ports by name, where each port is a listener with the settings of
+    /// `proxy` and `upstream`, named `<name>_<port>`.
+    #[serde(default)]
+    pub port_ranges: HashMap<String, PortRangeConfig>,
+
+    /// The control listener for management, separate from the game listeners.
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+
+    #[serde(default)]
+    pub control: ControlConfig,
+
+    /// The bytes per second which the whole process may relay in each direction, e.g.
+    /// to stay within the bandwidth quota of a VPS. The live sessions of every listener
+    /// get an even share of it once it runs short.
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
 }
 
 impl CCProxyConfig {
-    pub fn init() -> CCProxyResult<Self> {
-        // Create the config path
-        let config_path = DATA_PATH.join("config");
-        std::fs::create_dir_all(&config_path)?;
+    /// The config of a named listener, which replaces `proxy` and `upstream`.
+    pub fn with_listener(&self, listener: &ListenerConfig) -> Self {
+        Self {
+            proxy: listener.proxy.clone(),
+            upstream: listener.upstream.clone(),
+            listeners: Default::default(),
+            port_ranges: Default::default(),
+            admin: None,
+            ..self.clone()
+        }
+    }
+
+    /// The named listeners, including one for every port of the port ranges.
+    pub fn all_listeners(&self) -> Vec<(String, ListenerConfig)> {
+        let mut listeners = self
+            .listeners
+            .iter()
+            .map(|(name, l)| (name.clone(), l.clone()))
+            .collect::<Vec<_>>();
+
+        for (name, range) in &self.port_ranges {
+            listeners.extend(self.port_range_listeners(name, range));
+        }
+
+        listeners
+    }
+
+    /// The listeners of the ports of `range`, one for every port.
+    pub fn port_range_listeners(
+        &self,
+        name: &str,
+        range: &PortRangeConfig,
+    ) -> Vec<(String, ListenerConfig)> {
+        let mut listeners = vec![];
+        for port in range.ports.start..=range.ports.end {
+            let offset = port - range.ports.start;
+            let mut proxy = ProxyConfig {
+                address: SocketAddr::new(range.address, port),
+                ipv6_address: None,
+                stats_query: None,
+                tproxy: None,
+                java: None,
+                tunnel: None,
+                ..self.proxy.clone()
+            };
+            proxy.fallback_motd.ipv4_port = Some(port);
+            let upstream = UpstreamConfig {
+                address: UpstreamAddress {
+                    host: range.upstream.host.clone(),
+                    port: range.upstream.port.saturating_add(offset),
+                    srv: range.upstream.srv,
+                },
+                query_address: None,
+                regions: vec![],
+                servers: vec![],
+                ..self.upstream.clone()
+            };
+
+            listeners.push((format!("{name}_{port}"), ListenerConfig { proxy, upstream }));
+        }
+
+        listeners
+    }
+
+    /// The bind addresses of the main listener and the named ones.
+    pub fn listener_addresses(&self) -> Vec<SocketAddr> {
+        std::iter::once(self.proxy.clone())
+            .chain(self.all_listeners().into_iter().map(|(_, l)| l.proxy))
+            .flat_map(|p| std::iter::once(p.address).chain(p.ipv6_address))
+            .collect()
+    }
+
+    /// Check that no two listeners are bound to overlapping addresses, e.g. a port range
+    /// which includes the port of the main listener, as every listener is started.
+    pub fn check_listener_addresses(&self) -> CCProxyResult<()> {
+        let overlaps = |a: &SocketAddr, b: &SocketAddr| {
+            a.port() == b.port()
+                && a.is_ipv4() == b.is_ipv4()
+                && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+        };
+
+        let addresses = self.listener_addresses();
+        for (i, address) in addresses.iter().enumerate() {
+            if addresses[..i].iter().any(|a| overlaps(a, address)) {
+                return Err(CCProxyError::ListenerAddressConflict { address: *address });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the values which are parsed fine but cannot be run with, e.g. an interval of
+    /// zero.
+    pub fn validate(&self) -> CCProxyResult<()> {
+        self.check_intervals()?;
+        self.check_schedules()
+    }
+
+    /// Check that no interval or period is zero.
+    pub fn check_intervals(&self) -> CCProxyResult<()> {
+        let mut intervals = vec![
+            (
+                "log.error_summary.interval_secs".to_owned(),
+                self.log.error_summary.interval_secs,
+            ),
+            (
+                "retention.interval_secs".to_owned(),
+                self.retention.interval_secs,
+            ),
+            (
+                "update_check.interval_secs".to_owned(),
+                self.update_check.interval_secs,
+            ),
+            (
+                "dns.refresh_interval_secs".to_owned(),
+                self.dns.refresh_interval_secs,
+            ),
+            (
+                "metrics.per_ip.interval_secs".to_owned(),
+                self.metrics.per_ip.interval_secs,
+            ),
+            (
+                "metrics.packets.interval_secs".to_owned(),
+                self.metrics.packets.interval_secs,
+            ),
+        ];
+        if let Some(push) = &self.metrics.push {
+            intervals.push(("metrics.push.interval_secs".to_owned(), push.interval_secs));
+        }
+        if let Some(influxdb) = &self.metrics.influxdb {
+            intervals.push((
+                "metrics.influxdb.interval_secs".to_owned(),
+                influxdb.interval_secs,
+            ));
+        }
+        if let Some(statsd) = &self.metrics.statsd {
+            intervals.push((
+                "metrics.statsd.interval_secs".to_owned(),
+                statsd.interval_secs,
+            ));
+        }
+
+        let listeners = self.all_listeners();
+        let listeners = std::iter::once(("".to_owned(), &self.proxy, &self.upstream)).chain(
+            listeners
+                .iter()
+                .map(|(name, l)| (format!("listeners.{name}."), &l.proxy, &l.upstream)),
+        );
+        for (prefix, proxy, upstream) in listeners {
+            intervals.push((
+                format!("{prefix}upstream.latency_probe.interval_secs"),
+                upstream.latency_probe.interval_secs,
+            ));
+            intervals.push((
+                format!("{prefix}upstream.health_check.interval_secs"),
+                upstream.health_check.interval_secs,
+            ));
+            if let Some(limbo) = &proxy.limbo {
+                intervals.push((
+                    format!("{prefix}proxy.limbo.check_interval_secs"),
+                    limbo.check_interval_secs,
+                ));
+            }
+            if let Some(under_attack) = &proxy.under_attack {
+                intervals.push((
+                    format!("{prefix}proxy.under_attack.interval_secs"),
+                    under_attack.interval_secs,
+                ));
+            }
+            if let Some(lan_discovery) = &proxy.lan_discovery {
+                intervals.push((
+                    format!("{prefix}proxy.lan_discovery.interval_secs"),
+                    lan_discovery.interval_secs,
+                ));
+            }
+            if let Some(blocklists) = &proxy.access.blocklists {
+                intervals.push((
+                    format!("{prefix}proxy.access.blocklists.interval_secs"),
+                    blocklists.interval_secs,
+                ));
+            }
+        }
+
+        for (key, value) in intervals {
+            check_non_zero(key, value)?;
+        }
+
+        Ok(())
+    }
 
-        let config = config_path.join("config.yaml");
+    /// Check that the cron expression of every schedule parses.
+    pub fn check_schedules(&self) -> CCProxyResult<()> {
+        for schedule in &self.schedules {
+            if let Err(err) = cron::Schedule::from_str(&schedule.cron) {
+                return Err(CCProxyError::ConfigValueInvalid {
+                    key: format!("schedules.{}.cron", schedule.name),
+                    reason: err.to_string(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the transport can send the PROXY protocol of every upstream. The tunnel
+    /// origin writes either version, and the passthrough mode sends none, so only the
+    /// RakNet transport refuses `v1`.
+    pub fn check_proxy_protocol(&self) -> CCProxyResult<()> {
+        if self.proxy.passthrough.is_some() || self.proxy.tunnel.is_some() {
+            return Ok(());
+        }
+
+        self.upstream.check_proxy_protocol()
+    }
+
+    /// Load the config, merging `config.<profile>.yaml` over the base file if a profile
+    /// is given or set by `CCPROXY__PROFILE`.
+    pub fn init(profile: Option<&str>) -> CCProxyResult<Self> {
+        // Create the config path
+        let config = CONFIG_FILE_PATH.clone();
+        if let Some(config_path) = config.parent() {
+            std::fs::create_dir_all(config_path)?;
+        }
 
         // Init the default config if it doesn't exist.
         if !config.exists() {
@@ -67,11 +359,36 @@ impl CCProxyConfig {
         }
 
         // Load the config
-        Ok(Figment::new()
+        let mut figment = Figment::new()
             .merge(Env::prefixed(CCPROXY_ENV_PREFIX).split("__"))
-            .merge(Yaml::file(config))
-            .extract()
-            .map_err(Box::new)?)
+            .merge(Yaml::file(&config));
+
+        // Overlay the environment profile
+        let profile = profile
+            .map(|p| p.to_owned())
+            .or_else(|| ccproxy_env("PROFILE").ok());
+        if let Some(profile) = profile {
+            let profile_config = config.with_file_name(format!("config.{profile}.yaml"));
+            if !profile_config.exists() {
+                return Err(CCProxyError::ConfigProfileNotFound {
+                    profile,
+                    path: profile_config.display().to_string(),
+                });
+            }
+
+            figment = figment.merge(Yaml::file(profile_config));
+        }
+
+        Ok(figment.extract().map_err(Box::new)?)
+    }
+
+    pub fn timezone(&self) -> CCProxyResult<Tz> {
+        match &self.timezone {
+            Some(timezone) => timezone.parse().map_err(|_| CCProxyError::TimezoneInvalid {
+                timezone: timezone.clone(),
+            }),
+            None => Ok(Tz::UTC),
+        }
     }
 }
 
@@ -82,20 +399,72 @@ pub struct LogConfig {
 
     #[serde(default)]
     pub file: LogBaseConfig,
+
+    /// Write log timestamps in the configured timezone instead of UTC.
+    #[serde(default)]
+    pub use_timezone: bool,
+
+    #[serde(default)]
+    pub error_summary: ErrorSummaryConfig,
+
+    /// Export the spans of the sessions to an OpenTelemetry collector, e.g. for Jaeger or
+    /// Tempo. Disabled if unset.
+    #[serde(default)]
+    pub otlp: Option<LogOtlpConfig>,
+}
+
+fn default_error_summary_interval_secs() -> u64 {
+    60
+}
+
+fn default_error_summary_top_sources() -> usize {
+    5
+}
+
+/// Collapses repeated transport errors, e.g. connection-reset storms during an attack,
+/// into a periodic summary. Only the first error of each kind in a period is logged.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ErrorSummaryConfig {
+    /// The summary period. 0 logs every error.
+    #[serde(default = "default_error_summary_interval_secs")]
+    pub interval_secs: u64,
+
+    /// How many sources with the most errors are listed per kind.
+    #[serde(default = "default_error_summary_top_sources")]
+    pub top_sources: usize,
+}
+
+impl Default for ErrorSummaryConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_error_summary_interval_secs(),
+            top_sources: default_error_summary_top_sources(),
+        }
+    }
 }
 
 impl LogConfig {
-    pub fn tracing_subscriber(&self) -> CCProxyResult<(impl tracing::Subscriber, WorkerGuard)> {
-        let stdout_filter = EnvFilter::builder().parse(self.stdout.filter.clone())?;
-        let file_filter = EnvFilter::builder().parse(self.file.filter.clone())?;
+    pub fn tracing_subscriber(
+        &self,
+        timezone: Tz,
+    ) -> CCProxyResult<(impl tracing::Subscriber, LogGuard, LogFilterHandle)> {
+        let timer = LogTimer(self.use_timezone.then_some(timezone));
+
+        // The filters can be swapped at runtime through the returned handle.
+        let (stdout_filter, stdout_handle) =
+            reload::Layer::new(EnvFilter::builder().parse(self.stdout.filter.clone())?);
+        let (file_filter, file_handle) =
+            reload::Layer::new(EnvFilter::builder().parse(self.file.filter.clone())?);
 
         // stdout
         let stdout_log = match self.stdout.format {
             LogFormat::Plain => tracing_subscriber::fmt::layer()
+                .with_timer(timer)
                 .with_filter(stdout_filter)
                 .boxed(),
             LogFormat::Json => tracing_subscriber::fmt::layer()
                 .json()
+                .with_timer(timer)
                 .with_filter(stdout_filter)
                 .boxed(),
         };
@@ -104,28 +473,131 @@ impl LogConfig {
         let file_appender = RollingFileAppender::builder()
             .rotation(tracing_appender::rolling::Rotation::DAILY)
             .filename_suffix("log")
-            .build(DATA_PATH.join("logs"))?;
+            .build(&*LOG_PATH)?;
         let (file_writer, guard) = tracing_appender::non_blocking(file_appender);
         let file_log = match self.file.format {
             LogFormat::Plain => tracing_subscriber::fmt::layer()
                 // No colors in text file.
                 // TODO: Find why this not work in other crates.
                 .with_ansi(false)
+                .with_timer(timer)
                 .with_writer(file_writer)
                 .with_filter(file_filter)
                 .boxed(),
             LogFormat::Json => tracing_subscriber::fmt::layer()
                 .json()
+                .with_timer(timer)
                 .with_writer(file_writer)
                 .with_filter(file_filter)
                 .boxed(),
         };
 
+        // OpenTelemetry
+        let (otlp_trace, tracer_provider) = match &self.otlp {
+            Some(otlp) => {
+                let (layer, provider) = otlp.layer()?;
+                (Some(layer), Some(provider))
+            }
+            None => (None, None),
+        };
+
         let subscriber = tracing_subscriber::registry()
             .with(stdout_log)
-            .with(file_log);
+            .with(file_log)
+            .with(otlp_trace);
+
+        let handle = LogFilterHandle {
+            stdout: Box::new(move |filter| stdout_handle.reload(filter)),
+            file: Box::new(move |filter| file_handle.reload(filter)),
+            filters: Mutex::new((self.stdout.filter.clone(), self.file.filter.clone())),
+        };
+
+        let guard = LogGuard {
+            _file: guard,
+            tracer_provider,
+        };
+
+        Ok((subscriber, guard, handle))
+    }
+}
+
+/// Keeps the log outputs running until it is dropped, which flushes the file log and
+/// exports the last spans.
+pub struct LogGuard {
+    _file: WorkerGuard,
+
+    tracer_provider: Option<SdkTracerProvider>,
+}
+
+impl Drop for LogGuard {
+    fn drop(&mut self) {
+        if let Some(provider) = self.tracer_provider.take()
+            && let Err(err) = provider.shutdown()
+        {
+            tracing::warn!("Cannot export the last spans: {err}");
+        }
+    }
+}
+
+type ReloadFilter = Box<dyn Fn(EnvFilter) -> Result<(), reload::Error> + Send + Sync>;
+
+/// Swaps the filters of the stdout and the file log at runtime, e.g. to turn on debug
+/// logs without restarting and dropping players.
+pub struct LogFilterHandle {
+    stdout: ReloadFilter,
+
+    file: ReloadFilter,
+
+    /// The stdout and the file filters in effect.
+    filters: Mutex<(String, String)>,
+}
+
+impl LogFilterHandle {
+    /// The stdout and the file filters in effect.
+    pub fn filters(&self) -> (String, String) {
+        self.filters.lock().unwrap().clone()
+    }
+
+    /// Replace the filters which are given, keeping the others. Neither is replaced if
+    /// one cannot be parsed.
+    pub fn set(&self, stdout: Option<&str>, file: Option<&str>) -> CCProxyResult<()> {
+        let stdout = stdout
+            .map(|f| Ok::<_, CCProxyError>((f, EnvFilter::builder().parse(f)?)))
+            .transpose()?;
+        let file = file
+            .map(|f| Ok::<_, CCProxyError>((f, EnvFilter::builder().parse(f)?)))
+            .transpose()?;
+
+        let mut filters = self.filters.lock().unwrap();
+        if let Some((raw, filter)) = stdout {
+            (self.stdout)(filter)?;
+            filters.0 = raw.to_owned();
+        }
+        if let Some((raw, filter)) = file {
+            (self.file)(filter)?;
+            filters.1 = raw.to_owned();
+        }
+
+        Ok(())
+    }
+}
 
-        Ok((subscriber, guard))
+/// Formats log timestamps as RFC 3339 in the given timezone, or in UTC if it is `None`.
+#[derive(Clone, Copy)]
+struct LogTimer(Option<Tz>);
+
+impl FormatTime for LogTimer {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        let now = Utc::now();
+        match self.0 {
+            Some(timezone) => write!(
+                w,
+                "{}",
+                now.with_timezone(&timezone)
+                    .to_rfc3339_opts(SecondsFormat::Micros, false)
+            ),
+            None => write!(w, "{}", now.to_rfc3339_opts(SecondsFormat::Micros, true)),
+        }
     }
 }
 
@@ -146,6 +618,63 @@ impl Default for LogBaseConfig {
     }
 }
 
+fn default_log_otlp_filter() -> String {
+    "ccproxy=info".to_owned()
+}
+
+fn default_log_otlp_service_name() -> String {
+    "ccproxy".to_owned()
+}
+
+fn default_log_otlp_timeout_secs() -> u64 {
+    10
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LogOtlpConfig {
+    /// The OTLP/gRPC endpoint of the collector, e.g. `http://127.0.0.1:4317`.
+    pub endpoint: String,
+
+    /// Which spans are exported, e.g. `ccproxy=debug` to include the forwarding loops.
+    #[serde(default = "default_log_otlp_filter")]
+    pub filter: String,
+
+    #[serde(default = "default_log_otlp_service_name")]
+    pub service_name: String,
+
+    #[serde(default = "default_log_otlp_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+impl LogOtlpConfig {
+    /// The layer which sends the spans to the collector in batches, with the provider
+    /// to flush them on exit.
+    fn layer<S>(&self) -> CCProxyResult<(impl Layer<S> + use<S>, SdkTracerProvider)>
+    where
+        S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    {
+        let exporter = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(&self.endpoint)
+            .with_timeout(std::time::Duration::from_secs(self.timeout_secs))
+            .build()?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(self.service_name.clone())
+                    .build(),
+            )
+            .build();
+
+        let layer = tracing_opentelemetry::layer()
+            .with_tracer(provider.tracer("ccproxy"))
+            .with_filter(EnvFilter::builder().parse(self.filter.clone())?);
+
+        Ok((layer, provider))
+    }
+}
+
 #[derive(Clone, Default, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum LogFormat {
@@ -156,95 +685,1434 @@ pub enum LogFormat {
 }
 
 #[derive(Clone, Deserialize, Serialize)]
-pub struct ProxyConfig {
-    pub address: SocketAddr,
+pub struct ListenerConfig {
+    pub proxy: ProxyConfig,
 
-    pub fallback_motd: BedrockMotd,
+    pub upstream: UpstreamConfig,
+}
 
-    pub fallback_query: ProxyQueryConfig,
+fn default_admin_address() -> Option<SocketAddr> {
+    Some("127.0.0.1:19180".parse().unwrap())
 }
 
-impl Default for ProxyConfig {
-    fn default() -> Self {
-        Self {
-            address: "0.0.0.0:19132".parse().unwrap(),
-            fallback_motd: Default::default(),
-            fallback_query: Default::default(),
-        }
-    }
+/// The admin listener, which serves the control commands as a JSON object per line, and
+/// optionally as a REST API and a gRPC API.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AdminConfig {
+    /// The TCP address, which must be a loopback address unless `allow_remote` is set.
+    #[serde(default = "default_admin_address")]
+    pub address: Option<SocketAddr>,
+
+    /// Allow a non-loopback `address`, e.g. behind a firewall on a private network. At
+    /// least one of `tokens` is required then.
+    #[serde(default)]
+    pub allow_remote: bool,
+
+    /// Also serve on a Unix socket which only the owner of the process can connect to.
+    #[serde(default)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// Also serve the REST API over HTTP on this address, with the dashboard at `/`, which
+    /// must be a loopback address unless `allow_remote` is set.
+    #[serde(default)]
+    pub http_address: Option<SocketAddr>,
+
+    /// Also serve the gRPC API of `proto/ccproxy/admin/v1/admin.proto` on this address,
+    /// which must be a loopback address unless `allow_remote` is set.
+    #[serde(default)]
+    pub grpc_address: Option<SocketAddr>,
+
+    /// The bearer tokens which the admin listener and the control socket accept. Every
+    /// command is allowed without a token if empty.
+    #[serde(default)]
+    pub tokens: Vec<AdminTokenConfig>,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub struct ProxyQueryConfig {
-    pub motd: String,
+impl AdminConfig {
+    /// Check that a listener may be bound to `address`. A non-loopback address needs
+    /// `allow_remote`, and tokens so that not everyone can run every command.
+    pub fn check_address(&self, address: SocketAddr) -> CCProxyResult<()> {
+        if address.ip().is_loopback() {
+            return Ok(());
+        }
+        if !self.allow_remote {
+            return Err(CCProxyError::AdminAddressNotLoopback { address });
+        }
+        if self.tokens.is_empty() {
+            return Err(CCProxyError::AdminTokensRequired { address });
+        }
 
-    pub game_type: String,
+        Ok(())
+    }
 
-    pub map: String,
+    /// Read every token.
+    pub fn load_tokens(&self) -> CCProxyResult<Vec<AdminToken>> {
+        self.tokens
+            .iter()
+            .map(|t| {
+                Ok(AdminToken {
+                    token: t.load()?,
+                    role: t.role,
+                    name: t.name.clone(),
+                })
+            })
+            .collect()
+    }
 
-    pub num_players: u64,
+    /// The token which the CLI sends to the running proxy, preferring an operator one.
+    pub fn client_token(&self) -> CCProxyResult<Option<String>> {
+        let token = self
+            .tokens
+            .iter()
+            .find(|t| t.role == AdminRole::Operator)
+            .or(self.tokens.first());
 
-    pub max_players: u64,
+        token.map(|t| t.load()).transpose()
+    }
+}
 
-    pub host_port: u16,
+/// What the holder of an admin token may do.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    /// Read the status, the sessions, the upstreams, the bans, and the metrics.
+    ReadOnly,
 
-    pub host_ip: IpAddr,
+    /// Also kick, ban, reload, and change the maintenance mode.
+    Operator,
+}
 
-    pub version: String,
+impl AdminRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ReadOnly => "read_only",
+            Self::Operator => "operator",
+        }
+    }
+}
 
+/// A bearer token of the admin APIs, given inline or in a file such as a mounted secret.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AdminTokenConfig {
     #[serde(default)]
-    pub plugins: Option<String>,
+    pub token: Option<String>,
 
+    /// Read the token from this file, ignoring the whitespace around it.
     #[serde(default)]
-    pub players: Vec<String>,
+    pub token_file: Option<PathBuf>,
+
+    pub role: AdminRole,
+
+    /// Who holds the token, as written to the audit log.
+    #[serde(default)]
+    pub name: Option<String>,
 }
 
-impl Default for ProxyQueryConfig {
+/// An [`AdminTokenConfig`] whose token is read.
+#[derive(Clone)]
+pub struct AdminToken {
+    pub token: String,
+
+    pub role: AdminRole,
+
+    pub name: Option<String>,
+}
+
+impl AdminTokenConfig {
+    pub fn load(&self) -> CCProxyResult<String> {
+        let token = match (&self.token, &self.token_file) {
+            (Some(token), _) => token.clone(),
+            (None, Some(path)) => std::fs::read_to_string(path)?.trim().to_owned(),
+            (None, None) => return Err(CCProxyError::AdminTokenMissing),
+        };
+        if token.is_empty() {
+            return Err(CCProxyError::AdminTokenMissing);
+        }
+
+        Ok(token)
+    }
+}
+
+impl Default for AdminConfig {
     fn default() -> Self {
         Self {
-            motd: "CCProxy".to_owned(),
-            game_type: "SMP".to_owned(),
-            map: "CCProxy".to_owned(),
-            num_players: 0,
-            max_players: 100,
-            host_port: 19132,
-            host_ip: "0.0.0.0".parse().unwrap(),
-            version: "1.21.101".to_owned(),
-            plugins: Default::default(),
-            players: Default::default(),
+            address: default_admin_address(),
+            allow_remote: false,
+            unix_socket: None,
+            http_address: None,
+            grpc_address: None,
+            tokens: vec![],
         }
     }
 }
 
-impl ProxyQueryConfig {
-    pub fn from_kv_and_players(
-        k_v_section: HashMap<String, String>,
-        players: Vec<String>,
-    ) -> CCProxyResult<Self> {
-        Ok(Self {
-            motd: k_v_section
-                .get("hostname")
-                .ok_or(CCProxyError::QueryInvalid)?
-                .to_owned(),
-            game_type: k_v_section
-                .get("gametype")
-                .ok_or(CCProxyError::QueryInvalid)?
-                .to_owned(),
-            map: k_v_section
-                .get("map")
-                .ok_or(CCProxyError::QueryInvalid)?
-                .to_owned(),
-            num_players: k_v_section
-                .get("numplayers")
-                .ok_or(CCProxyError::QueryInvalid)?
-                .parse()
-                .map_err(|_| CCProxyError::QueryInvalid)?,
-            max_players: k_v_section
-                .get("maxplayers")
-                .ok_or(CCProxyError::QueryInvalid)?
-                .parse()
-                .map_err(|_| CCProxyError::QueryInvalid)?,
-            host_port: k_v_section
+fn default_control_path() -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(r"\\.\pipe\ccproxy")
+    } else {
+        DATA_PATH.join("ccproxy.sock")
+    }
+}
+
+/// The local control socket which `ccproxy ctl` talks to, serving the admin commands
+/// without any network exposure: a Unix socket which only the owner of the process can
+/// connect to, or a named pipe which refuses remote clients on Windows.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ControlConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+
+    /// The socket path, or the pipe name on Windows. Defaults to `ccproxy.sock` in the
+    /// data path, or `\\.\pipe\ccproxy`.
+    #[serde(default = "default_control_path")]
+    pub path: PathBuf,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            path: default_control_path(),
+        }
+    }
+}
+
+fn default_port_range_address() -> IpAddr {
+    IpAddr::from([0, 0, 0, 0])
+}
+
+/// A range of ports, e.g. allocated by a hosting panel per customer, where each port is
+/// relayed to its own upstream port.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PortRangeConfig {
+    #[serde(default = "default_port_range_address")]
+    pub address: IpAddr,
+
+    pub ports: PortRange,
+
+    /// The upstream of the first port. The next ports are relayed to the next upstream
+    /// ports, e.g. `19133` of `19132-19140` to `10.0.0.5:19201` for `10.0.0.5:19200`.
+    pub upstream: UpstreamAddress,
+}
+
+/// An inclusive range of ports written as `<start>-<end>`, e.g. `19132-19140`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRange {
+    pub start: u16,
+
+    pub end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("The port range ({s}) is invalid."))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+            return Err(format!("The port range ({s}) ends before it starts."));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+impl TryFrom<String> for PortRange {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PortRange> for String {
+    fn from(range: PortRange) -> Self {
+        format!("{}-{}", range.start, range.end)
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ProxyConfig {
+    pub address: SocketAddr,
+
+    /// Also listen on an IPv6 address, e.g. `[::]:19133`, for dual-stack clients. Its
+    /// port is advertised as the IPv6 port of the MOTD unless `fallback_motd` sets one.
+    #[serde(default)]
+    pub ipv6_address: Option<SocketAddr>,
+
+    /// The MOTD served while the upstream does not answer pings.
+    ///
+    /// This and the MOTD profiles may use the `{upstream_ping}` placeholder in the server
+    /// names, which is replaced by the last RTT to the upstream in milliseconds.
+    pub fallback_motd: BedrockMotd,
+
+    pub fallback_query: ProxyQueryConfig,
+
+    #[serde(default)]
+    pub session_state: SessionStateConfig,
+
+    #[serde(default)]
+    pub disconnect_messages: DisconnectMessagesConfig,
+
+    /// Alternative MOTDs which can be served instead of the upstream MOTD by name.
+    #[serde(default)]
+    pub motd_profiles: HashMap<String, BedrockMotd>,
+
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    /// The most players of the listener at once, including the ones which are still
+    /// connecting to the upstream. New players get `disconnect_messages.full` beyond it.
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+
+    /// Advertise `max_sessions` as the max players of the MOTD instead of the one of the
+    /// upstream, so the server list shows the cap of the proxy.
+    #[serde(default)]
+    pub advertise_max_sessions: bool,
+
+    /// The bytes per second which each session may send in each direction, so a single
+    /// client cannot saturate the uplink of the proxy.
+    #[serde(default)]
+    pub session_bandwidth: BandwidthConfig,
+
+    /// The source IP ranges which may connect, checked before any other limit.
+    #[serde(default)]
+    pub access: AccessConfig,
+
+    /// The sanity checks of the raw client datagrams, and what breaking each gets.
+    #[serde(default)]
+    pub datagram_filter: DatagramFilterConfig,
+
+    /// The players which may log in, checked against the Login packet of the client.
+    #[serde(default)]
+    pub identity: IdentityConfig,
+
+    /// What happens when a player logs in with the XUID or the game install of a live
+    /// session. Duplicates are allowed if unset.
+    #[serde(default)]
+    pub duplicate_identity: Option<DuplicateIdentityAction>,
+
+    /// The most connections which a source IP can hold at once, including the ones which
+    /// are still connecting to the upstream. A household behind one NAT counts as one
+    /// IP, so leave room for it.
+    #[serde(default)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// Connect to the upstream only once the client sent its first game packet.
+    #[serde(default)]
+    pub handshake_gate: Option<HandshakeGateConfig>,
+
+    /// Switch to a stricter posture on its own while the listener is flooded.
+    #[serde(default)]
+    pub under_attack: Option<UnderAttackConfig>,
+
+    /// Kick the sessions whose client sends no game packet for a while, whatever
+    /// RakNet keeps alive on its own.
+    #[serde(default)]
+    pub idle_kick: Option<IdleKickConfig>,
+
+    /// Advertise a random GUID instead of a stable one, so clients do not merge multiple
+    /// server list entries which point at the same proxy.
+    ///
+    /// The passthrough mode and the tunnel edge put a fresh GUID in every pong, which they
+    /// relay or answer themselves. On the RakNet transport, rust-raknet answers pings from the shared
+    /// MOTD, so the GUID in the MOTD string is re-rolled whenever the MOTD is refreshed,
+    /// about every 5 seconds, and the GUID in the RakNet header of the pong stays the
+    /// stable GUID of the listener.
+    #[serde(default)]
+    pub randomize_guid: bool,
+
+    /// Hold players in a built-in limbo while the upstream is down instead of kicking them.
+    ///
+    /// The limbo accepts the login but sends no world, so the players wait on the loading
+    /// screen until they are transferred back.
+    #[serde(default)]
+    pub limbo: Option<LimboConfig>,
+
+    /// Answer Query Protocol requests on a separate port with the proxy's own statistics.
+    #[serde(default)]
+    pub stats_query: Option<StatsQueryConfig>,
+
+    /// Relay traffic redirected by the iptables TPROXY target to its original destination.
+    #[serde(default)]
+    pub tproxy: Option<TproxyConfig>,
+
+    /// Refuse new players and serve a dedicated MOTD, e.g. while the upstream is updated.
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+
+    /// Relay raw datagrams to the upstream instead of terminating RakNet.
+    #[serde(default)]
+    pub passthrough: Option<PassthroughConfig>,
+
+    /// The number of sockets bound to `address` with `SO_REUSEPORT` (Linux only), which
+    /// the kernel spreads the clients over. Each worker relays its clients with its own
+    /// flow table. Only the passthrough mode uses it, as the RakNet transport binds
+    /// its own socket.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+
+    /// Cap the MTU which the clients of the raw relays negotiate with the upstream: the
+    /// passthrough mode, the edge of a tunnel, and the TPROXY gateway.
+    #[serde(default)]
+    pub mtu: Option<MtuConfig>,
+
+    /// Take the client address from the PROXY protocol v2 header which a UDP load
+    /// balancer in front of the raw relays puts on the datagrams. The RakNet transport
+    /// reads its own socket, so the default mode does not support it.
+    #[serde(default)]
+    pub inbound_proxy_protocol: InboundProxyProtocolConfig,
+
+    /// Map the port of `address` on the local router, e.g. for hosting at home.
+    #[serde(default)]
+    pub port_mapping: Option<PortMappingConfig>,
+
+    /// Announce the proxy in the LAN Games tab of consoles on the local network.
+    #[serde(default)]
+    pub lan_discovery: Option<LanDiscoveryConfig>,
+
+    /// Proxy Java Edition players over TCP next to the Bedrock listener.
+    #[serde(default)]
+    pub java: Option<JavaConfig>,
+
+    /// Carry the clients over an encrypted tunnel between an internet-facing edge and
+    /// an origin next to the upstream, so the upstream address is never exposed.
+    #[serde(default)]
+    pub tunnel: Option<TunnelConfig>,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            address: "0.0.0.0:19132".parse().unwrap(),
+            ipv6_address: None,
+            fallback_motd: Default::default(),
+            fallback_query: Default::default(),
+            session_state: Default::default(),
+            disconnect_messages: Default::default(),
+            motd_profiles: Default::default(),
+            rate_limit: Default::default(),
+            max_sessions: None,
+            advertise_max_sessions: false,
+            session_bandwidth: Default::default(),
+            access: Default::default(),
+            datagram_filter: Default::default(),
+            identity: Default::default(),
+            duplicate_identity: None,
+            max_connections_per_ip: None,
+            handshake_gate: None,
+            under_attack: None,
+            idle_kick: None,
+            randomize_guid: false,
+            limbo: None,
+            stats_query: None,
+            tproxy: None,
+            maintenance: Default::default(),
+            passthrough: None,
+            workers: default_workers(),
+            mtu: None,
+            inbound_proxy_protocol: Default::default(),
+            port_mapping: None,
+            lan_discovery: None,
+            java: None,
+            tunnel: None,
+        }
+    }
+}
+
+/// The maintenance mode, where new players are refused with
+/// `disconnect_messages.maintenance`.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct MaintenanceConfig {
+    /// Start in the maintenance mode. Schedules and config reloads can also toggle it.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// The MOTD served during the maintenance. Defaults to `fallback_motd`.
+    #[serde(default)]
+    pub motd: Option<BedrockMotd>,
+
+    /// Kick the players who are still connected this long after the maintenance
+    /// starts. They stay connected if unset.
+    #[serde(default)]
+    pub kick_after_secs: Option<u64>,
+}
+
+fn default_tproxy_idle_timeout_secs() -> u64 {
+    60
+}
+
+/// The transparent gateway mode (Linux, IPv4 only).
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TproxyConfig {
+    /// The address which the TPROXY rule redirects to with `--on-port`.
+    pub address: SocketAddr,
+
+    /// How long a flow is kept without datagrams from the destination.
+    #[serde(default = "default_tproxy_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+/// The MTU is capped by lowering the one which the OpenConnectionReply1 of the upstream
+/// offers, as RakNet clients settle on the smaller of theirs and the offered one.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MtuConfig {
+    /// The cap for every client, if any.
+    #[serde(default)]
+    pub max: Option<u16>,
+
+    /// Lower caps for the clients of some autonomous systems, e.g. mobile carriers
+    /// whose networks drop large datagrams. Needs `geoip.asn_database`.
+    #[serde(default)]
+    pub asn_rules: Vec<AsnMtuRule>,
+}
+
+impl MtuConfig {
+    /// The cap for a client at `geo`, which is the lowest of the rules matching it.
+    pub fn max_for(&self, geo: &GeoInfo) -> Option<u16> {
+        let asn_max = self
+            .asn_rules
+            .iter()
+            .filter(|r| geo.asn.is_some_and(|asn| r.asns.contains(&asn)))
+            .map(|r| r.max);
+
+        self.max.into_iter().chain(asn_max).min()
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AsnMtuRule {
+    pub asns: Vec<u32>,
+
+    pub max: u16,
+}
+
+/// A load balancer may put the header before every datagram, or send it as a datagram of
+/// its own which names the client of the datagrams from the same source port.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct InboundProxyProtocolConfig {
+    /// Only the load balancers in these ranges may name the client. A header from any
+    /// other source is not parsed, so clients cannot spoof their address.
+    #[serde(default)]
+    pub trusted_sources: Vec<IpNet>,
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_passthrough_idle_timeout_secs() -> u64 {
+    60
+}
+
+/// The passthrough mode, where each client gets its own UDP flow to the selected
+/// upstream like a NAT. Packets are not inspected, so the MOTD, the Query Protocol,
+/// the login features and the disconnect messages are either served by the upstream
+/// or not at all, and no PROXY protocol header is sent.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PassthroughConfig {
+    /// How long a flow is kept without datagrams from the upstream.
+    #[serde(default = "default_passthrough_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+impl Default for PassthroughConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_secs: default_passthrough_idle_timeout_secs(),
+        }
+    }
+}
+
+fn default_tunnel_certificate() -> PathBuf {
+    DATA_PATH.join("config").join("tunnel.pem")
+}
+
+fn default_tunnel_key() -> PathBuf {
+    DATA_PATH.join("config").join("tunnel.key")
+}
+
+fn default_tunnel_idle_timeout_secs() -> u64 {
+    60
+}
+
+/// The side of a tunnel which a proxy runs.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TunnelRole {
+    /// Accept the clients on `proxy.address` and forward them to the origin. The
+    /// `upstream` section is not used.
+    Edge,
+
+    /// Accept the edges and relay their clients to the upstream like the passthrough
+    /// mode, with the real client addresses.
+    Origin,
+}
+
+/// The QUIC tunnel between an edge and an origin. Both sides authenticate each other
+/// with the same certificate, which the origin generates on its first start.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TunnelConfig {
+    pub role: TunnelRole,
+
+    /// The address of the origin which the edge connects to, or which the origin binds.
+    pub address: SocketAddr,
+
+    /// The shared certificate in PEM.
+    #[serde(default = "default_tunnel_certificate")]
+    pub certificate: PathBuf,
+
+    /// The private key of `certificate` in PEM.
+    #[serde(default = "default_tunnel_key")]
+    pub key: PathBuf,
+
+    /// How long a flow is kept without datagrams from the upstream.
+    #[serde(default = "default_tunnel_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+}
+
+fn default_port_mapping_lease_secs() -> u32 {
+    60 * 60
+}
+
+/// The port mapping on the local router, which is renewed at half of the lease.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PortMappingConfig {
+    #[serde(default)]
+    pub method: PortMappingMethod,
+
+    /// The router for NAT-PMP. The gateway of the default route is used if unset.
+    #[serde(default)]
+    pub gateway: Option<Ipv4Addr>,
+
+    #[serde(default = "default_port_mapping_lease_secs")]
+    pub lease_secs: u32,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PortMappingMethod {
+    /// UPnP IGD, which the router is discovered for with SSDP.
+    #[default]
+    Upnp,
+
+    NatPmp,
+}
+
+fn default_lan_broadcast_address() -> SocketAddr {
+    "255.255.255.255:19132".parse().unwrap()
+}
+
+fn default_lan_interval_secs() -> u64 {
+    2
+}
+
+/// The LAN announcement, which broadcasts the MOTD as unconnected pongs so consoles,
+/// which cannot add servers by address, can join the proxied server as a LAN game.
+/// They join the port of `ipv4_port` in the MOTD on the announcing host.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LanDiscoveryConfig {
+    /// Where the pongs are sent to, e.g. the broadcast address of a subnet.
+    #[serde(default = "default_lan_broadcast_address")]
+    pub broadcast_address: SocketAddr,
+
+    #[serde(default = "default_lan_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl Default for LanDiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            broadcast_address: default_lan_broadcast_address(),
+            interval_secs: default_lan_interval_secs(),
+        }
+    }
+}
+
+fn default_java_address() -> SocketAddr {
+    "0.0.0.0:25565".parse().unwrap()
+}
+
+fn default_java_status_timeout_ms() -> u64 {
+    3_000
+}
+
+fn default_java_max_connections() -> usize {
+    1_024
+}
+
+/// The Java Edition listener, which relays TCP connections to a single upstream, e.g.
+/// the Java server of a Geyser setup.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct JavaConfig {
+    #[serde(default = "default_java_address")]
+    pub address: SocketAddr,
+
+    /// The Java server as `host:port`. SRV names are not supported.
+    pub upstream: UpstreamAddress,
+
+    /// Send the PROXY protocol header to the upstream, which must accept it. Both
+    /// versions are supported over TCP.
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolVersion,
+
+    /// The Server List Ping response while the upstream does not respond.
+    #[serde(default)]
+    pub fallback_motd: JavaMotd,
+
+    /// How long the upstream may take to answer a Server List Ping.
+    #[serde(default = "default_java_status_timeout_ms")]
+    pub status_timeout_ms: u64,
+
+    /// The most TCP connections open at once, including the Server List Pings. Any more
+    /// are closed right after they are accepted.
+    #[serde(default = "default_java_max_connections")]
+    pub max_connections: usize,
+}
+
+/// The Query Protocol responder which lets Minecraft monitoring tools watch the proxy
+/// itself. It reports the version of the proxy, the active sessions as `numplayers`,
+/// and the upstream address as `map`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct StatsQueryConfig {
+    pub address: SocketAddr,
+}
+
+/// Reject an interval or a period of zero, which `tokio::time::interval` panics with.
+fn check_non_zero(key: String, value: u64) -> CCProxyResult<()> {
+    match value {
+        0 => Err(CCProxyError::ConfigValueInvalid {
+            key,
+            reason: "it must be greater than zero".to_owned(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn default_limbo_check_interval_secs() -> u64 {
+    5
+}
+
+fn default_limbo_max_hold_secs() -> u64 {
+    5 * 60
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LimboConfig {
+    /// The public host of this proxy which players are transferred back to.
+    pub transfer_host: String,
+
+    pub transfer_port: u16,
+
+    /// How often the upstream is pinged while players are held. It must not be zero.
+    #[serde(default = "default_limbo_check_interval_secs")]
+    pub check_interval_secs: u64,
+
+    /// How long a player is held before being disconnected.
+    #[serde(default = "default_limbo_max_hold_secs")]
+    pub max_hold_secs: u64,
+
+    /// Also hold players whose upstream connection is lost mid-session, e.g. while the
+    /// upstream restarts. They are transferred back to the proxy, which holds them once
+    /// the upstream refuses the new connection.
+    ///
+    /// The Transfer packet cannot be injected once the upstream starts encryption, which
+    /// online-mode servers always do, so it only works for upstreams without encryption.
+    /// `doctor` warns when it is set.
+    #[serde(default)]
+    pub hold_on_upstream_loss: bool,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct RateLimitConfig {
+    /// The proxy-wide limit of new sessions, regardless of where they come from.
+    ///
+    /// This protects the upstream's login pipeline from a distributed flood where
+    /// every source stays under the per-IP limits.
+    #[serde(default)]
+    pub global_new_sessions: Option<TokenBucketConfig>,
+
+    /// The limit of new sessions from each source IP, e.g. a join bot on one host.
+    #[serde(default)]
+    pub new_sessions_per_ip: Option<TokenBucketConfig>,
+
+    /// What the clients over the new session limits and `max_connections_per_ip` get.
+    #[serde(default)]
+    pub action: RateLimitAction,
+
+    /// The limit of unconnected pings from each source IP, which server list scanners
+    /// send the most of. Excess pings are dropped without a pong.
+    ///
+    /// This only applies where the proxy relays raw datagrams, i.e. the passthrough mode
+    /// and the edge of a tunnel. The default listener is not covered: its RakNet transport
+    /// answers pings before the proxy sees them, so it has no ping limit, and `doctor`
+    /// warns if this is set for it.
+    #[serde(default)]
+    pub pings_per_ip: Option<TokenBucketConfig>,
+}
+
+/// The CIDR ranges which clients are allowed or denied from, e.g. the ranges of hosting
+/// providers which bot attacks come from. A denied client is dropped without a message.
+///
+/// The RakNet transport completes its handshake before a connection is accepted, so it
+/// is closed right after. The datagram relays drop the datagrams before any flow opens.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct AccessConfig {
+    /// Only these ranges may connect if any is set.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+
+    /// These ranges may not connect, even if they are in `allow`.
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+
+    /// Remote blocklists whose ranges are denied as well.
+    #[serde(default)]
+    pub blocklists: Option<BlocklistConfig>,
+}
+
+/// IP and CIDR blocklists fetched over HTTP(S), e.g. the FireHOL lists. Each line holds
+/// an address or a range, and anything after `#` or `;` is a comment.
+///
+/// A blocklist which cannot be fetched keeps its last ranges until the next refresh.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BlocklistConfig {
+    pub urls: Vec<String>,
+
+    #[serde(default = "default_blocklist_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default = "default_blocklist_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+/// The XUIDs and gamertags which players are allowed or denied by, e.g. an allowlist
+/// for an upstream which does not implement one. An entry of only digits is an XUID,
+/// and any other entry is a gamertag, which is matched case-insensitively.
+///
+/// The RakNet transport checks the Login packet of a client before it is forwarded, so
+/// the raw datagram relays cannot check it. Only players whose certificate chain is signed
+/// by Mojang pass `allow`, so an allowlist refuses every offline client.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct IdentityConfig {
+    /// Only these players may log in if any is set.
+    #[serde(default)]
+    pub allow: Vec<String>,
+
+    /// These players may not log in, even if they are in `allow`.
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+impl IdentityConfig {
+    pub fn is_denied(&self, identity: &LoginIdentity) -> bool {
+        self.deny.iter().any(|e| Self::matches(e, identity))
+    }
+
+    /// Only an authenticated player can be allowlisted, since anyone can claim to be any
+    /// player.
+    pub fn is_allowlisted(&self, identity: &LoginIdentity) -> bool {
+        self.allow.is_empty()
+            || (identity.authenticated && self.allow.iter().any(|e| Self::matches(e, identity)))
+    }
+
+    fn matches(entry: &str, identity: &LoginIdentity) -> bool {
+        // Gamertags cannot be all digits, so this must be an XUID.
+        if !entry.is_empty() && entry.chars().all(|c| c.is_ascii_digit()) {
+            identity.xuid.as_deref() == Some(entry)
+        } else {
+            identity
+                .display_name
+                .as_ref()
+                .is_some_and(|n| n.eq_ignore_ascii_case(entry))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateIdentityAction {
+    /// Disconnect the new client with `disconnect_messages.duplicate_identity`.
+    Refuse,
+
+    /// Disconnect the live session with `disconnect_messages.logged_in_elsewhere`.
+    ///
+    /// Only a player authenticated by Xbox Live replaces a session; any other duplicate is
+    /// refused, so nobody can end the session of another player by claiming its XUID.
+    Replace,
+}
+
+/// The rules which every client datagram is checked against where the proxy relays raw
+/// datagrams, i.e. the passthrough mode and the edge of a tunnel. The default listener is
+/// not covered: its RakNet transport parses the datagrams before the proxy sees them, so
+/// neither malformed offline messages nor the other rules are checked there.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DatagramFilterConfig {
+    /// The largest datagram which a client may send. RakNet never pads past the largest
+    /// MTU of 1500 bytes.
+    #[serde(default = "default_max_datagram_size")]
+    pub max_size: usize,
+
+    /// What a datagram larger than `max_size` gets.
+    #[serde(default)]
+    pub oversized: DatagramFilterAction,
+
+    /// What a datagram gets which is neither an offline message that clients send,
+    /// with its exact layout, nor a connected datagram of an open flow, e.g. an unknown
+    /// ID from a UDP scan.
+    #[serde(default)]
+    pub invalid: DatagramFilterAction,
+
+    /// What a frame set gets whose sequence number is more than `max_sequence_jump`
+    /// away from the highest one of its flow, which a real client never sends.
+    #[serde(default)]
+    pub impossible_sequence: DatagramFilterAction,
+
+    #[serde(default = "default_max_sequence_jump")]
+    pub max_sequence_jump: u32,
+
+    /// How long `ban_temporarily` bans the source IP for.
+    #[serde(default = "default_datagram_filter_ban_secs")]
+    pub ban_secs: u64,
+}
+
+impl Default for DatagramFilterConfig {
+    fn default() -> Self {
+        Self {
+            max_size: default_max_datagram_size(),
+            oversized: Default::default(),
+            invalid: Default::default(),
+            impossible_sequence: Default::default(),
+            max_sequence_jump: default_max_sequence_jump(),
+            ban_secs: default_datagram_filter_ban_secs(),
+        }
+    }
+}
+
+fn default_max_datagram_size() -> usize {
+    1500
+}
+
+fn default_max_sequence_jump() -> u32 {
+    4096
+}
+
+fn default_datagram_filter_ban_secs() -> u64 {
+    600
+}
+
+/// What a datagram which breaks a rule of the datagram filter gets.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DatagramFilterAction {
+    #[default]
+    Drop,
+
+    /// Log the datagram and relay it anyway, e.g. to try a rule out.
+    Log,
+
+    /// Drop the datagram, and ban its source IP for `ban_secs` with its sessions kicked.
+    BanTemporarily,
+}
+
+/// The RakNet transport always completes the handshake of a client before it connects to
+/// the upstream. With this, it also waits for the first game packet, so bots which
+/// complete the handshake and go silent never cost an upstream connection.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HandshakeGateConfig {
+    /// How long a client has to send its first game packet before it is closed.
+    #[serde(default = "default_handshake_gate_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_handshake_gate_timeout_secs() -> u64 {
+    10
+}
+
+/// The listener is under attack while its connection attempts or its drops exceed a
+/// threshold, and until both stay below them for `cooldown_secs`. Meanwhile, the limits
+/// here apply on top of the usual ones, and the raw datagram relays answer pings from
+/// the last pong of the upstream instead of relaying them, or not at all if there is
+/// none yet.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UnderAttackConfig {
+    /// The connection attempts per second which switch the mode on.
+    #[serde(default)]
+    pub max_attempts_per_sec: Option<f64>,
+
+    /// The refused connection attempts, dropped datagrams, and dropped pings per second
+    /// which switch the mode on.
+    #[serde(default)]
+    pub max_drops_per_sec: Option<f64>,
+
+    /// How often the rates are measured.
+    #[serde(default = "default_under_attack_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default = "default_under_attack_cooldown_secs")]
+    pub cooldown_secs: u64,
+
+    #[serde(default)]
+    pub new_sessions_per_ip: Option<TokenBucketConfig>,
+
+    #[serde(default)]
+    pub global_new_sessions: Option<TokenBucketConfig>,
+
+    /// The handshake gate of the RakNet transport while under attack, unless
+    /// `handshake_gate` is always on.
+    #[serde(default = "default_under_attack_handshake_gate")]
+    pub handshake_gate: Option<HandshakeGateConfig>,
+
+    /// The URL which a JSON event is posted to when the mode is switched on or off.
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+fn default_under_attack_interval_secs() -> u64 {
+    5
+}
+
+fn default_under_attack_cooldown_secs() -> u64 {
+    300
+}
+
+fn default_under_attack_handshake_gate() -> Option<HandshakeGateConfig> {
+    Some(HandshakeGateConfig {
+        timeout_secs: default_handshake_gate_timeout_secs(),
+    })
+}
+
+/// A client whose game packets stopped while its RakNet connection lives on, e.g. a
+/// crashed game or a stuck bot, holds a slot on the upstream until it is kicked with
+/// `disconnect_messages.idle`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct IdleKickConfig {
+    /// How long a session may go without a game packet from its client.
+    #[serde(default = "default_idle_kick_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_idle_kick_timeout_secs() -> u64 {
+    300
+}
+
+fn default_blocklist_interval_secs() -> u64 {
+    3600
+}
+
+fn default_blocklist_timeout_secs() -> u64 {
+    30
+}
+
+/// A byte rate in each direction, whose `per_sec` is in bytes per second and whose
+/// `burst` is how many bytes may be sent at once after a quiet period.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct BandwidthConfig {
+    /// From the client to the upstream.
+    #[serde(default)]
+    pub c2s: Option<TokenBucketConfig>,
+
+    /// From the upstream to the client.
+    #[serde(default)]
+    pub s2c: Option<TokenBucketConfig>,
+}
+
+/// How a client over a rate limit is refused.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitAction {
+    /// Disconnect the client with `disconnect_messages.rate_limited`.
+    #[default]
+    Kick,
+
+    /// Close the connection without a message, which costs a flood the least. The
+    /// datagram relays always refuse this way, as they cannot inject a message.
+    Ignore,
+}
+
+/// The messages shown to players when the proxy disconnects them.
+///
+/// They can only be delivered before the upstream starts encryption, so players who
+/// are already in game see the client's generic disconnect screen instead.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DisconnectMessagesConfig {
+    pub shutdown: String,
+
+    pub upstream_unavailable: String,
+
+    pub kick: String,
+
+    pub ban: String,
+
+    pub full: String,
+
+    pub maintenance: String,
+
+    pub rate_limited: String,
+
+    pub upstream_drained: String,
+
+    pub region_blocked: String,
+
+    pub network_blocked: String,
+
+    pub not_allowlisted: String,
+
+    pub duplicate_identity: String,
+
+    pub logged_in_elsewhere: String,
+
+    pub idle: String,
+}
+
+impl Default for DisconnectMessagesConfig {
+    fn default() -> Self {
+        Self {
+            shutdown: "The proxy server is restarting. Please join again in a moment.".to_owned(),
+            upstream_unavailable: "The server is not available now. Please try again later."
+                .to_owned(),
+            kick: "You are kicked from the server.".to_owned(),
+            ban: "You are banned from the server.".to_owned(),
+            full: "The server is full.".to_owned(),
+            maintenance: "The server is under maintenance.".to_owned(),
+            rate_limited: "Too many players are joining now. Please try again in a moment."
+                .to_owned(),
+            upstream_drained: "The server is moving. Please join again.".to_owned(),
+            region_blocked: "The server is not available in your region.".to_owned(),
+            network_blocked:
+                "The server cannot be joined from your network. Please turn off any VPN.".to_owned(),
+            not_allowlisted: "You are not on the allowlist of the server.".to_owned(),
+            duplicate_identity: "You are already logged in to the server.".to_owned(),
+            logged_in_elsewhere: "You logged in from another location.".to_owned(),
+            idle: "You are kicked for being idle.".to_owned(),
+        }
+    }
+}
+
+/// An action set which is applied whenever the cron expression fires.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ScheduleConfig {
+    #[serde(default)]
+    pub name: String,
+
+    /// A cron expression with seconds, e.g. `0 0 4 * * *` for every day at 04:00:00.
+    pub cron: String,
+
+    /// Enter (`true`) or leave (`false`) drain mode, where new sessions are refused.
+    #[serde(default)]
+    pub drain: Option<bool>,
+
+    /// Switch to a profile in `proxy.motd_profiles`. An empty name goes back to the upstream MOTD.
+    #[serde(default)]
+    pub motd_profile: Option<String>,
+
+    /// Enter (`true`) or leave (`false`) the maintenance mode of `proxy.maintenance`.
+    #[serde(default)]
+    pub maintenance: Option<bool>,
+
+    /// Shut down the proxy gracefully so the host's restart automation can start it again.
+    #[serde(default)]
+    pub exit: bool,
+
+    /// Move the connected players to another server with the Transfer packet, e.g. to
+    /// migrate the upstream to new hardware. Combine it with `drain` to keep new players
+    /// off the old upstream.
+    #[serde(default)]
+    pub transfer: Option<TransferConfig>,
+}
+
+/// A server which players are moved to with the Transfer packet.
+///
+/// Only sessions whose login sequence is still unencrypted can be transferred, which is
+/// the case when the upstream does not enable encryption. Encrypted sessions are left
+/// alone.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TransferConfig {
+    pub host: String,
+
+    pub port: u16,
+}
+
+fn default_update_check_url() -> String {
+    "https://api.github.com/repos/chungchan-dev/ccproxy/releases/latest".to_owned()
+}
+
+fn default_update_check_interval_secs() -> u64 {
+    24 * 60 * 60
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UpdateCheckConfig {
+    /// Check the release feed for a newer version at startup and on every interval.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// A GitHub-compatible "latest release" endpoint.
+    #[serde(default = "default_update_check_url")]
+    pub url: String,
+
+    #[serde(default = "default_update_check_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl Default for UpdateCheckConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            url: default_update_check_url(),
+            interval_secs: default_update_check_interval_secs(),
+        }
+    }
+}
+
+/// MaxMind-format (`.mmdb`) databases, e.g. GeoLite2-Country and GeoLite2-ASN, which
+/// sessions, the session history, and metrics are annotated from.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct GeoIpConfig {
+    #[serde(default)]
+    pub country_database: Option<PathBuf>,
+
+    #[serde(default)]
+    pub asn_database: Option<PathBuf>,
+
+    /// Only clients from these countries may connect if any is set, by ISO 3166-1
+    /// alpha-2 codes such as `KR`. Clients of an unknown country, e.g. on the LAN, are
+    /// allowed.
+    #[serde(default)]
+    pub allowed_countries: Vec<String>,
+
+    /// Clients from these countries may not connect.
+    #[serde(default)]
+    pub blocked_countries: Vec<String>,
+
+    /// Clients from these autonomous systems may not connect, e.g. the datacenters
+    /// which attack traffic comes from.
+    #[serde(default)]
+    pub blocked_asns: Vec<u32>,
+
+    /// Clients from these autonomous systems are never refused by the GeoIP filters,
+    /// e.g. the residential ISPs of the players.
+    #[serde(default)]
+    pub allowed_asns: Vec<u32>,
+}
+
+impl GeoIpConfig {
+    /// Whether the GeoIP filters do not apply to a client at `geo`.
+    pub fn is_exempt(&self, geo: &GeoInfo) -> bool {
+        geo.asn.is_some_and(|asn| self.allowed_asns.contains(&asn))
+    }
+
+    pub fn is_asn_allowed(&self, geo: &GeoInfo) -> bool {
+        !geo.asn.is_some_and(|asn| self.blocked_asns.contains(&asn))
+    }
+
+    pub fn is_country_allowed(&self, geo: &GeoInfo) -> bool {
+        let Some(country) = &geo.country else {
+            return true;
+        };
+
+        !self
+            .blocked_countries
+            .iter()
+            .any(|c| c.eq_ignore_ascii_case(country))
+            && (self.allowed_countries.is_empty()
+                || self
+                    .allowed_countries
+                    .iter()
+                    .any(|c| c.eq_ignore_ascii_case(country)))
+    }
+}
+
+fn default_dns_cache_size() -> u64 {
+    1024
+}
+
+fn default_dns_min_ttl_secs() -> u64 {
+    5
+}
+
+fn default_dns_max_ttl_secs() -> u64 {
+    60 * 60
+}
+
+fn default_dns_negative_min_ttl_secs() -> u64 {
+    5
+}
+
+fn default_dns_negative_max_ttl_secs() -> u64 {
+    30
+}
+
+fn default_dns_refresh_interval_secs() -> u64 {
+    30
+}
+
+fn default_dns_timeout_secs() -> u64 {
+    3
+}
+
+/// The resolver cache for hostnames of upstreams.
+///
+/// Answers are cached for their record TTL, clamped to the min/max TTLs. Failed lookups
+/// are cached too, so a broken name server does not stall every new connection.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct DnsConfig {
+    /// The number of cached responses.
+    #[serde(default = "default_dns_cache_size")]
+    pub cache_size: u64,
+
+    #[serde(default = "default_dns_min_ttl_secs")]
+    pub min_ttl_secs: u64,
+
+    #[serde(default = "default_dns_max_ttl_secs")]
+    pub max_ttl_secs: u64,
+
+    #[serde(default = "default_dns_negative_min_ttl_secs")]
+    pub negative_min_ttl_secs: u64,
+
+    #[serde(default = "default_dns_negative_max_ttl_secs")]
+    pub negative_max_ttl_secs: u64,
+
+    #[serde(default = "default_dns_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// Keep using the last answer for a hostname while its lookups fail.
+    #[serde(default = "default_true")]
+    pub serve_stale: bool,
+
+    /// How often the hostnames of the upstreams are resolved again.
+    #[serde(default = "default_dns_refresh_interval_secs")]
+    pub refresh_interval_secs: u64,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            cache_size: default_dns_cache_size(),
+            min_ttl_secs: default_dns_min_ttl_secs(),
+            max_ttl_secs: default_dns_max_ttl_secs(),
+            negative_min_ttl_secs: default_dns_negative_min_ttl_secs(),
+            negative_max_ttl_secs: default_dns_negative_max_ttl_secs(),
+            timeout_secs: default_dns_timeout_secs(),
+            serve_stale: true,
+            refresh_interval_secs: default_dns_refresh_interval_secs(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_affinity_ttl_secs() -> u64 {
+    600
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SessionStateConfig {
+    /// Save the session/affinity table on graceful shutdown and restore it at startup.
+    #[serde(default)]
+    pub persist: bool,
+
+    /// How long a client is routed back to its previous upstream after disconnecting.
+    #[serde(default = "default_affinity_ttl_secs")]
+    pub affinity_ttl_secs: u64,
+
+    /// How long a client IP is routed back to its previous upstream after its last
+    /// session, even when it reconnects from another port. 0 disables it.
+    #[serde(default)]
+    pub sticky_ttl_secs: u64,
+}
+
+impl Default for SessionStateConfig {
+    fn default() -> Self {
+        Self {
+            persist: false,
+            affinity_ttl_secs: default_affinity_ttl_secs(),
+            sticky_ttl_secs: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProxyQueryConfig {
+    pub motd: String,
+
+    pub game_type: String,
+
+    pub map: String,
+
+    pub num_players: u64,
+
+    pub max_players: u64,
+
+    pub host_port: u16,
+
+    pub host_ip: IpAddr,
+
+    pub version: String,
+
+    #[serde(default)]
+    pub plugins: Option<String>,
+
+    #[serde(default)]
+    pub players: Vec<String>,
+}
+
+impl Default for ProxyQueryConfig {
+    fn default() -> Self {
+        Self {
+            motd: "CCProxy".to_owned(),
+            game_type: "SMP".to_owned(),
+            map: "CCProxy".to_owned(),
+            num_players: 0,
+            max_players: 100,
+            host_port: 19132,
+            host_ip: "0.0.0.0".parse().unwrap(),
+            version: "1.21.101".to_owned(),
+            plugins: Default::default(),
+            players: Default::default(),
+        }
+    }
+}
+
+impl ProxyQueryConfig {
+    pub fn from_kv_and_players(
+        k_v_section: HashMap<String, String>,
+        players: Vec<String>,
+    ) -> CCProxyResult<Self> {
+        Ok(Self {
+            motd: k_v_section
+                .get("hostname")
+                .ok_or(CCProxyError::QueryInvalid)?
+                .to_owned(),
+            game_type: k_v_section
+                .get("gametype")
+                .ok_or(CCProxyError::QueryInvalid)?
+                .to_owned(),
+            map: k_v_section
+                .get("map")
+                .ok_or(CCProxyError::QueryInvalid)?
+                .to_owned(),
+            num_players: k_v_section
+                .get("numplayers")
+                .ok_or(CCProxyError::QueryInvalid)?
+                .parse()
+                .map_err(|_| CCProxyError::QueryInvalid)?,
+            max_players: k_v_section
+                .get("maxplayers")
+                .ok_or(CCProxyError::QueryInvalid)?
+                .parse()
+                .map_err(|_| CCProxyError::QueryInvalid)?,
+            host_port: k_v_section
                 .get("hostport")
                 .ok_or(CCProxyError::QueryInvalid)?
                 .parse()
@@ -266,21 +2134,912 @@ impl ProxyQueryConfig {
 }
 
 #[derive(Clone, Deserialize, Serialize)]
-pub struct UpstreamConfig {
+pub struct UpstreamConfig {
+    pub address: UpstreamAddress,
+
+    pub query_address: Option<SocketAddr>,
+
+    /// The PROXY protocol of `address`, and of the `servers`, `regions`, and `backup`
+    /// which do not choose their own.
+    #[serde(default)]
+    pub proxy_protocol: ProxyProtocolVersion,
+
+    /// Refresh the MOTD every second for a short while after the upstream restarts
+    /// (its GUID changes), so the proxy converges quickly on the new server state.
+    #[serde(default)]
+    pub restart_refresh: bool,
+
+    /// The server which players are transferred to when the upstream fails.
+    #[serde(default)]
+    pub backup: Option<BackupConfig>,
+
+    #[serde(default)]
+    pub latency_probe: LatencyProbeConfig,
+
+    #[serde(default)]
+    pub connect_retry: ConnectRetryConfig,
+
+    /// Stop routing to an upstream whose connections keep failing for a while.
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+
+    /// Upstreams in other regions. A new client is routed to the region which serves its
+    /// GeoIP country and answers the latency probes fastest, falling back to the pool of
+    /// `address` and `servers`.
+    #[serde(default)]
+    pub regions: Vec<UpstreamRegionConfig>,
+
+    /// More upstreams which new sessions are distributed over together with `address`.
+    #[serde(default)]
+    pub servers: Vec<UpstreamServerConfig>,
+
+    #[serde(default)]
+    pub balancer: BalancerStrategy,
+
+    /// The share of new sessions which `address` receives with the weighted balancer.
+    #[serde(default = "default_upstream_weight")]
+    pub weight: u32,
+
+    /// How long the sessions of an upstream which is removed by a config reload may
+    /// continue before they are transferred to the backup or kicked.
+    #[serde(default = "default_upstream_drain_timeout_secs")]
+    pub drain_timeout_secs: u64,
+
+    /// The local end of the sockets which the raw relays and the Java listener open to
+    /// the upstreams. The RakNet transport binds its own sockets, so the default mode
+    /// does not use it.
+    #[serde(default)]
+    pub outbound: OutboundConfig,
+}
+
+impl UpstreamConfig {
+    /// The upstreams which new sessions are balanced over, starting with `address`.
+    pub fn pool(&self) -> Vec<UpstreamServerConfig> {
+        let primary = UpstreamServerConfig {
+            address: self.address.clone(),
+            query_address: self.query_address,
+            weight: self.weight,
+            proxy_protocol: Some(self.proxy_protocol),
+        };
+
+        std::iter::once(primary)
+            .chain(self.servers.iter().cloned())
+            .collect()
+    }
+
+    /// The PROXY protocol which `server` of the pool expects.
+    pub fn proxy_protocol_of(&self, server: &UpstreamServerConfig) -> ProxyProtocolVersion {
+        server.proxy_protocol.unwrap_or(self.proxy_protocol)
+    }
+
+    /// Check that the RakNet transport can send the PROXY protocol of every upstream.
+    pub fn check_proxy_protocol(&self) -> CCProxyResult<()> {
+        match self
+            .pool()
+            .iter()
+            .find(|s| self.proxy_protocol_of(s) == ProxyProtocolVersion::V1)
+        {
+            Some(server) => Err(CCProxyError::ProxyProtocolUnsupported {
+                upstream: server.address.to_string(),
+                version: "v1",
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1:19133".parse().unwrap(),
+            query_address: Some("127.0.0.1:19133".parse().unwrap()),
+            proxy_protocol: Default::default(),
+            restart_refresh: false,
+            backup: None,
+            latency_probe: Default::default(),
+            connect_retry: Default::default(),
+            circuit_breaker: None,
+            health_check: Default::default(),
+            regions: vec![],
+            servers: vec![],
+            balancer: Default::default(),
+            weight: default_upstream_weight(),
+            drain_timeout_secs: default_upstream_drain_timeout_secs(),
+            outbound: Default::default(),
+        }
+    }
+}
+
+fn default_upstream_weight() -> u32 {
+    1
+}
+
+fn default_upstream_drain_timeout_secs() -> u64 {
+    300
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UpstreamServerConfig {
+    pub address: UpstreamAddress,
+
+    #[serde(default)]
+    pub query_address: Option<SocketAddr>,
+
+    /// The share of new sessions which this upstream receives with the weighted balancer.
+    #[serde(default = "default_upstream_weight")]
+    pub weight: u32,
+
+    /// Defaults to `upstream.proxy_protocol`.
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolVersion>,
+}
+
+/// The PROXY protocol header which carries the real client address to an upstream.
+///
+/// The RakNet transport sends the binary v2 header, which is the version defined for
+/// UDP, so `v1` is refused at startup unless a tunnel origin relays the clients, which
+/// writes the v1 text line ahead of each flow. `true` and `false` are read as `v2` and
+/// `off`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case", from = "ProxyProtocolSetting")]
+pub enum ProxyProtocolVersion {
+    #[default]
+    Off,
+
+    V1,
+
+    V2,
+}
+
+impl ProxyProtocolVersion {
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::Off
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProxyProtocolSetting {
+    Switch(bool),
+
+    Version(ProxyProtocolName),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ProxyProtocolName {
+    Off,
+
+    V1,
+
+    V2,
+}
+
+impl From<ProxyProtocolSetting> for ProxyProtocolVersion {
+    fn from(setting: ProxyProtocolSetting) -> Self {
+        match setting {
+            ProxyProtocolSetting::Switch(false)
+            | ProxyProtocolSetting::Version(ProxyProtocolName::Off) => Self::Off,
+            ProxyProtocolSetting::Version(ProxyProtocolName::V1) => Self::V1,
+            ProxyProtocolSetting::Switch(true)
+            | ProxyProtocolSetting::Version(ProxyProtocolName::V2) => Self::V2,
+        }
+    }
+}
+
+/// The `host:port` of an upstream. The host is an IP address or a hostname, which is
+/// resolved with `dns` and again every `dns.refresh_interval_secs`. A name without a
+/// port, such as `_minecraft._udp.example.com`, is an SRV name.
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct UpstreamAddress {
+    pub host: String,
+
+    /// Unused for an SRV name, whose records give the ports.
+    pub port: u16,
+
+    pub srv: bool,
+}
+
+impl UpstreamAddress {
+    /// Whether the host is an SRV name, e.g. `_minecraft._udp.example.com`, whose records
+    /// give the servers and their ports.
+    pub fn is_srv(&self) -> bool {
+        self.srv
+    }
+
+    /// The socket address, if the host is an IP address.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        self.host
+            .parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, self.port))
+    }
+}
+
+impl FromStr for UpstreamAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = s.parse::<SocketAddr>() {
+            return Ok(address.into());
+        }
+        // The port of an SRV name comes from its records.
+        if !s.is_empty() && !s.contains(':') {
+            return Ok(Self {
+                host: s.to_owned(),
+                port: 0,
+                srv: true,
+            });
+        }
+
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && !host.contains(':') => Ok(Self {
+                host: host.to_owned(),
+                port: port
+                    .parse()
+                    .map_err(|_| format!("The port of the address ({s}) is invalid."))?,
+                srv: false,
+            }),
+            _ => Err(format!(
+                "The address ({s}) is not a `host:port` or an SRV name."
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for UpstreamAddress {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<SocketAddr> for UpstreamAddress {
+    fn from(address: SocketAddr) -> Self {
+        Self {
+            host: address.ip().to_string(),
+            port: address.port(),
+            srv: false,
+        }
+    }
+}
+
+impl From<UpstreamAddress> for String {
+    fn from(address: UpstreamAddress) -> Self {
+        address.to_string()
+    }
+}
+
+impl std::fmt::Display for UpstreamAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.socket_addr() {
+            Some(address) => address.fmt(f),
+            None if self.is_srv() => f.write_str(&self.host),
+            None => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// How new sessions are distributed over the upstream pool.
+#[derive(Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalancerStrategy {
+    #[default]
+    RoundRobin,
+
+    /// Proportionally to the `weight` of each upstream.
+    Weighted,
+
+    /// To the upstream with the fewest live sessions.
+    LeastConnections,
+
+    /// By the hash of the client IP, so a client lands on the same upstream across
+    /// reconnects. The `weight` of each upstream scales its share of the ring.
+    ConsistentHash,
+
+    /// To `address` while it is healthy, then down the `servers` in order, failing
+    /// back when an earlier upstream recovers.
+    Failover,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UpstreamRegionConfig {
+    pub name: String,
+
     pub address: SocketAddr,
 
-    pub query_address: Option<SocketAddr>,
+    /// The ISO country codes of the clients this region serves, e.g. `KR`. A region
+    /// without countries serves clients from anywhere.
+    #[serde(default)]
+    pub countries: Vec<String>,
+}
+
+fn default_latency_probe_interval_secs() -> u64 {
+    5
+}
+
+fn default_latency_probe_timeout_ms() -> u64 {
+    1_000
+}
+
+fn default_latency_probe_window() -> usize {
+    60
+}
+
+/// Pings every upstream on a fixed cadence to track its RTT, jitter, and loss.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LatencyProbeConfig {
+    #[serde(default = "default_latency_probe_interval_secs")]
+    pub interval_secs: u64,
+
+    /// A ping without a pong within this time counts as lost.
+    #[serde(default = "default_latency_probe_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// The number of recent probes which the average RTT and the loss are computed over.
+    #[serde(default = "default_latency_probe_window")]
+    pub window: usize,
+}
+
+impl Default for LatencyProbeConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_latency_probe_interval_secs(),
+            timeout_ms: default_latency_probe_timeout_ms(),
+            window: default_latency_probe_window(),
+        }
+    }
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_connect_initial_backoff_ms() -> u64 {
+    250
+}
+
+fn default_connect_max_backoff_ms() -> u64 {
+    2_000
+}
 
+/// Retries a failed connection to the upstream before the client is given up on. The
+/// client waits in its loading screen meanwhile, so the total should stay short.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ConnectRetryConfig {
+    /// The attempts after the first one.
     #[serde(default)]
-    pub proxy_protocol: bool,
+    pub retries: u32,
+
+    #[serde(default = "default_connect_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// The backoff before the first retry, which doubles for every next one.
+    #[serde(default = "default_connect_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,
+
+    #[serde(default = "default_connect_max_backoff_ms")]
+    pub max_backoff_ms: u64,
 }
 
-impl Default for UpstreamConfig {
+impl Default for ConnectRetryConfig {
     fn default() -> Self {
         Self {
-            address: "127.0.0.1:19133".parse().unwrap(),
-            query_address: Some("127.0.0.1:19133".parse().unwrap()),
-            proxy_protocol: false,
+            retries: 0,
+            timeout_secs: default_connect_timeout_secs(),
+            initial_backoff_ms: default_connect_initial_backoff_ms(),
+            max_backoff_ms: default_connect_max_backoff_ms(),
+        }
+    }
+}
+
+fn default_circuit_breaker_failure_threshold() -> u32 {
+    5
+}
+
+fn default_circuit_breaker_open_secs() -> u64 {
+    30
+}
+
+/// While the circuit of an upstream is open, new clients are routed to the others, or
+/// refused when there is none.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CircuitBreakerConfig {
+    /// The consecutive failed connections which open the circuit.
+    #[serde(default = "default_circuit_breaker_failure_threshold")]
+    pub failure_threshold: u32,
+
+    /// How long the circuit stays open before a trial connection is let through.
+    #[serde(default = "default_circuit_breaker_open_secs")]
+    pub open_secs: u64,
+}
+
+/// For multi-homed hosts where the upstream only accepts traffic from one address.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct OutboundConfig {
+    /// The local address of the sockets to the upstreams of the same IP version.
+    #[serde(default)]
+    pub source_address: Option<IpAddr>,
+
+    /// Rotate the new sockets over these local addresses instead, e.g. to stay under
+    /// the per-source connection limits of the upstream network. Only the ones of the
+    /// IP version of the upstream are used.
+    #[serde(default)]
+    pub source_addresses: Vec<IpAddr>,
+
+    /// Bind the sockets to this network interface with `SO_BINDTODEVICE` (Linux only,
+    /// needs `CAP_NET_RAW`).
+    #[serde(default)]
+    pub interface: Option<String>,
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    5
+}
+
+fn default_health_check_timeout_ms() -> u64 {
+    1_000
+}
+
+fn default_health_check_unhealthy_threshold() -> u32 {
+    3
+}
+
+fn default_health_check_healthy_threshold() -> u32 {
+    2
+}
+
+/// Checks every upstream on a fixed cadence so new sessions are only routed to the
+/// healthy ones.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HealthCheckConfig {
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default = "default_health_check_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// The consecutive failed checks which mark a healthy upstream down.
+    #[serde(default = "default_health_check_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+
+    /// The consecutive passed checks which mark an unhealthy upstream up.
+    #[serde(default = "default_health_check_healthy_threshold")]
+    pub healthy_threshold: u32,
+
+    /// Also send a GS4 query to the upstreams which have a query address.
+    #[serde(default)]
+    pub query: bool,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_health_check_interval_secs(),
+            timeout_ms: default_health_check_timeout_ms(),
+            unhealthy_threshold: default_health_check_unhealthy_threshold(),
+            healthy_threshold: default_health_check_healthy_threshold(),
+            query: false,
+        }
+    }
+}
+
+/// A backup server which players are transferred to with a Transfer packet.
+///
+/// The transfer only works until the upstream starts encryption, so players who are
+/// already in game are disconnected as before.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BackupConfig {
+    /// The address which is pinged to check that the backup is healthy before a transfer.
+    pub address: SocketAddr,
+
+    /// The host which the client joins, e.g. a public domain of the backup.
+    pub transfer_host: String,
+
+    pub transfer_port: u16,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct HistoryConfig {
+    /// Record session starts and ends to the history under the data directory.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self { enabled: true }
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct AccessLogConfig {
+    /// Write one record per ended session to the access log under the data directory,
+    /// apart from the diagnostic log.
+    #[serde(default)]
+    pub enabled: bool,
+}
+
+fn default_retention_interval_secs() -> u64 {
+    60 * 60
+}
+
+fn default_log_retention() -> RetentionPolicy {
+    RetentionPolicy {
+        max_age_days: Some(30),
+        max_size_mb: None,
+    }
+}
+
+fn default_access_log_retention() -> RetentionPolicy {
+    RetentionPolicy {
+        max_age_days: Some(30),
+        max_size_mb: None,
+    }
+}
+
+fn default_history_retention() -> RetentionPolicy {
+    RetentionPolicy {
+        max_age_days: Some(90),
+        max_size_mb: None,
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct RetentionConfig {
+    /// How often the retention policies are enforced.
+    #[serde(default = "default_retention_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default = "default_log_retention")]
+    pub logs: RetentionPolicy,
+
+    #[serde(default = "default_history_retention")]
+    pub history: RetentionPolicy,
+
+    #[serde(default = "default_access_log_retention")]
+    pub access_log: RetentionPolicy,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: default_retention_interval_secs(),
+            logs: default_log_retention(),
+            history: default_history_retention(),
+            access_log: default_access_log_retention(),
+        }
+    }
+}
+
+/// Limits for the files in a single store. Unset limits are not enforced.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct RetentionPolicy {
+    #[serde(default)]
+    pub max_age_days: Option<u64>,
+
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct MetricsConfig {
+    /// The address which Prometheus scrapes `/metrics` from, e.g. `0.0.0.0:9100`.
+    /// Disabled if unset.
+    #[serde(default)]
+    pub address: Option<SocketAddr>,
+
+    #[serde(default)]
+    pub push: Option<MetricsPushConfig>,
+
+    #[serde(default)]
+    pub influxdb: Option<MetricsInfluxConfig>,
+
+    #[serde(default)]
+    pub statsd: Option<MetricsStatsdConfig>,
+
+    #[serde(default)]
+    pub per_ip: PerIpMetricsConfig,
+
+    #[serde(default)]
+    pub packets: PacketMetricsConfig,
+}
+
+fn default_per_ip_top_n() -> usize {
+    10
+}
+
+fn default_per_ip_interval_secs() -> u64 {
+    15
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PerIpMetricsConfig {
+    /// How many source IPs with the most sessions are exported. 0 disables the gauge.
+    #[serde(default = "default_per_ip_top_n")]
+    pub top_n: usize,
+
+    #[serde(default = "default_per_ip_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default)]
+    pub alerts: Vec<PerIpAlertRule>,
+}
+
+impl Default for PerIpMetricsConfig {
+    fn default() -> Self {
+        Self {
+            top_n: default_per_ip_top_n(),
+            interval_secs: default_per_ip_interval_secs(),
+            alerts: vec![],
+        }
+    }
+}
+
+fn default_packets_top_n() -> usize {
+    10
+}
+
+fn default_packets_interval_secs() -> u64 {
+    15
+}
+
+/// Count the forwarded game packets by packet ID, to see which packet types take the
+/// bandwidth. Only the packets before encryption starts can be told apart; the encrypted
+/// frames are counted together.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PacketMetricsConfig {
+    /// Off by default because every forwarded frame is decoded once more.
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// How many packet IDs with the most bytes are exported in each direction.
+    #[serde(default = "default_packets_top_n")]
+    pub top_n: usize,
+
+    #[serde(default = "default_packets_interval_secs")]
+    pub interval_secs: u64,
+}
+
+impl Default for PacketMetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            top_n: default_packets_top_n(),
+            interval_secs: default_packets_interval_secs(),
         }
     }
 }
+
+/// Fires when a single source IP holds at least `max_sessions` concurrent sessions.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PerIpAlertRule {
+    pub name: String,
+
+    pub max_sessions: usize,
+
+    /// The URL which a JSON event is posted to when the alert fires or resolves.
+    #[serde(default)]
+    pub webhook: Option<String>,
+}
+
+fn default_metrics_push_interval_secs() -> u64 {
+    15
+}
+
+fn default_metrics_push_timeout_secs() -> u64 {
+    5
+}
+
+fn default_metrics_push_job() -> String {
+    "ccproxy".to_owned()
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MetricsPushConfig {
+    #[serde(default)]
+    pub mode: MetricsPushMode,
+
+    /// The Pushgateway base URL or the remote-write endpoint URL.
+    pub url: String,
+
+    #[serde(default = "default_metrics_push_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default = "default_metrics_push_timeout_secs")]
+    pub timeout_secs: u64,
+
+    #[serde(default = "default_metrics_push_job")]
+    pub job: String,
+
+    #[serde(default)]
+    pub instance: Option<String>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsPushMode {
+    #[default]
+    Pushgateway,
+
+    RemoteWrite,
+}
+
+fn default_metrics_influx_interval_secs() -> u64 {
+    10
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MetricsInfluxConfig {
+    #[serde(flatten)]
+    pub transport: MetricsInfluxTransport,
+
+    #[serde(default = "default_metrics_influx_interval_secs")]
+    pub interval_secs: u64,
+
+    #[serde(default = "default_metrics_push_timeout_secs")]
+    pub timeout_secs: u64,
+
+    /// Tags added to every line, e.g. `host` or `region`.
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+fn default_metrics_statsd_prefix() -> String {
+    "ccproxy".to_owned()
+}
+
+fn default_metrics_statsd_interval_secs() -> u64 {
+    10
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MetricsStatsdConfig {
+    /// The StatsD or DogStatsD agent address, e.g. `127.0.0.1:8125`.
+    pub address: String,
+
+    #[serde(default)]
+    pub flavor: MetricsStatsdFlavor,
+
+    /// Put before every metric name with a dot, e.g. `ccproxy.sessions_active`.
+    #[serde(default = "default_metrics_statsd_prefix")]
+    pub prefix: String,
+
+    #[serde(default = "default_metrics_statsd_interval_secs")]
+    pub interval_secs: u64,
+
+    /// Tags added to every metric, e.g. `env` or `region`. Only DogStatsD has tags.
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Clone, Copy, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetricsStatsdFlavor {
+    /// The Datadog agent, which takes the labels as tags.
+    #[default]
+    Dogstatsd,
+
+    /// Plain StatsD, which takes the labels as parts of the metric name.
+    Statsd,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "transport", rename_all = "snake_case")]
+pub enum MetricsInfluxTransport {
+    /// The InfluxDB write endpoint, e.g. `http://127.0.0.1:8086/api/v2/write?org=o&bucket=b`.
+    Http {
+        url: String,
+
+        #[serde(default)]
+        token: Option<String>,
+    },
+
+    /// The InfluxDB or Telegraf UDP listener address, e.g. `127.0.0.1:8089`.
+    Udp { address: String },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_default() {
+        assert!(CCProxyConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn validate_rejects_zero_intervals() {
+        let mut config = CCProxyConfig::default();
+        config.retention.interval_secs = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(CCProxyError::ConfigValueInvalid { key, .. }) if key == "retention.interval_secs"
+        ));
+
+        let mut config = CCProxyConfig::default();
+        config.upstream.health_check.interval_secs = 0;
+        assert!(matches!(
+            config.validate(),
+            Err(CCProxyError::ConfigValueInvalid { key, .. })
+                if key == "upstream.health_check.interval_secs"
+        ));
+    }
+
+    #[test]
+    fn validate_rejects_invalid_cron() {
+        let schedule = |cron: &str| ScheduleConfig {
+            name: "restart".to_owned(),
+            cron: cron.to_owned(),
+            drain: None,
+            motd_profile: None,
+            maintenance: None,
+            exit: true,
+            transfer: None,
+        };
+
+        let mut config = CCProxyConfig {
+            schedules: vec![schedule("0 0 4 * * *")],
+            ..Default::default()
+        };
+        assert!(config.validate().is_ok());
+
+        config.schedules = vec![schedule("every day")];
+        assert!(matches!(
+            config.validate(),
+            Err(CCProxyError::ConfigValueInvalid { key, .. }) if key == "schedules.restart.cron"
+        ));
+    }
+
+    #[test]
+    fn parse_upstream_address() {
+        let address: UpstreamAddress = "10.0.0.5:19132".parse().unwrap();
+        assert_eq!(address.socket_addr(), Some(([10, 0, 0, 5], 19132).into()));
+        assert!(!address.is_srv());
+
+        let address: UpstreamAddress = "play.example.com:19132".parse().unwrap();
+        assert_eq!(
+            (address.host.as_str(), address.port),
+            ("play.example.com", 19132)
+        );
+        assert!(!address.is_srv());
+
+        let address: UpstreamAddress = "_play.example.com:19132".parse().unwrap();
+        assert!(!address.is_srv());
+        assert_eq!(address.to_string(), "_play.example.com:19132");
+
+        let address: UpstreamAddress = "_minecraft._udp.example.com".parse().unwrap();
+        assert!(address.is_srv());
+        assert_eq!(address.to_string(), "_minecraft._udp.example.com");
+
+        assert!("play.example.com:port".parse::<UpstreamAddress>().is_err());
+        assert!(":19132".parse::<UpstreamAddress>().is_err());
+        assert!("".parse::<UpstreamAddress>().is_err());
+    }
+
+    #[test]
+    fn parse_port_range() {
+        assert_eq!(
+            "19132-19140".parse(),
+            Ok(PortRange {
+                start: 19132,
+                end: 19140
+            })
+        );
+        assert_eq!(
+            "19132".parse(),
+            Ok(PortRange {
+                start: 19132,
+                end: 19132
+            })
+        );
+        assert!("19140-19132".parse::<PortRange>().is_err());
+        assert!("19132-".parse::<PortRange>().is_err());
+        assert!("ports".parse::<PortRange>().is_err());
+    }
+}
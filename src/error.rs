@@ -1,3 +1,4 @@
+use std::net::SocketAddr;
 use thiserror::Error;
 use tokio_graceful_shutdown::errors::{SubsystemError, SubsystemJoinError};
 
@@ -23,6 +24,9 @@ pub enum CCProxyError {
         err: Box<figment::Error>,
     },
 
+    #[error("The config profile ({profile}) is not found at {path}.")]
+    ConfigProfileNotFound { profile: String, path: String },
+
     #[error("The tracing appender rolling init error is occurred: {err}")]
     TracingAppenderRollingInit {
         #[from]
@@ -35,6 +39,18 @@ pub enum CCProxyError {
         err: tracing_subscriber::filter::ParseError,
     },
 
+    #[error("The log filter reload error is occurred: {err}")]
+    LogFilterReload {
+        #[from]
+        err: tracing_subscriber::reload::Error,
+    },
+
+    #[error("The OTLP exporter error is occurred: {err}")]
+    OtlpExporter {
+        #[from]
+        err: opentelemetry_otlp::ExporterBuildError,
+    },
+
     #[error("The RakNet error is occurred: {err}")]
     RakNet {
         err: rust_raknet::error::RaknetError,
@@ -51,6 +67,395 @@ pub enum CCProxyError {
 
     #[error("Cannot receive the Query Protocol packet due to timeout.")]
     QueryTimeout,
+
+    #[error("The JSON error is occurred: {err}")]
+    Json {
+        #[from]
+        err: serde_json::Error,
+    },
+
+    #[error("The YAML error is occurred: {err}")]
+    Yaml {
+        #[from]
+        err: serde_yaml::Error,
+    },
+
+    #[error("The cron expression is invalid: {err}")]
+    Cron {
+        #[from]
+        err: cron::error::Error,
+    },
+
+    #[error("The timezone ({timezone}) is unknown.")]
+    TimezoneInvalid { timezone: String },
+
+    #[error("The game packet is invalid.")]
+    GamePacketInvalid,
+
+    #[error("The Java Edition packet is invalid.")]
+    JavaPacketInvalid,
+
+    #[error("The HTTP error is occurred: {err}")]
+    Http {
+        #[from]
+        err: reqwest::Error,
+    },
+
+    #[error("The snappy compression error is occurred: {err}")]
+    Snappy {
+        #[from]
+        err: snap::Error,
+    },
+
+    #[error("The version is invalid: {err}")]
+    Semver {
+        #[from]
+        err: semver::Error,
+    },
+
+    #[error("The prompt error is occurred: {err}")]
+    Prompt {
+        #[from]
+        err: dialoguer::Error,
+    },
+
+    #[error("The zip archive error is occurred: {err}")]
+    Zip {
+        #[from]
+        err: zip::result::ZipError,
+    },
+
+    #[error("The release asset ({name}) is not found.")]
+    UpdateAssetNotFound { name: String },
+
+    #[error("The checksum of the downloaded release does not match.")]
+    UpdateChecksumMismatch,
+
+    #[error("The signature of the downloaded release is invalid.")]
+    UpdateSignatureInvalid,
+
+    #[error("This build has no release signing key, so it cannot update itself.")]
+    UpdateSigningKeyMissing,
+
+    #[error("The release asset ({name}) is larger than {limit} bytes.")]
+    UpdateAssetTooLarge { name: String, limit: usize },
+
+    #[error("Another ccproxy process ({pid}) is already running.")]
+    AlreadyRunning { pid: u32 },
+
+    #[error("The daemon mode is only supported on Unix.")]
+    DaemonUnsupported,
+
+    #[error("The TPROXY gateway mode is only supported on Linux with IPv4.")]
+    TproxyUnsupported,
+
+    #[error("Multiple workers are only supported on Linux.")]
+    WorkersUnsupported,
+
+    #[error("Binding to a network interface is only supported on Linux.")]
+    BindToDeviceUnsupported,
+
+    #[error("The admin listener address ({address}) is not a loopback address.")]
+    AdminAddressNotLoopback { address: SocketAddr },
+
+    #[error(
+        "The admin listener address ({address}) is not a loopback address, so it needs admin tokens."
+    )]
+    AdminTokensRequired { address: SocketAddr },
+
+    #[error("Multiple listeners are bound to overlapping addresses ({address}).")]
+    ListenerAddressConflict { address: SocketAddr },
+
+    #[error("The config value ({key}) is invalid: {reason}")]
+    ConfigValueInvalid { key: String, reason: String },
+
+    #[error("Unix sockets are only supported on Unix.")]
+    UnixSocketUnsupported,
+
+    #[error("The admin command is failed: {reason}")]
+    AdminCommandFailed { reason: String },
+
+    #[error("The ban duration ({secs}s) is too long.")]
+    BanDurationTooLong { secs: u64 },
+
+    #[error("The control socket is disabled in the config.")]
+    ControlDisabled,
+
+    #[error("The listener ({name}) is not found.")]
+    ListenerNotFound { name: String },
+
+    #[error("No running proxy is reachable on the control socket or the admin listener.")]
+    ProxyUnreachable,
+
+    #[error("The log filters can only be changed on a running proxy.")]
+    LogFilterUnavailable,
+
+    #[error("The packet statistics are disabled. Set `metrics.packets.enabled` to count them.")]
+    PacketStatsDisabled,
+
+    #[error("The admin token has neither a token nor a token file.")]
+    AdminTokenMissing,
+
+    #[error("The admin token is missing or unknown.")]
+    AdminUnauthorized,
+
+    #[error("The admin token is not allowed to run the command ({command}).")]
+    AdminForbidden { command: String },
+
+    #[error("A state-changing admin request needs the Authorization header or a JSON body.")]
+    AdminCrossSiteRequest,
+
+    #[error(
+        "The PROXY protocol {version} of the upstream ({upstream}) is not supported by the RakNet transport."
+    )]
+    ProxyProtocolUnsupported {
+        upstream: String,
+        version: &'static str,
+    },
+
+    #[error("The proxy server is not ready to start: {failures} checks failed.")]
+    NotReady { failures: usize },
+
+    #[error("The doctor found {failures} failures.")]
+    DoctorFailed { failures: usize },
+
+    #[error("Cannot connect to the upstream server ({address}) after {attempts} attempts.")]
+    UpstreamConnectExhausted { address: SocketAddr, attempts: u32 },
+
+    #[error("The DNS error is occurred: {err}")]
+    Dns {
+        #[from]
+        err: hickory_resolver::net::NetError,
+    },
+
+    #[error("The hostname ({host}) has no address records.")]
+    DnsNoRecords { host: String },
+
+    #[error("The port mapping error is occurred: {reason}")]
+    PortMapping { reason: String },
+
+    #[error("The tunnel error is occurred: {reason}")]
+    Tunnel { reason: String },
+
+    #[error("The GeoIP database error is occurred: {err}")]
+    GeoIp {
+        #[from]
+        err: maxminddb::MaxMindDBError,
+    },
+
+    #[error("The metrics push is rejected with the status code {status}.")]
+    MetricsPushRejected { status: u16 },
+
+    #[error("The blocklist ({url}) is not fetched with the status code {status}.")]
+    BlocklistRejected { url: String, status: u16 },
+
+    #[error("The gRPC error is occurred: {err}")]
+    Grpc {
+        #[from]
+        err: tonic::transport::Error,
+    },
+}
+
+/// A coarse class of [`CCProxyError`]s which decides the process exit code.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorCategory {
+    Config,
+
+    Io,
+
+    Network,
+
+    Protocol,
+
+    Data,
+
+    Internal,
+}
+
+impl ErrorCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Config => "config",
+            Self::Io => "io",
+            Self::Network => "network",
+            Self::Protocol => "protocol",
+            Self::Data => "data",
+            Self::Internal => "internal",
+        }
+    }
+
+    /// The exit code, following the BSD `sysexits.h` convention.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config => 78,
+            Self::Io => 74,
+            Self::Network => 69,
+            Self::Protocol => 76,
+            Self::Data => 65,
+            Self::Internal => 70,
+        }
+    }
+}
+
+impl CCProxyError {
+    /// A stable identifier of the error for scripts.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::IO { .. } => "io",
+            Self::GracefulShutdown { err } => first_subsystem_error(err)
+                .map(|e| e.code())
+                .unwrap_or("graceful_shutdown"),
+            Self::Config { .. } => "config",
+            Self::ConfigProfileNotFound { .. } => "config_profile_not_found",
+            Self::TracingAppenderRollingInit { .. } => "tracing_appender_rolling_init",
+            Self::TracingSubscriberParse { .. } => "tracing_subscriber_parse",
+            Self::LogFilterReload { .. } => "log_filter_reload",
+            Self::OtlpExporter { .. } => "otlp_exporter",
+            Self::RakNet { .. } => "raknet",
+            Self::UpstreamMotdInvalid => "upstream_motd_invalid",
+            Self::MotdInvalid => "motd_invalid",
+            Self::QueryInvalid => "query_invalid",
+            Self::QueryTimeout => "query_timeout",
+            Self::Json { .. } => "json",
+            Self::Yaml { .. } => "yaml",
+            Self::Cron { .. } => "cron",
+            Self::TimezoneInvalid { .. } => "timezone_invalid",
+            Self::GamePacketInvalid => "game_packet_invalid",
+            Self::JavaPacketInvalid => "java_packet_invalid",
+            Self::Http { .. } => "http",
+            Self::Snappy { .. } => "snappy",
+            Self::Semver { .. } => "semver",
+            Self::Prompt { .. } => "prompt",
+            Self::Zip { .. } => "zip",
+            Self::UpdateAssetNotFound { .. } => "update_asset_not_found",
+            Self::UpdateChecksumMismatch => "update_checksum_mismatch",
+            Self::UpdateSignatureInvalid => "update_signature_invalid",
+            Self::UpdateSigningKeyMissing => "update_signing_key_missing",
+            Self::UpdateAssetTooLarge { .. } => "update_asset_too_large",
+            Self::AlreadyRunning { .. } => "already_running",
+            Self::DaemonUnsupported => "daemon_unsupported",
+            Self::TproxyUnsupported => "tproxy_unsupported",
+            Self::WorkersUnsupported => "workers_unsupported",
+            Self::BindToDeviceUnsupported => "bind_to_device_unsupported",
+            Self::AdminAddressNotLoopback { .. } => "admin_address_not_loopback",
+            Self::AdminTokensRequired { .. } => "admin_tokens_required",
+            Self::ListenerAddressConflict { .. } => "listener_address_conflict",
+            Self::ConfigValueInvalid { .. } => "config_value_invalid",
+            Self::UnixSocketUnsupported => "unix_socket_unsupported",
+            Self::AdminCommandFailed { .. } => "admin_command_failed",
+            Self::BanDurationTooLong { .. } => "ban_duration_too_long",
+            Self::ControlDisabled => "control_disabled",
+            Self::ListenerNotFound { .. } => "listener_not_found",
+            Self::ProxyUnreachable => "proxy_unreachable",
+            Self::LogFilterUnavailable => "log_filter_unavailable",
+            Self::PacketStatsDisabled => "packet_stats_disabled",
+            Self::AdminTokenMissing => "admin_token_missing",
+            Self::AdminUnauthorized => "admin_unauthorized",
+            Self::AdminForbidden { .. } => "admin_forbidden",
+            Self::AdminCrossSiteRequest => "admin_cross_site_request",
+            Self::ProxyProtocolUnsupported { .. } => "proxy_protocol_unsupported",
+            Self::NotReady { .. } => "not_ready",
+            Self::DoctorFailed { .. } => "doctor_failed",
+            Self::UpstreamConnectExhausted { .. } => "upstream_connect_exhausted",
+            Self::Dns { .. } => "dns",
+            Self::DnsNoRecords { .. } => "dns_no_records",
+            Self::PortMapping { .. } => "port_mapping",
+            Self::Tunnel { .. } => "tunnel",
+            Self::GeoIp { .. } => "geoip",
+            Self::MetricsPushRejected { .. } => "metrics_push_rejected",
+            Self::BlocklistRejected { .. } => "blocklist_rejected",
+            Self::Grpc { .. } => "grpc",
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::GracefulShutdown { err } => first_subsystem_error(err)
+                .map(|e| e.category())
+                .unwrap_or(ErrorCategory::Internal),
+            Self::Config { .. }
+            | Self::ConfigProfileNotFound { .. }
+            | Self::TracingSubscriberParse { .. }
+            | Self::OtlpExporter { .. }
+            | Self::Cron { .. }
+            | Self::TimezoneInvalid { .. }
+            | Self::DaemonUnsupported
+            | Self::TproxyUnsupported
+            | Self::WorkersUnsupported
+            | Self::BindToDeviceUnsupported
+            | Self::AdminAddressNotLoopback { .. }
+            | Self::AdminTokensRequired { .. }
+            | Self::UpdateSigningKeyMissing
+            | Self::ListenerAddressConflict { .. }
+            | Self::ConfigValueInvalid { .. }
+            | Self::UnixSocketUnsupported
+            | Self::ControlDisabled
+            | Self::ListenerNotFound { .. }
+            | Self::AdminTokenMissing
+            | Self::LogFilterUnavailable
+            | Self::PacketStatsDisabled
+            | Self::ProxyProtocolUnsupported { .. } => ErrorCategory::Config,
+            Self::IO { .. }
+            | Self::TracingAppenderRollingInit { .. }
+            | Self::Prompt { .. }
+            | Self::AlreadyRunning { .. } => ErrorCategory::Io,
+            Self::RakNet { .. }
+            | Self::Http { .. }
+            | Self::QueryTimeout
+            | Self::NotReady { .. }
+            | Self::DoctorFailed { .. }
+            | Self::UpstreamConnectExhausted { .. }
+            | Self::Dns { .. }
+            | Self::DnsNoRecords { .. }
+            | Self::PortMapping { .. }
+            | Self::Tunnel { .. }
+            | Self::Grpc { .. }
+            | Self::ProxyUnreachable => ErrorCategory::Network,
+            Self::UpstreamMotdInvalid
+            | Self::MotdInvalid
+            | Self::QueryInvalid
+            | Self::GamePacketInvalid
+            | Self::JavaPacketInvalid
+            | Self::MetricsPushRejected { .. }
+            | Self::BlocklistRejected { .. }
+            | Self::AdminCommandFailed { .. }
+            | Self::BanDurationTooLong { .. }
+            | Self::AdminUnauthorized
+            | Self::AdminForbidden { .. }
+            | Self::AdminCrossSiteRequest => ErrorCategory::Protocol,
+            Self::Json { .. }
+            | Self::Yaml { .. }
+            | Self::Snappy { .. }
+            | Self::Semver { .. }
+            | Self::Zip { .. }
+            | Self::GeoIp { .. }
+            | Self::UpdateAssetNotFound { .. }
+            | Self::UpdateChecksumMismatch
+            | Self::UpdateSignatureInvalid
+            | Self::UpdateAssetTooLarge { .. } => ErrorCategory::Data,
+            Self::LogFilterReload { .. } => ErrorCategory::Internal,
+        }
+    }
+
+    /// A single-line JSON object which describes the error, printed to stderr on exit.
+    pub fn summary(&self) -> String {
+        serde_json::json!({
+            "code": self.code(),
+            "category": self.category().as_str(),
+            "message": self.to_string(),
+        })
+        .to_string()
+    }
+}
+
+/// Get the error which made a subsystem fail, ignoring panics.
+fn first_subsystem_error(
+    err: &tokio_graceful_shutdown::errors::GracefulShutdownError<CCProxyError>,
+) -> Option<&CCProxyError> {
+    err.get_subsystem_errors().iter().find_map(|e| match e {
+        SubsystemError::Failed(_name, err) => Some(err.get_error()),
+        _ => None,
+    })
 }
 
 impl From<rust_raknet::error::RaknetError> for CCProxyError {
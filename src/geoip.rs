@@ -0,0 +1,78 @@
+use crate::config::GeoIpConfig;
+use crate::error::CCProxyResult;
+use maxminddb::{Reader, geoip2};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+
+/// Where a client address is located, as far as the databases know.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct GeoInfo {
+    /// The ISO 3166-1 alpha-2 country code, e.g. `KR`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub as_org: Option<String>,
+}
+
+impl GeoInfo {
+    /// The country for metrics labels, which stays within the ~250 ISO codes.
+    pub fn country_label(&self) -> &str {
+        self.country.as_deref().unwrap_or("unknown")
+    }
+}
+
+/// Looks up client addresses in MaxMind-format (`.mmdb`) databases.
+pub struct GeoIp {
+    country: Option<Reader<Vec<u8>>>,
+
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Load the configured databases into memory. Returns `None` if none is configured.
+    pub fn open(config: &GeoIpConfig) -> CCProxyResult<Option<Self>> {
+        if config.country_database.is_none() && config.asn_database.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            country: config
+                .country_database
+                .as_ref()
+                .map(Reader::open_readfile)
+                .transpose()?,
+            asn: config
+                .asn_database
+                .as_ref()
+                .map(Reader::open_readfile)
+                .transpose()?,
+        }))
+    }
+
+    /// Look up `ip`. Addresses missing from a database, e.g. private ones, are left empty.
+    pub fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        let mut info = GeoInfo::default();
+
+        if let Some(reader) = &self.country
+            && let Ok(country) = reader.lookup::<geoip2::Country>(ip)
+        {
+            info.country = country
+                .country
+                .and_then(|c| c.iso_code)
+                .map(|c| c.to_owned());
+        }
+
+        if let Some(reader) = &self.asn
+            && let Ok(asn) = reader.lookup::<geoip2::Asn>(ip)
+        {
+            info.asn = asn.autonomous_system_number;
+            info.as_org = asn.autonomous_system_organization.map(|o| o.to_owned());
+        }
+
+        info
+    }
+}
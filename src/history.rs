@@ -0,0 +1,133 @@
+use crate::config::DATA_PATH;
+use crate::error::CCProxyResult;
+use crate::geoip::GeoInfo;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::LazyLock;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// The directory which the session history files are written to.
+///
+/// One NDJSON file is written per UTC day so old history can be removed file by file.
+pub static HISTORY_PATH: LazyLock<PathBuf> = LazyLock::new(|| DATA_PATH.join("history"));
+
+/// A single line in the session history.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HistoryRecord {
+    pub event: HistoryEvent,
+
+    pub session_id: String,
+
+    pub client_address: SocketAddr,
+
+    pub upstream_address: SocketAddr,
+
+    /// Unix timestamp in seconds.
+    pub timestamp: u64,
+
+    #[serde(default)]
+    pub name: Option<String>,
+
+    #[serde(default)]
+    pub xuid: Option<String>,
+
+    #[serde(default)]
+    pub bytes_c2s: u64,
+
+    #[serde(default)]
+    pub bytes_s2c: u64,
+
+    #[serde(flatten)]
+    pub geo: GeoInfo,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protocol_version: Option<i32>,
+
+    /// The compression algorithm negotiated by the upstream, e.g. `zlib`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression_threshold: Option<u16>,
+
+    /// The message which the proxy kicked the client with, on the end of a kicked session.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kick_message: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryEvent {
+    Start,
+
+    End,
+}
+
+/// Appends [`HistoryRecord`]s to the daily history file.
+#[derive(Default)]
+pub struct SessionHistory {
+    lock: Mutex<()>,
+}
+
+impl SessionHistory {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub async fn append(&self, record: &HistoryRecord) -> CCProxyResult<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let date = chrono::DateTime::from_timestamp(record.timestamp as i64, 0)
+            .unwrap_or_default()
+            .format("%Y-%m-%d");
+        let path = HISTORY_PATH.join(format!("sessions-{date}.ndjson"));
+
+        // Serialize writers so lines from concurrent sessions never interleave.
+        let _lock = self.lock.lock().await;
+        tokio::fs::create_dir_all(&*HISTORY_PATH).await?;
+        tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?
+            .write_all(&line)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Read every record from the history files in chronological order.
+    ///
+    /// Lines which cannot be parsed (e.g. cut off by a crash) are skipped.
+    pub async fn read_all() -> CCProxyResult<Vec<HistoryRecord>> {
+        if !HISTORY_PATH.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut paths = vec![];
+        let mut dir = tokio::fs::read_dir(&*HISTORY_PATH).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_some_and(|e| e == "ndjson") {
+                paths.push(path);
+            }
+        }
+        // The date in the file name sorts chronologically.
+        paths.sort();
+
+        let mut records = vec![];
+        for path in paths {
+            let content = tokio::fs::read_to_string(path).await?;
+            records.extend(
+                content
+                    .lines()
+                    .filter_map(|line| serde_json::from_str::<HistoryRecord>(line).ok()),
+            );
+        }
+
+        Ok(records)
+    }
+}
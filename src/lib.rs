@@ -1,7 +1,20 @@
 pub mod built_info {
     include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
+pub mod access_log;
+pub mod admin;
+pub mod audit;
+pub mod ban;
 pub mod cli;
 pub mod config;
 pub mod error;
+pub mod geoip;
+pub mod history;
+pub mod maintenance;
+pub mod metrics;
 pub mod network;
+pub mod reload;
+pub mod retention;
+pub mod scheduler;
+pub mod state;
+pub mod update;
@@ -1,31 +1,65 @@
-use ccproxy::cli;
+use ccproxy::cli::{self, CCProxyCli};
 use ccproxy::config::CCProxyConfig;
 use ccproxy::error::CCProxyResult;
+use clap::Parser;
+use std::process::ExitCode;
 
-#[tokio::main]
-async fn main() -> CCProxyResult<()> {
+fn main() -> ExitCode {
+    match start() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            // A machine-readable summary for wrapper scripts and panels.
+            eprintln!("{}", err.summary());
+
+            ExitCode::from(err.category().exit_code())
+        }
+    }
+}
+
+fn start() -> CCProxyResult<()> {
+    let cli = CCProxyCli::parse();
+
+    // Get from .env file, which may move the data directory.
+    dotenvy::dotenv().ok();
+
+    if cli.execute_standalone()? {
+        return Ok(());
+    }
+
+    // Threads do not survive a fork, so daemonize before the async runtime starts.
+    let _pidfile = cli.prepare_process()?;
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(cli))
+}
+
+async fn run(cli: CCProxyCli) -> CCProxyResult<()> {
     // Init config.
-    let config = init()?;
+    let mut config = init(cli.profile())?;
+    if let Some(filter) = cli.stdout_filter() {
+        config.log.stdout.filter = filter.to_owned();
+    }
 
     // Init tracing subscriber.
-    let (subscriber, _guard) = config.log.tracing_subscriber()?;
+    let (subscriber, _guard, log_filter) = config.log.tracing_subscriber(config.timezone()?)?;
     tracing::subscriber::set_global_default(subscriber).expect("Failed to init tracing subscriber");
 
     #[cfg(debug_assertions)]
     rust_raknet::enable_raknet_log(7);
 
-    if let Err(err) = cli::execute(config).await {
+    // Log here while the file writer guard is still alive.
+    let result = cli::execute(cli, config, log_filter).await;
+    if let Err(err) = &result {
         tracing::error!("{}", err);
-    };
+    }
 
-    Ok(())
+    result
 }
 
-/// Set environment variables from .env file and load the config.
-pub fn init() -> CCProxyResult<CCProxyConfig> {
-    // Get from .env file.
-    dotenvy::dotenv().ok();
-
+/// Load the config.
+pub fn init(profile: Option<&str>) -> CCProxyResult<CCProxyConfig> {
     // Load config from environment variables.
-    CCProxyConfig::init()
+    CCProxyConfig::init(profile)
 }
@@ -0,0 +1,73 @@
+use crate::config::ProxyConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{METRICS, SESSIONS_EVICTED_TOTAL};
+use crate::network::session::{Eviction, SessionRegistry};
+use crate::state::ProxyState;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+use tokio_util::sync::CancellationToken;
+
+/// Enter or leave the maintenance mode.
+///
+/// Entering it starts the countdown of `maintenance.kick_after_secs`, after which the
+/// players who are still connected are kicked.
+pub fn set_maintenance(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    config: &ProxyConfig,
+    state: &Arc<ProxyState>,
+    sessions: &Arc<SessionRegistry>,
+    maintenance: bool,
+) {
+    if state.set_maintenance(maintenance) == maintenance {
+        return;
+    }
+
+    if !maintenance {
+        tracing::info!("The maintenance mode is ended. New players are accepted.");
+        return;
+    }
+    tracing::info!("The maintenance mode is started. New players are refused.");
+
+    let Some(kick_after_secs) = config.maintenance.kick_after_secs else {
+        return;
+    };
+    tracing::info!("The connected players are kicked in {kick_after_secs}s.");
+
+    let countdown = Duration::from_secs(kick_after_secs);
+    let message = config.disconnect_messages.maintenance.clone();
+    let left = state.maintenance_countdown();
+    let countdown_sessions = sessions.clone();
+    sub_sys.start(SubsystemBuilder::new("MaintenanceCountdown", move |sub| {
+        run_maintenance_countdown(sub, countdown, message, left, countdown_sessions)
+    }));
+}
+
+/// Kick every player after `countdown` unless the maintenance mode is left meanwhile.
+async fn run_maintenance_countdown(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    countdown: Duration,
+    message: String,
+    left: CancellationToken,
+    sessions: Arc<SessionRegistry>,
+) -> CCProxyResult<()> {
+    tokio::select! {
+        _ = tokio::time::sleep(countdown) => {
+            let sessions = sessions.sessions().await;
+            tracing::info!("{} players are kicked for the maintenance.", sessions.len());
+            METRICS.counter_add(
+                &SESSIONS_EVICTED_TOTAL,
+                &[("reason", "maintenance")],
+                sessions.len() as f64,
+            );
+            for session in sessions {
+                session.evict(Eviction::Kick(message.clone()));
+            }
+        },
+        _ = left.cancelled() => (),
+        // Shutdown handler
+        _ = sub_sys.on_shutdown_requested() => (),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,171 @@
+use crate::config::{MetricsInfluxConfig, MetricsInfluxTransport};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{METRICS, MetricSample, connect_udp};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// Keep UDP datagrams below the common path MTU so Telegraf never sees truncated lines.
+const INFLUX_UDP_MAX_DATAGRAM: usize = 1_400;
+
+/// Write the metrics as InfluxDB line protocol to the configured endpoint on an interval.
+pub async fn run_influx_exporter(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: MetricsInfluxConfig,
+) -> CCProxyResult<()> {
+    let writer = InfluxWriter::new(&config).await?;
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos();
+                let lines = encode_line_protocol(&METRICS.snapshot(), &config.tags, timestamp);
+
+                if let Err(err) = writer.write(lines).await {
+                    tracing::error!("Cannot write the metrics to InfluxDB: {err}");
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+enum InfluxWriter {
+    Http {
+        client: reqwest::Client,
+
+        url: String,
+
+        token: Option<String>,
+    },
+
+    Udp {
+        socket: UdpSocket,
+    },
+}
+
+impl InfluxWriter {
+    async fn new(config: &MetricsInfluxConfig) -> CCProxyResult<Self> {
+        Ok(match &config.transport {
+            MetricsInfluxTransport::Http { url, token } => Self::Http {
+                client: reqwest::Client::builder()
+                    .timeout(Duration::from_secs(config.timeout_secs))
+                    .build()?,
+                url: url.clone(),
+                token: token.clone(),
+            },
+            MetricsInfluxTransport::Udp { address } => {
+                let socket = connect_udp(address).await?;
+
+                Self::Udp { socket }
+            }
+        })
+    }
+
+    async fn write(&self, lines: Vec<String>) -> CCProxyResult<()> {
+        match self {
+            Self::Http { client, url, token } => {
+                let mut request = client
+                    .post(url)
+                    .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+                    .body(lines.join("\n"));
+                if let Some(token) = token {
+                    request = request.header(AUTHORIZATION, format!("Token {token}"));
+                }
+
+                let status = request.send().await?.status();
+                if !status.is_success() {
+                    return Err(CCProxyError::MetricsPushRejected {
+                        status: status.as_u16(),
+                    });
+                }
+            }
+            Self::Udp { socket } => {
+                // Pack as many whole lines as possible into each datagram.
+                let mut datagram = String::new();
+                for line in lines {
+                    if !datagram.is_empty()
+                        && datagram.len() + line.len() + 1 > INFLUX_UDP_MAX_DATAGRAM
+                    {
+                        socket.send(datagram.as_bytes()).await?;
+                        datagram.clear();
+                    }
+
+                    datagram.push_str(&line);
+                    datagram.push('\n');
+                }
+
+                if !datagram.is_empty() {
+                    socket.send(datagram.as_bytes()).await?;
+                }
+            }
+        };
+
+        Ok(())
+    }
+}
+
+/// Encode the samples as InfluxDB line protocol with a nanosecond timestamp.
+///
+/// Each metric family becomes a measurement, labels and `tags` become tags,
+/// and the value is written to the `value` field.
+pub fn encode_line_protocol(
+    samples: &[MetricSample],
+    tags: &HashMap<String, String>,
+    timestamp: u128,
+) -> Vec<String> {
+    let mut extra_tags = tags.iter().collect::<Vec<_>>();
+    extra_tags.sort();
+
+    samples
+        .iter()
+        .map(|sample| {
+            let mut line = escape_influx(sample.desc.name, false);
+            for (k, v) in &sample.labels {
+                write!(
+                    line,
+                    ",{}={}",
+                    escape_influx(k, true),
+                    escape_influx(v, true)
+                )
+                .unwrap();
+            }
+            for (k, v) in &extra_tags {
+                write!(
+                    line,
+                    ",{}={}",
+                    escape_influx(k, true),
+                    escape_influx(v, true)
+                )
+                .unwrap();
+            }
+            write!(line, " value={} {timestamp}", sample.value).unwrap();
+
+            line
+        })
+        .collect()
+}
+
+fn escape_influx(value: &str, is_tag: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == ' ' || (is_tag && c == '=') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
@@ -0,0 +1,497 @@
+use crate::error::CCProxyResult;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::net::UdpSocket;
+
+pub mod influx;
+pub mod packets;
+pub mod per_ip;
+pub mod prometheus;
+pub mod push;
+pub mod statsd;
+
+/// The process-wide metrics registry.
+///
+/// Every subsystem records into this registry and every exporter reads a
+/// [`Metrics::snapshot`] from it, so exporters never need to know who produced a metric.
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+pub const SESSIONS_ACTIVE: MetricDesc = MetricDesc::gauge(
+    "ccproxy_sessions_active",
+    "Number of currently proxied sessions.",
+);
+
+pub const UPSTREAM_SESSIONS_ACTIVE: MetricDesc = MetricDesc::gauge(
+    "ccproxy_upstream_sessions_active",
+    "Number of currently proxied sessions by upstream.",
+);
+
+pub const SESSIONS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_sessions_total",
+    "Number of accepted sessions since the proxy started.",
+);
+
+pub const SESSIONS_BY_COUNTRY_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_sessions_by_country_total",
+    "Number of accepted sessions by the GeoIP country of the client.",
+);
+
+pub const CONNECTION_ATTEMPTS_BY_COUNTRY_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_connection_attempts_by_country_total",
+    "Number of new clients by the GeoIP country, including the refused ones.",
+);
+
+pub const SESSIONS_REFUSED_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_sessions_refused_total",
+    "Number of new sessions refused by the proxy by reason.",
+);
+
+pub const PINGS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_pings_total",
+    "Number of unconnected pings from clients on the raw listener paths.",
+);
+
+pub const PINGS_RATE_LIMITED_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_pings_rate_limited_total",
+    "Number of unconnected pings dropped by the per-IP rate limit.",
+);
+
+pub const DATAGRAMS_DROPPED_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_datagrams_dropped_total",
+    "Number of client datagrams dropped on the raw listener paths by reason.",
+);
+
+pub const UNDER_ATTACK: MetricDesc = MetricDesc::gauge(
+    "ccproxy_under_attack",
+    "Number of listeners in the under-attack mode.",
+);
+
+pub const BLOCKLIST_RANGES: MetricDesc = MetricDesc::gauge(
+    "ccproxy_blocklist_ranges",
+    "Number of ranges denied by each remote blocklist.",
+);
+
+pub const SESSIONS_REPLACED_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_sessions_replaced_total",
+    "Number of stale sessions torn down because the same endpoint reconnected.",
+);
+
+pub const SESSIONS_EVICTED_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_sessions_evicted_total",
+    "Number of sessions ended by the proxy, by reason.",
+);
+
+pub const SESSIONS_PER_IP: MetricDesc = MetricDesc::gauge(
+    "ccproxy_sessions_per_ip",
+    "Number of concurrent sessions of the source IPs with the most sessions.",
+);
+
+pub const ALERTS_FIRED_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_alerts_fired_total",
+    "Number of times an alert rule fired by rule.",
+);
+
+pub const LIMBO_SESSIONS_ACTIVE: MetricDesc = MetricDesc::gauge(
+    "ccproxy_limbo_sessions_active",
+    "Number of clients held in the limbo while the upstream is down.",
+);
+
+pub const NETWORK_SETTINGS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_network_settings_total",
+    "Number of sessions by the compression algorithm and threshold negotiated by the upstream.",
+);
+
+pub const RAKNET_ERRORS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_raknet_errors_total",
+    "Number of transport errors which ended client sessions by kind.",
+);
+
+pub const HANDSHAKE_FAILURES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_handshake_failures_total",
+    "Number of clients which did not complete the login sequence by reason.",
+);
+
+pub const UPSTREAM_CONNECT_FAILURES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_upstream_connect_failures_total",
+    "Number of failed connection attempts to the upstream server.",
+);
+
+pub const UPSTREAM_CONNECT_RETRIES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_upstream_connect_retries_total",
+    "Number of retried connection attempts to the upstream server.",
+);
+
+pub const UPSTREAM_CIRCUIT_OPEN: MetricDesc = MetricDesc::gauge(
+    "ccproxy_upstream_circuit_open",
+    "Whether the circuit breaker of each upstream is open (1) or closed (0).",
+);
+
+pub const UPSTREAM_RTT_MS: MetricDesc = MetricDesc::gauge(
+    "ccproxy_upstream_rtt_ms",
+    "Round-trip time of the last ping to each upstream in milliseconds.",
+);
+
+pub const UPSTREAM_JITTER_MS: MetricDesc = MetricDesc::gauge(
+    "ccproxy_upstream_jitter_ms",
+    "Smoothed variation of the round-trip time to each upstream in milliseconds.",
+);
+
+pub const UPSTREAM_LOSS_RATIO: MetricDesc = MetricDesc::gauge(
+    "ccproxy_upstream_loss_ratio",
+    "Ratio of unanswered pings to each upstream over the probe window.",
+);
+
+pub const UPSTREAM_PROBES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_upstream_probes_total",
+    "Number of latency probes sent to each upstream by result.",
+);
+
+pub const UPSTREAM_UP: MetricDesc = MetricDesc::gauge(
+    "ccproxy_upstream_up",
+    "Whether each upstream passes the health checks (1) or not (0).",
+);
+
+pub const UPSTREAM_HEALTH_CHECKS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_upstream_health_checks_total",
+    "Number of health checks sent to each upstream by result.",
+);
+
+pub const FORWARDED_BYTES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_forwarded_bytes_total",
+    "Number of game packet bytes forwarded by direction.",
+);
+
+pub const FORWARDED_PACKETS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_forwarded_packets_total",
+    "Number of game packets forwarded by direction.",
+);
+
+pub const PACKET_ID_PACKETS: MetricDesc = MetricDesc::gauge(
+    "ccproxy_packet_id_packets",
+    "Number of game packets forwarded since the start of the packet IDs with the most bytes by direction.",
+);
+
+pub const PACKET_ID_BYTES: MetricDesc = MetricDesc::gauge(
+    "ccproxy_packet_id_bytes",
+    "Number of uncompressed game packet bytes forwarded since the start of the packet IDs with the most bytes by direction.",
+);
+
+pub const TPROXY_FLOWS_ACTIVE: MetricDesc = MetricDesc::gauge(
+    "ccproxy_tproxy_flows_active",
+    "Number of flows relayed by the TPROXY gateway.",
+);
+
+pub const TPROXY_BYTES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_tproxy_bytes_total",
+    "Number of datagram bytes relayed by the TPROXY gateway by direction.",
+);
+
+pub const PASSTHROUGH_FLOWS_ACTIVE: MetricDesc = MetricDesc::gauge(
+    "ccproxy_passthrough_flows_active",
+    "Number of flows relayed by the passthrough mode.",
+);
+
+pub const PASSTHROUGH_BYTES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_passthrough_bytes_total",
+    "Number of datagram bytes relayed by the passthrough mode by direction.",
+);
+
+pub const TUNNEL_FLOWS_ACTIVE: MetricDesc = MetricDesc::gauge(
+    "ccproxy_tunnel_flows_active",
+    "Number of flows carried over the edge/origin tunnel by role.",
+);
+
+pub const TUNNEL_BYTES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_tunnel_bytes_total",
+    "Number of datagram bytes carried over the edge/origin tunnel by role and direction.",
+);
+
+pub const JAVA_CONNECTIONS_ACTIVE: MetricDesc = MetricDesc::gauge(
+    "ccproxy_java_connections_active",
+    "Number of Java Edition connections relayed to the upstream.",
+);
+
+pub const JAVA_STATUS_REQUESTS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_java_status_requests_total",
+    "Number of Java Edition Server List Pings by the source of the response.",
+);
+
+pub const JAVA_BYTES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_java_bytes_total",
+    "Number of Java Edition bytes relayed by direction.",
+);
+
+pub const BACKUP_TRANSFERS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_backup_transfers_total",
+    "Number of clients transferred to the backup server because the upstream failed.",
+);
+
+pub const UPSTREAM_RESTARTS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_upstream_restarts_total",
+    "Number of upstream restarts detected by a change of the advertised GUID.",
+);
+
+pub const MOTD_UPDATES_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_motd_updates_total",
+    "Number of MOTD updates from the upstream server by result.",
+);
+
+pub const QUERY_REQUESTS_TOTAL: MetricDesc = MetricDesc::counter(
+    "ccproxy_query_requests_total",
+    "Number of Query Protocol requests handled by result.",
+);
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricKind {
+    Counter,
+
+    Gauge,
+}
+
+impl MetricKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Counter => "counter",
+            Self::Gauge => "gauge",
+        }
+    }
+}
+
+/// Static description of a metric family.
+#[derive(Debug)]
+pub struct MetricDesc {
+    pub name: &'static str,
+
+    pub help: &'static str,
+
+    pub kind: MetricKind,
+}
+
+impl MetricDesc {
+    pub const fn counter(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: MetricKind::Counter,
+        }
+    }
+
+    pub const fn gauge(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            kind: MetricKind::Gauge,
+        }
+    }
+}
+
+pub type MetricLabels = Vec<(&'static str, String)>;
+
+/// The name and the sorted labels of a value.
+type MetricKey = (&'static str, MetricLabels);
+
+/// A single value of a metric family with its labels.
+#[derive(Clone, Debug)]
+pub struct MetricSample {
+    pub desc: &'static MetricDesc,
+
+    pub labels: MetricLabels,
+
+    pub value: f64,
+}
+
+/// A counter which is resolved once, so the per-packet paths add to it without taking
+/// the lock of the registry or allocating the labels.
+#[derive(Clone)]
+pub struct CounterHandle(Arc<AtomicU64>);
+
+impl CounterHandle {
+    pub fn add(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    values: Mutex<BTreeMap<MetricKey, MetricSample>>,
+
+    handles: Mutex<BTreeMap<MetricKey, (&'static MetricDesc, CounterHandle)>>,
+}
+
+impl Metrics {
+    /// Add `value` to the counter. Counters must never decrease.
+    pub fn counter_add(
+        &self,
+        desc: &'static MetricDesc,
+        labels: &[(&'static str, &str)],
+        value: f64,
+    ) {
+        debug_assert_eq!(desc.kind, MetricKind::Counter);
+        debug_assert!(value >= 0.0);
+
+        self.update(desc, labels, |v| *v += value);
+    }
+
+    pub fn gauge_set(
+        &self,
+        desc: &'static MetricDesc,
+        labels: &[(&'static str, &str)],
+        value: f64,
+    ) {
+        debug_assert_eq!(desc.kind, MetricKind::Gauge);
+
+        self.update(desc, labels, |v| *v = value);
+    }
+
+    pub fn gauge_add(
+        &self,
+        desc: &'static MetricDesc,
+        labels: &[(&'static str, &str)],
+        value: f64,
+    ) {
+        debug_assert_eq!(desc.kind, MetricKind::Gauge);
+
+        self.update(desc, labels, |v| *v += value);
+    }
+
+    /// Get the handle of the counter, which is added into the same value for the same
+    /// labels.
+    pub fn counter_handle(
+        &self,
+        desc: &'static MetricDesc,
+        labels: &[(&'static str, &str)],
+    ) -> CounterHandle {
+        debug_assert_eq!(desc.kind, MetricKind::Counter);
+
+        let labels = sorted_labels(labels);
+        self.handles
+            .lock()
+            .unwrap()
+            .entry((desc.name, labels))
+            .or_insert_with(|| (desc, CounterHandle(Default::default())))
+            .1
+            .clone()
+    }
+
+    /// Remove every value of the metric family, e.g. before re-exporting a top-N set
+    /// whose labels change over time.
+    pub fn clear(&self, desc: &'static MetricDesc) {
+        self.values
+            .lock()
+            .unwrap()
+            .retain(|(name, _), _| *name != desc.name);
+    }
+
+    /// Get a consistent copy of every recorded value, ordered by name and labels.
+    pub fn snapshot(&self) -> Vec<MetricSample> {
+        let mut values = self.values.lock().unwrap().clone();
+        for ((name, labels), (desc, handle)) in self.handles.lock().unwrap().iter() {
+            let value = handle.0.load(Ordering::Relaxed) as f64;
+            values
+                .entry((name, labels.clone()))
+                .or_insert_with(|| MetricSample {
+                    desc,
+                    labels: labels.clone(),
+                    value: 0.0,
+                })
+                .value += value;
+        }
+
+        values.into_values().collect()
+    }
+
+    fn update(
+        &self,
+        desc: &'static MetricDesc,
+        labels: &[(&'static str, &str)],
+        f: impl FnOnce(&mut f64),
+    ) {
+        let labels = sorted_labels(labels);
+
+        let mut values = self.values.lock().unwrap();
+        let sample = values
+            .entry((desc.name, labels.clone()))
+            .or_insert_with(|| MetricSample {
+                desc,
+                labels,
+                value: 0.0,
+            });
+        f(&mut sample.value);
+    }
+}
+
+fn sorted_labels(labels: &[(&'static str, &str)]) -> MetricLabels {
+    let mut labels = labels
+        .iter()
+        .map(|(k, v)| (*k, (*v).to_owned()))
+        .collect::<MetricLabels>();
+    labels.sort();
+
+    labels
+}
+
+/// Connect a UDP socket to the collector at `address` (`host:port`), bound in the family of
+/// the address which it resolves to first.
+async fn connect_udp(address: &str) -> CCProxyResult<UdpSocket> {
+    let target = tokio::net::lookup_host(address)
+        .await?
+        .next()
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{address} resolves to no address."),
+            )
+        })?;
+
+    let socket = UdpSocket::bind(if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    socket.connect(target).await?;
+
+    Ok(socket)
+}
+
+/// Render samples in the Prometheus text exposition format (version 0.0.4).
+pub fn encode_prometheus_text(samples: &[MetricSample]) -> String {
+    let mut buf = String::new();
+
+    let mut last_name = None;
+    for sample in samples {
+        if last_name != Some(sample.desc.name) {
+            writeln!(buf, "# HELP {} {}", sample.desc.name, sample.desc.help).unwrap();
+            writeln!(
+                buf,
+                "# TYPE {} {}",
+                sample.desc.name,
+                sample.desc.kind.as_str()
+            )
+            .unwrap();
+            last_name = Some(sample.desc.name);
+        }
+
+        buf.push_str(sample.desc.name);
+        if !sample.labels.is_empty() {
+            let labels = sample
+                .labels
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{}\"", escape_prometheus_label(v)))
+                .collect::<Vec<_>>();
+            write!(buf, "{{{}}}", labels.join(",")).unwrap();
+        }
+        writeln!(buf, " {}", sample.value).unwrap();
+    }
+
+    buf
+}
+
+fn escape_prometheus_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,154 @@
+use crate::config::PacketMetricsConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{METRICS, PACKET_ID_BYTES, PACKET_ID_PACKETS};
+use crate::network::game::{self, HandshakeState};
+use crate::network::rate_limit::Direction;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// The forwarded game packets of the process by packet ID, counted only if
+/// `metrics.packets.enabled` is set because every frame is decoded a second time.
+pub static PACKET_STATS: LazyLock<PacketStats> = LazyLock::new(PacketStats::default);
+
+/// The forwarded packets of a packet ID in a direction since the start.
+#[derive(Clone, Debug, Serialize)]
+pub struct PacketIdStats {
+    pub direction: Direction,
+
+    /// Unset for the frames which cannot be decoded, i.e. the encrypted ones.
+    pub packet_id: Option<u32>,
+
+    pub packets: u64,
+
+    /// The uncompressed bytes of the packets, or the frame bytes if they are encrypted.
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+struct PacketCount {
+    packets: u64,
+
+    bytes: u64,
+}
+
+#[derive(Default)]
+pub struct PacketStats {
+    enabled: AtomicBool,
+
+    counts: Mutex<HashMap<(Direction, Option<u32>), PacketCount>>,
+}
+
+impl PacketStats {
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Count the packets of a `0xfe` game frame. Call it before the frame is observed by
+    /// `handshake`, so the frame which negotiates compression is decoded as it was sent.
+    pub fn record(&self, direction: Direction, handshake: &HandshakeState, frame: &[u8]) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let packets = if handshake.opaque {
+            None
+        } else {
+            game::decode_batch(frame, handshake.network_settings.is_some()).ok()
+        };
+
+        let mut counts = self.counts.lock().unwrap();
+        match packets {
+            Some(packets) => {
+                for packet in packets {
+                    let Ok((id, _)) = game::decode_packet_header(&packet) else {
+                        continue;
+                    };
+
+                    let count = counts.entry((direction, Some(id))).or_default();
+                    count.packets += 1;
+                    count.bytes += packet.len() as u64;
+                }
+            }
+            None => {
+                let count = counts.entry((direction, None)).or_default();
+                count.packets += 1;
+                count.bytes += frame.len() as u64;
+            }
+        }
+    }
+
+    /// The `top_n` packet IDs with the most bytes in each direction, the busiest first.
+    pub fn top(&self, top_n: usize) -> Vec<PacketIdStats> {
+        let mut stats = self
+            .counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&(direction, packet_id), count)| PacketIdStats {
+                direction,
+                packet_id,
+                packets: count.packets,
+                bytes: count.bytes,
+            })
+            .collect::<Vec<_>>();
+        stats.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.packet_id.cmp(&b.packet_id)));
+
+        let mut c2s = 0;
+        let mut s2c = 0;
+        stats.retain(|s| {
+            let taken = match s.direction {
+                Direction::C2s => &mut c2s,
+                Direction::S2c => &mut s2c,
+            };
+            *taken += 1;
+            *taken <= top_n
+        });
+
+        stats
+    }
+}
+
+/// Export the packet IDs with the most bytes in each direction on an interval.
+pub async fn run_packet_stats_exporter(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: PacketMetricsConfig,
+) -> CCProxyResult<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                export_top_n(config.top_n);
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn export_top_n(top_n: usize) {
+    METRICS.clear(&PACKET_ID_PACKETS);
+    METRICS.clear(&PACKET_ID_BYTES);
+    for stats in PACKET_STATS.top(top_n) {
+        let packet_id = stats
+            .packet_id
+            .map_or("encrypted".to_owned(), |id| format!("0x{id:02x}"));
+        let labels = [
+            ("direction", stats.direction.as_str()),
+            ("packet_id", packet_id.as_str()),
+        ];
+        METRICS.gauge_set(&PACKET_ID_PACKETS, &labels, stats.packets as f64);
+        METRICS.gauge_set(&PACKET_ID_BYTES, &labels, stats.bytes as f64);
+    }
+}
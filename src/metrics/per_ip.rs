@@ -0,0 +1,161 @@
+use crate::config::{PerIpAlertRule, PerIpMetricsConfig};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{ALERTS_FIRED_TOTAL, METRICS, SESSIONS_PER_IP};
+use crate::network::session::SessionRegistry;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AlertStatus {
+    Firing,
+
+    Resolved,
+}
+
+/// The body posted to an alert webhook.
+#[derive(Serialize)]
+struct AlertEvent<'a> {
+    rule: &'a str,
+
+    status: AlertStatus,
+
+    ip: IpAddr,
+
+    sessions: usize,
+
+    max_sessions: usize,
+}
+
+/// Export the concurrent sessions of the busiest source IPs and evaluate the alert rules.
+///
+/// A single IP holding dozens of sessions usually means bots, so this is where an
+/// operator gets told about it.
+pub async fn run_per_ip_monitor(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: PerIpMetricsConfig,
+    sessions: Arc<SessionRegistry>,
+) -> CCProxyResult<()> {
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+
+    // The IPs each rule is currently firing for, so an alert is sent once per episode.
+    let mut firing = vec![HashSet::<IpAddr>::new(); config.alerts.len()];
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let mut counts = HashMap::<IpAddr, usize>::new();
+                for session in sessions.sessions().await {
+                    *counts.entry(session.client_address.ip()).or_default() += 1;
+                }
+
+                export_top_n(&counts, config.top_n);
+
+                for (rule, firing) in config.alerts.iter().zip(firing.iter_mut()) {
+                    evaluate(&client, rule, &counts, firing).await;
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn export_top_n(counts: &HashMap<IpAddr, usize>, top_n: usize) {
+    let mut counts = counts.iter().collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+    METRICS.clear(&SESSIONS_PER_IP);
+    for (ip, count) in counts.into_iter().take(top_n) {
+        METRICS.gauge_set(&SESSIONS_PER_IP, &[("ip", &ip.to_string())], *count as f64);
+    }
+}
+
+async fn evaluate(
+    client: &reqwest::Client,
+    rule: &PerIpAlertRule,
+    counts: &HashMap<IpAddr, usize>,
+    firing: &mut HashSet<IpAddr>,
+) {
+    for (ip, sessions) in counts {
+        if *sessions >= rule.max_sessions && firing.insert(*ip) {
+            tracing::warn!(
+                "The alert ({}) is fired: {ip} holds {sessions} sessions.",
+                rule.name
+            );
+            METRICS.counter_add(&ALERTS_FIRED_TOTAL, &[("rule", &rule.name)], 1.0);
+
+            notify(client, rule, AlertStatus::Firing, *ip, *sessions).await;
+        }
+    }
+
+    let resolved = firing
+        .iter()
+        .filter(|ip| {
+            counts
+                .get(ip)
+                .is_none_or(|sessions| *sessions < rule.max_sessions)
+        })
+        .copied()
+        .collect::<Vec<_>>();
+    for ip in resolved {
+        firing.remove(&ip);
+        tracing::info!("The alert ({}) is resolved for {ip}.", rule.name);
+
+        let sessions = counts.get(&ip).copied().unwrap_or_default();
+        notify(client, rule, AlertStatus::Resolved, ip, sessions).await;
+    }
+}
+
+async fn notify(
+    client: &reqwest::Client,
+    rule: &PerIpAlertRule,
+    status: AlertStatus,
+    ip: IpAddr,
+    sessions: usize,
+) {
+    let Some(webhook) = &rule.webhook else {
+        return;
+    };
+
+    let event = AlertEvent {
+        rule: &rule.name,
+        status,
+        ip,
+        sessions,
+        max_sessions: rule.max_sessions,
+    };
+    let result = async {
+        let status = client
+            .post(webhook)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(&event)?)
+            .send()
+            .await?
+            .status();
+        if !status.is_success() {
+            tracing::error!("The alert webhook {webhook} responded with {status}.");
+        }
+
+        CCProxyResult::Ok(())
+    }
+    .await;
+
+    if let Err(err) = result {
+        tracing::error!("Cannot post the alert to {webhook}: {err}");
+    }
+}
@@ -0,0 +1,32 @@
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{METRICS, encode_prometheus_text};
+use axum::Router;
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// Serve the metrics on `GET /metrics` of `address` for Prometheus to scrape.
+pub async fn run_prometheus_exporter(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    address: SocketAddr,
+) -> CCProxyResult<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("The metrics are served on http://{address}/metrics.");
+
+    let router = Router::new().route("/metrics", get(metrics));
+    axum::serve(listener, router)
+        .with_graceful_shutdown(sub_sys.create_cancellation_token().cancelled_owned())
+        .await?;
+
+    Ok(())
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        encode_prometheus_text(&METRICS.snapshot()),
+    )
+}
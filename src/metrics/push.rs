@@ -0,0 +1,159 @@
+use crate::config::{MetricsPushConfig, MetricsPushMode};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{METRICS, MetricSample, encode_prometheus_text};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_TYPE};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// Push the metrics to the configured endpoint on an interval.
+///
+/// Used where the proxy cannot be scraped (NAT, ephemeral containers). The last values
+/// are pushed once more on shutdown so short-lived instances are not lost.
+pub async fn run_metrics_pusher(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: MetricsPushConfig,
+) -> CCProxyResult<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build()?;
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if let Err(err) = push(&client, &config).await {
+                    tracing::error!("Cannot push the metrics to {}: {err}", config.url);
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                if let Err(err) = push(&client, &config).await {
+                    tracing::error!("Cannot push the metrics to {}: {err}", config.url);
+                }
+
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn push(client: &reqwest::Client, config: &MetricsPushConfig) -> CCProxyResult<()> {
+    let samples = METRICS.snapshot();
+
+    let request = match config.mode {
+        MetricsPushMode::Pushgateway => {
+            let mut url = format!(
+                "{}/metrics/{}",
+                config.url.trim_end_matches('/'),
+                grouping_key_segment("job", &config.job)
+            );
+            if let Some(instance) = &config.instance {
+                url.push_str(&format!("/{}", grouping_key_segment("instance", instance)));
+            }
+
+            client
+                .put(url)
+                .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+                .body(encode_prometheus_text(&samples))
+        }
+        MetricsPushMode::RemoteWrite => {
+            let mut labels = vec![("job", config.job.clone())];
+            if let Some(instance) = &config.instance {
+                labels.push(("instance", instance.clone()));
+            }
+
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as i64;
+            let body = snap::raw::Encoder::new()
+                .compress_vec(&encode_remote_write(&samples, &labels, timestamp))?;
+
+            client
+                .post(&config.url)
+                .header(CONTENT_TYPE, "application/x-protobuf")
+                .header(CONTENT_ENCODING, "snappy")
+                .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                .body(body)
+        }
+    };
+
+    let status = request.send().await?.status();
+    if !status.is_success() {
+        return Err(CCProxyError::MetricsPushRejected {
+            status: status.as_u16(),
+        });
+    }
+
+    tracing::debug!("The metrics are pushed to {}.", config.url);
+
+    Ok(())
+}
+
+/// Encode a label of the Pushgateway grouping key as path segments.
+///
+/// The value is always base64-encoded, which Pushgateway supports for values that
+/// cannot be put into a path as they are, e.g. empty ones or ones with a slash.
+fn grouping_key_segment(name: &str, value: &str) -> String {
+    format!("{name}@base64/{}", URL_SAFE.encode(value))
+}
+
+/// Encode the samples to the remote-write `WriteRequest` protobuf message.
+///
+/// The message is small and stable, so it is written by hand instead of pulling
+/// in a protobuf code generator.
+fn encode_remote_write(
+    samples: &[MetricSample],
+    extra_labels: &[(&'static str, String)],
+    timestamp: i64,
+) -> Vec<u8> {
+    let mut write_request = vec![];
+
+    for sample in samples {
+        let mut labels = vec![("__name__", sample.desc.name.to_owned())];
+        labels.extend(sample.labels.iter().cloned());
+        labels.extend(extra_labels.iter().cloned());
+        // Remote-write receivers require the labels to be sorted by name.
+        labels.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut time_series = vec![];
+        for (name, value) in labels {
+            let mut label = vec![];
+            write_protobuf_bytes(&mut label, 1, name.as_bytes());
+            write_protobuf_bytes(&mut label, 2, value.as_bytes());
+            write_protobuf_bytes(&mut time_series, 1, &label);
+        }
+
+        let mut proto_sample = vec![];
+        // Field 1: double value (64-bit wire type).
+        proto_sample.push((1 << 3) | 1);
+        proto_sample.extend_from_slice(&sample.value.to_le_bytes());
+        // Field 2: int64 timestamp (varint wire type).
+        proto_sample.push(2 << 3);
+        write_protobuf_varint(&mut proto_sample, timestamp as u64);
+        write_protobuf_bytes(&mut time_series, 2, &proto_sample);
+
+        write_protobuf_bytes(&mut write_request, 1, &time_series);
+    }
+
+    write_request
+}
+
+fn write_protobuf_bytes(buf: &mut Vec<u8>, field: u8, bytes: &[u8]) {
+    // Length-delimited wire type.
+    buf.push((field << 3) | 2);
+    write_protobuf_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_protobuf_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
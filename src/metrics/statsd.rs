@@ -0,0 +1,178 @@
+use crate::config::{MetricsStatsdConfig, MetricsStatsdFlavor};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{METRICS, MetricKind, MetricLabels, MetricSample, connect_udp};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// Keep UDP datagrams below the common path MTU so the agent never sees truncated lines.
+const STATSD_MAX_DATAGRAM: usize = 1_400;
+
+/// Send the metrics to a StatsD or DogStatsD agent on an interval.
+///
+/// The counters of the registry are totals since the start, so the increase since the
+/// last send is what the agent receives. The last increase is sent once more on shutdown.
+pub async fn run_statsd_exporter(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: MetricsStatsdConfig,
+) -> CCProxyResult<()> {
+    let socket = connect_udp(&config.address).await?;
+
+    if config.flavor == MetricsStatsdFlavor::Statsd && !config.tags.is_empty() {
+        tracing::warn!(
+            "The plain StatsD protocol has no tags, so `metrics.statsd.tags` is ignored."
+        );
+    }
+
+    let mut encoder = StatsdEncoder::new(&config);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let lines = encoder.encode(&METRICS.snapshot());
+                if let Err(err) = send(&socket, lines).await {
+                    tracing::error!("Cannot send the metrics to StatsD ({}): {err}", config.address);
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                let lines = encoder.encode(&METRICS.snapshot());
+                if let Err(err) = send(&socket, lines).await {
+                    tracing::error!("Cannot send the metrics to StatsD ({}): {err}", config.address);
+                }
+
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn send(socket: &UdpSocket, lines: Vec<String>) -> CCProxyResult<()> {
+    // Pack as many whole lines as possible into each datagram.
+    let mut datagram = String::new();
+    for line in lines {
+        if !datagram.is_empty() && datagram.len() + line.len() + 1 > STATSD_MAX_DATAGRAM {
+            socket.send(datagram.as_bytes()).await?;
+            datagram.clear();
+        }
+
+        datagram.push_str(&line);
+        datagram.push('\n');
+    }
+
+    if !datagram.is_empty() {
+        socket.send(datagram.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+/// Encodes the samples as StatsD lines, remembering the counters of the last encode.
+struct StatsdEncoder {
+    flavor: MetricsStatsdFlavor,
+
+    prefix: String,
+
+    /// The `tags` of the config as a DogStatsD tag list, sorted.
+    tags: Vec<String>,
+
+    last_counters: HashMap<(&'static str, MetricLabels), f64>,
+}
+
+impl StatsdEncoder {
+    fn new(config: &MetricsStatsdConfig) -> Self {
+        let mut tags = config
+            .tags
+            .iter()
+            .map(|(k, v)| format!("{}:{}", escape_tag(k), escape_tag(v)))
+            .collect::<Vec<_>>();
+        tags.sort();
+
+        Self {
+            flavor: config.flavor,
+            prefix: config.prefix.clone(),
+            tags,
+            last_counters: HashMap::new(),
+        }
+    }
+
+    fn encode(&mut self, samples: &[MetricSample]) -> Vec<String> {
+        let mut lines = vec![];
+        for sample in samples {
+            let (value, kind) = match sample.desc.kind {
+                MetricKind::Counter => {
+                    let last = self
+                        .last_counters
+                        .insert((sample.desc.name, sample.labels.clone()), sample.value)
+                        .unwrap_or_default();
+                    // Nothing happened since the last send.
+                    let delta = sample.value - last;
+                    if delta <= 0.0 {
+                        continue;
+                    }
+
+                    (delta, "c")
+                }
+                MetricKind::Gauge => (sample.value, "g"),
+            };
+
+            lines.push(self.encode_sample(sample, value, kind));
+        }
+
+        lines
+    }
+
+    fn encode_sample(&self, sample: &MetricSample, value: f64, kind: &str) -> String {
+        // The registry names carry the prefix of Prometheus, e.g. `ccproxy_sessions_active`.
+        let name = sample
+            .desc
+            .name
+            .strip_prefix("ccproxy_")
+            .unwrap_or(sample.desc.name);
+        let mut line = if self.prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}.{name}", self.prefix)
+        };
+
+        match self.flavor {
+            MetricsStatsdFlavor::Dogstatsd => {
+                write!(line, ":{value}|{kind}").unwrap();
+
+                let tags = sample
+                    .labels
+                    .iter()
+                    .map(|(k, v)| format!("{k}:{}", escape_tag(v)))
+                    .chain(self.tags.iter().cloned())
+                    .collect::<Vec<_>>();
+                if !tags.is_empty() {
+                    write!(line, "|#{}", tags.join(",")).unwrap();
+                }
+            }
+            // Without tags, the label values become parts of the name instead, e.g.
+            // `ccproxy.sessions_refused_total.banned`.
+            MetricsStatsdFlavor::Statsd => {
+                for (_, v) in &sample.labels {
+                    write!(line, ".{}", escape_name(v)).unwrap();
+                }
+                write!(line, ":{value}|{kind}").unwrap();
+            }
+        }
+
+        line
+    }
+}
+
+/// Replace the separators of the DogStatsD datagram format in a tag.
+fn escape_tag(value: &str) -> String {
+    value.replace([',', '|', '#', '\n'], "_")
+}
+
+/// Replace the separators of the StatsD datagram format and the name in a name part.
+fn escape_name(value: &str) -> String {
+    value.replace([':', '|', '@', '.', ' ', '\n'], "_")
+}
@@ -0,0 +1,448 @@
+use crate::ban::BanStore;
+use crate::config::{
+    DisconnectMessagesConfig, DuplicateIdentityAction, GeoIpConfig, HandshakeGateConfig,
+    IdentityConfig, ProxyConfig, RateLimitAction,
+};
+use crate::metrics::{CONNECTION_ATTEMPTS_BY_COUNTRY_TOTAL, METRICS};
+use crate::network::blocklist::AccessList;
+use crate::network::game::LoginIdentity;
+use crate::network::rate_limit::{PerIpTokenBucket, TokenBucket};
+use crate::network::session::{Eviction, Session, SessionRegistry};
+use crate::network::under_attack::AttackCounters;
+use crate::state::ProxyState;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+/// The live connections of the listener.
+#[derive(Default)]
+struct Connections {
+    total: usize,
+
+    per_ip: HashMap<IpAddr, usize>,
+
+    /// The slots of each client endpoint, which is at most two while a reconnecting
+    /// client replaces its stale session.
+    per_address: HashMap<SocketAddr, usize>,
+}
+
+/// Decrement the count of `key`, forgetting it at zero so the map does not grow.
+fn release<K: Eq + std::hash::Hash>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// Why a new client is refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    /// The source IP is not allowed by `access` or is on a remote blocklist.
+    Denied,
+
+    /// The source IP or the XUID of the player is in the ban store.
+    Banned,
+
+    /// The player is in `identity.deny`.
+    IdentityDenied,
+
+    /// The player is not in `identity.allow`.
+    NotAllowlisted,
+
+    /// The player is already logged in with another session.
+    DuplicateIdentity,
+
+    /// The GeoIP country of the source IP is not allowed.
+    RegionBlocked,
+
+    /// The autonomous system of the source IP is not allowed.
+    NetworkBlocked,
+
+    Draining,
+
+    Maintenance,
+
+    Full,
+
+    IpConnectionLimit,
+
+    IpRateLimit,
+
+    GlobalRateLimit,
+
+    /// The circuit of the selected upstream is open, which the caller checks itself.
+    CircuitOpen,
+
+    /// The hostname of the selected upstream is not resolved yet, which the caller checks
+    /// itself.
+    UpstreamUnresolved,
+}
+
+impl Refusal {
+    /// The `reason` label of `ccproxy_sessions_refused_total`.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::Denied => "denied",
+            Self::Banned => "banned",
+            Self::IdentityDenied => "identity_denied",
+            Self::NotAllowlisted => "not_allowlisted",
+            Self::DuplicateIdentity => "duplicate_identity",
+            Self::RegionBlocked => "region_blocked",
+            Self::NetworkBlocked => "network_blocked",
+            Self::Draining => "draining",
+            Self::Maintenance => "maintenance",
+            Self::Full => "full",
+            Self::IpConnectionLimit => "ip_connection_limit",
+            Self::IpRateLimit => "ip_rate_limit",
+            Self::GlobalRateLimit => "global_rate_limit",
+            Self::CircuitOpen => "circuit_open",
+            Self::UpstreamUnresolved => "upstream_unresolved",
+        }
+    }
+
+    /// Why the client is refused, for the log.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Denied => "its IP is not allowed",
+            Self::Banned => "it is banned",
+            Self::IdentityDenied => "its player is denied",
+            Self::NotAllowlisted => "its player is not on the allowlist",
+            Self::DuplicateIdentity => "its player is already logged in",
+            Self::RegionBlocked => "its country is not allowed",
+            Self::NetworkBlocked => "its network is not allowed",
+            Self::Draining => "the proxy server is draining",
+            Self::Maintenance => "the proxy server is under maintenance",
+            Self::Full => "the proxy server is full",
+            Self::IpConnectionLimit => "too many connections are open from its IP",
+            Self::IpRateLimit => "too many new sessions are being established from its IP",
+            Self::GlobalRateLimit => "too many new sessions are being established",
+            Self::CircuitOpen => "the circuit of the upstream server is open",
+            Self::UpstreamUnresolved => "the upstream server is not resolved yet",
+        }
+    }
+
+    /// The message on the disconnect screen of the client.
+    pub fn message(self, messages: &DisconnectMessagesConfig) -> &str {
+        match self {
+            Self::Denied | Self::Banned | Self::IdentityDenied => &messages.ban,
+            Self::NotAllowlisted => &messages.not_allowlisted,
+            Self::DuplicateIdentity => &messages.duplicate_identity,
+            Self::RegionBlocked => &messages.region_blocked,
+            Self::NetworkBlocked => &messages.network_blocked,
+            Self::Draining | Self::Maintenance => &messages.maintenance,
+            Self::Full => &messages.full,
+            Self::IpConnectionLimit | Self::IpRateLimit | Self::GlobalRateLimit => {
+                &messages.rate_limited
+            }
+            Self::CircuitOpen | Self::UpstreamUnresolved => &messages.upstream_unavailable,
+        }
+    }
+}
+
+/// The place of an admitted client under `max_sessions` and `max_connections_per_ip`,
+/// which is given back when this is dropped at the end of the connection.
+pub struct AdmissionSlot {
+    client_address: SocketAddr,
+
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl Drop for AdmissionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.connections.lock().unwrap();
+        connections.total = connections.total.saturating_sub(1);
+        release(&mut connections.per_ip, &self.client_address.ip());
+        release(&mut connections.per_address, &self.client_address);
+    }
+}
+
+/// The checks of a listener which a new client passes before an upstream is selected
+/// for it, shared by the RakNet transport and the datagram relays.
+pub struct Admission {
+    state: Arc<ProxyState>,
+
+    access: Arc<AccessList>,
+
+    bans: Arc<BanStore>,
+
+    geoip: GeoIpConfig,
+
+    identity: IdentityConfig,
+
+    duplicate_identity: Option<DuplicateIdentityAction>,
+
+    /// What a session which is replaced by a duplicate gets.
+    logged_in_elsewhere: String,
+
+    sessions: Arc<SessionRegistry>,
+
+    rate_limit_action: RateLimitAction,
+
+    global_new_sessions: Option<TokenBucket>,
+
+    new_sessions_per_ip: Option<PerIpTokenBucket>,
+
+    /// The stricter limits while the listener is under attack.
+    attack_global_new_sessions: Option<TokenBucket>,
+
+    attack_new_sessions_per_ip: Option<PerIpTokenBucket>,
+
+    handshake_gate: Option<HandshakeGateConfig>,
+
+    attack_handshake_gate: Option<HandshakeGateConfig>,
+
+    counters: Arc<AttackCounters>,
+
+    max_sessions: Option<usize>,
+
+    max_connections_per_ip: Option<usize>,
+
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl Admission {
+    /// `sessions` looks up the GeoIP country and ASN of the clients. The attempts and
+    /// the refusals are recorded to `counters`.
+    pub fn new(
+        config: &ProxyConfig,
+        geoip: &GeoIpConfig,
+        state: Arc<ProxyState>,
+        access: Arc<AccessList>,
+        bans: Arc<BanStore>,
+        sessions: Arc<SessionRegistry>,
+        counters: Arc<AttackCounters>,
+    ) -> Self {
+        let under_attack = config.under_attack.as_ref();
+
+        Self {
+            state,
+            access,
+            bans,
+            geoip: geoip.clone(),
+            identity: config.identity.clone(),
+            duplicate_identity: config.duplicate_identity,
+            logged_in_elsewhere: config.disconnect_messages.logged_in_elsewhere.clone(),
+            sessions,
+            rate_limit_action: config.rate_limit.action,
+            global_new_sessions: config.rate_limit.global_new_sessions.map(TokenBucket::new),
+            new_sessions_per_ip: config
+                .rate_limit
+                .new_sessions_per_ip
+                .map(PerIpTokenBucket::new),
+            attack_global_new_sessions: under_attack
+                .and_then(|c| c.global_new_sessions)
+                .map(TokenBucket::new),
+            attack_new_sessions_per_ip: under_attack
+                .and_then(|c| c.new_sessions_per_ip)
+                .map(PerIpTokenBucket::new),
+            handshake_gate: config.handshake_gate.clone(),
+            attack_handshake_gate: under_attack.and_then(|c| c.handshake_gate.clone()),
+            counters,
+            max_sessions: config.max_sessions,
+            max_connections_per_ip: config.max_connections_per_ip,
+            connections: Default::default(),
+        }
+    }
+
+    /// Admit a new client from `client_address`, or return the reason to refuse it. The
+    /// client counts towards `max_sessions` and `max_connections_per_ip` while the slot
+    /// is held.
+    ///
+    /// The per-IP limits are checked first, so a single flooding source does not take
+    /// the tokens of the global limit.
+    pub fn check(&self, client_address: &SocketAddr) -> Result<AdmissionSlot, Refusal> {
+        self.counters.record_attempt();
+
+        let admitted = self.check_limits(client_address);
+        if admitted.is_err() {
+            self.counters.record_drop();
+        }
+
+        admitted
+    }
+
+    fn check_limits(&self, client_address: &SocketAddr) -> Result<AdmissionSlot, Refusal> {
+        let geo = self.sessions.geo(client_address.ip());
+        METRICS.counter_add(
+            &CONNECTION_ATTEMPTS_BY_COUNTRY_TOTAL,
+            &[("country", geo.country_label())],
+            1.0,
+        );
+
+        self.check_ip(client_address.ip())?;
+        if !self.geoip.is_exempt(&geo) {
+            if !self.geoip.is_country_allowed(&geo) {
+                return Err(Refusal::RegionBlocked);
+            }
+            if !self.geoip.is_asn_allowed(&geo) {
+                return Err(Refusal::NetworkBlocked);
+            }
+        }
+        if self.state.is_draining() {
+            return Err(Refusal::Draining);
+        }
+        if self.state.is_maintenance() {
+            return Err(Refusal::Maintenance);
+        }
+
+        let slot = self.take_slot(*client_address)?;
+        if self
+            .new_sessions_per_ip
+            .as_ref()
+            .is_some_and(|b| !b.try_acquire(client_address.ip()))
+        {
+            return Err(Refusal::IpRateLimit);
+        }
+        if self
+            .global_new_sessions
+            .as_ref()
+            .is_some_and(|b| !b.try_acquire())
+        {
+            return Err(Refusal::GlobalRateLimit);
+        }
+
+        if self.state.is_under_attack() {
+            if self
+                .attack_new_sessions_per_ip
+                .as_ref()
+                .is_some_and(|b| !b.try_acquire(client_address.ip()))
+            {
+                return Err(Refusal::IpRateLimit);
+            }
+            if self
+                .attack_global_new_sessions
+                .as_ref()
+                .is_some_and(|b| !b.try_acquire())
+            {
+                return Err(Refusal::GlobalRateLimit);
+            }
+        }
+
+        Ok(slot)
+    }
+
+    /// Forget the full buckets of the per-IP new session limits.
+    pub fn prune(&self) {
+        for limit in [&self.new_sessions_per_ip, &self.attack_new_sessions_per_ip]
+            .into_iter()
+            .flatten()
+        {
+            limit.prune();
+        }
+    }
+
+    /// Check whether the source IP is refused outright by `access`, a remote blocklist,
+    /// or a ban, before anything of the client is read.
+    pub fn check_ip(&self, ip: IpAddr) -> Result<(), Refusal> {
+        if !self.access.is_allowed(ip) {
+            return Err(Refusal::Denied);
+        }
+        if self.bans.find(ip, None).is_some() {
+            return Err(Refusal::Banned);
+        }
+
+        Ok(())
+    }
+
+    /// The handshake gate of the RakNet transport, which the listener may only have
+    /// while it is under attack.
+    pub fn handshake_gate(&self) -> Option<&HandshakeGateConfig> {
+        self.handshake_gate.as_ref().or_else(|| {
+            self.attack_handshake_gate
+                .as_ref()
+                .filter(|_| self.state.is_under_attack())
+        })
+    }
+
+    /// Check the player which the client of `session` logs in as. A live session of the
+    /// same player is ended here if it is to be replaced.
+    pub async fn check_identity(
+        &self,
+        session: &Session,
+        identity: &LoginIdentity,
+    ) -> Result<(), Refusal> {
+        // A claimed XUID is good enough here, since claiming a banned one only gets the
+        // client itself refused.
+        if self
+            .bans
+            .find(session.client_address.ip(), identity.xuid.as_deref())
+            .is_some()
+        {
+            return Err(Refusal::Banned);
+        }
+        if self.identity.is_denied(identity) {
+            return Err(Refusal::IdentityDenied);
+        }
+        if !self.identity.is_allowlisted(identity) {
+            return Err(Refusal::NotAllowlisted);
+        }
+
+        if let Some(action) = self.duplicate_identity
+            && let Some(duplicate) = self.sessions.find_duplicate(session, identity).await
+        {
+            match action {
+                DuplicateIdentityAction::Refuse => return Err(Refusal::DuplicateIdentity),
+                DuplicateIdentityAction::Replace if !identity.authenticated => {
+                    return Err(Refusal::DuplicateIdentity);
+                }
+                DuplicateIdentityAction::Replace => {
+                    tracing::info!(
+                        "The session ({}) of the client ({}) is replaced by the same player from ({}).",
+                        duplicate.id,
+                        duplicate.client_address,
+                        session.client_address
+                    );
+                    duplicate.evict(Eviction::Kick(self.logged_in_elsewhere.clone()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn take_slot(&self, client_address: SocketAddr) -> Result<AdmissionSlot, Refusal> {
+        let ip = client_address.ip();
+        let mut connections = self.connections.lock().unwrap();
+
+        // The same endpoint reconnected, and its stale session is torn down as the new
+        // one starts, so the slot of the stale one is not counted against the new one.
+        let replaced = usize::from(connections.per_address.get(&client_address) == Some(&1));
+
+        if self
+            .max_sessions
+            .is_some_and(|max| connections.total - replaced >= max)
+        {
+            return Err(Refusal::Full);
+        }
+        let count = connections.per_ip.get(&ip).copied().unwrap_or_default();
+        if self
+            .max_connections_per_ip
+            .is_some_and(|max| count - replaced >= max)
+        {
+            return Err(Refusal::IpConnectionLimit);
+        }
+
+        connections.total += 1;
+        *connections.per_ip.entry(ip).or_default() += 1;
+        *connections.per_address.entry(client_address).or_default() += 1;
+
+        Ok(AdmissionSlot {
+            client_address,
+            connections: self.connections.clone(),
+        })
+    }
+
+    /// Whether the client of `refusal` is dropped without a disconnect message. Denied
+    /// clients never get one, as they are usually bots.
+    pub fn is_silent(&self, refusal: Refusal) -> bool {
+        match refusal {
+            Refusal::Denied => true,
+            Refusal::IpConnectionLimit | Refusal::IpRateLimit | Refusal::GlobalRateLimit => {
+                self.rate_limit_action == RateLimitAction::Ignore
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use crate::config::{BalancerStrategy, UpstreamAddress, UpstreamServerConfig};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+
+/// The points on the hash ring per unit of weight, which keep the shares even.
+const VIRTUAL_NODES: u32 = 160;
+
+/// Distributes new sessions over the upstream pool by the configured strategy.
+pub struct Balancer {
+    strategy: BalancerStrategy,
+
+    upstreams: Vec<UpstreamServerConfig>,
+
+    /// The position of the next upstream for round-robin.
+    next: Mutex<usize>,
+
+    /// The current weights of the smooth weighted round-robin, in the order of
+    /// `upstreams`.
+    current_weights: Mutex<Vec<i64>>,
+
+    /// The points of the upstreams on the consistent hash ring, sorted by the hash.
+    ring: Vec<(u64, usize)>,
+
+    /// The upstream which the failover chain routed to last.
+    active: Mutex<Option<usize>>,
+}
+
+impl Balancer {
+    pub fn new(strategy: BalancerStrategy, upstreams: Vec<UpstreamServerConfig>) -> Self {
+        let ring = match strategy {
+            BalancerStrategy::ConsistentHash => build_ring(&upstreams),
+            _ => vec![],
+        };
+
+        Self {
+            strategy,
+            current_weights: Mutex::new(vec![0; upstreams.len()]),
+            upstreams,
+            next: Default::default(),
+            ring,
+            active: Default::default(),
+        }
+    }
+
+    /// Select the upstream for a new session among the ones which `is_eligible`,
+    /// or among all of them when none is. `load` gives the live sessions of an upstream.
+    pub fn select(
+        &self,
+        client_address: &SocketAddr,
+        is_eligible: impl Fn(&UpstreamAddress) -> bool,
+        load: impl Fn(&UpstreamAddress) -> usize,
+    ) -> &UpstreamAddress {
+        let mut candidates = (0..self.upstreams.len())
+            .filter(|&i| is_eligible(&self.upstreams[i].address))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            candidates = (0..self.upstreams.len()).collect();
+        }
+
+        let selected = match self.strategy {
+            BalancerStrategy::RoundRobin => self.round_robin(&candidates),
+            BalancerStrategy::Weighted => self.weighted(&candidates),
+            BalancerStrategy::LeastConnections => self.least_connections(&candidates, load),
+            BalancerStrategy::ConsistentHash => {
+                self.consistent_hash(&candidates, client_address.ip())
+            }
+            BalancerStrategy::Failover => self.failover(&candidates),
+        };
+
+        &self.upstreams[selected].address
+    }
+
+    fn round_robin(&self, candidates: &[usize]) -> usize {
+        let mut next = self.next.lock().unwrap();
+        let selected = candidates[*next % candidates.len()];
+        *next = next.wrapping_add(1);
+
+        selected
+    }
+
+    /// Select the upstream with the fewest live sessions, rotating the start so ties
+    /// are spread instead of piling on the first upstream.
+    fn least_connections(
+        &self,
+        candidates: &[usize],
+        load: impl Fn(&UpstreamAddress) -> usize,
+    ) -> usize {
+        let start = self.round_robin(candidates);
+        let start = candidates.iter().position(|&i| i == start).unwrap_or(0);
+
+        candidates[start..]
+            .iter()
+            .chain(&candidates[..start])
+            .copied()
+            .min_by_key(|&i| load(&self.upstreams[i].address))
+            .unwrap_or(candidates[0])
+    }
+
+    /// Select the first eligible upstream clockwise from the hash of the client IP, so
+    /// a client keeps its upstream across reconnects and only the clients of a removed
+    /// upstream move.
+    fn consistent_hash(&self, candidates: &[usize], client_ip: IpAddr) -> usize {
+        let hash = hash(client_ip.to_string().as_bytes());
+        let start = self.ring.partition_point(|&(point, _)| point < hash);
+
+        self.ring[start..]
+            .iter()
+            .chain(&self.ring[..start])
+            .map(|&(_, i)| i)
+            .find(|i| candidates.contains(i))
+            .unwrap_or(candidates[0])
+    }
+
+    /// Select the first eligible upstream in the order of the pool, so the traffic fails
+    /// over down the chain and fails back once an earlier upstream recovers.
+    fn failover(&self, candidates: &[usize]) -> usize {
+        let selected = candidates[0];
+
+        let mut active = self.active.lock().unwrap();
+        if let Some(previous) = active.replace(selected)
+            && previous != selected
+        {
+            let verb = if selected < previous { "back" } else { "over" };
+            tracing::warn!(
+                "New clients fail {verb} from the upstream server ({}) to ({}).",
+                self.upstreams[previous].address,
+                self.upstreams[selected].address
+            );
+        }
+
+        selected
+    }
+
+    /// Select by the smooth weighted round-robin of nginx, which interleaves the
+    /// upstreams instead of sending a burst of sessions to the heaviest one.
+    fn weighted(&self, candidates: &[usize]) -> usize {
+        let mut current_weights = self.current_weights.lock().unwrap();
+
+        let mut total = 0;
+        let mut selected = candidates[0];
+        for &i in candidates {
+            let weight = self.upstreams[i].weight as i64;
+            current_weights[i] += weight;
+            total += weight;
+
+            if current_weights[i] > current_weights[selected] {
+                selected = i;
+            }
+        }
+        current_weights[selected] -= total;
+
+        selected
+    }
+}
+
+fn build_ring(upstreams: &[UpstreamServerConfig]) -> Vec<(u64, usize)> {
+    let mut ring = upstreams
+        .iter()
+        .enumerate()
+        .flat_map(|(i, upstream)| {
+            (0..VIRTUAL_NODES * upstream.weight.max(1))
+                .map(move |node| (hash(format!("{}#{node}", upstream.address).as_bytes()), i))
+        })
+        .collect::<Vec<_>>();
+    ring.sort_unstable();
+
+    ring
+}
+
+/// A hash which is stable across restarts and builds, unlike the std hasher.
+fn hash(key: &[u8]) -> u64 {
+    let digest = Sha256::digest(key);
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
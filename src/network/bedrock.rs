@@ -53,6 +53,18 @@ impl Default for BedrockMotd {
 }
 
 impl BedrockMotd {
+    /// Replace `{name}` placeholders in the server name and the sub name.
+    pub fn with_placeholders(&self, placeholders: &[(&str, &str)]) -> Self {
+        let mut motd = self.clone();
+        for (name, value) in placeholders {
+            let placeholder = format!("{{{name}}}");
+            motd.server_name = motd.server_name.replace(&placeholder, value);
+            motd.server_sub_name = motd.server_sub_name.replace(&placeholder, value);
+        }
+
+        motd
+    }
+
     /// Encode the [`BedrockMotd`] to the [`String`].
     ///
     /// You can pass optional `guid` to override the GUID during encoding.
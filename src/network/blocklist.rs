@@ -0,0 +1,115 @@
+use crate::config::{AccessConfig, BlocklistConfig};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{BLOCKLIST_RANGES, METRICS};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// The access lists of a listener, whose deny list is `access.deny` merged with the
+/// remote blocklists.
+pub struct AccessList {
+    allow: Vec<IpNet>,
+
+    local_deny: Vec<IpNet>,
+
+    /// Replaced as a whole, so a check sees either the old or the new blocklists.
+    deny: RwLock<Arc<Vec<IpNet>>>,
+}
+
+impl AccessList {
+    pub fn new(config: &AccessConfig) -> Self {
+        Self {
+            allow: config.allow.clone(),
+            local_deny: config.deny.clone(),
+            deny: RwLock::new(Arc::new(IpNet::aggregate(&config.deny))),
+        }
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // A dual-stack socket reports IPv4 clients as IPv4-mapped IPv6 addresses.
+        let ip = ip.to_canonical();
+
+        let deny = self.deny.read().unwrap().clone();
+        !deny.iter().any(|n| n.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|n| n.contains(&ip)))
+    }
+
+    fn set_remote(&self, remote: &[IpNet]) {
+        let mut deny = self.local_deny.clone();
+        deny.extend_from_slice(remote);
+
+        *self.deny.write().unwrap() = Arc::new(IpNet::aggregate(&deny));
+    }
+}
+
+/// Fetch the remote blocklists on an interval and merge them into `access`.
+pub async fn run_blocklist_refresher(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: BlocklistConfig,
+    access: Arc<AccessList>,
+) -> CCProxyResult<()> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build()?;
+
+    // The last ranges of each blocklist, kept while it cannot be fetched.
+    let mut ranges = HashMap::<&str, Vec<IpNet>>::new();
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                for url in &config.urls {
+                    match fetch(&client, url).await {
+                        Ok(fetched) => {
+                            METRICS.gauge_set(&BLOCKLIST_RANGES, &[("url", url)], fetched.len() as f64);
+                            ranges.insert(url, fetched);
+                        }
+                        Err(err) => {
+                            tracing::error!("Cannot fetch the blocklist ({url}): {err}");
+                        }
+                    }
+                }
+
+                let remote = ranges.values().flatten().copied().collect::<Vec<_>>();
+                access.set_remote(&remote);
+                tracing::debug!("The blocklists are refreshed with {} ranges.", remote.len());
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> CCProxyResult<Vec<IpNet>> {
+    let response = client.get(url).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(CCProxyError::BlocklistRejected {
+            url: url.to_owned(),
+            status: status.as_u16(),
+        });
+    }
+
+    Ok(parse(&response.text().await?))
+}
+
+/// Parse the ranges of a blocklist, skipping the lines which are not one.
+fn parse(text: &str) -> Vec<IpNet> {
+    text.lines()
+        .filter_map(|line| {
+            let entry = line.split(['#', ';']).next()?.split_whitespace().next()?;
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .ok()
+        })
+        .collect()
+}
@@ -0,0 +1,216 @@
+use crate::admin::events::EVENTS;
+use crate::config::CircuitBreakerConfig;
+use crate::metrics::{METRICS, UPSTREAM_CIRCUIT_OPEN};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+enum CircuitState {
+    /// Connections are allowed, counting the consecutive failures.
+    Closed { failures: u32 },
+
+    /// Connections are refused until the time passes.
+    Open { until: Instant },
+
+    /// A single trial connection decides whether the circuit closes again.
+    HalfOpen,
+}
+
+/// Stops routing to an upstream whose connections keep failing, so a dead server is not
+/// hammered with connection attempts while it recovers.
+pub struct CircuitBreaker {
+    config: Option<CircuitBreakerConfig>,
+
+    circuits: Mutex<HashMap<SocketAddr, CircuitState>>,
+}
+
+impl CircuitBreaker {
+    /// Create a breaker, which never trips without `config`.
+    pub fn new(config: Option<CircuitBreakerConfig>) -> Self {
+        Self {
+            config,
+            circuits: Default::default(),
+        }
+    }
+
+    /// Whether new sessions may be routed to `upstream`, without taking the trial of a
+    /// half-open circuit.
+    pub fn is_available(&self, upstream: &SocketAddr) -> bool {
+        match self.circuits.lock().unwrap().get(upstream) {
+            Some(CircuitState::Open { until }) => Instant::now() >= *until,
+            Some(CircuitState::HalfOpen) => false,
+            _ => true,
+        }
+    }
+
+    /// The state of the circuit of `upstream`: `closed`, `open`, or `half_open`.
+    pub fn state(&self, upstream: &SocketAddr) -> &'static str {
+        match self.circuits.lock().unwrap().get(upstream) {
+            Some(CircuitState::Open { .. }) => "open",
+            Some(CircuitState::HalfOpen) => "half_open",
+            _ => "closed",
+        }
+    }
+
+    /// Take the permission to connect to `upstream`. An open circuit whose time passed
+    /// lets this connection through as the trial.
+    pub fn try_acquire(&self, upstream: &SocketAddr) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(state) = circuits.get_mut(upstream) else {
+            return true;
+        };
+
+        match state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until } if Instant::now() >= *until => {
+                *state = CircuitState::HalfOpen;
+                tracing::info!("The circuit of the upstream server ({upstream}) is half-open.");
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Give back the permission of a connection to `upstream` which ended before it was
+    /// tried, so a half-open circuit lets the next one through as the trial.
+    pub fn release(&self, upstream: &SocketAddr) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(state @ CircuitState::HalfOpen) = circuits.get_mut(upstream) {
+            *state = CircuitState::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+
+    /// Record the result of a connection to `upstream`.
+    pub fn record(&self, upstream: SocketAddr, success: bool) {
+        let Some(config) = &self.config else {
+            return;
+        };
+
+        let mut circuits = self.circuits.lock().unwrap();
+        let state = circuits
+            .entry(upstream)
+            .or_insert(CircuitState::Closed { failures: 0 });
+
+        let trip = match (&*state, success) {
+            (CircuitState::HalfOpen, true) => {
+                tracing::info!("The circuit of the upstream server ({upstream}) is closed.");
+                EVENTS.push(
+                    "circuit_closed",
+                    format!("The circuit of {upstream} is closed."),
+                );
+                *state = CircuitState::Closed { failures: 0 };
+                false
+            }
+            (_, true) => {
+                *state = CircuitState::Closed { failures: 0 };
+                false
+            }
+            (CircuitState::Closed { failures }, false) => {
+                let failures = failures + 1;
+                *state = CircuitState::Closed { failures };
+                failures >= config.failure_threshold.max(1)
+            }
+            (CircuitState::HalfOpen, false) => true,
+            // A connection which was let through before the circuit opened.
+            (CircuitState::Open { .. }, false) => false,
+        };
+
+        if trip {
+            *state = CircuitState::Open {
+                until: Instant::now() + Duration::from_secs(config.open_secs),
+            };
+            tracing::warn!(
+                "The circuit of the upstream server ({upstream}) is open for {}s after repeated connection failures.",
+                config.open_secs
+            );
+            EVENTS.push(
+                "circuit_open",
+                format!("The circuit of {upstream} is open."),
+            );
+        }
+
+        let open = if matches!(state, CircuitState::Closed { .. }) {
+            0.0
+        } else {
+            1.0
+        };
+        METRICS.gauge_set(
+            &UPSTREAM_CIRCUIT_OPEN,
+            &[("upstream", &upstream.to_string())],
+            open,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(open_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new(Some(CircuitBreakerConfig {
+            failure_threshold: 2,
+            open_secs,
+        }))
+    }
+
+    fn upstream() -> SocketAddr {
+        "127.0.0.1:19132".parse().unwrap()
+    }
+
+    #[test]
+    fn opens_after_consecutive_failures() {
+        let breaker = breaker(60);
+        breaker.record(upstream(), false);
+        breaker.record(upstream(), true);
+        breaker.record(upstream(), false);
+        assert_eq!(breaker.state(&upstream()), "closed");
+
+        breaker.record(upstream(), false);
+        assert_eq!(breaker.state(&upstream()), "open");
+        assert!(!breaker.is_available(&upstream()));
+        assert!(!breaker.try_acquire(&upstream()));
+    }
+
+    #[test]
+    fn half_open_trial_decides() {
+        let breaker = breaker(0);
+        breaker.record(upstream(), false);
+        breaker.record(upstream(), false);
+        assert!(breaker.try_acquire(&upstream()));
+        assert_eq!(breaker.state(&upstream()), "half_open");
+        // Only one trial at a time.
+        assert!(!breaker.try_acquire(&upstream()));
+
+        breaker.record(upstream(), false);
+        assert_eq!(breaker.state(&upstream()), "open");
+
+        assert!(breaker.try_acquire(&upstream()));
+        breaker.record(upstream(), true);
+        assert_eq!(breaker.state(&upstream()), "closed");
+    }
+
+    #[test]
+    fn released_trial_is_given_to_the_next_connection() {
+        let breaker = breaker(0);
+        breaker.record(upstream(), false);
+        breaker.record(upstream(), false);
+        assert!(breaker.try_acquire(&upstream()));
+
+        // The trial connection ended before it reached the upstream.
+        breaker.release(&upstream());
+        assert!(breaker.is_available(&upstream()));
+        assert!(breaker.try_acquire(&upstream()));
+    }
+
+    #[test]
+    fn never_trips_without_config() {
+        let breaker = CircuitBreaker::new(None);
+        for _ in 0..10 {
+            breaker.record(upstream(), false);
+        }
+        assert!(breaker.try_acquire(&upstream()));
+    }
+}
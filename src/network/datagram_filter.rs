@@ -0,0 +1,182 @@
+use crate::ban::{Ban, BanStore};
+use crate::config::{DatagramFilterAction, DatagramFilterConfig};
+use crate::metrics::{DATAGRAMS_DROPPED_TOTAL, METRICS};
+use crate::network::raknet::{frame_set_sequence, is_valid_client_datagram};
+use crate::network::session::{Eviction, SessionRegistry};
+use crate::network::under_attack::AttackCounters;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The sequence numbers of frame sets wrap around at 24 bits.
+const SEQUENCE_MASK: u32 = 0xff_ffff;
+
+/// A rule of the datagram filter which a client datagram breaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DatagramViolation {
+    Oversized,
+
+    Invalid,
+
+    ImpossibleSequence,
+}
+
+impl DatagramViolation {
+    /// The `reason` label of `ccproxy_datagrams_dropped_total`.
+    pub fn reason(self) -> &'static str {
+        match self {
+            Self::Oversized => "oversized",
+            Self::Invalid => "invalid",
+            Self::ImpossibleSequence => "impossible_sequence",
+        }
+    }
+
+    /// What is wrong with the datagram, for the log and the ban reason.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Oversized => "larger than the max datagram size",
+            Self::Invalid => "not a valid RakNet datagram",
+            Self::ImpossibleSequence => "a frame set with an impossible sequence number",
+        }
+    }
+}
+
+/// The highest sequence number of the frame sets of a flow.
+#[derive(Default)]
+pub struct SequenceTracker {
+    highest: u32,
+}
+
+impl SequenceTracker {
+    /// Record `sequence`, or return false if it is too far from the ones before. A flow
+    /// starts at 0, and a frame set which is resent or reordered is a little behind.
+    fn observe(&mut self, sequence: u32, max_jump: u32) -> bool {
+        let ahead = sequence.wrapping_sub(self.highest) & SEQUENCE_MASK;
+        if ahead <= max_jump {
+            self.highest = sequence;
+            return true;
+        }
+
+        SEQUENCE_MASK + 1 - ahead <= max_jump
+    }
+}
+
+/// The datagram filter of a listener, shared by its raw datagram relays.
+pub struct DatagramFilter {
+    config: DatagramFilterConfig,
+
+    bans: Arc<BanStore>,
+
+    /// The sessions of the listener, which a banned IP is kicked from.
+    sessions: Arc<SessionRegistry>,
+
+    ban_message: String,
+
+    counters: Arc<AttackCounters>,
+}
+
+impl DatagramFilter {
+    pub fn new(
+        config: DatagramFilterConfig,
+        bans: Arc<BanStore>,
+        sessions: Arc<SessionRegistry>,
+        ban_message: String,
+        counters: Arc<AttackCounters>,
+    ) -> Self {
+        Self {
+            config,
+            bans,
+            sessions,
+            ban_message,
+            counters,
+        }
+    }
+
+    /// The first rule which `packet` breaks, if any. `sequence` tracks the flow of the
+    /// client once it is open.
+    pub fn check(
+        &self,
+        packet: &[u8],
+        sequence: Option<&mut SequenceTracker>,
+    ) -> Option<DatagramViolation> {
+        if packet.len() > self.config.max_size {
+            return Some(DatagramViolation::Oversized);
+        }
+        if !is_valid_client_datagram(packet, sequence.is_some()) {
+            return Some(DatagramViolation::Invalid);
+        }
+        if let Some(sequence) = sequence
+            && let Some(number) = frame_set_sequence(packet)
+            && !sequence.observe(number, self.config.max_sequence_jump)
+        {
+            return Some(DatagramViolation::ImpossibleSequence);
+        }
+
+        None
+    }
+
+    /// Take the action of the rule which a datagram from `client` breaks, and return
+    /// whether the datagram is relayed anyway.
+    pub async fn apply(&self, client: SocketAddr, violation: DatagramViolation) -> bool {
+        let action = match violation {
+            DatagramViolation::Oversized => self.config.oversized,
+            DatagramViolation::Invalid => self.config.invalid,
+            DatagramViolation::ImpossibleSequence => self.config.impossible_sequence,
+        };
+
+        match action {
+            DatagramFilterAction::Log => {
+                tracing::warn!(
+                    "The datagram from ({client}) is {}.",
+                    violation.description()
+                );
+                return true;
+            }
+            DatagramFilterAction::Drop => {}
+            DatagramFilterAction::BanTemporarily => {
+                // Every later datagram of a banned flood breaks the rule too.
+                if self.bans.find(client.ip(), None).is_none() {
+                    self.ban(client, violation).await;
+                }
+            }
+        }
+
+        METRICS.counter_add(
+            &DATAGRAMS_DROPPED_TOTAL,
+            &[("reason", violation.reason())],
+            1.0,
+        );
+        self.counters.record_drop();
+        false
+    }
+
+    async fn ban(&self, client: SocketAddr, violation: DatagramViolation) {
+        let ip = client.ip().to_canonical();
+        let ban = match Ban::new(
+            ip,
+            None,
+            format!("Its datagram is {}.", violation.description()),
+            Some(Duration::from_secs(self.config.ban_secs)),
+        ) {
+            Ok(ban) => ban,
+            Err(err) => {
+                tracing::error!("Cannot ban the client ({client}): {err}");
+                return;
+            }
+        };
+        if let Err(err) = self.bans.add(ban).await {
+            tracing::error!("Cannot save the bans: {err}");
+        }
+        tracing::warn!(
+            "The client ({client}) is banned for {}s as its datagram is {}.",
+            self.config.ban_secs,
+            violation.description()
+        );
+
+        for session in self.sessions.sessions().await {
+            if session.client_address.ip().to_canonical() == ip {
+                session.evict(Eviction::Kick(self.ban_message.clone()));
+            }
+        }
+    }
+}
@@ -0,0 +1,326 @@
+use crate::config::{DnsConfig, UpstreamAddress};
+use crate::error::{CCProxyError, CCProxyResult};
+use hickory_resolver::TokioResolver;
+use hickory_resolver::proto::rr::{RData, RecordType};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// A caching resolver for upstream hostnames.
+///
+/// The TTL clamping and the negative cache are done by the underlying resolver. On top
+/// of that, the last good answer of every hostname is kept to be served while its
+/// lookups fail, if `serve_stale` is set.
+pub struct DnsResolver {
+    resolver: TokioResolver,
+
+    serve_stale: bool,
+
+    stale: Mutex<HashMap<String, Vec<IpAddr>>>,
+}
+
+impl DnsResolver {
+    /// Create a resolver with the system name servers.
+    pub fn new(config: &DnsConfig) -> CCProxyResult<Self> {
+        let mut builder = TokioResolver::builder_tokio()?;
+
+        let options = builder.options_mut();
+        options.cache_size = config.cache_size;
+        options.positive_min_ttl = Some(Duration::from_secs(config.min_ttl_secs));
+        options.positive_max_ttl = Some(Duration::from_secs(config.max_ttl_secs));
+        options.negative_min_ttl = Some(Duration::from_secs(config.negative_min_ttl_secs));
+        options.negative_max_ttl = Some(Duration::from_secs(config.negative_max_ttl_secs));
+        options.timeout = Duration::from_secs(config.timeout_secs);
+
+        Ok(Self {
+            resolver: builder.build()?,
+            serve_stale: config.serve_stale,
+            stale: Default::default(),
+        })
+    }
+
+    /// Resolve `host` to the socket addresses at `port`.
+    pub async fn resolve(&self, host: &str, port: u16) -> CCProxyResult<Vec<SocketAddr>> {
+        let result = match self.resolver.lookup_ip(host).await {
+            Ok(lookup) => match lookup.iter().collect::<Vec<_>>() {
+                ips if ips.is_empty() => Err(CCProxyError::DnsNoRecords {
+                    host: host.to_owned(),
+                }),
+                ips => Ok(ips),
+            },
+            Err(err) => Err(err.into()),
+        };
+
+        let ips = match result {
+            Ok(ips) => {
+                if self.serve_stale {
+                    self.stale
+                        .lock()
+                        .unwrap()
+                        .insert(host.to_owned(), ips.clone());
+                }
+
+                ips
+            }
+            Err(err) => match self.stale.lock().unwrap().get(host) {
+                Some(ips) => {
+                    tracing::warn!("Cannot resolve {host}, so the last answer is used: {err}");
+                    ips.clone()
+                }
+                None => return Err(err),
+            },
+        };
+
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Resolve the SRV records of `name`, e.g. `_minecraft._udp.example.com`, to their
+    /// targets sorted by the priority. Targets which cannot be resolved are skipped.
+    pub async fn resolve_srv(&self, name: &str) -> CCProxyResult<Vec<SrvTarget>> {
+        let lookup = self.resolver.lookup(name, RecordType::SRV).await?;
+
+        let mut targets = vec![];
+        for record in lookup.answers() {
+            let RData::SRV(srv) = &record.data else {
+                continue;
+            };
+            // The target `.` means that the service is not available.
+            if srv.target.is_root() {
+                continue;
+            }
+
+            let host = srv.target.to_ascii();
+            match self.resolve(&host, srv.port).await {
+                Ok(addresses) => targets.push(SrvTarget {
+                    priority: srv.priority,
+                    weight: srv.weight,
+                    address: addresses[0],
+                }),
+                Err(err) => {
+                    tracing::warn!("Cannot resolve the SRV target ({host}) of {name}: {err}")
+                }
+            }
+        }
+
+        if targets.is_empty() {
+            return Err(CCProxyError::DnsNoRecords {
+                host: name.to_owned(),
+            });
+        }
+        targets.sort_by_key(|t| (t.priority, Reverse(t.weight)));
+
+        Ok(targets)
+    }
+
+    /// Resolve an upstream to its targets. An IP address or a hostname has one target.
+    pub async fn resolve_targets(
+        &self,
+        address: &UpstreamAddress,
+    ) -> CCProxyResult<Vec<SrvTarget>> {
+        let address = match address.socket_addr() {
+            Some(address) => address,
+            None if address.is_srv() => return self.resolve_srv(&address.host).await,
+            None => self.resolve(&address.host, address.port).await?[0],
+        };
+
+        Ok(vec![SrvTarget {
+            priority: 0,
+            weight: 0,
+            address,
+        }])
+    }
+
+    /// Resolve an upstream to its preferred socket address.
+    pub async fn resolve_upstream(&self, address: &UpstreamAddress) -> CCProxyResult<SocketAddr> {
+        Ok(self.resolve_targets(address).await?[0].address)
+    }
+}
+
+/// A server of an upstream which is discovered by an SRV record.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SrvTarget {
+    pub priority: u16,
+
+    pub weight: u16,
+
+    pub address: SocketAddr,
+}
+
+/// The current socket addresses of the upstreams, which follow the DNS of the ones
+/// configured by hostname or SRV name.
+pub struct UpstreamAddresses {
+    resolver: DnsResolver,
+
+    targets: RwLock<HashMap<UpstreamAddress, Vec<SrvTarget>>>,
+}
+
+impl UpstreamAddresses {
+    /// Resolve every upstream. One which cannot be resolved yet starts without addresses
+    /// and is filled in by [`run_dns_refresher`], so a DNS outage does not stop the proxy.
+    pub async fn resolve(
+        resolver: DnsResolver,
+        upstreams: impl IntoIterator<Item = UpstreamAddress>,
+    ) -> CCProxyResult<Self> {
+        let mut targets = HashMap::new();
+        for upstream in upstreams {
+            let resolved = match resolver.resolve_targets(&upstream).await {
+                Ok(resolved) => resolved,
+                Err(err) => {
+                    tracing::warn!(
+                        "Cannot resolve the upstream ({upstream}), which is tried again: {err}"
+                    );
+                    vec![]
+                }
+            };
+            targets.insert(upstream, resolved);
+        }
+
+        Ok(Self {
+            resolver,
+            targets: RwLock::new(targets),
+        })
+    }
+
+    /// Get the preferred socket address of a configured upstream, if it is resolved.
+    pub fn get(&self, upstream: &UpstreamAddress) -> Option<SocketAddr> {
+        self.targets(upstream).first().copied()
+    }
+
+    /// Get every socket address of a configured upstream, which is empty until it is
+    /// resolved.
+    pub fn targets(&self, upstream: &UpstreamAddress) -> Vec<SocketAddr> {
+        match upstream.socket_addr() {
+            Some(address) => vec![address],
+            None => self
+                .targets
+                .read()
+                .unwrap()
+                .get(upstream)
+                .map_or(vec![], |targets| {
+                    targets.iter().map(|t| t.address).collect()
+                }),
+        }
+    }
+
+    /// Pick the socket address of a configured upstream for a new session, as RFC 2782
+    /// orders SRV targets: the lowest priority with an eligible target, then randomly
+    /// by the weight within it. Returns `None` until the upstream is resolved.
+    pub fn pick(
+        &self,
+        upstream: &UpstreamAddress,
+        is_eligible: impl Fn(&SocketAddr) -> bool,
+    ) -> Option<SocketAddr> {
+        if !upstream.is_srv() {
+            return self.get(upstream);
+        }
+
+        let targets = self.targets.read().unwrap();
+        let targets = targets.get(upstream)?;
+
+        let Some(priority) = targets
+            .iter()
+            .find(|t| is_eligible(&t.address))
+            .map(|t| t.priority)
+        else {
+            return targets.first().map(|t| t.address);
+        };
+        let mut candidates = targets
+            .iter()
+            .filter(|t| t.priority == priority && is_eligible(&t.address))
+            .collect::<Vec<_>>();
+
+        let total = candidates.iter().map(|t| t.weight as u32).sum::<u32>();
+        if total == 0 {
+            return Some(candidates[rand::random_range(0..candidates.len())].address);
+        }
+
+        // Targets of weight 0 go first, so they have a small chance of being selected.
+        candidates.sort_by_key(|t| t.weight);
+        let mut point = rand::random_range(0..=total);
+        for target in &candidates {
+            if point <= target.weight as u32 {
+                return Some(target.address);
+            }
+            point -= target.weight as u32;
+        }
+
+        Some(candidates[0].address)
+    }
+
+    /// Resolve the hostnames again, keeping the previous targets of the ones which fail.
+    pub async fn refresh(&self) {
+        let upstreams = self
+            .targets
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|u| u.socket_addr().is_none())
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for upstream in upstreams {
+            let targets = match self.resolver.resolve_targets(&upstream).await {
+                Ok(targets) => targets,
+                Err(err) => {
+                    tracing::warn!("Cannot resolve the upstream ({upstream}) again: {err}");
+                    continue;
+                }
+            };
+
+            let previous = self
+                .targets
+                .write()
+                .unwrap()
+                .insert(upstream.clone(), targets.clone());
+            if previous.as_ref().is_some_and(|p| p.is_empty()) {
+                tracing::info!("The upstream ({upstream}) is resolved.");
+            } else if let Some(previous) = previous
+                && previous != targets
+            {
+                let addresses = |targets: &[SrvTarget]| {
+                    targets
+                        .iter()
+                        .map(|t| t.address.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                tracing::info!(
+                    "The upstream ({upstream}) is moved from [{}] to [{}].",
+                    addresses(&previous),
+                    addresses(&targets)
+                );
+            }
+        }
+    }
+}
+
+/// Resolve the upstream hostnames on an interval.
+pub async fn run_dns_refresher(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    interval: Duration,
+    addresses: Arc<UpstreamAddresses>,
+) -> CCProxyResult<()> {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick completes immediately, right after the startup resolution.
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                addresses.refresh().await;
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
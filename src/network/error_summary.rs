@@ -0,0 +1,107 @@
+use crate::config::ErrorSummaryConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{METRICS, RAKNET_ERRORS_TOTAL};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// The occurrences of one error kind in the current period.
+#[derive(Default)]
+struct ErrorKindCount {
+    total: u64,
+
+    sources: HashMap<IpAddr, u64>,
+}
+
+/// Counts repeated errors so that only the first of each kind per period is logged.
+pub struct ErrorSummary {
+    config: ErrorSummaryConfig,
+
+    counts: Mutex<HashMap<String, ErrorKindCount>>,
+}
+
+impl ErrorSummary {
+    pub fn new(config: ErrorSummaryConfig) -> Self {
+        Self {
+            config,
+            counts: Default::default(),
+        }
+    }
+
+    /// Record an error of `kind` from `source`.
+    ///
+    /// Returns whether the error should be logged on its own, which is only the case
+    /// for the first error of its kind in the period.
+    pub fn record(&self, kind: &str, source: IpAddr) -> bool {
+        METRICS.counter_add(&RAKNET_ERRORS_TOTAL, &[("kind", kind)], 1.0);
+
+        if self.config.interval_secs == 0 {
+            return true;
+        }
+
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(kind.to_owned()).or_default();
+        count.total += 1;
+        *count.sources.entry(source).or_default() += 1;
+
+        count.total == 1
+    }
+
+    /// Log what was suppressed in the period and start a new one.
+    fn flush(&self) {
+        let counts = std::mem::take(&mut *self.counts.lock().unwrap());
+
+        for (kind, count) in counts {
+            // The first error was already logged on its own.
+            if count.total <= 1 {
+                continue;
+            }
+
+            let mut sources = count.sources.into_iter().collect::<Vec<_>>();
+            sources.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            let top_sources = sources
+                .iter()
+                .take(self.config.top_sources)
+                .map(|(ip, n)| format!("{ip} x{n}"))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            tracing::warn!(
+                "The {kind} error is occurred {} times from {} sources in the last {}s. Top sources: {top_sources}",
+                count.total,
+                sources.len(),
+                self.config.interval_secs
+            );
+        }
+    }
+}
+
+/// Log the error summary on every period, and once more on shutdown.
+pub async fn run_error_summarizer(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    summary: Arc<ErrorSummary>,
+) -> CCProxyResult<()> {
+    if summary.config.interval_secs == 0 {
+        return Ok(());
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(summary.config.interval_secs));
+    // The first tick completes immediately and there is nothing to summarize yet.
+    interval.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                summary.flush();
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                summary.flush();
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,769 @@
+use crate::error::{CCProxyError, CCProxyResult};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use ring::signature::{ECDSA_P384_SHA384_FIXED, UnparsedPublicKey};
+use std::io::Read;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The RakNet frame ID which carries Bedrock game packets.
+///
+/// Only the unencrypted part of the login sequence can be decoded. Once the upstream
+/// starts encryption, game packets are forwarded as opaque bytes.
+pub const GAME_PACKET_ID: u8 = 0xfe;
+
+pub const LOGIN_PACKET_ID: u32 = 0x01;
+
+pub const PLAY_STATUS_PACKET_ID: u32 = 0x02;
+
+pub const DISCONNECT_PACKET_ID: u32 = 0x05;
+
+pub const REQUEST_NETWORK_SETTINGS_PACKET_ID: u32 = 0xc1;
+
+pub const SERVER_TO_CLIENT_HANDSHAKE_PACKET_ID: u32 = 0x03;
+
+pub const NETWORK_SETTINGS_PACKET_ID: u32 = 0x8f;
+
+pub const TRANSFER_PACKET_ID: u32 = 0x55;
+
+/// The header byte of an uncompressed batch after compression is negotiated.
+const COMPRESSION_NONE_HEADER: u8 = 0xff;
+
+/// The key of Mojang which signs the certificate chain of a player signed in to Xbox
+/// Live, as base64 of a DER SubjectPublicKeyInfo.
+const MOJANG_ROOT_PUBLIC_KEY: &str = "MHYwEAYHKoZIzj0CAQYFK4EEACIDYgAECRXueJeTDqNRRgJi/vlRufByu/2G0i2Ebt6YMar5QX/R0DIIyrJMcUpruK4QveTfJSTp3Shlq4Gk34cD/4GUWwkv0DVuzeuB+tXija7HBxii03NHDbPAD0AKnLr2wdAp";
+
+/// How far the clocks of the client and the proxy may be apart for the chain to be
+/// valid.
+const CHAIN_CLOCK_SKEW_SECS: i64 = 60;
+
+/// The largest decompressed batch which is decoded, as PocketMine-MP allows, so a small
+/// compressed frame cannot expand into gigabytes.
+const MAX_BATCH_LEN: usize = 8 * 1024 * 1024;
+
+/// The PlayStatus which tells the client that the login is accepted.
+pub const PLAY_STATUS_LOGIN_SUCCESS: i32 = 0;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CompressionAlgorithm {
+    Zlib,
+
+    Snappy,
+
+    None,
+}
+
+impl CompressionAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Zlib => "zlib",
+            Self::Snappy => "snappy",
+            Self::None => "none",
+        }
+    }
+
+    pub fn encode(&self) -> u16 {
+        match self {
+            Self::Zlib => 0x0000,
+            Self::Snappy => 0x0001,
+            Self::None => 0xffff,
+        }
+    }
+
+    pub fn decode(id: u16) -> CCProxyResult<Self> {
+        Ok(match id {
+            0x0000 => Self::Zlib,
+            0x0001 => Self::Snappy,
+            0xffff => Self::None,
+            _ => Err(CCProxyError::GamePacketInvalid)?,
+        })
+    }
+
+    fn from_header(header: u8) -> CCProxyResult<Self> {
+        Ok(match header {
+            0x00 => Self::Zlib,
+            0x01 => Self::Snappy,
+            COMPRESSION_NONE_HEADER => Self::None,
+            _ => Err(CCProxyError::GamePacketInvalid)?,
+        })
+    }
+}
+
+/// The content of the NetworkSettings packet which enables compression.
+#[derive(Clone, Debug)]
+pub struct NetworkSettings {
+    pub compression_threshold: u16,
+
+    pub compression_algorithm: CompressionAlgorithm,
+}
+
+impl NetworkSettings {
+    pub fn decode(body: &[u8]) -> CCProxyResult<Self> {
+        if body.len() < 4 {
+            return Err(CCProxyError::GamePacketInvalid);
+        }
+
+        Ok(Self {
+            compression_threshold: u16::from_le_bytes([body[0], body[1]]),
+            compression_algorithm: CompressionAlgorithm::decode(u16::from_le_bytes([
+                body[2], body[3],
+            ]))?,
+        })
+    }
+
+    /// Encode the NetworkSettings packet, without client throttling.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = vec![];
+        write_varuint32(&mut packet, NETWORK_SETTINGS_PACKET_ID);
+        packet.extend_from_slice(&self.compression_threshold.to_le_bytes());
+        packet.extend_from_slice(&self.compression_algorithm.encode().to_le_bytes());
+        // Client throttling: disabled, threshold, and scalar.
+        packet.push(0);
+        packet.push(0);
+        packet.extend_from_slice(&0f32.to_le_bytes());
+
+        packet
+    }
+}
+
+/// The player which a client presents in its Login packet.
+///
+/// Anyone can put any XUID or gamertag in a Login packet, so only an `authenticated`
+/// identity may be trusted to be the player it claims.
+#[derive(Clone, Debug, Default)]
+pub struct LoginIdentity {
+    /// Whether Mojang signed the certificate chain, its tokens are valid now, and the
+    /// client data is signed by the key the chain ends with.
+    pub authenticated: bool,
+
+    pub xuid: Option<String>,
+
+    pub display_name: Option<String>,
+
+    /// The random ID which the game install of the client keeps across sessions.
+    pub client_random_id: Option<i64>,
+}
+
+impl LoginIdentity {
+    /// Decode the body of a Login packet: the protocol version, then the connection
+    /// request with the certificate chain as JSON and the client data as a JWT.
+    pub fn decode(body: &[u8]) -> CCProxyResult<Self> {
+        Self::decode_with_root(body, MOJANG_ROOT_PUBLIC_KEY)
+    }
+
+    fn decode_with_root(body: &[u8], root: &str) -> CCProxyResult<Self> {
+        let mut cursor = body.get(4..).ok_or(CCProxyError::GamePacketInvalid)?;
+        let len = read_varuint32(&mut cursor)? as usize;
+        let request = cursor.get(..len).ok_or(CCProxyError::GamePacketInvalid)?;
+        let chain_len = request
+            .get(..4)
+            .map(|l| u32::from_le_bytes(l.try_into().unwrap()) as usize)
+            .ok_or(CCProxyError::GamePacketInvalid)?;
+        let chain = request
+            .get(4..4 + chain_len)
+            .ok_or(CCProxyError::GamePacketInvalid)?;
+        let client_data = request
+            .get(4 + chain_len..)
+            .and_then(|rest| {
+                let len = u32::from_le_bytes(rest.get(..4)?.try_into().unwrap()) as usize;
+                rest.get(4..4 + len)
+            })
+            .ok_or(CCProxyError::GamePacketInvalid)?;
+
+        let chain = serde_json::from_slice::<serde_json::Value>(chain)
+            .map_err(|_| CCProxyError::GamePacketInvalid)?;
+        // Newer clients wrap the chain in a certificate next to an authentication token.
+        let (chain, token) = match chain["Certificate"].as_str() {
+            Some(certificate) => (
+                serde_json::from_str::<serde_json::Value>(certificate)
+                    .map_err(|_| CCProxyError::GamePacketInvalid)?,
+                chain["Token"].as_str().map(ToOwned::to_owned),
+            ),
+            None => (chain, None),
+        };
+
+        let mut identity = Self::default();
+        // Each token is signed by the key which the one before it certifies, and the
+        // first one names its own signer.
+        let links = chain["chain"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let mut key = None::<String>;
+        let mut verified = true;
+        let mut mojang_link = None;
+        let mut extra_link = None;
+        for (index, token) in links.iter().enumerate() {
+            let token = token.as_str().unwrap_or_default();
+            let header = decode_jwt_part(token, 0)?;
+            let claims = decode_jwt_claims(token)?;
+
+            let x5u = header["x5u"].as_str().unwrap_or_default();
+            let signer = key.as_deref().unwrap_or(x5u);
+            verified &= signer == x5u && verify_jwt(token, signer) && is_jwt_valid_now(&claims);
+            if verified && signer == root {
+                mojang_link.get_or_insert(index);
+            }
+            key = claims["identityPublicKey"].as_str().map(ToOwned::to_owned);
+
+            let extra_data = &claims["extraData"];
+            if extra_data.is_object() {
+                // Only one link may name the player, so none can be slipped in next to it.
+                if extra_link.replace(index).is_some() {
+                    return Err(CCProxyError::GamePacketInvalid);
+                }
+                identity.xuid = extra_data["XUID"].as_str().map(ToOwned::to_owned);
+                identity.display_name = extra_data["displayName"].as_str().map(ToOwned::to_owned);
+            }
+        }
+        // The player is named by the last link, and the key which ends the chain is theirs.
+        if extra_link.is_some_and(|index| index + 1 != links.len()) {
+            return Err(CCProxyError::GamePacketInvalid);
+        }
+        // Only the link signed by the key which Mojang certifies can name the player.
+        let mut signed_by_mojang =
+            extra_link.is_some() && extra_link == mojang_link.map(|index| index + 1);
+        if identity.xuid.is_none()
+            && let Some(token) = token.filter(|t| !t.is_empty())
+        {
+            // The authentication token is not verified, so it never authenticates.
+            let claims = decode_jwt_claims(&token)?;
+            identity.xuid = claims["xid"].as_str().map(ToOwned::to_owned);
+            identity.display_name = claims["xname"].as_str().map(ToOwned::to_owned);
+            signed_by_mojang = false;
+        }
+        // Guests and offline clients have no XUID.
+        identity.xuid = identity.xuid.filter(|x| !x.is_empty());
+
+        // A chain captured from another session is useless without its private key.
+        let client_data = String::from_utf8_lossy(client_data);
+        identity.authenticated = verified
+            && signed_by_mojang
+            && identity.xuid.is_some()
+            && key.is_some_and(|key| verify_jwt(&client_data, &key));
+
+        let client_data = decode_jwt_claims(&client_data)?;
+        identity.client_random_id = client_data["ClientRandomId"].as_i64();
+
+        Ok(identity)
+    }
+
+    /// Whether `other` is the same player or the same game install as this.
+    pub fn is_same(&self, other: &LoginIdentity) -> bool {
+        (self.xuid.is_some() && self.xuid == other.xuid)
+            || (self.client_random_id.is_some() && self.client_random_id == other.client_random_id)
+    }
+}
+
+/// Decode the claims of a JWT without verifying its signature.
+fn decode_jwt_claims(token: &str) -> CCProxyResult<serde_json::Value> {
+    decode_jwt_part(token, 1)
+}
+
+/// Decode the header (0) or the claims (1) of a JWT.
+fn decode_jwt_part(token: &str, index: usize) -> CCProxyResult<serde_json::Value> {
+    let part = token
+        .split('.')
+        .nth(index)
+        .ok_or(CCProxyError::GamePacketInvalid)?;
+    let part = URL_SAFE_NO_PAD
+        .decode(part.trim_end_matches('='))
+        .map_err(|_| CCProxyError::GamePacketInvalid)?;
+
+    serde_json::from_slice(&part).map_err(|_| CCProxyError::GamePacketInvalid)
+}
+
+/// Whether the ES384 signature of a JWT is made by `public_key`, which is base64 of a
+/// DER SubjectPublicKeyInfo of a P-384 key.
+fn verify_jwt(token: &str, public_key: &str) -> bool {
+    let Some((message, signature)) = token.rsplit_once('.') else {
+        return false;
+    };
+    let Ok(signature) = URL_SAFE_NO_PAD.decode(signature.trim_end_matches('=')) else {
+        return false;
+    };
+    let Ok(der) = STANDARD.decode(public_key) else {
+        return false;
+    };
+    // The uncompressed point, which ends the SubjectPublicKeyInfo.
+    let Some(point) = der.len().checked_sub(97).map(|start| &der[start..]) else {
+        return false;
+    };
+
+    UnparsedPublicKey::new(&ECDSA_P384_SHA384_FIXED, point)
+        .verify(message.as_bytes(), &signature)
+        .is_ok()
+}
+
+/// Whether the `nbf` and the `exp` of a token, if any, allow it to be used now.
+fn is_jwt_valid_now(claims: &serde_json::Value) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+
+    claims["nbf"]
+        .as_i64()
+        .is_none_or(|nbf| nbf <= now + CHAIN_CLOCK_SKEW_SECS)
+        && claims["exp"]
+            .as_i64()
+            .is_none_or(|exp| now - CHAIN_CLOCK_SKEW_SECS <= exp)
+}
+
+/// How far the login sequence of a session has progressed, as seen by the proxy.
+#[derive(Clone, Debug, Default)]
+pub struct HandshakeState {
+    /// The protocol version the client sent in RequestNetworkSettings.
+    pub protocol_version: Option<i32>,
+
+    /// The player of the client, set once its Login packet is seen.
+    pub identity: Option<LoginIdentity>,
+
+    /// Set once the upstream sent NetworkSettings, after which every batch has a compression header.
+    pub network_settings: Option<NetworkSettings>,
+
+    /// Set once the upstream started encryption or sent an undecodable frame, after which
+    /// nothing can be decoded or injected.
+    pub opaque: bool,
+
+    /// Set once the Login packet is seen or a frame cannot be decoded, after which
+    /// client-to-server frames are not decoded at all.
+    c2s_observed: bool,
+}
+
+impl HandshakeState {
+    /// Inspect a client-to-server game frame to record the protocol version and the
+    /// player of the client.
+    ///
+    /// Returns the identity if the frame carried the Login packet. The login comes
+    /// right after RequestNetworkSettings, so only the first few frames are decoded.
+    pub fn observe_c2s(&mut self, frame: &[u8]) -> CCProxyResult<Option<LoginIdentity>> {
+        if self.c2s_observed {
+            return Ok(None);
+        }
+
+        self.observe_c2s_frame(frame)
+            .inspect_err(|_| self.c2s_observed = true)
+    }
+
+    fn observe_c2s_frame(&mut self, frame: &[u8]) -> CCProxyResult<Option<LoginIdentity>> {
+        for packet in decode_batch(frame, self.network_settings.is_some())? {
+            let (id, body) = decode_packet_header(&packet)?;
+            match id {
+                REQUEST_NETWORK_SETTINGS_PACKET_ID => {
+                    let version = body.get(..4).ok_or(CCProxyError::GamePacketInvalid)?;
+                    self.protocol_version = Some(i32::from_be_bytes(version.try_into().unwrap()));
+                }
+                LOGIN_PACKET_ID => {
+                    self.c2s_observed = true;
+                    let identity = LoginIdentity::decode(body)?;
+                    self.identity = Some(identity.clone());
+
+                    return Ok(Some(identity));
+                }
+                _ => (),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Inspect a server-to-client game frame to follow the login sequence.
+    ///
+    /// Returns the NetworkSettings if the frame negotiated them. The session is treated
+    /// as opaque from the first frame which cannot be decoded, so the proxy never injects
+    /// plain packets into a stream it does not understand.
+    pub fn observe_s2c(&mut self, frame: &[u8]) -> CCProxyResult<Option<NetworkSettings>> {
+        if self.opaque {
+            return Ok(None);
+        }
+
+        self.observe_s2c_frame(frame)
+            .inspect_err(|_| self.opaque = true)
+    }
+
+    fn observe_s2c_frame(&mut self, frame: &[u8]) -> CCProxyResult<Option<NetworkSettings>> {
+        let mut negotiated = None;
+        for packet in decode_batch(frame, self.network_settings.is_some())? {
+            let (id, body) = decode_packet_header(&packet)?;
+            match id {
+                NETWORK_SETTINGS_PACKET_ID => {
+                    let network_settings = NetworkSettings::decode(body)?;
+                    self.network_settings = Some(network_settings.clone());
+                    negotiated = Some(network_settings);
+                }
+                SERVER_TO_CLIENT_HANDSHAKE_PACKET_ID => {
+                    self.opaque = true;
+                    break;
+                }
+                _ => (),
+            }
+        }
+
+        Ok(negotiated)
+    }
+
+    /// Whether the proxy can still inject its own packets into the session.
+    pub fn can_inject(&self) -> bool {
+        !self.opaque
+    }
+
+    /// Encode packets as a game frame suitable for the current handshake stage.
+    pub fn encode_batch(&self, packets: &[Vec<u8>]) -> Vec<u8> {
+        encode_batch(packets, self.network_settings.is_some())
+    }
+}
+
+/// Decode a `0xfe` game frame into its packets.
+///
+/// `compressed` tells whether compression was negotiated, in which case the batch
+/// starts with a compression algorithm header.
+pub fn decode_batch(frame: &[u8], compressed: bool) -> CCProxyResult<Vec<Vec<u8>>> {
+    let (&id, payload) = frame.split_first().ok_or(CCProxyError::GamePacketInvalid)?;
+    if id != GAME_PACKET_ID {
+        return Err(CCProxyError::GamePacketInvalid);
+    }
+
+    let batch = if compressed {
+        let (&header, data) = payload
+            .split_first()
+            .ok_or(CCProxyError::GamePacketInvalid)?;
+        match CompressionAlgorithm::from_header(header)? {
+            CompressionAlgorithm::Zlib => {
+                let mut batch = vec![];
+                flate2::read::DeflateDecoder::new(data)
+                    .take(MAX_BATCH_LEN as u64 + 1)
+                    .read_to_end(&mut batch)
+                    .map_err(|_| CCProxyError::GamePacketInvalid)?;
+                if batch.len() > MAX_BATCH_LEN {
+                    return Err(CCProxyError::GamePacketInvalid);
+                }
+                batch
+            }
+            CompressionAlgorithm::Snappy => {
+                let len =
+                    snap::raw::decompress_len(data).map_err(|_| CCProxyError::GamePacketInvalid)?;
+                if len > MAX_BATCH_LEN {
+                    return Err(CCProxyError::GamePacketInvalid);
+                }
+                snap::raw::Decoder::new()
+                    .decompress_vec(data)
+                    .map_err(|_| CCProxyError::GamePacketInvalid)?
+            }
+            CompressionAlgorithm::None => data.to_vec(),
+        }
+    } else {
+        payload.to_vec()
+    };
+
+    let mut packets = vec![];
+    let mut cursor = batch.as_slice();
+    while !cursor.is_empty() {
+        let len = read_varuint32(&mut cursor)? as usize;
+        if cursor.len() < len {
+            return Err(CCProxyError::GamePacketInvalid);
+        }
+
+        let (packet, rest) = cursor.split_at(len);
+        packets.push(packet.to_vec());
+        cursor = rest;
+    }
+
+    Ok(packets)
+}
+
+/// Encode packets into a `0xfe` game frame without compressing them.
+pub fn encode_batch(packets: &[Vec<u8>], compressed: bool) -> Vec<u8> {
+    let mut frame = vec![GAME_PACKET_ID];
+    if compressed {
+        frame.push(COMPRESSION_NONE_HEADER);
+    }
+
+    for packet in packets {
+        write_varuint32(&mut frame, packet.len() as u32);
+        frame.extend_from_slice(packet);
+    }
+
+    frame
+}
+
+/// Split a packet into its ID and body. The sub-client bits of the header are ignored.
+pub fn decode_packet_header(packet: &[u8]) -> CCProxyResult<(u32, &[u8])> {
+    let mut cursor = packet;
+    let header = read_varuint32(&mut cursor)?;
+
+    Ok((header & 0x3ff, cursor))
+}
+
+/// Encode a Disconnect packet which shows `message` on the client's disconnect screen.
+pub fn encode_disconnect(message: &str) -> Vec<u8> {
+    let mut packet = vec![];
+    write_varuint32(&mut packet, DISCONNECT_PACKET_ID);
+    // Reason: unknown (zigzag-encoded 0).
+    write_varuint32(&mut packet, 0);
+    // Do not skip the message.
+    packet.push(0);
+    write_string(&mut packet, message);
+    // Filtered message.
+    write_string(&mut packet, message);
+
+    packet
+}
+
+/// Encode a PlayStatus packet.
+pub fn encode_play_status(status: i32) -> Vec<u8> {
+    let mut packet = vec![];
+    write_varuint32(&mut packet, PLAY_STATUS_PACKET_ID);
+    packet.extend_from_slice(&status.to_be_bytes());
+
+    packet
+}
+
+/// Encode a Transfer packet which makes the client join `host`:`port` instead.
+pub fn encode_transfer(host: &str, port: u16) -> Vec<u8> {
+    let mut packet = vec![];
+    write_varuint32(&mut packet, TRANSFER_PACKET_ID);
+    write_string(&mut packet, host);
+    packet.extend_from_slice(&port.to_le_bytes());
+    // Do not reload the world.
+    packet.push(0);
+
+    packet
+}
+
+pub fn read_varuint32(buf: &mut &[u8]) -> CCProxyResult<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or(CCProxyError::GamePacketInvalid)?;
+        *buf = rest;
+
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(CCProxyError::GamePacketInvalid)
+}
+
+pub fn write_varuint32(buf: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+pub fn write_string(buf: &mut Vec<u8>, value: &str) {
+    write_varuint32(buf, value.len() as u32);
+    buf.extend_from_slice(value.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{ECDSA_P384_SHA384_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+
+    /// The DER prefix of a SubjectPublicKeyInfo of a P-384 key.
+    const SPKI_PREFIX: &[u8] = &[
+        0x30, 0x76, 0x30, 0x10, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x05,
+        0x2b, 0x81, 0x04, 0x00, 0x22, 0x03, 0x62, 0x00,
+    ];
+
+    struct Key {
+        pair: EcdsaKeyPair,
+        public: String,
+    }
+
+    impl Key {
+        fn generate() -> Self {
+            let rng = SystemRandom::new();
+            let pkcs8 =
+                EcdsaKeyPair::generate_pkcs8(&ECDSA_P384_SHA384_FIXED_SIGNING, &rng).unwrap();
+            let pair =
+                EcdsaKeyPair::from_pkcs8(&ECDSA_P384_SHA384_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                    .unwrap();
+            let public = STANDARD.encode([SPKI_PREFIX, pair.public_key().as_ref()].concat());
+
+            Self { pair, public }
+        }
+
+        fn sign(&self, claims: serde_json::Value) -> String {
+            let header = serde_json::json!({ "alg": "ES384", "x5u": self.public });
+            let message = format!(
+                "{}.{}",
+                URL_SAFE_NO_PAD.encode(header.to_string()),
+                URL_SAFE_NO_PAD.encode(claims.to_string())
+            );
+            let signature = self
+                .pair
+                .sign(&SystemRandom::new(), message.as_bytes())
+                .unwrap();
+
+            format!("{message}.{}", URL_SAFE_NO_PAD.encode(signature))
+        }
+    }
+
+    fn login_body(chain: &[String], client_data: &str) -> Vec<u8> {
+        let chain = serde_json::json!({ "chain": chain }).to_string();
+        let mut request = vec![];
+        request.extend_from_slice(&(chain.len() as u32).to_le_bytes());
+        request.extend_from_slice(chain.as_bytes());
+        request.extend_from_slice(&(client_data.len() as u32).to_le_bytes());
+        request.extend_from_slice(client_data.as_bytes());
+
+        let mut body = 766i32.to_be_bytes().to_vec();
+        write_varuint32(&mut body, request.len() as u32);
+        body.extend_from_slice(&request);
+
+        body
+    }
+
+    fn player_link(signer: &Key, client: &Key, xuid: &str) -> String {
+        signer.sign(serde_json::json!({
+            "identityPublicKey": client.public,
+            "extraData": { "XUID": xuid, "displayName": xuid },
+        }))
+    }
+
+    /// A chain like the one a client gets: certified by itself, then Mojang, then an
+    /// intermediate key which names the player.
+    fn chain(root: &Key, intermediate: &Key, client: &Key) -> Vec<String> {
+        vec![
+            client.sign(serde_json::json!({ "identityPublicKey": root.public })),
+            root.sign(serde_json::json!({ "identityPublicKey": intermediate.public })),
+            player_link(intermediate, client, "1234"),
+        ]
+    }
+
+    fn client_data(client: &Key) -> String {
+        client.sign(serde_json::json!({ "ClientRandomId": 42 }))
+    }
+
+    #[test]
+    fn login_chain_signed_by_root_authenticates() {
+        let (root, intermediate, client) = (Key::generate(), Key::generate(), Key::generate());
+        let body = login_body(&chain(&root, &intermediate, &client), &client_data(&client));
+
+        let identity = LoginIdentity::decode_with_root(&body, &root.public).unwrap();
+        assert!(identity.authenticated);
+        assert_eq!(identity.xuid.as_deref(), Some("1234"));
+        assert_eq!(identity.client_random_id, Some(42));
+    }
+
+    #[test]
+    fn login_chain_signed_by_other_root_is_not_authenticated() {
+        let (root, intermediate, client) = (Key::generate(), Key::generate(), Key::generate());
+        let body = login_body(&chain(&root, &intermediate, &client), &client_data(&client));
+
+        let identity = LoginIdentity::decode_with_root(&body, &Key::generate().public).unwrap();
+        assert!(!identity.authenticated);
+        assert_eq!(identity.xuid.as_deref(), Some("1234"));
+    }
+
+    #[test]
+    fn login_chain_with_appended_link_is_rejected() {
+        let (root, intermediate, client) = (Key::generate(), Key::generate(), Key::generate());
+        let attacker = Key::generate();
+        // The client key of a genuine chain signs a link which names another player.
+        let mut links = chain(&root, &intermediate, &client);
+        links[2] = intermediate.sign(serde_json::json!({ "identityPublicKey": client.public }));
+        links.push(player_link(&client, &attacker, "5678"));
+        let body = login_body(&links, &client_data(&attacker));
+
+        let identity = LoginIdentity::decode_with_root(&body, &root.public).unwrap();
+        assert!(!identity.authenticated);
+    }
+
+    #[test]
+    fn login_chain_with_link_after_player_is_rejected() {
+        let (root, intermediate, client) = (Key::generate(), Key::generate(), Key::generate());
+        let attacker = Key::generate();
+        let mut links = chain(&root, &intermediate, &client);
+        links.push(client.sign(serde_json::json!({ "identityPublicKey": attacker.public })));
+        let body = login_body(&links, &client_data(&attacker));
+
+        assert!(LoginIdentity::decode_with_root(&body, &root.public).is_err());
+    }
+
+    #[test]
+    fn login_chain_naming_two_players_is_rejected() {
+        let (root, intermediate, client) = (Key::generate(), Key::generate(), Key::generate());
+        let mut links = chain(&root, &intermediate, &client);
+        links.push(player_link(&client, &client, "5678"));
+        let body = login_body(&links, &client_data(&client));
+
+        assert!(LoginIdentity::decode_with_root(&body, &root.public).is_err());
+    }
+
+    #[test]
+    fn login_chain_with_tampered_claims_is_not_authenticated() {
+        let (root, intermediate, client) = (Key::generate(), Key::generate(), Key::generate());
+        let mut links = chain(&root, &intermediate, &client);
+        let forged = player_link(&Key::generate(), &client, "5678");
+        let (_, forged_claims, _) = split_jwt(&forged);
+        let (header, _, signature) = split_jwt(&links[2]);
+        links[2] = format!("{header}.{forged_claims}.{signature}");
+        let body = login_body(&links, &client_data(&client));
+
+        let identity = LoginIdentity::decode_with_root(&body, &root.public).unwrap();
+        assert!(!identity.authenticated);
+    }
+
+    #[test]
+    fn login_client_data_signed_by_other_key_is_not_authenticated() {
+        let (root, intermediate, client) = (Key::generate(), Key::generate(), Key::generate());
+        let body = login_body(
+            &chain(&root, &intermediate, &client),
+            &client_data(&Key::generate()),
+        );
+
+        let identity = LoginIdentity::decode_with_root(&body, &root.public).unwrap();
+        assert!(!identity.authenticated);
+    }
+
+    fn split_jwt(token: &str) -> (&str, &str, &str) {
+        let mut parts = token.splitn(3, '.');
+        (
+            parts.next().unwrap(),
+            parts.next().unwrap(),
+            parts.next().unwrap(),
+        )
+    }
+
+    /// A frame of `batch` compressed by the algorithm of `header`.
+    fn compressed_frame(header: u8, batch: &[u8]) -> Vec<u8> {
+        let data = match header {
+            0x00 => {
+                use std::io::Write;
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(vec![], flate2::Compression::fast());
+                encoder.write_all(batch).unwrap();
+                encoder.finish().unwrap()
+            }
+            _ => snap::raw::Encoder::new().compress_vec(batch).unwrap(),
+        };
+
+        [&[GAME_PACKET_ID, header][..], &data].concat()
+    }
+
+    #[test]
+    fn decode_batch_decompresses_packets() {
+        let packets = vec![vec![0x01, 0x02], vec![0x03]];
+        let batch = encode_batch(&packets, false)[1..].to_vec();
+        for header in [0x00, 0x01] {
+            let frame = compressed_frame(header, &batch);
+            assert_eq!(decode_batch(&frame, true).unwrap(), packets);
+        }
+    }
+
+    #[test]
+    fn decode_batch_rejects_batch_over_the_cap() {
+        let batch = vec![0; MAX_BATCH_LEN + 1];
+        for header in [0x00, 0x01] {
+            let frame = compressed_frame(header, &batch);
+            assert!(frame.len() < MAX_BATCH_LEN / 16);
+            assert!(decode_batch(&frame, true).is_err());
+        }
+    }
+}
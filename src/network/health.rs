@@ -0,0 +1,169 @@
+use crate::admin::events::EVENTS;
+use crate::config::HealthCheckConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{METRICS, UPSTREAM_HEALTH_CHECKS_TOTAL, UPSTREAM_UP};
+use crate::network::query::QueryHandler;
+use rust_raknet::RaknetSocket;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// An upstream which the health checker watches.
+#[derive(Clone, Copy)]
+pub struct HealthCheckTarget {
+    pub address: SocketAddr,
+
+    /// Also required to answer a GS4 basic stat when `query` is enabled.
+    pub query_address: Option<SocketAddr>,
+
+    /// Whether the pings carry a PROXY protocol header, as the upstream expects one.
+    pub proxy_protocol: bool,
+}
+
+#[derive(Default)]
+struct HealthState {
+    down: bool,
+
+    /// The consecutive checks which disagree with the current state.
+    streak: u32,
+}
+
+/// Whether each upstream is up, as decided by the consecutive results of the checks.
+pub struct UpstreamHealth {
+    states: RwLock<HashMap<SocketAddr, HealthState>>,
+
+    healthy_threshold: u32,
+
+    unhealthy_threshold: u32,
+}
+
+impl UpstreamHealth {
+    pub fn new(config: &HealthCheckConfig) -> Self {
+        Self {
+            states: Default::default(),
+            healthy_threshold: config.healthy_threshold.max(1),
+            unhealthy_threshold: config.unhealthy_threshold.max(1),
+        }
+    }
+
+    /// Whether new sessions may be routed to `upstream`. An upstream which was not
+    /// checked yet is healthy.
+    pub fn is_healthy(&self, upstream: &SocketAddr) -> bool {
+        self.states
+            .read()
+            .unwrap()
+            .get(upstream)
+            .is_none_or(|s| !s.down)
+    }
+
+    /// Record a check of `upstream`, returning the new health if it changed.
+    pub fn record(&self, upstream: SocketAddr, success: bool) -> Option<bool> {
+        let mut states = self.states.write().unwrap();
+        let state = states.entry(upstream).or_default();
+
+        if success != state.down {
+            state.streak = 0;
+            return None;
+        }
+
+        state.streak += 1;
+        let threshold = if state.down {
+            self.healthy_threshold
+        } else {
+            self.unhealthy_threshold
+        };
+        if state.streak < threshold {
+            return None;
+        }
+
+        state.down = !state.down;
+        state.streak = 0;
+        Some(!state.down)
+    }
+}
+
+/// Check every target on an interval and mark it up or down in `health`. The targets
+/// are listed again on every tick, so re-resolved upstreams are followed.
+pub async fn run_health_checker(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: HealthCheckConfig,
+    targets: impl Fn() -> Vec<HealthCheckTarget> + Send,
+    health: Arc<UpstreamHealth>,
+) -> CCProxyResult<()> {
+    let timeout = Duration::from_millis(config.timeout_ms);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let mut checks = JoinSet::new();
+                for target in targets() {
+                    let query = config.query;
+                    checks.spawn(async move {
+                        (target.address, check(target, query, timeout).await)
+                    });
+                }
+
+                while let Some(Ok((address, success))) = checks.join_next().await {
+                    record(&health, address, success);
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn check(target: HealthCheckTarget, query: bool, timeout: Duration) -> bool {
+    if RaknetSocket::ping_with(&target.address, timeout, 1, target.proxy_protocol)
+        .await
+        .is_err()
+    {
+        return false;
+    }
+
+    match target.query_address {
+        Some(query_address) if query => QueryHandler::query(&query_address, timeout, 1, false)
+            .await
+            .is_ok(),
+        _ => true,
+    }
+}
+
+fn record(health: &UpstreamHealth, address: SocketAddr, success: bool) {
+    let upstream = address.to_string();
+    let result = if success { "success" } else { "failure" };
+    METRICS.counter_add(
+        &UPSTREAM_HEALTH_CHECKS_TOTAL,
+        &[("upstream", &upstream), ("result", result)],
+        1.0,
+    );
+
+    match health.record(address, success) {
+        Some(true) => {
+            tracing::info!("The upstream server ({upstream}) is up.");
+            EVENTS.push("upstream_up", format!("{upstream} is up."));
+        }
+        Some(false) => {
+            tracing::warn!(
+                "The upstream server ({upstream}) is down. New clients are routed to the others."
+            );
+            EVENTS.push("upstream_down", format!("{upstream} is down."));
+        }
+        None => (),
+    }
+
+    let up = if health.is_healthy(&address) {
+        1.0
+    } else {
+        0.0
+    };
+    METRICS.gauge_set(&UPSTREAM_UP, &[("upstream", &upstream)], up);
+}
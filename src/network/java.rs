@@ -0,0 +1,424 @@
+use crate::config::{DisconnectMessagesConfig, JavaConfig};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{
+    JAVA_BYTES_TOTAL, JAVA_CONNECTIONS_ACTIVE, JAVA_STATUS_REQUESTS_TOTAL, METRICS,
+    SESSIONS_REFUSED_TOTAL,
+};
+use crate::network::admission::Admission;
+use crate::network::game::{read_varuint32, write_string, write_varuint32};
+use crate::network::outbound::Outbound;
+use crate::network::proxy_protocol::encode_tcp_header;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+
+/// The ID of the Handshake packet, and of the Status Request and Status Response.
+const HANDSHAKE_PACKET_ID: u32 = 0x00;
+
+const STATUS_PACKET_ID: u32 = 0x00;
+
+const PING_PACKET_ID: u32 = 0x01;
+
+/// The ID of the Disconnect packet in the login state.
+const LOGIN_DISCONNECT_PACKET_ID: u32 = 0x00;
+
+/// The state which the client asks for in the Handshake.
+const NEXT_STATE_STATUS: u32 = 1;
+
+/// Clients must send the Handshake right after connecting, and every packet before the
+/// relay starts as soon as it is asked for.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The Handshake carries a hostname, which modded clients may append data to.
+const MAX_HANDSHAKE_LEN: usize = 32 * 1024;
+
+/// The Status Response may carry a base64 favicon.
+const MAX_STATUS_LEN: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct JavaMotd {
+    pub version: String,
+
+    pub protocol_version: i32,
+
+    pub description: String,
+
+    pub num_players: i32,
+
+    pub max_players: i32,
+}
+
+impl Default for JavaMotd {
+    fn default() -> Self {
+        Self {
+            version: "1.21.8".to_owned(),
+            protocol_version: 772,
+            description: "CCProxy".to_owned(),
+            num_players: 0,
+            max_players: 100,
+        }
+    }
+}
+
+impl JavaMotd {
+    /// Encode the [`JavaMotd`] to the JSON of a Status Response.
+    pub fn encode(&self) -> String {
+        serde_json::json!({
+            "version": {
+                "name": self.version,
+                "protocol": self.protocol_version,
+            },
+            "players": {
+                "max": self.max_players,
+                "online": self.num_players,
+            },
+            "description": {
+                "text": self.description,
+            },
+        })
+        .to_string()
+    }
+}
+
+struct Handshake {
+    next_state: u32,
+}
+
+impl Handshake {
+    fn decode(mut body: &[u8]) -> CCProxyResult<Self> {
+        let buf = &mut body;
+        if read_varint(buf)? != HANDSHAKE_PACKET_ID {
+            return Err(CCProxyError::JavaPacketInvalid);
+        }
+
+        // Protocol version
+        read_varint(buf)?;
+        // Server address
+        let len = read_varint(buf)? as usize;
+        *buf = buf.get(len..).ok_or(CCProxyError::JavaPacketInvalid)?;
+        // Server port
+        *buf = buf.get(2..).ok_or(CCProxyError::JavaPacketInvalid)?;
+
+        Ok(Self {
+            next_state: read_varint(buf)?,
+        })
+    }
+}
+
+/// Relay Java Edition connections to the upstream, answering the Server List Ping with
+/// the `fallback_motd` while the upstream does not respond.
+///
+/// New players are admitted by the [`Admission`] of the Bedrock listener, so its bans,
+/// filters, and limits apply to them too, and refused players get a disconnect message.
+/// The upstream is connected to through `outbound`.
+pub async fn run_java_listener(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: JavaConfig,
+    disconnect_messages: DisconnectMessagesConfig,
+    admission: Arc<Admission>,
+    outbound: Arc<Outbound>,
+) -> CCProxyResult<()> {
+    let listener = TcpListener::bind(config.address).await?;
+    tracing::info!(
+        "The Java Edition listener is started on {}.",
+        config.address
+    );
+
+    let connections = Arc::new(Semaphore::new(config.max_connections));
+    let config = Arc::new(config);
+    let disconnect_messages = Arc::new(disconnect_messages);
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (client, client_address) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::debug!("Cannot accept a Java Edition connection: {err}");
+                        continue;
+                    }
+                };
+
+                // Denied clients are dropped before anything is read, like on the Bedrock listener.
+                if let Err(refusal) = admission.check_ip(client_address.ip()) {
+                    METRICS.counter_add(&SESSIONS_REFUSED_TOTAL, &[("reason", refusal.reason())], 1.0);
+                    continue;
+                }
+                let Ok(permit) = connections.clone().try_acquire_owned() else {
+                    METRICS.counter_add(&SESSIONS_REFUSED_TOTAL, &[("reason", "java_connection_limit")], 1.0);
+                    tracing::debug!("The Java Edition client ({client_address}) is refused because too many connections are open.");
+                    continue;
+                };
+
+                let conn_config = config.clone();
+                let conn_disconnect_messages = disconnect_messages.clone();
+                let conn_admission = admission.clone();
+                let conn_outbound = outbound.clone();
+                sub_sys.start(SubsystemBuilder::new(
+                    format!("Java_{client_address}"),
+                    move |sub| async move {
+                        let _permit = permit;
+                        // A broken connection must not stop the listener.
+                        if let Err(err) = handle_connection(&sub, client, client_address, &conn_config, &conn_disconnect_messages, &conn_admission, &conn_outbound).await {
+                            tracing::debug!("The Java Edition client ({client_address}) error is occurred: {err}");
+                        }
+
+                        Ok::<_, CCProxyError>(())
+                    },
+                ));
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_connection(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    mut client: TcpStream,
+    client_address: SocketAddr,
+    config: &JavaConfig,
+    disconnect_messages: &DisconnectMessagesConfig,
+    admission: &Admission,
+    outbound: &Outbound,
+) -> CCProxyResult<()> {
+    let handshake = read_frame_timeout(&mut client, MAX_HANDSHAKE_LEN).await?;
+    let proxy_address = client.local_addr()?;
+
+    if Handshake::decode(&handshake)?.next_state == NEXT_STATE_STATUS {
+        return handle_status(
+            client,
+            client_address,
+            proxy_address,
+            config,
+            outbound,
+            &handshake,
+        )
+        .await;
+    }
+
+    // Held until the relay ends, so the player counts towards `max_sessions` and
+    // `max_connections_per_ip` like a Bedrock one.
+    let _slot = match admission.check(&client_address) {
+        Ok(slot) => slot,
+        Err(refusal) => {
+            METRICS.counter_add(
+                &SESSIONS_REFUSED_TOTAL,
+                &[("reason", refusal.reason())],
+                1.0,
+            );
+            tracing::info!(
+                "The Java Edition client ({client_address}) is refused because {}.",
+                refusal.description()
+            );
+            if admission.is_silent(refusal) {
+                return Ok(());
+            }
+
+            return disconnect(&mut client, refusal.message(disconnect_messages)).await;
+        }
+    };
+
+    let mut server = match connect_upstream(
+        config,
+        outbound,
+        client_address,
+        proxy_address,
+        &handshake,
+    )
+    .await
+    {
+        Ok(server) => server,
+        Err(err) => {
+            tracing::error!(
+                "Cannot connect the Java Edition client ({client_address}) to the upstream ({}): {err}",
+                config.upstream
+            );
+            return disconnect(&mut client, &disconnect_messages.upstream_unavailable).await;
+        }
+    };
+    tracing::info!(
+        "The Java Edition client ({client_address}) is connected to the upstream ({}).",
+        config.upstream
+    );
+
+    METRICS.gauge_add(&JAVA_CONNECTIONS_ACTIVE, &[], 1.0);
+    let relayed = tokio::select! {
+        relayed = tokio::io::copy_bidirectional(&mut client, &mut server) => Some(relayed),
+        // Shutdown handler
+        _ = sub_sys.on_shutdown_requested() => None,
+    };
+    METRICS.gauge_add(&JAVA_CONNECTIONS_ACTIVE, &[], -1.0);
+    tracing::info!("The Java Edition client ({client_address}) is disconnected.");
+
+    if let Some(relayed) = relayed {
+        let (c2s, s2c) = relayed?;
+        METRICS.counter_add(&JAVA_BYTES_TOTAL, &[("direction", "c2s")], c2s as f64);
+        METRICS.counter_add(&JAVA_BYTES_TOTAL, &[("direction", "s2c")], s2c as f64);
+    }
+
+    Ok(())
+}
+
+/// Show the message on the disconnect screen of a client in the login state.
+async fn disconnect(client: &mut TcpStream, message: &str) -> CCProxyResult<()> {
+    let mut packet = vec![];
+    write_varuint32(&mut packet, LOGIN_DISCONNECT_PACKET_ID);
+    write_string(
+        &mut packet,
+        &serde_json::json!({ "text": message }).to_string(),
+    );
+    client.write_all(&encode_frame(&packet)).await?;
+
+    Ok(())
+}
+
+/// Answer the Server List Ping with the status of the upstream, or the fallback MOTD.
+async fn handle_status(
+    mut client: TcpStream,
+    client_address: SocketAddr,
+    proxy_address: SocketAddr,
+    config: &JavaConfig,
+    outbound: &Outbound,
+    handshake: &[u8],
+) -> CCProxyResult<()> {
+    let request = read_frame_timeout(&mut client, MAX_HANDSHAKE_LEN).await?;
+    if read_varint(&mut request.as_slice())? != STATUS_PACKET_ID {
+        return Err(CCProxyError::JavaPacketInvalid);
+    }
+
+    let timeout = Duration::from_millis(config.status_timeout_ms);
+    let status = tokio::time::timeout(
+        timeout,
+        fetch_upstream_status(
+            config,
+            outbound,
+            client_address,
+            proxy_address,
+            handshake,
+            &request,
+        ),
+    )
+    .await;
+    let response = match status {
+        Ok(Ok(response)) => {
+            METRICS.counter_add(&JAVA_STATUS_REQUESTS_TOTAL, &[("source", "upstream")], 1.0);
+            response
+        }
+        result => {
+            if let Ok(Err(err)) = result {
+                tracing::debug!("Cannot get the status of the Java Edition upstream: {err}");
+            }
+            METRICS.counter_add(&JAVA_STATUS_REQUESTS_TOTAL, &[("source", "fallback")], 1.0);
+
+            let mut response = vec![];
+            write_varuint32(&mut response, STATUS_PACKET_ID);
+            write_string(&mut response, &config.fallback_motd.encode());
+            response
+        }
+    };
+    client.write_all(&encode_frame(&response)).await?;
+
+    // The client measures the latency with a Ping, which is echoed back as the Pong.
+    let ping = read_frame_timeout(&mut client, MAX_HANDSHAKE_LEN).await?;
+    if read_varint(&mut ping.as_slice())? == PING_PACKET_ID {
+        client.write_all(&encode_frame(&ping)).await?;
+    }
+
+    Ok(())
+}
+
+/// Replay the Handshake and the Status Request to the upstream and read its response.
+async fn fetch_upstream_status(
+    config: &JavaConfig,
+    outbound: &Outbound,
+    client_address: SocketAddr,
+    proxy_address: SocketAddr,
+    handshake: &[u8],
+    request: &[u8],
+) -> CCProxyResult<Vec<u8>> {
+    let mut server =
+        connect_upstream(config, outbound, client_address, proxy_address, handshake).await?;
+    server.write_all(&encode_frame(request)).await?;
+
+    let response = read_frame(&mut server, MAX_STATUS_LEN).await?;
+    if read_varint(&mut response.as_slice())? != STATUS_PACKET_ID {
+        return Err(CCProxyError::JavaPacketInvalid);
+    }
+
+    Ok(response)
+}
+
+/// Connect to the upstream with the PROXY protocol header, if enabled, and replay the
+/// Handshake of the client.
+async fn connect_upstream(
+    config: &JavaConfig,
+    outbound: &Outbound,
+    client_address: SocketAddr,
+    proxy_address: SocketAddr,
+    handshake: &[u8],
+) -> CCProxyResult<TcpStream> {
+    let mut server = outbound
+        .connect_tcp(&config.upstream.host, config.upstream.port)
+        .await?;
+    server.set_nodelay(true)?;
+
+    let mut buf = encode_tcp_header(config.proxy_protocol, client_address, proxy_address);
+    buf.extend_from_slice(&encode_frame(handshake));
+    server.write_all(&buf).await?;
+
+    Ok(server)
+}
+
+/// Read a length-prefixed packet without reading ahead, so the rest of the stream can
+/// be relayed as is.
+async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: usize,
+) -> CCProxyResult<Vec<u8>> {
+    let mut len = 0usize;
+    for shift in (0..35).step_by(7) {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            if len == 0 || len > max_len {
+                return Err(CCProxyError::JavaPacketInvalid);
+            }
+
+            let mut body = vec![0u8; len];
+            reader.read_exact(&mut body).await?;
+            return Ok(body);
+        }
+    }
+
+    Err(CCProxyError::JavaPacketInvalid)
+}
+
+/// Read a packet of the client, which must arrive within [`HANDSHAKE_TIMEOUT`].
+async fn read_frame_timeout(client: &mut TcpStream, max_len: usize) -> CCProxyResult<Vec<u8>> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(client, max_len))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?
+}
+
+fn encode_frame(body: &[u8]) -> Vec<u8> {
+    let mut frame = vec![];
+    write_varuint32(&mut frame, body.len() as u32);
+    frame.extend_from_slice(body);
+
+    frame
+}
+
+/// Java Edition VarInts share the encoding of the Bedrock ones.
+fn read_varint(buf: &mut &[u8]) -> CCProxyResult<u32> {
+    read_varuint32(buf).map_err(|_| CCProxyError::JavaPacketInvalid)
+}
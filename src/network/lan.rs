@@ -0,0 +1,48 @@
+use crate::config::LanDiscoveryConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::network::raknet::encode_unconnected_pong;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::RwLock;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// Broadcast the current MOTD of the listener as unconnected pongs, the same way a
+/// server hosted on the LAN answers the discovery pings of clients.
+pub async fn run_lan_announcer(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: LanDiscoveryConfig,
+    motd: Arc<RwLock<String>>,
+    guid: u64,
+) -> CCProxyResult<()> {
+    let socket = UdpSocket::bind(if config.broadcast_address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    socket.set_broadcast(true)?;
+    tracing::info!(
+        "The LAN announcement is sent to {}.",
+        config.broadcast_address
+    );
+
+    let start_time = Instant::now();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let pong = encode_unconnected_pong(start_time.elapsed().as_millis() as i64, guid, &motd.read().await);
+                if let Err(err) = socket.send_to(&pong, config.broadcast_address).await {
+                    tracing::debug!("Cannot send the LAN announcement to {}: {err}", config.broadcast_address);
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,157 @@
+use crate::config::LatencyProbeConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{
+    METRICS, UPSTREAM_JITTER_MS, UPSTREAM_LOSS_RATIO, UPSTREAM_PROBES_TOTAL, UPSTREAM_RTT_MS,
+};
+use rust_raknet::RaknetSocket;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// The latency of an upstream over the recent probes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LatencyStats {
+    /// The RTT of the last answered probe.
+    pub rtt_ms: Option<f64>,
+
+    /// The mean RTT of the answered probes in the window.
+    pub avg_rtt_ms: Option<f64>,
+
+    /// The interarrival jitter as in RFC 3550, smoothed over the answered probes.
+    pub jitter_ms: f64,
+
+    pub loss_ratio: f64,
+}
+
+#[derive(Default)]
+struct ProbeWindow {
+    /// `None` for a lost probe.
+    samples: VecDeque<Option<f64>>,
+
+    last_rtt_ms: Option<f64>,
+
+    jitter_ms: f64,
+}
+
+impl ProbeWindow {
+    fn stats(&self) -> LatencyStats {
+        let answered = self.samples.iter().flatten().collect::<Vec<_>>();
+
+        LatencyStats {
+            rtt_ms: self.last_rtt_ms,
+            avg_rtt_ms: (!answered.is_empty())
+                .then(|| answered.iter().copied().sum::<f64>() / answered.len() as f64),
+            jitter_ms: self.jitter_ms,
+            loss_ratio: match self.samples.len() {
+                0 => 0.0,
+                len => (len - answered.len()) as f64 / len as f64,
+            },
+        }
+    }
+}
+
+/// The latency measurements of every probed upstream, shared with whoever routes or
+/// reports by latency.
+pub struct LatencyProbes {
+    windows: RwLock<HashMap<SocketAddr, ProbeWindow>>,
+
+    window: usize,
+}
+
+impl LatencyProbes {
+    pub fn new(window: usize) -> Self {
+        Self {
+            windows: Default::default(),
+            window: window.max(1),
+        }
+    }
+
+    /// Record a probe of `upstream`, with `None` for a lost one.
+    pub fn record(&self, upstream: SocketAddr, rtt_ms: Option<f64>) -> LatencyStats {
+        let mut windows = self.windows.write().unwrap();
+        let window = windows.entry(upstream).or_default();
+
+        if window.samples.len() >= self.window {
+            window.samples.pop_front();
+        }
+        window.samples.push_back(rtt_ms);
+
+        if let Some(rtt_ms) = rtt_ms {
+            if let Some(last_rtt_ms) = window.last_rtt_ms {
+                window.jitter_ms += ((rtt_ms - last_rtt_ms).abs() - window.jitter_ms) / 16.0;
+            }
+            window.last_rtt_ms = Some(rtt_ms);
+        }
+
+        window.stats()
+    }
+
+    /// Get the latency of `upstream`, if it was probed.
+    pub fn stats(&self, upstream: &SocketAddr) -> Option<LatencyStats> {
+        self.windows
+            .read()
+            .unwrap()
+            .get(upstream)
+            .map(|w| w.stats())
+    }
+}
+
+/// Ping every target on an interval and record the results to `probes` and the metrics.
+/// The targets are listed again on every tick, so re-resolved upstreams are followed,
+/// each with whether its pings carry a PROXY protocol header.
+pub async fn run_latency_prober(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: LatencyProbeConfig,
+    targets: impl Fn() -> Vec<(SocketAddr, bool)> + Send,
+    probes: Arc<LatencyProbes>,
+) -> CCProxyResult<()> {
+    let timeout = Duration::from_millis(config.timeout_ms);
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let mut pings = JoinSet::new();
+                for (target, proxy_protocol) in targets() {
+                    pings.spawn(async move {
+                        let pong = RaknetSocket::ping_with(&target, timeout, 1, proxy_protocol).await;
+                        (target, pong.ok().map(|(latency, _motd)| latency as f64))
+                    });
+                }
+
+                while let Some(Ok((target, rtt_ms))) = pings.join_next().await {
+                    record(&probes, target, rtt_ms);
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn record(probes: &LatencyProbes, target: SocketAddr, rtt_ms: Option<f64>) {
+    let stats = probes.record(target, rtt_ms);
+
+    let upstream = target.to_string();
+    let labels = [("upstream", upstream.as_str())];
+    let result = if rtt_ms.is_some() { "success" } else { "lost" };
+    METRICS.counter_add(
+        &UPSTREAM_PROBES_TOTAL,
+        &[("upstream", &upstream), ("result", result)],
+        1.0,
+    );
+    if let Some(rtt_ms) = rtt_ms {
+        METRICS.gauge_set(&UPSTREAM_RTT_MS, &labels, rtt_ms);
+    }
+    METRICS.gauge_set(&UPSTREAM_JITTER_MS, &labels, stats.jitter_ms);
+    METRICS.gauge_set(&UPSTREAM_LOSS_RATIO, &labels, stats.loss_ratio);
+
+    tracing::trace!("The latency of the upstream ({upstream}) is probed: {stats:?}");
+}
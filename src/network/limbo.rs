@@ -0,0 +1,140 @@
+use crate::config::LimboConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::network::game::{
+    self, CompressionAlgorithm, GAME_PACKET_ID, HandshakeState, LOGIN_PACKET_ID, NetworkSettings,
+    PLAY_STATUS_LOGIN_SUCCESS, REQUEST_NETWORK_SETTINGS_PACKET_ID,
+};
+use rust_raknet::{RaknetSocket, Reliability};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// Why a client left the limbo.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LimboExit {
+    /// The upstream recovered and the client is transferred back to the proxy.
+    Transferred,
+
+    /// The upstream did not recover within the hold limit.
+    TimedOut,
+
+    /// The proxy server is shutting down.
+    Shutdown,
+}
+
+/// A minimal Bedrock server which accepts the login of a client while the upstream
+/// is down, then transfers it back to the proxy once the upstream answers pings.
+///
+/// The client is held on the loading screen right after the login is accepted. No
+/// world is sent because the StartGame layout changes with every protocol version.
+/// Encryption is never started, so [`Limbo::handshake`] always allows injection.
+pub struct Limbo<'a> {
+    client: &'a RaknetSocket,
+
+    config: &'a LimboConfig,
+
+    handshake: HandshakeState,
+}
+
+impl<'a> Limbo<'a> {
+    pub fn new(client: &'a RaknetSocket, config: &'a LimboConfig) -> Self {
+        Self {
+            client,
+            config,
+            handshake: Default::default(),
+        }
+    }
+
+    /// The login state of the client, for sending it packets after it left the limbo.
+    pub fn handshake(&self) -> &HandshakeState {
+        &self.handshake
+    }
+
+    /// Hold the client until the upstream recovers, the hold limit is reached, or the
+    /// proxy shuts down.
+    pub async fn hold(
+        &mut self,
+        sub_sys: &SubsystemHandle<CCProxyError>,
+        upstream_address: SocketAddr,
+        upstream_proxy_protocol: bool,
+    ) -> CCProxyResult<LimboExit> {
+        let deadline = Instant::now() + Duration::from_secs(self.config.max_hold_secs);
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(self.config.check_interval_secs));
+
+        loop {
+            tokio::select! {
+                packet = self.client.recv() => {
+                    self.handle_packet(&packet?).await?;
+                }
+                _ = interval.tick() => {
+                    let recovered = RaknetSocket::ping_with(
+                        &upstream_address,
+                        Duration::from_secs(2),
+                        1,
+                        upstream_proxy_protocol,
+                    )
+                    .await
+                    .is_ok();
+
+                    if recovered {
+                        self.send(&[game::encode_transfer(
+                            &self.config.transfer_host,
+                            self.config.transfer_port,
+                        )])
+                        .await?;
+
+                        return Ok(LimboExit::Transferred);
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    return Ok(LimboExit::TimedOut);
+                }
+                _ = sub_sys.on_shutdown_requested() => {
+                    return Ok(LimboExit::Shutdown);
+                }
+            }
+        }
+    }
+
+    /// Answer a frame of the client, e.g. one which was read before it entered the limbo.
+    pub async fn handle_packet(&mut self, frame: &[u8]) -> CCProxyResult<()> {
+        if frame.first() != Some(&GAME_PACKET_ID) {
+            return Ok(());
+        }
+
+        for packet in game::decode_batch(frame, self.handshake.network_settings.is_some())? {
+            let (id, _body) = game::decode_packet_header(&packet)?;
+            match id {
+                REQUEST_NETWORK_SETTINGS_PACKET_ID => {
+                    // Nothing is large enough to be compressed, so every batch stays plain.
+                    let network_settings = NetworkSettings {
+                        compression_threshold: u16::MAX,
+                        compression_algorithm: CompressionAlgorithm::Zlib,
+                    };
+                    self.send(&[network_settings.encode()]).await?;
+                    self.handshake.network_settings = Some(network_settings);
+                }
+                LOGIN_PACKET_ID => {
+                    self.send(&[game::encode_play_status(PLAY_STATUS_LOGIN_SUCCESS)])
+                        .await?;
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, packets: &[Vec<u8>]) -> CCProxyResult<()> {
+        self.client
+            .send(
+                &self.handshake.encode_batch(packets),
+                Reliability::ReliableOrdered,
+            )
+            .await?;
+
+        Ok(())
+    }
+}
@@ -1,2 +1,26 @@
+pub mod admission;
+pub mod balancer;
 pub mod bedrock;
+pub mod blocklist;
+pub mod circuit;
+pub mod datagram_filter;
+pub mod dns;
+pub mod error_summary;
+pub mod game;
+pub mod health;
+pub mod java;
+pub mod lan;
+pub mod latency;
+pub mod limbo;
+pub mod outbound;
+pub mod passthrough;
+pub mod ping_guard;
+pub mod port_mapping;
+pub mod proxy_protocol;
 pub mod query;
+pub mod raknet;
+pub mod rate_limit;
+pub mod session;
+pub mod tproxy;
+pub mod tunnel;
+pub mod under_attack;
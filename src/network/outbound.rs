@@ -0,0 +1,156 @@
+use crate::config::OutboundConfig;
+use crate::error::CCProxyResult;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+
+/// Opens the sockets to the upstreams from the configured local address or network
+/// interface.
+pub struct Outbound {
+    config: OutboundConfig,
+
+    /// The rotation over the source addresses.
+    next: AtomicUsize,
+}
+
+impl Outbound {
+    pub fn new(config: OutboundConfig) -> Self {
+        Self {
+            config,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The local address of a socket to `upstream`: the next of the source addresses of
+    /// its IP version, or the source address, or else any address.
+    fn local_address(&self, upstream: &SocketAddr) -> SocketAddr {
+        let is_same_version = |ip: &IpAddr| ip.is_ipv4() == upstream.is_ipv4();
+        let pool = self
+            .config
+            .source_addresses
+            .iter()
+            .copied()
+            .filter(is_same_version)
+            .collect::<Vec<_>>();
+        let rotated = (!pool.is_empty())
+            .then(|| pool[self.next.fetch_add(1, Ordering::Relaxed) % pool.len()]);
+
+        let ip = rotated
+            .or(self.config.source_address.filter(is_same_version))
+            .unwrap_or(if upstream.is_ipv4() {
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+            } else {
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+            });
+
+        SocketAddr::new(ip, 0)
+    }
+
+    /// A UDP socket connected to `upstream`.
+    pub async fn connect_udp(&self, upstream: SocketAddr) -> CCProxyResult<UdpSocket> {
+        let socket = UdpSocket::bind(self.local_address(&upstream)).await?;
+        if let Some(interface) = &self.config.interface {
+            bind_to_device(&socket, interface)?;
+        }
+        socket.connect(upstream).await?;
+
+        Ok(socket)
+    }
+
+    /// A TCP connection to the first address of `host` which accepts it.
+    pub async fn connect_tcp(&self, host: &str, port: u16) -> CCProxyResult<TcpStream> {
+        let mut last_err = None;
+        for upstream in tokio::net::lookup_host((host, port)).await? {
+            match self.connect_tcp_address(upstream).await {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{host} resolves to no address."),
+            )
+            .into()
+        }))
+    }
+
+    async fn connect_tcp_address(&self, upstream: SocketAddr) -> CCProxyResult<TcpStream> {
+        let socket = if upstream.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(interface) = &self.config.interface {
+            bind_to_device(&socket, interface)?;
+        }
+        socket.bind(self.local_address(&upstream))?;
+
+        Ok(socket.connect(upstream).await?)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_to_device(socket: &impl std::os::fd::AsFd, interface: &str) -> CCProxyResult<()> {
+    use nix::sys::socket::{setsockopt, sockopt};
+
+    setsockopt(
+        socket,
+        sockopt::BindToDevice,
+        &std::ffi::OsString::from(interface),
+    )
+    .map_err(std::io::Error::from)?;
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_device<S>(_socket: &S, _interface: &str) -> CCProxyResult<()> {
+    Err(crate::error::CCProxyError::BindToDeviceUnsupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn rotates_source_addresses_of_upstream_version() {
+        let outbound = Outbound::new(OutboundConfig {
+            source_address: Some(ip("192.0.2.1")),
+            source_addresses: vec![ip("192.0.2.10"), ip("2001:db8::10"), ip("192.0.2.11")],
+            interface: None,
+        });
+        let upstream = "198.51.100.1:19132".parse().unwrap();
+
+        let locals = (0..3)
+            .map(|_| outbound.local_address(&upstream).ip())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            locals,
+            [ip("192.0.2.10"), ip("192.0.2.11"), ip("192.0.2.10")]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_source_address_or_any() {
+        let outbound = Outbound::new(OutboundConfig {
+            source_address: Some(ip("192.0.2.1")),
+            source_addresses: vec![],
+            interface: None,
+        });
+
+        assert_eq!(
+            outbound.local_address(&"198.51.100.1:19132".parse().unwrap()),
+            "192.0.2.1:0".parse().unwrap()
+        );
+        assert_eq!(
+            outbound.local_address(&"[2001:db8::1]:19132".parse().unwrap()),
+            "[::]:0".parse().unwrap()
+        );
+    }
+}
@@ -0,0 +1,460 @@
+use crate::config::{MtuConfig, PassthroughConfig};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{
+    CounterHandle, DATAGRAMS_DROPPED_TOTAL, METRICS, PASSTHROUGH_BYTES_TOTAL,
+    PASSTHROUGH_FLOWS_ACTIVE, SESSIONS_TOTAL,
+};
+use crate::network::admission::AdmissionSlot;
+use crate::network::datagram_filter::{DatagramFilter, SequenceTracker};
+use crate::network::outbound::Outbound;
+use crate::network::ping_guard::{PingGuard, PingVerdict};
+use crate::network::proxy_protocol::InboundProxyProtocol;
+use crate::network::raknet::{
+    OPEN_CONNECTION_REQUEST_1_ID, clamp_open_connection_reply_1, encode_unconnected_ping,
+    is_unconnected_ping,
+};
+use crate::network::rate_limit::Direction;
+use crate::network::session::{Session, SessionRegistry, new_session_id, session_span};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+use tracing::Instrument;
+
+/// The upstream leg of an open flow.
+struct Flow {
+    upstream: Arc<UdpSocket>,
+
+    session: Arc<Session>,
+
+    sequence: SequenceTracker,
+}
+
+/// The open flows of a worker by the client.
+type Flows = Arc<Mutex<HashMap<SocketAddr, Flow>>>;
+
+/// How often the upstream is pinged for the pong which the clients without a flow get.
+const PONG_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the upstream has to answer a ping.
+const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The counters of every relayed datagram.
+static PASSTHROUGH_BYTES_C2S: LazyLock<CounterHandle> =
+    LazyLock::new(|| METRICS.counter_handle(&PASSTHROUGH_BYTES_TOTAL, &[("direction", "c2s")]));
+static PASSTHROUGH_BYTES_S2C: LazyLock<CounterHandle> =
+    LazyLock::new(|| METRICS.counter_handle(&PASSTHROUGH_BYTES_TOTAL, &[("direction", "s2c")]));
+
+/// Relay datagrams between each client and the upstream which `select` picks for it,
+/// without terminating RakNet. A flow is only opened by an OpenConnectionRequest1, and
+/// ends when the upstream is silent for the idle timeout or the session is evicted.
+///
+/// The unconnected pings of the clients without a flow are answered from the last pong
+/// of `pong_upstream`, which is pinged on an interval, so a ping flood opens no sockets
+/// and takes no slots.
+///
+/// With multiple `workers`, the kernel spreads the clients over sockets bound with
+/// `SO_REUSEPORT` by their address, so every worker keeps the flows of its own clients.
+///
+/// Datagrams are checked against `filter`, and unconnected pings against `pings`. `select`
+/// returns `None` to refuse the client, and records the reason itself. The slot which it
+/// admits the client with is held until the flow ends. The MTU of each client is capped
+/// by `mtu`, and `inbound` takes the client address from the header of a load balancer.
+/// The upstreams are connected to through `outbound`.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_passthrough<U, F, Fut>(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: PassthroughConfig,
+    address: SocketAddr,
+    workers: usize,
+    sessions: Arc<SessionRegistry>,
+    filter: Arc<DatagramFilter>,
+    pings: Arc<PingGuard>,
+    mtu: Option<MtuConfig>,
+    inbound: Arc<InboundProxyProtocol>,
+    outbound: Arc<Outbound>,
+    pong_upstream: U,
+    select: F,
+) -> CCProxyResult<()>
+where
+    U: Fn() -> Option<SocketAddr> + Send + Sync + 'static,
+    F: Fn(SocketAddr) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Option<(SocketAddr, AdmissionSlot)>> + Send + 'static,
+{
+    let sockets = bind_workers(address, workers).await?;
+
+    let updater_pings = pings.clone();
+    let updater_outbound = outbound.clone();
+    sub_sys.start(SubsystemBuilder::new(
+        "PassthroughPongUpdater",
+        move |sub| run_pong_updater(sub, updater_pings, updater_outbound, pong_upstream),
+    ));
+    tracing::info!(
+        "The passthrough relay is started on {address} with {} workers.",
+        sockets.len()
+    );
+
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    for (worker, socket) in sockets.into_iter().enumerate() {
+        let worker_sessions = sessions.clone();
+        let worker_filter = filter.clone();
+        let worker_pings = pings.clone();
+        let worker_mtu = mtu.clone();
+        let worker_inbound = inbound.clone();
+        let worker_outbound = outbound.clone();
+        let worker_select = select.clone();
+        sub_sys.start(SubsystemBuilder::new(
+            format!("PassthroughWorker_{worker}"),
+            move |sub| {
+                run_worker(
+                    sub,
+                    Arc::new(socket),
+                    idle_timeout,
+                    worker_sessions,
+                    worker_filter,
+                    worker_pings,
+                    worker_mtu,
+                    worker_inbound,
+                    worker_outbound,
+                    worker_select,
+                )
+            },
+        ));
+    }
+
+    sub_sys.on_shutdown_requested().await;
+
+    Ok(())
+}
+
+async fn bind_workers(address: SocketAddr, workers: usize) -> CCProxyResult<Vec<UdpSocket>> {
+    if workers <= 1 {
+        return Ok(vec![UdpSocket::bind(address).await?]);
+    }
+
+    #[cfg(target_os = "linux")]
+    return (0..workers).map(|_| bind_reuse_port(address)).collect();
+
+    #[cfg(not(target_os = "linux"))]
+    Err(CCProxyError::WorkersUnsupported)
+}
+
+#[cfg(target_os = "linux")]
+fn bind_reuse_port(address: SocketAddr) -> CCProxyResult<UdpSocket> {
+    use nix::sys::socket::{
+        AddressFamily, SockFlag, SockType, SockaddrStorage, bind, setsockopt, socket, sockopt,
+    };
+    use std::os::fd::AsRawFd;
+
+    let family = if address.is_ipv4() {
+        AddressFamily::Inet
+    } else {
+        AddressFamily::Inet6
+    };
+    let fd = socket(
+        family,
+        SockType::Datagram,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .map_err(std::io::Error::from)?;
+    setsockopt(&fd, sockopt::ReusePort, &true).map_err(std::io::Error::from)?;
+    bind(fd.as_raw_fd(), &SockaddrStorage::from(address)).map_err(std::io::Error::from)?;
+
+    Ok(UdpSocket::from_std(std::net::UdpSocket::from(fd))?)
+}
+
+/// Relay the clients which the kernel delivers to `socket`.
+#[allow(clippy::too_many_arguments)]
+async fn run_worker<F, Fut>(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    socket: Arc<UdpSocket>,
+    idle_timeout: Duration,
+    sessions: Arc<SessionRegistry>,
+    filter: Arc<DatagramFilter>,
+    pings: Arc<PingGuard>,
+    mtu: Option<MtuConfig>,
+    inbound: Arc<InboundProxyProtocol>,
+    outbound: Arc<Outbound>,
+    select: F,
+) -> CCProxyResult<()>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Option<(SocketAddr, AdmissionSlot)>>,
+{
+    let flows = Flows::default();
+
+    let mut buf = vec![0u8; 2048];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, peer) = match received {
+                    Ok(received) => received,
+                    // e.g. ICMP port unreachable of a previous reply on some platforms.
+                    Err(err) => {
+                        tracing::debug!("Cannot receive a datagram for the passthrough relay: {err}");
+                        continue;
+                    }
+                };
+                // A trusted load balancer names the client in a PROXY protocol header.
+                let Some((client, packet)) = inbound.strip(peer, &buf[..len]) else {
+                    continue;
+                };
+                let len = packet.len();
+
+                // Only an offline message opens a flow.
+                let (flow, violation) = match flows.lock().unwrap().get_mut(&client) {
+                    Some(f) => (
+                        Some((f.upstream.clone(), f.session.clone())),
+                        filter.check(packet, Some(&mut f.sequence)),
+                    ),
+                    None => (None, filter.check(packet, None)),
+                };
+                if let Some(violation) = violation
+                    && !filter.apply(client, violation).await
+                {
+                    continue;
+                }
+                if is_unconnected_ping(packet) {
+                    let verdict = match pings.check(client.ip(), packet) {
+                        // A ping never opens a flow, so it is answered statelessly.
+                        PingVerdict::Relay if flow.is_none() => pings
+                            .answer(packet)
+                            .map_or(PingVerdict::Drop, PingVerdict::Answer),
+                        verdict => verdict,
+                    };
+                    match verdict {
+                        PingVerdict::Relay => (),
+                        PingVerdict::Drop => continue,
+                        PingVerdict::Answer(pong) => {
+                            if let Err(err) = socket.send_to(&pong, peer).await {
+                                tracing::debug!("Cannot answer the ping of ({client}): {err}");
+                            }
+                            continue;
+                        }
+                    }
+                }
+
+                let (upstream, session) = match flow {
+                    Some(flow) => flow,
+                    None => {
+                        if packet.first() != Some(&OPEN_CONNECTION_REQUEST_1_ID) {
+                            METRICS.counter_add(&DATAGRAMS_DROPPED_TOTAL, &[("reason", "no_flow")], 1.0);
+                            continue;
+                        }
+
+                        let Some((upstream_address, slot)) = select(client).await else {
+                            continue;
+                        };
+
+                        match open_flow(&sub_sys, &socket, &flows, &sessions, &pings, mtu.as_ref(), &outbound, peer, client, upstream_address, slot, idle_timeout).await {
+                            Ok(flow) => flow,
+                            Err(err) => {
+                                tracing::error!("Cannot open the passthrough flow from ({client}) to ({upstream_address}): {err}");
+                                continue;
+                            }
+                        }
+                    }
+                };
+
+                if !session.bandwidth.try_send(Direction::C2s, len) {
+                    METRICS.counter_add(&DATAGRAMS_DROPPED_TOTAL, &[("reason", "bandwidth")], 1.0);
+                    continue;
+                }
+                if let Err(err) = upstream.send(packet).await {
+                    tracing::debug!("Cannot forward a datagram from ({client}) to ({}): {err}", session.upstream_address);
+                    continue;
+                }
+                session.add_c2s(len);
+                PASSTHROUGH_BYTES_C2S.add(len as u64);
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Ping the upstream which `upstream` returns on an interval, once it is resolved, and
+/// keep its pong in `pings`.
+async fn run_pong_updater(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    pings: Arc<PingGuard>,
+    outbound: Arc<Outbound>,
+    upstream: impl Fn() -> Option<SocketAddr>,
+) -> CCProxyResult<()> {
+    let mut interval = tokio::time::interval(PONG_UPDATE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let Some(upstream_address) = upstream() else {
+                    continue;
+                };
+                if let Err(err) = update_pong(&pings, &outbound, upstream_address).await {
+                    tracing::debug!("Cannot ping the upstream ({upstream_address}) for the passthrough relay: {err}");
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn update_pong(
+    pings: &PingGuard,
+    outbound: &Outbound,
+    upstream_address: SocketAddr,
+) -> CCProxyResult<()> {
+    let socket = outbound.connect_udp(upstream_address).await?;
+    socket
+        .send(&encode_unconnected_ping(0, rand::random()))
+        .await?;
+
+    let mut buf = vec![0u8; 2048];
+    let len = tokio::time::timeout(PONG_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    pings.observe_reply(&buf[..len]);
+
+    Ok(())
+}
+
+/// Open the upstream leg of a new flow and relay the replies to `peer`, which is the
+/// client or its load balancer, until it ends.
+#[allow(clippy::too_many_arguments)]
+async fn open_flow(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    socket: &Arc<UdpSocket>,
+    flows: &Flows,
+    sessions: &Arc<SessionRegistry>,
+    pings: &Arc<PingGuard>,
+    mtu: Option<&MtuConfig>,
+    outbound: &Outbound,
+    peer: SocketAddr,
+    client: SocketAddr,
+    upstream_address: SocketAddr,
+    slot: AdmissionSlot,
+    idle_timeout: Duration,
+) -> CCProxyResult<(Arc<UdpSocket>, Arc<Session>)> {
+    let upstream = Arc::new(outbound.connect_udp(upstream_address).await?);
+
+    let session = sessions
+        .register(new_session_id(), client, upstream_address)
+        .await;
+    flows.lock().unwrap().insert(
+        client,
+        Flow {
+            upstream: upstream.clone(),
+            session: session.clone(),
+            sequence: Default::default(),
+        },
+    );
+    tracing::info!("The client ({client}) is relayed to the upstream ({upstream_address}).");
+
+    let max_mtu = mtu.and_then(|m| m.max_for(&session.geo));
+    let reply = socket.clone();
+    let relay_upstream = upstream.clone();
+    let relay_session = session.clone();
+    let flows = flows.clone();
+    let sessions = sessions.clone();
+    let pings = pings.clone();
+    let span = session_span(&session.id, client, upstream_address);
+    sub_sys.start(SubsystemBuilder::new(
+        format!("Passthrough_{client}"),
+        move |sub| {
+            async move {
+                METRICS.counter_add(&SESSIONS_TOTAL, &[], 1.0);
+                METRICS.gauge_add(&PASSTHROUGH_FLOWS_ACTIVE, &[], 1.0);
+                let result = relay(
+                    &sub,
+                    &relay_upstream,
+                    &reply,
+                    peer,
+                    &relay_session,
+                    &pings,
+                    max_mtu,
+                    idle_timeout,
+                )
+                .await;
+                METRICS.gauge_add(&PASSTHROUGH_FLOWS_ACTIVE, &[], -1.0);
+                drop(slot);
+
+                flows.lock().unwrap().remove(&client);
+                sessions.unregister(&relay_session).await;
+                tracing::info!("The client ({client}) is disconnected.");
+
+                // A broken flow must not stop the relay.
+                if let Err(err) = result {
+                    tracing::debug!(
+                        "The passthrough flow of the client ({client}) is broken: {err}"
+                    );
+                }
+
+                Ok::<_, CCProxyError>(())
+            }
+            .instrument(span)
+        },
+    ));
+
+    Ok((upstream, session))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn relay(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    upstream: &UdpSocket,
+    reply: &UdpSocket,
+    peer: SocketAddr,
+    session: &Session,
+    pings: &PingGuard,
+    max_mtu: Option<u16>,
+    idle_timeout: Duration,
+) -> CCProxyResult<()> {
+    let client = session.client_address;
+
+    let mut buf = vec![0u8; 2048];
+    loop {
+        tokio::select! {
+            received = tokio::time::timeout(idle_timeout, upstream.recv(&mut buf)) => {
+                let Ok(received) = received else {
+                    tracing::debug!("The passthrough flow of the client ({client}) is idle.");
+                    session.set_end_reason("idle");
+                    break;
+                };
+
+                let len = received?;
+                pings.observe_reply(&buf[..len]);
+                let rewritten = pings
+                    .randomize_reply(&buf[..len])
+                    .or_else(|| max_mtu.and_then(|m| clamp_open_connection_reply_1(&buf[..len], m)));
+                session.bandwidth.throttle(Direction::S2c, len).await;
+                reply.send_to(rewritten.as_deref().unwrap_or(&buf[..len]), peer).await?;
+                session.add_s2c(len);
+                PASSTHROUGH_BYTES_S2C.add(len as u64);
+            },
+            // Nothing can be injected into a relayed flow, so the client just times out.
+            _ = session.evicted.cancelled() => {
+                break;
+            },
+            _ = session.terminate.cancelled() => {
+                break;
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                session.set_end_reason("shutdown");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,111 @@
+use crate::metrics::{METRICS, PINGS_RATE_LIMITED_TOTAL, PINGS_TOTAL};
+use crate::network::raknet::{UNCONNECTED_PONG_ID, replace_pong_guid};
+use crate::network::rate_limit::{PerIpTokenBucket, TokenBucketConfig};
+use crate::network::under_attack::AttackCounters;
+use crate::state::ProxyState;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/// The ID and the time of an unconnected ping or pong.
+const PING_TIME_END: usize = 9;
+
+/// The ID, time, server GUID, and magic of an unconnected pong, which the MOTD follows.
+const MIN_PONG_LEN: usize = 33;
+
+/// What a raw datagram relay does with an unconnected ping.
+pub enum PingVerdict {
+    Relay,
+
+    Drop,
+
+    /// Answer it with this pong instead of relaying it.
+    Answer(Vec<u8>),
+}
+
+/// How the raw datagram relays treat the unconnected pings of the clients, which server
+/// list scanners and ping floods send the most of.
+pub struct PingGuard {
+    /// The limit of pings from each source IP.
+    limit: Option<PerIpTokenBucket>,
+
+    state: Arc<ProxyState>,
+
+    counters: Arc<AttackCounters>,
+
+    /// Answer every ping with a fresh random GUID instead of the one of the upstream.
+    randomize_guid: bool,
+
+    /// The last pong of the upstream, which pings are answered from while under attack,
+    /// and the pings of the clients without a flow always.
+    pong: RwLock<Option<Vec<u8>>>,
+}
+
+impl PingGuard {
+    pub fn new(
+        limit: Option<TokenBucketConfig>,
+        state: Arc<ProxyState>,
+        counters: Arc<AttackCounters>,
+        randomize_guid: bool,
+    ) -> Self {
+        Self {
+            limit: limit.map(PerIpTokenBucket::new),
+            state,
+            counters,
+            randomize_guid,
+            pong: Default::default(),
+        }
+    }
+
+    /// What to do with `ping` from `ip`.
+    pub fn check(&self, ip: IpAddr, ping: &[u8]) -> PingVerdict {
+        METRICS.counter_add(&PINGS_TOTAL, &[], 1.0);
+        if self.limit.as_ref().is_some_and(|b| !b.try_acquire(ip)) {
+            METRICS.counter_add(&PINGS_RATE_LIMITED_TOTAL, &[], 1.0);
+            self.counters.record_drop();
+            return PingVerdict::Drop;
+        }
+        if !self.state.is_under_attack() {
+            return PingVerdict::Relay;
+        }
+
+        self.answer(ping)
+            .map_or(PingVerdict::Drop, PingVerdict::Answer)
+    }
+
+    /// The last pong of the upstream as the answer to `ping`, if there is one.
+    pub fn answer(&self, ping: &[u8]) -> Option<Vec<u8>> {
+        let mut pong = self.pong.read().unwrap().clone()?;
+        // The client matches the pong to its ping by the time.
+        let time = ping.get(1..PING_TIME_END)?;
+        pong[1..PING_TIME_END].copy_from_slice(time);
+
+        if self.randomize_guid {
+            return replace_pong_guid(&pong, rand::random());
+        }
+        Some(pong)
+    }
+
+    /// Forget the full buckets of the ping limit.
+    pub fn prune(&self) {
+        if let Some(limit) = &self.limit {
+            limit.prune();
+        }
+    }
+
+    /// A copy of `packet` from the upstream with a fresh GUID, if it is an unconnected pong
+    /// and the GUID is randomized.
+    pub fn randomize_reply(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        if !self.randomize_guid {
+            return None;
+        }
+
+        replace_pong_guid(packet, rand::random())
+    }
+
+    /// Keep `packet` from the upstream if it is an unconnected pong.
+    pub fn observe_reply(&self, packet: &[u8]) {
+        if packet.first() == Some(&UNCONNECTED_PONG_ID) && packet.len() >= MIN_PONG_LEN {
+            *self.pong.write().unwrap() = Some(packet.to_vec());
+        }
+    }
+}
@@ -0,0 +1,201 @@
+use crate::config::{PortMappingConfig, PortMappingMethod};
+use crate::error::{CCProxyError, CCProxyResult};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+const NAT_PMP_PORT: u16 = 5351;
+
+const NAT_PMP_TIMEOUT: Duration = Duration::from_secs(3);
+
+const NAT_PMP_EXTERNAL_ADDRESS_OP: u8 = 0;
+
+const NAT_PMP_MAP_UDP_OP: u8 = 1;
+
+/// Map the UDP port of the listener on the local router and renew the mapping at half
+/// of its lease, so players can join a server hosted behind a home router.
+///
+/// A failure is logged and retried at the next renewal instead of stopping the proxy.
+/// The mapping is removed on shutdown.
+pub async fn run_port_mapper(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: PortMappingConfig,
+    address: SocketAddr,
+) -> CCProxyResult<()> {
+    let renew_interval = Duration::from_secs((config.lease_secs / 2).max(1) as u64);
+    let mut external_address = None;
+
+    let mut interval = tokio::time::interval(renew_interval);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                match map_port(&config, address.port(), config.lease_secs).await {
+                    Ok(mapped) => {
+                        if external_address != Some(mapped) {
+                            tracing::info!("The listener is reachable at {mapped} through the router.");
+                            external_address = Some(mapped);
+                        }
+                    }
+                    Err(err) => {
+                        tracing::error!("Cannot map the port {} on the router: {err}", address.port());
+                        external_address = None;
+                    }
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    if external_address.is_some()
+        && let Err(err) = unmap_port(&config, address.port()).await
+    {
+        tracing::warn!("Cannot remove the port mapping on the router: {err}");
+    }
+
+    Ok(())
+}
+
+/// Returns the external address which the router forwards to `port`.
+async fn map_port(
+    config: &PortMappingConfig,
+    port: u16,
+    lease_secs: u32,
+) -> CCProxyResult<SocketAddr> {
+    match config.method {
+        PortMappingMethod::Upnp => {
+            let gateway = igd_next::aio::tokio::search_gateway(Default::default())
+                .await
+                .map_err(port_mapping_error)?;
+            let local_ip = local_ip_towards(gateway.addr.ip()).await?;
+            gateway
+                .add_port(
+                    igd_next::PortMappingProtocol::UDP,
+                    port,
+                    SocketAddr::new(local_ip, port),
+                    lease_secs,
+                    "ccproxy",
+                )
+                .await
+                .map_err(port_mapping_error)?;
+            let external_ip = gateway
+                .get_external_ip()
+                .await
+                .map_err(port_mapping_error)?;
+
+            Ok(SocketAddr::new(external_ip, port))
+        }
+        PortMappingMethod::NatPmp => {
+            let gateway = nat_pmp_gateway(config)?;
+
+            let response = nat_pmp_request(gateway, &[0, NAT_PMP_EXTERNAL_ADDRESS_OP]).await?;
+            let external_ip = response
+                .get(8..12)
+                .map(|ip| Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))
+                .ok_or_else(|| port_mapping_error("the response is too short"))?;
+
+            let response = nat_pmp_request(gateway, &nat_pmp_map_request(port, lease_secs)).await?;
+            let external_port = response
+                .get(10..12)
+                .map(|p| u16::from_be_bytes([p[0], p[1]]))
+                .ok_or_else(|| port_mapping_error("the response is too short"))?;
+
+            Ok(SocketAddr::new(external_ip.into(), external_port))
+        }
+    }
+}
+
+async fn unmap_port(config: &PortMappingConfig, port: u16) -> CCProxyResult<()> {
+    match config.method {
+        PortMappingMethod::Upnp => {
+            let gateway = igd_next::aio::tokio::search_gateway(Default::default())
+                .await
+                .map_err(port_mapping_error)?;
+            gateway
+                .remove_port(igd_next::PortMappingProtocol::UDP, port)
+                .await
+                .map_err(port_mapping_error)?;
+        }
+        PortMappingMethod::NatPmp => {
+            // A mapping with no lifetime is removed.
+            nat_pmp_request(nat_pmp_gateway(config)?, &nat_pmp_map_request(port, 0)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+fn nat_pmp_map_request(port: u16, lease_secs: u32) -> Vec<u8> {
+    let mut request = vec![0, NAT_PMP_MAP_UDP_OP, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&lease_secs.to_be_bytes());
+
+    request
+}
+
+/// Send a NAT-PMP request and return its successful response.
+async fn nat_pmp_request(gateway: Ipv4Addr, request: &[u8]) -> CCProxyResult<Vec<u8>> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect((gateway, NAT_PMP_PORT)).await?;
+    socket.send(request).await?;
+
+    let mut buf = [0u8; 16];
+    let len = tokio::time::timeout(NAT_PMP_TIMEOUT, socket.recv(&mut buf))
+        .await
+        .map_err(|_| port_mapping_error("the router does not respond"))??;
+    let response = &buf[..len];
+
+    // The opcode of a response is the one of its request plus 128.
+    if response.len() < 4 || response[1] != request[1] + 128 {
+        return Err(port_mapping_error("the response is invalid"));
+    }
+    let result = u16::from_be_bytes([response[2], response[3]]);
+    if result != 0 {
+        return Err(port_mapping_error(format!(
+            "the router refused with the result code {result}"
+        )));
+    }
+
+    Ok(response.to_vec())
+}
+
+fn nat_pmp_gateway(config: &PortMappingConfig) -> CCProxyResult<Ipv4Addr> {
+    config
+        .gateway
+        .or_else(default_gateway)
+        .ok_or_else(|| port_mapping_error("the gateway is unknown, so set `gateway`"))
+}
+
+/// The gateway of the default route, read from the routing table on Linux.
+fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+
+        // The address is in the byte order of the host, which is little-endian on the
+        // platforms Linux routers are found on.
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_le_bytes()))
+    })
+}
+
+/// The local address which the route to `ip` leaves from, which the router forwards to.
+async fn local_ip_towards(ip: IpAddr) -> CCProxyResult<IpAddr> {
+    let socket = UdpSocket::bind(if ip.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).await?;
+    socket.connect((ip, NAT_PMP_PORT)).await?;
+
+    Ok(socket.local_addr()?.ip())
+}
+
+fn port_mapping_error(reason: impl ToString) -> CCProxyError {
+    CCProxyError::PortMapping {
+        reason: reason.to_string(),
+    }
+}
@@ -0,0 +1,469 @@
+use crate::config::{InboundProxyProtocolConfig, ProxyProtocolVersion};
+use crate::network::raknet::{
+    OFFLINE_MESSAGE_MAGIC, OPEN_CONNECTION_REQUEST_1_ID, OPEN_CONNECTION_REQUEST_2_ID,
+    UDP_IPV4_HEADER_SIZE, UDP_IPV6_HEADER_SIZE,
+};
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The signature which starts every PROXY protocol v2 header.
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+
+/// The transport protocols in the address family byte of a v2 header.
+const V2_STREAM: u8 = 0x01;
+const V2_DGRAM: u8 = 0x02;
+
+/// The clients named by headers of their own are forgotten after this long without
+/// datagrams, once there are more than `MAX_INBOUND_CLIENTS` of them.
+const INBOUND_CLIENT_TTL: Duration = Duration::from_secs(300);
+const MAX_INBOUND_CLIENTS: usize = 65_536;
+
+/// The TLV types of the RakNet metadata, in the range which v2 leaves to applications.
+const TLV_CLIENT_GUID: u8 = 0xe0;
+const TLV_MTU: u8 = 0xe1;
+const TLV_RAKNET_PROTOCOL_VERSION: u8 = 0xe2;
+
+/// The RakNet metadata of a UDP flow, which its header carries in TLVs for upstream
+/// plugins: the client GUID (`0xE0`, u64), the MTU (`0xE1`, u16), and the RakNet
+/// protocol version (`0xE2`, u8), all big-endian.
+///
+/// The raw relays never see the game login, so the protocol version is the RakNet one.
+#[derive(Debug, Default, PartialEq)]
+pub struct RaknetTlvs {
+    pub client_guid: Option<u64>,
+
+    pub mtu: Option<u16>,
+
+    pub protocol_version: Option<u8>,
+}
+
+impl RaknetTlvs {
+    /// The metadata which an OpenConnectionRequest1 or 2 from `client` carries, or `None`
+    /// for any other datagram.
+    pub fn from_request(packet: &[u8], client: SocketAddr) -> Option<Self> {
+        if packet.get(1..17) != Some(&OFFLINE_MESSAGE_MAGIC) {
+            return None;
+        }
+
+        match packet[0] {
+            // ID, magic, protocol version, and padding up to the MTU
+            OPEN_CONNECTION_REQUEST_1_ID => {
+                let header_size = if client.is_ipv4() {
+                    UDP_IPV4_HEADER_SIZE
+                } else {
+                    UDP_IPV6_HEADER_SIZE
+                };
+                Some(Self {
+                    client_guid: None,
+                    mtu: u16::try_from(packet.len() + header_size).ok(),
+                    protocol_version: Some(*packet.get(17)?),
+                })
+            }
+            // ID, magic, server address by its IP version, MTU, and client GUID
+            OPEN_CONNECTION_REQUEST_2_ID => {
+                let offset = match packet.get(17)? {
+                    4 => 24,
+                    6 => 46,
+                    _ => return None,
+                };
+                let mtu = packet.get(offset..offset + 2)?;
+                let guid = packet.get(offset + 2..offset + 10)?;
+                Some(Self {
+                    client_guid: Some(u64::from_be_bytes(guid.try_into().unwrap())),
+                    mtu: Some(u16::from_be_bytes([mtu[0], mtu[1]])),
+                    protocol_version: None,
+                })
+            }
+            _ => None,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut tlvs = vec![];
+        let mut push = |kind: u8, value: &[u8]| {
+            tlvs.push(kind);
+            tlvs.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            tlvs.extend_from_slice(value);
+        };
+        if let Some(guid) = self.client_guid {
+            push(TLV_CLIENT_GUID, &guid.to_be_bytes());
+        }
+        if let Some(mtu) = self.mtu {
+            push(TLV_MTU, &mtu.to_be_bytes());
+        }
+        if let Some(version) = self.protocol_version {
+            push(TLV_RAKNET_PROTOCOL_VERSION, &[version]);
+        }
+
+        tlvs
+    }
+}
+
+/// Encode the PROXY protocol header of a TCP connection from `source` to `destination`,
+/// which is empty if the protocol is off.
+///
+/// The RakNet transport sends its own header, so this is for the TCP listeners.
+pub fn encode_tcp_header(
+    version: ProxyProtocolVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::Off => vec![],
+        ProxyProtocolVersion::V1 => encode_v1(source, destination),
+        ProxyProtocolVersion::V2 => encode_v2(source, destination, V2_STREAM, &[]),
+    }
+}
+
+/// Encode the PROXY protocol header which opens a relayed UDP flow, as its own datagram,
+/// which is empty if the protocol is off.
+///
+/// v1 is not defined for UDP, but some Bedrock servers read its text line ahead of the
+/// flow anyway. It carries the addresses only, without the RakNet metadata.
+pub fn encode_flow_header(
+    version: ProxyProtocolVersion,
+    source: SocketAddr,
+    destination: SocketAddr,
+    tlvs: &RaknetTlvs,
+) -> Vec<u8> {
+    match version {
+        ProxyProtocolVersion::Off => vec![],
+        ProxyProtocolVersion::V1 => encode_v1(source, destination),
+        ProxyProtocolVersion::V2 => encode_udp_header(source, destination, tlvs),
+    }
+}
+
+/// Encode the PROXY protocol header of a UDP flow with its RakNet metadata, which is sent
+/// as its own datagram. Only v2 is defined for UDP.
+pub fn encode_udp_header(
+    source: SocketAddr,
+    destination: SocketAddr,
+    tlvs: &RaknetTlvs,
+) -> Vec<u8> {
+    encode_v2(source, destination, V2_DGRAM, &tlvs.encode())
+}
+
+/// Strips the PROXY protocol v2 headers which trusted load balancers put on the datagrams
+/// of their clients.
+pub struct InboundProxyProtocol {
+    trusted_sources: Vec<IpNet>,
+
+    /// The client which the last header of its own from each source port named.
+    clients: Mutex<HashMap<SocketAddr, (SocketAddr, Instant)>>,
+}
+
+impl InboundProxyProtocol {
+    pub fn new(config: &InboundProxyProtocolConfig) -> Self {
+        Self {
+            trusted_sources: config.trusted_sources.clone(),
+            clients: Default::default(),
+        }
+    }
+
+    /// The client of a datagram from `peer`, and the datagram without its header.
+    /// Returns `None` for a datagram which is only a header, or whose header is invalid.
+    pub fn strip<'a>(&self, peer: SocketAddr, packet: &'a [u8]) -> Option<(SocketAddr, &'a [u8])> {
+        let peer_ip = peer.ip().to_canonical();
+        if !self.trusted_sources.iter().any(|n| n.contains(&peer_ip)) {
+            return Some((peer, packet));
+        }
+
+        let mut clients = self.clients.lock().unwrap();
+        if !packet.starts_with(&V2_SIGNATURE) {
+            let client = match clients.get_mut(&peer) {
+                Some((client, last_seen)) => {
+                    *last_seen = Instant::now();
+                    *client
+                }
+                None => peer,
+            };
+            return Some((client, packet));
+        }
+
+        let (source, len) = decode_v2(packet)?;
+        // A LOCAL header, e.g. of a health check, is from the load balancer itself.
+        let client = source.unwrap_or(peer);
+        let payload = &packet[len..];
+        if payload.is_empty() {
+            if clients.len() >= MAX_INBOUND_CLIENTS {
+                clients.retain(|_, (_, last_seen)| last_seen.elapsed() < INBOUND_CLIENT_TTL);
+            }
+            clients.insert(peer, (client, Instant::now()));
+            return None;
+        }
+
+        Some((client, payload))
+    }
+}
+
+/// Decode the v2 header which starts `packet` into the source address it names, if any,
+/// and its length. Returns `None` if the header is invalid.
+fn decode_v2(packet: &[u8]) -> Option<(Option<SocketAddr>, usize)> {
+    let version_command = *packet.get(12)?;
+    if version_command >> 4 != 2 {
+        return None;
+    }
+    let family = *packet.get(13)?;
+    let len = 16 + u16::from_be_bytes([*packet.get(14)?, *packet.get(15)?]) as usize;
+    let addresses = packet.get(16..len)?;
+
+    // The LOCAL command, and the address families which name no IP.
+    if version_command & 0x0f == 0 {
+        return Some((None, len));
+    }
+    let source = match family >> 4 {
+        1 => {
+            let a = addresses.get(..12)?;
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&a[..4]).unwrap());
+            SocketAddr::new(ip.into(), u16::from_be_bytes([a[8], a[9]]))
+        }
+        2 => {
+            let a = addresses.get(..36)?;
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&a[..16]).unwrap());
+            SocketAddr::new(ip.to_canonical(), u16::from_be_bytes([a[32], a[33]]))
+        }
+        _ => return Some((None, len)),
+    };
+
+    Some((Some(source), len))
+}
+
+fn encode_v1(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let protocol = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => "TCP4",
+        (IpAddr::V6(_), IpAddr::V6(_)) => "TCP6",
+        _ => return b"PROXY UNKNOWN\r\n".to_vec(),
+    };
+
+    format!(
+        "PROXY {protocol} {} {} {} {}\r\n",
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+fn encode_v2(source: SocketAddr, destination: SocketAddr, transport: u8, tlvs: &[u8]) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    // Version 2, PROXY command
+    header.push(0x21);
+
+    match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            // IPv4
+            header.push(0x10 | transport);
+            header.extend_from_slice(&(12 + tlvs.len() as u16).to_be_bytes());
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&destination_ip.octets());
+        }
+        (source_ip, destination_ip) => {
+            // IPv6, with IPv4 addresses mapped if the families differ.
+            let to_ipv6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.push(0x20 | transport);
+            header.extend_from_slice(&(36 + tlvs.len() as u16).to_be_bytes());
+            header.extend_from_slice(&to_ipv6(source_ip).octets());
+            header.extend_from_slice(&to_ipv6(destination_ip).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header.extend_from_slice(tlvs);
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn encodes_v2_ipv4_udp_header() {
+        let header = encode_udp_header(
+            address("1.2.3.4:5000"),
+            address("10.0.0.1:19132"),
+            &RaknetTlvs::default(),
+        );
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x12, 0, 12, 1, 2, 3, 4, 10, 0, 0, 1]);
+        expected.extend_from_slice(&5000u16.to_be_bytes());
+        expected.extend_from_slice(&19132u16.to_be_bytes());
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn encodes_v2_mixed_families_as_ipv6() {
+        let header = encode_tcp_header(
+            ProxyProtocolVersion::V2,
+            address("1.2.3.4:5000"),
+            address("[::1]:25565"),
+        );
+
+        assert_eq!(header[12..16], [0x21, 0x21, 0, 36]);
+        assert_eq!(header.len(), 16 + 36);
+        assert_eq!(
+            header[16..32],
+            "1.2.3.4"
+                .parse::<std::net::Ipv4Addr>()
+                .unwrap()
+                .to_ipv6_mapped()
+                .octets()
+        );
+    }
+
+    #[test]
+    fn encodes_v2_tlvs() {
+        let tlvs = RaknetTlvs {
+            client_guid: Some(0x0102030405060708),
+            mtu: Some(1400),
+            protocol_version: Some(11),
+        };
+        let header = encode_udp_header(address("1.2.3.4:5000"), address("10.0.0.1:19132"), &tlvs);
+
+        // 12 bytes of addresses, then 11 + 5 + 4 bytes of TLVs
+        assert_eq!(header[14..16], 32u16.to_be_bytes());
+        assert_eq!(header.len(), 16 + 32);
+        assert_eq!(
+            header[28..],
+            [
+                0xe0, 0, 8, 1, 2, 3, 4, 5, 6, 7, 8, 0xe1, 0, 2, 0x05, 0x78, 0xe2, 0, 1, 11
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_v1_header() {
+        let header = encode_tcp_header(
+            ProxyProtocolVersion::V1,
+            address("1.2.3.4:5000"),
+            address("10.0.0.1:25565"),
+        );
+        assert_eq!(header, b"PROXY TCP4 1.2.3.4 10.0.0.1 5000 25565\r\n");
+    }
+
+    #[test]
+    fn encodes_flow_header_of_each_version() {
+        let (source, destination) = (address("1.2.3.4:5000"), address("10.0.0.1:19132"));
+        let tlvs = RaknetTlvs::default();
+
+        assert!(
+            encode_flow_header(ProxyProtocolVersion::Off, source, destination, &tlvs).is_empty()
+        );
+        assert_eq!(
+            encode_flow_header(ProxyProtocolVersion::V1, source, destination, &tlvs),
+            b"PROXY TCP4 1.2.3.4 10.0.0.1 5000 19132\r\n"
+        );
+        assert_eq!(
+            encode_flow_header(ProxyProtocolVersion::V2, source, destination, &tlvs),
+            encode_udp_header(source, destination, &tlvs)
+        );
+    }
+
+    fn inbound() -> InboundProxyProtocol {
+        InboundProxyProtocol::new(&InboundProxyProtocolConfig {
+            trusted_sources: vec!["10.0.0.0/8".parse().unwrap()],
+        })
+    }
+
+    #[test]
+    fn strips_header_of_trusted_source() {
+        let inbound = inbound();
+        let mut packet = encode_udp_header(
+            address("1.2.3.4:5000"),
+            address("10.0.0.1:19132"),
+            &RaknetTlvs::default(),
+        );
+        packet.extend_from_slice(b"datagram");
+
+        assert_eq!(
+            inbound.strip(address("10.0.0.2:40000"), &packet),
+            Some((address("1.2.3.4:5000"), &b"datagram"[..]))
+        );
+        // Anyone else may not name a client.
+        assert_eq!(
+            inbound.strip(address("192.0.2.1:40000"), &packet),
+            Some((address("192.0.2.1:40000"), &packet[..]))
+        );
+    }
+
+    #[test]
+    fn remembers_client_of_header_datagram() {
+        let inbound = inbound();
+        let header = encode_udp_header(
+            address("[2001:db8::1]:5000"),
+            address("10.0.0.1:19132"),
+            &RaknetTlvs::default(),
+        );
+
+        assert_eq!(inbound.strip(address("10.0.0.2:40000"), &header), None);
+        assert_eq!(
+            inbound.strip(address("10.0.0.2:40000"), b"datagram"),
+            Some((address("[2001:db8::1]:5000"), &b"datagram"[..]))
+        );
+        assert_eq!(
+            inbound.strip(address("10.0.0.2:40001"), b"datagram"),
+            Some((address("10.0.0.2:40001"), &b"datagram"[..]))
+        );
+    }
+
+    #[test]
+    fn drops_invalid_header() {
+        let mut header = encode_udp_header(
+            address("1.2.3.4:5000"),
+            address("10.0.0.1:19132"),
+            &RaknetTlvs::default(),
+        );
+        header.truncate(20);
+
+        assert_eq!(inbound().strip(address("10.0.0.2:40000"), &header), None);
+    }
+
+    #[test]
+    fn reads_tlvs_from_open_connection_requests() {
+        let mut request_1 = vec![OPEN_CONNECTION_REQUEST_1_ID];
+        request_1.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+        request_1.push(11);
+        request_1.resize(1400 - UDP_IPV4_HEADER_SIZE, 0);
+        assert_eq!(
+            RaknetTlvs::from_request(&request_1, address("1.2.3.4:5000")),
+            Some(RaknetTlvs {
+                client_guid: None,
+                mtu: Some(1400),
+                protocol_version: Some(11),
+            })
+        );
+
+        let mut request_2 = vec![OPEN_CONNECTION_REQUEST_2_ID];
+        request_2.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+        request_2.extend_from_slice(&[4, 10, 0, 0, 1, 0x4a, 0xbc]);
+        request_2.extend_from_slice(&1400u16.to_be_bytes());
+        request_2.extend_from_slice(&42u64.to_be_bytes());
+        assert_eq!(
+            RaknetTlvs::from_request(&request_2, address("1.2.3.4:5000")),
+            Some(RaknetTlvs {
+                client_guid: Some(42),
+                mtu: Some(1400),
+                protocol_version: None,
+            })
+        );
+
+        assert_eq!(
+            RaknetTlvs::from_request(&[0x84, 0, 0, 0], address("1.2.3.4:5000")),
+            None
+        );
+    }
+}
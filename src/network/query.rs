@@ -15,7 +15,7 @@ use tokio_graceful_shutdown::{ErrorAction, SubsystemBuilder, SubsystemHandle};
 pub const QUERY_PACKET_MAGIC: u16 = 0xFEFD;
 
 pub struct QueryHandler {
-    upstream_address: SocketAddr,
+    upstream_address: Option<SocketAddr>,
 
     query: Arc<RwLock<ProxyQueryConfig>>,
 
@@ -25,12 +25,21 @@ pub struct QueryHandler {
 impl QueryHandler {
     pub fn new(upstream_address: SocketAddr, fallback_query: &ProxyQueryConfig) -> Self {
         Self {
-            upstream_address,
+            upstream_address: Some(upstream_address),
             query: Arc::new(RwLock::new(fallback_query.clone())),
             challenge_tokens: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
+    /// Create a handler which answers with `query` as it is, without following an upstream.
+    pub fn with_query(query: Arc<RwLock<ProxyQueryConfig>>) -> Self {
+        Self {
+            upstream_address: None,
+            query,
+            challenge_tokens: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
     pub async fn init(&self, sub_sys: &SubsystemHandle<CCProxyError>) {
         let challenge_tokens = self.challenge_tokens.clone();
 
@@ -55,7 +64,9 @@ impl QueryHandler {
             },
         ));
 
-        let upstream_address = self.upstream_address;
+        let Some(upstream_address) = self.upstream_address else {
+            return;
+        };
         let fallback_query = { self.query.read().await.clone() };
         let query_clone = self.query.clone();
 
@@ -159,15 +170,7 @@ impl QueryHandler {
                 let response = QueryResponsePacket {
                     ty: QueryPacketType::Stat,
                     session_id: request.session_id,
-                    payload: QueryResponsePacketPayload::BasicStat {
-                        motd: query.motd,
-                        game_type: query.game_type,
-                        map: query.map,
-                        num_players: query.max_players,
-                        max_players: query.num_players,
-                        host_port: query.host_port,
-                        host_ip: query.host_ip,
-                    },
+                    payload: QueryResponsePacketPayload::query_config_to_basic_stat(query),
                 };
                 Self::send_response_packet(socket, address, response).await?;
             }
@@ -638,6 +641,18 @@ impl QueryResponsePacketPayload {
         Ok(())
     }
 
+    pub fn query_config_to_basic_stat(query: ProxyQueryConfig) -> Self {
+        Self::BasicStat {
+            motd: query.motd,
+            game_type: query.game_type,
+            map: query.map,
+            num_players: query.num_players,
+            max_players: query.max_players,
+            host_port: query.host_port,
+            host_ip: query.host_ip,
+        }
+    }
+
     pub fn query_config_to_k_v_section(query: ProxyQueryConfig) -> HashMap<String, String> {
         HashMap::from([
             ("hostname".to_owned(), query.motd),
@@ -660,3 +675,25 @@ pub async fn read_string_to_null(buf: &mut Cursor<Vec<u8>>) -> CCProxyResult<Str
     string.pop();
     String::from_utf8(string).map_err(|_| CCProxyError::QueryInvalid)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic_stat_keeps_player_counts() {
+        let query = ProxyQueryConfig {
+            num_players: 3,
+            max_players: 20,
+            ..Default::default()
+        };
+        assert!(matches!(
+            QueryResponsePacketPayload::query_config_to_basic_stat(query),
+            QueryResponsePacketPayload::BasicStat {
+                num_players: 3,
+                max_players: 20,
+                ..
+            }
+        ));
+    }
+}
@@ -0,0 +1,342 @@
+use crate::error::CCProxyResult;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// The magic bytes which every RakNet offline message carries.
+pub const OFFLINE_MESSAGE_MAGIC: [u8; 16] = [
+    0x00, 0xff, 0xff, 0x00, 0xfe, 0xfe, 0xfe, 0xfe, 0xfd, 0xfd, 0xfd, 0xfd, 0x12, 0x34, 0x56, 0x78,
+];
+
+pub const UNCONNECTED_PING_ID: u8 = 0x01;
+
+/// The unconnected ping which is only answered while the client has open connections.
+pub const UNCONNECTED_PING_OPEN_CONNECTIONS_ID: u8 = 0x02;
+
+pub const UNCONNECTED_PONG_ID: u8 = 0x1c;
+
+pub const OPEN_CONNECTION_REQUEST_1_ID: u8 = 0x05;
+
+pub const OPEN_CONNECTION_REPLY_1_ID: u8 = 0x06;
+
+pub const OPEN_CONNECTION_REQUEST_2_ID: u8 = 0x07;
+
+/// The bit which the ID of every connected datagram (a frame set, an ACK, or a NACK) has.
+const CONNECTED_DATAGRAM_FLAG: u8 = 0x80;
+
+/// The bits of the ID of an ACK or a NACK, which a frame set has neither of.
+const ACK_NACK_FLAGS: u8 = 0x60;
+
+/// The ID and the smallest sequence number or record of a connected datagram.
+const MIN_CONNECTED_DATAGRAM_LEN: usize = 4;
+
+/// The largest MTU which an OpenConnectionRequest1 is padded to.
+const MAX_MTU_SIZE: usize = 1500;
+
+/// The smallest MTU which RakNet implementations accept.
+const MIN_MTU_SIZE: u16 = 576;
+
+/// The RakNet protocol version which Bedrock clients use.
+pub const RAKNET_PROTOCOL_VERSION: u8 = 11;
+
+/// Whether the datagram is a RakNet offline message, e.g. an unconnected ping or an
+/// OpenConnectionRequest, which carry the magic right after a short header.
+pub fn is_offline_message(packet: &[u8]) -> bool {
+    packet
+        .get(..25)
+        .unwrap_or(packet)
+        .windows(OFFLINE_MESSAGE_MAGIC.len())
+        .any(|w| w == OFFLINE_MESSAGE_MAGIC)
+}
+
+/// Whether a datagram from a client is well-formed: an offline message which clients
+/// send, with its exact layout, or a connected datagram once the client is `connected`.
+///
+/// Random UDP scans and reflected junk fail this, so they are dropped before they reach
+/// RakNet or the upstream.
+pub fn is_valid_client_datagram(packet: &[u8], connected: bool) -> bool {
+    let magic_at = |offset: usize| {
+        packet.get(offset..offset + OFFLINE_MESSAGE_MAGIC.len()) == Some(&OFFLINE_MESSAGE_MAGIC)
+    };
+
+    match packet.first() {
+        Some(id) if id & CONNECTED_DATAGRAM_FLAG != 0 => {
+            connected && packet.len() >= MIN_CONNECTED_DATAGRAM_LEN
+        }
+        // ID, time, magic, and client GUID
+        Some(&UNCONNECTED_PING_ID | &UNCONNECTED_PING_OPEN_CONNECTIONS_ID) => {
+            packet.len() == 33 && magic_at(9)
+        }
+        // ID, magic, protocol version, and padding up to the MTU
+        Some(&OPEN_CONNECTION_REQUEST_1_ID) => {
+            (18..=MAX_MTU_SIZE).contains(&packet.len()) && magic_at(1)
+        }
+        // ID, magic, server address by its IP version, MTU, and client GUID
+        Some(&OPEN_CONNECTION_REQUEST_2_ID) => {
+            magic_at(1)
+                && match packet.get(17) {
+                    Some(4) => packet.len() == 34,
+                    Some(6) => packet.len() == 56,
+                    _ => false,
+                }
+        }
+        _ => false,
+    }
+}
+
+/// The 24-bit sequence number of a frame set, or `None` for any other datagram.
+pub fn frame_set_sequence(packet: &[u8]) -> Option<u32> {
+    let (&id, rest) = packet.split_first()?;
+    if id & CONNECTED_DATAGRAM_FLAG == 0 || id & ACK_NACK_FLAGS != 0 {
+        return None;
+    }
+
+    match rest {
+        [a, b, c, ..] => Some(u32::from_le_bytes([*a, *b, *c, 0])),
+        _ => None,
+    }
+}
+
+/// Whether the datagram is an unconnected ping, which asks for the MOTD.
+pub fn is_unconnected_ping(packet: &[u8]) -> bool {
+    matches!(
+        packet.first(),
+        Some(&UNCONNECTED_PING_ID | &UNCONNECTED_PING_OPEN_CONNECTIONS_ID)
+    ) && is_offline_message(packet)
+}
+
+/// Encode an unconnected ping, which asks a server for its MOTD.
+pub fn encode_unconnected_ping(time: i64, client_guid: u64) -> Vec<u8> {
+    let mut ping = vec![UNCONNECTED_PING_ID];
+    ping.extend_from_slice(&time.to_be_bytes());
+    ping.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+    ping.extend_from_slice(&client_guid.to_be_bytes());
+
+    ping
+}
+
+/// Encode an unconnected pong, which answers a ping with the MOTD of the server.
+pub fn encode_unconnected_pong(time: i64, guid: u64, motd: &str) -> Vec<u8> {
+    let mut pong = vec![UNCONNECTED_PONG_ID];
+    pong.extend_from_slice(&time.to_be_bytes());
+    pong.extend_from_slice(&guid.to_be_bytes());
+    pong.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+    pong.extend_from_slice(&(motd.len() as u16).to_be_bytes());
+    pong.extend_from_slice(motd.as_bytes());
+
+    pong
+}
+
+/// A copy of an unconnected pong which advertises `guid` instead, both in its header and
+/// in its MOTD. Returns `None` for any other datagram.
+pub fn replace_pong_guid(pong: &[u8], guid: u64) -> Option<Vec<u8>> {
+    if pong.first() != Some(&UNCONNECTED_PONG_ID)
+        || pong.get(17..33) != Some(&OFFLINE_MESSAGE_MAGIC)
+    {
+        return None;
+    }
+
+    // ID, time, server GUID, magic, and the length of the MOTD
+    let time = i64::from_be_bytes(pong[1..9].try_into().unwrap());
+    let len = u16::from_be_bytes(pong.get(33..35)?.try_into().unwrap()) as usize;
+    let motd = std::str::from_utf8(pong.get(35..35 + len)?).ok()?;
+
+    // The GUID is the 7th field of the MOTD.
+    let guid_field = guid.to_string();
+    let motd = motd
+        .split(';')
+        .enumerate()
+        .map(|(i, field)| if i == 6 { guid_field.as_str() } else { field })
+        .collect::<Vec<_>>()
+        .join(";");
+
+    Some(encode_unconnected_pong(time, guid, &motd))
+}
+
+/// A copy of an OpenConnectionReply1 which offers no larger MTU than `max_mtu`, but not
+/// below what RakNet accepts. Returns `None` for any other datagram, and for a reply
+/// which already offers a small enough MTU.
+pub fn clamp_open_connection_reply_1(packet: &[u8], max_mtu: u16) -> Option<Vec<u8>> {
+    if packet.first() != Some(&OPEN_CONNECTION_REPLY_1_ID)
+        || packet.get(1..17) != Some(&OFFLINE_MESSAGE_MAGIC)
+    {
+        return None;
+    }
+
+    // ID, magic, server GUID, whether a security cookie follows, the cookie, and the MTU
+    let offset = if *packet.get(25)? == 0 { 26 } else { 30 };
+    let mtu = packet.get(offset..offset + 2)?;
+
+    let max_mtu = max_mtu.max(MIN_MTU_SIZE);
+    if u16::from_be_bytes([mtu[0], mtu[1]]) <= max_mtu {
+        return None;
+    }
+
+    let mut reply = packet.to_vec();
+    reply[offset..offset + 2].copy_from_slice(&max_mtu.to_be_bytes());
+
+    Some(reply)
+}
+
+/// The size of the IP and UDP headers which count towards the MTU.
+pub const UDP_IPV4_HEADER_SIZE: usize = 28;
+pub const UDP_IPV6_HEADER_SIZE: usize = 48;
+
+/// Find the largest of `sizes` which reaches `address`, the same way clients discover
+/// the MTU: an OpenConnectionRequest1 padded to the size must be answered.
+///
+/// The request does not open a connection, so the upstream keeps no state for it. The
+/// Don't Fragment bit is only set on Linux; elsewhere a link with a smaller MTU may
+/// fragment the request instead of dropping it.
+pub async fn probe_mtu(
+    address: &SocketAddr,
+    sizes: &[u16],
+    timeout: Duration,
+) -> CCProxyResult<Option<u16>> {
+    let socket = UdpSocket::bind(if address.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    socket.connect(address).await?;
+    // A fragmented request would be answered at any size.
+    #[cfg(target_os = "linux")]
+    set_dont_fragment(&socket, address.is_ipv4())?;
+
+    let header_size = if address.is_ipv4() {
+        UDP_IPV4_HEADER_SIZE
+    } else {
+        UDP_IPV6_HEADER_SIZE
+    };
+
+    for &size in sizes {
+        let mut request = vec![OPEN_CONNECTION_REQUEST_1_ID];
+        request.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+        request.push(RAKNET_PROTOCOL_VERSION);
+        request.resize((size as usize).saturating_sub(header_size), 0);
+
+        // Packets over the path MTU may be rejected locally instead of being dropped.
+        if socket.send(&request).await.is_err() {
+            continue;
+        }
+
+        let mut buf = [0u8; 64];
+        if let Ok(Ok(len)) = tokio::time::timeout(timeout, socket.recv(&mut buf)).await
+            && len > 0
+            && buf[0] == OPEN_CONNECTION_REPLY_1_ID
+        {
+            return Ok(Some(size));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Set the Don't Fragment bit on the datagrams of `socket`, so a datagram over the MTU of
+/// a link is dropped, or refused locally, instead of fragmented.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &UdpSocket, ipv4: bool) -> std::io::Result<()> {
+    use nix::libc;
+    use std::os::fd::AsRawFd;
+
+    let (level, name, value) = if ipv4 {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    } else {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )
+    };
+
+    // nix has no option for the MTU discovery, so it is set directly.
+    // SAFETY: The descriptor stays open while `socket` is borrowed, and `value` outlives
+    // the call with the length which is passed.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_pong_guid() {
+        let pong =
+            encode_unconnected_pong(7, 1, "MCPE;Proxy;800;1.21.0;0;10;1;Sub;Survival;1;19132;");
+
+        assert_eq!(
+            replace_pong_guid(&pong, 42),
+            Some(encode_unconnected_pong(
+                7,
+                42,
+                "MCPE;Proxy;800;1.21.0;0;10;42;Sub;Survival;1;19132;"
+            ))
+        );
+        assert_eq!(replace_pong_guid(&pong[..pong.len() - 1], 42), None);
+        assert_eq!(replace_pong_guid(&reply_1(false, 1492), 42), None);
+    }
+
+    fn reply_1(security: bool, mtu: u16) -> Vec<u8> {
+        let mut reply = vec![OPEN_CONNECTION_REPLY_1_ID];
+        reply.extend_from_slice(&OFFLINE_MESSAGE_MAGIC);
+        reply.extend_from_slice(&42u64.to_be_bytes());
+        reply.push(security as u8);
+        if security {
+            reply.extend_from_slice(&7u32.to_be_bytes());
+        }
+        reply.extend_from_slice(&mtu.to_be_bytes());
+
+        reply
+    }
+
+    #[test]
+    fn clamps_reply_1_mtu() {
+        assert_eq!(
+            clamp_open_connection_reply_1(&reply_1(false, 1492), 1200),
+            Some(reply_1(false, 1200))
+        );
+        assert_eq!(
+            clamp_open_connection_reply_1(&reply_1(true, 1492), 1200),
+            Some(reply_1(true, 1200))
+        );
+        assert_eq!(
+            clamp_open_connection_reply_1(&reply_1(false, 1492), 100),
+            Some(reply_1(false, MIN_MTU_SIZE))
+        );
+    }
+
+    #[test]
+    fn keeps_smaller_reply_1_mtu() {
+        assert_eq!(
+            clamp_open_connection_reply_1(&reply_1(false, 1000), 1200),
+            None
+        );
+    }
+
+    #[test]
+    fn leaves_other_datagrams() {
+        let pong = encode_unconnected_pong(0, 42, "MCPE;ccproxy;");
+        assert_eq!(clamp_open_connection_reply_1(&pong, 576), None);
+
+        let mut truncated = reply_1(false, 1492);
+        truncated.truncate(27);
+        assert_eq!(clamp_open_connection_reply_1(&truncated, 576), None);
+    }
+}
@@ -0,0 +1,436 @@
+use crate::config::BandwidthConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// The most buckets a [`PerIpTokenBucket`] keeps. The oldest one is dropped for a new
+/// source past this, so a spoofed flood of sources cannot grow the table.
+const MAX_BUCKETS: usize = 16_384;
+
+/// How often the full buckets are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The rate and burst of a [`TokenBucket`].
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct TokenBucketConfig {
+    /// How many tokens are refilled per second.
+    pub per_sec: f64,
+
+    /// How many tokens can be stored, i.e. the largest burst which is allowed at once.
+    pub burst: u32,
+}
+
+/// A token bucket which starts full and refills continuously.
+#[derive(Debug)]
+pub struct TokenBucket {
+    config: TokenBucketConfig,
+
+    state: Mutex<TokenBucketState>,
+}
+
+#[derive(Debug)]
+struct TokenBucketState {
+    tokens: f64,
+
+    refilled_at: Instant,
+}
+
+impl TokenBucketState {
+    fn new(config: TokenBucketConfig) -> Self {
+        Self {
+            tokens: config.burst as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            tokens: 0.0,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, config: TokenBucketConfig, now: Instant) {
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * config.per_sec).min(config.burst as f64);
+        self.refilled_at = now;
+    }
+
+    fn try_acquire(&mut self, config: TokenBucketConfig) -> bool {
+        self.refill(config, Instant::now());
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take `n` tokens if they are available. A full bucket gives anything at once, so
+    /// nothing larger than the burst is refused forever.
+    fn try_acquire_n(&mut self, config: TokenBucketConfig, n: f64) -> bool {
+        self.refill(config, Instant::now());
+
+        if self.tokens >= n || self.tokens >= config.burst as f64 {
+            self.tokens -= n;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take `n` tokens, going into debt if they are not available, and return how long
+    /// it takes to pay the debt back.
+    fn take(&mut self, config: TokenBucketConfig, n: f64) -> Duration {
+        self.refill(config, Instant::now());
+        self.tokens -= n;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64(-self.tokens / config.per_sec).unwrap_or(Duration::MAX)
+        }
+    }
+}
+
+impl TokenBucket {
+    pub fn new(config: TokenBucketConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(TokenBucketState::new(config)),
+        }
+    }
+
+    /// Take a token if one is available.
+    pub fn try_acquire(&self) -> bool {
+        self.state.lock().unwrap().try_acquire(self.config)
+    }
+
+    /// Take `n` tokens if they are available.
+    pub fn try_acquire_n(&self, n: usize) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .try_acquire_n(self.config, n as f64)
+    }
+
+    /// Take `n` tokens, waiting until the bucket is refilled if it is short of them.
+    pub async fn acquire(&self, n: usize) {
+        let wait = self.state.lock().unwrap().take(self.config, n as f64);
+        sleep_unless_zero(wait).await;
+    }
+
+    /// Take `n` tokens which are not available, so the bucket stays short while it is
+    /// overdrawn. The debt never grows past a burst.
+    fn owe(&self, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.refill(self.config, Instant::now());
+        state.tokens = (state.tokens - n as f64).max(-(self.config.burst as f64));
+    }
+}
+
+async fn sleep_unless_zero(duration: Duration) {
+    if !duration.is_zero() {
+        tokio::time::sleep(duration).await;
+    }
+}
+
+/// Which way bytes go through a session.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    C2s,
+
+    S2c,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::C2s => "c2s",
+            Self::S2c => "s2c",
+        }
+    }
+}
+
+/// The byte budgets of the whole process in each direction, shared by the sessions of
+/// every listener.
+#[derive(Debug, Default)]
+pub struct SharedBandwidth {
+    c2s: Option<TokenBucket>,
+
+    s2c: Option<TokenBucket>,
+
+    /// The live sessions, which split a budget evenly while it runs short.
+    sessions: AtomicUsize,
+}
+
+impl SharedBandwidth {
+    pub fn new(config: &BandwidthConfig) -> Self {
+        Self {
+            c2s: config.c2s.map(TokenBucket::new),
+            s2c: config.s2c.map(TokenBucket::new),
+            sessions: AtomicUsize::new(0),
+        }
+    }
+
+    fn bucket(&self, direction: Direction) -> Option<&TokenBucket> {
+        match direction {
+            Direction::C2s => self.c2s.as_ref(),
+            Direction::S2c => self.s2c.as_ref(),
+        }
+    }
+
+    /// The even share of `bucket` which each live session gets.
+    fn fair_share(&self, bucket: &TokenBucket) -> TokenBucketConfig {
+        let sessions = self.sessions.load(Ordering::Relaxed).max(1);
+
+        TokenBucketConfig {
+            per_sec: bucket.config.per_sec / sessions as f64,
+            burst: (bucket.config.burst / sessions as u32).max(1),
+        }
+    }
+}
+
+/// The byte budgets of a session in each direction.
+///
+/// The session also draws from the shared budgets. While one has bytes left, any session
+/// may take them; once it runs short, each session gets an even share of its rate, so a
+/// heavy session cannot starve the others and the total stays within the budget.
+#[derive(Debug)]
+pub struct SessionBandwidth {
+    c2s: Option<TokenBucket>,
+
+    s2c: Option<TokenBucket>,
+
+    shared: Arc<SharedBandwidth>,
+
+    /// What is left of the fair share of the session while a shared budget runs short.
+    c2s_share: Mutex<TokenBucketState>,
+
+    s2c_share: Mutex<TokenBucketState>,
+}
+
+impl Drop for SessionBandwidth {
+    fn drop(&mut self) {
+        self.shared.sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl SessionBandwidth {
+    pub fn new(config: &BandwidthConfig, shared: Arc<SharedBandwidth>) -> Self {
+        shared.sessions.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            c2s: config.c2s.map(TokenBucket::new),
+            s2c: config.s2c.map(TokenBucket::new),
+            shared,
+            c2s_share: Mutex::new(TokenBucketState::empty()),
+            s2c_share: Mutex::new(TokenBucketState::empty()),
+        }
+    }
+
+    fn bucket(&self, direction: Direction) -> Option<&TokenBucket> {
+        match direction {
+            Direction::C2s => self.c2s.as_ref(),
+            Direction::S2c => self.s2c.as_ref(),
+        }
+    }
+
+    fn share(&self, direction: Direction) -> &Mutex<TokenBucketState> {
+        match direction {
+            Direction::C2s => &self.c2s_share,
+            Direction::S2c => &self.s2c_share,
+        }
+    }
+
+    /// Wait until `len` bytes may be sent in `direction`, which delays the relay of a
+    /// reliable stream instead of losing its packets.
+    pub async fn throttle(&self, direction: Direction, len: usize) {
+        if let Some(bucket) = self.bucket(direction) {
+            bucket.acquire(len).await;
+        }
+
+        if let Some(shared) = self.shared.bucket(direction)
+            && !shared.try_acquire_n(len)
+        {
+            shared.owe(len);
+            let share = self.shared.fair_share(shared);
+            let wait = self
+                .share(direction)
+                .lock()
+                .unwrap()
+                .take(share, len as f64);
+            sleep_unless_zero(wait).await;
+        }
+    }
+
+    /// Whether a datagram of `len` bytes may be sent in `direction` now. A relay which
+    /// serves many sessions from one loop drops the datagrams over the budget, as RakNet
+    /// resends them slower.
+    pub fn try_send(&self, direction: Direction, len: usize) -> bool {
+        if !self
+            .bucket(direction)
+            .is_none_or(|bucket| bucket.try_acquire_n(len))
+        {
+            return false;
+        }
+
+        let Some(shared) = self.shared.bucket(direction) else {
+            return true;
+        };
+        if shared.try_acquire_n(len) {
+            return true;
+        }
+
+        let share = self.shared.fair_share(shared);
+        let admitted = self
+            .share(direction)
+            .lock()
+            .unwrap()
+            .try_acquire_n(share, len as f64);
+        if admitted {
+            shared.owe(len);
+        }
+
+        admitted
+    }
+}
+
+/// A [`TokenBucket`] for each source IP, created on its first use.
+pub struct PerIpTokenBucket {
+    config: TokenBucketConfig,
+
+    buckets: Mutex<PerIpBuckets>,
+}
+
+#[derive(Default)]
+struct PerIpBuckets {
+    states: HashMap<IpAddr, TokenBucketState>,
+
+    /// The IPs of `states` in the order they were created, the oldest first.
+    order: VecDeque<IpAddr>,
+}
+
+impl PerIpTokenBucket {
+    pub fn new(config: TokenBucketConfig) -> Self {
+        Self {
+            config,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Take a token of `ip` if one is available.
+    pub fn try_acquire(&self, ip: IpAddr) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if !buckets.states.contains_key(&ip) {
+            while buckets.states.len() >= MAX_BUCKETS
+                && let Some(oldest) = buckets.order.pop_front()
+            {
+                buckets.states.remove(&oldest);
+            }
+            buckets.order.push_back(ip);
+        }
+
+        buckets
+            .states
+            .entry(ip)
+            .or_insert_with(|| TokenBucketState::new(self.config))
+            .try_acquire(self.config)
+    }
+
+    /// Forget the full buckets, which are the same as missing ones.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let PerIpBuckets { states, order } = &mut *buckets;
+        states.retain(|_, state| {
+            state.refill(self.config, now);
+            state.tokens < self.config.burst as f64
+        });
+        order.retain(|ip| states.contains_key(ip));
+    }
+}
+
+/// Call `prune` on an interval to forget the full buckets of the [`PerIpTokenBucket`]s
+/// of a listener, outside of the path of the clients.
+pub async fn run_bucket_pruner(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    prune: impl Fn(),
+) -> CCProxyResult<()> {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                prune();
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: TokenBucketConfig = TokenBucketConfig {
+        per_sec: 0.001,
+        burst: 1,
+    };
+
+    #[test]
+    fn per_ip_limits_each_source() {
+        let bucket = PerIpTokenBucket::new(CONFIG);
+        let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        assert!(bucket.try_acquire(a));
+        assert!(!bucket.try_acquire(a));
+        assert!(bucket.try_acquire(b));
+    }
+
+    #[test]
+    fn per_ip_drops_the_oldest_past_the_bound() {
+        let bucket = PerIpTokenBucket::new(CONFIG);
+        let ip = |i: usize| IpAddr::from((i as u32).to_be_bytes());
+        for i in 0..=MAX_BUCKETS {
+            assert!(bucket.try_acquire(ip(i)));
+        }
+
+        let buckets = bucket.buckets.lock().unwrap();
+        assert_eq!(buckets.states.len(), MAX_BUCKETS);
+        assert_eq!(buckets.order.len(), MAX_BUCKETS);
+        assert!(!buckets.states.contains_key(&ip(0)));
+        assert!(buckets.states.contains_key(&ip(MAX_BUCKETS)));
+    }
+
+    #[test]
+    fn prune_forgets_full_buckets() {
+        let bucket = PerIpTokenBucket::new(TokenBucketConfig { burst: 2, ..CONFIG });
+        let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        assert!(bucket.try_acquire(a));
+        {
+            let mut buckets = bucket.buckets.lock().unwrap();
+            buckets
+                .states
+                .insert(b, TokenBucketState::new(bucket.config));
+            buckets.order.push_back(b);
+        }
+
+        bucket.prune();
+        let buckets = bucket.buckets.lock().unwrap();
+        assert!(buckets.states.contains_key(&a));
+        assert!(!buckets.states.contains_key(&b));
+        assert_eq!(buckets.order, [a]);
+    }
+}
@@ -0,0 +1,539 @@
+use crate::access_log::{AccessLog, AccessRecord};
+use crate::admin::events::EVENTS;
+use crate::config::{BandwidthConfig, DATA_PATH};
+use crate::error::CCProxyResult;
+use crate::geoip::{GeoInfo, GeoIp};
+use crate::history::{HistoryEvent, HistoryRecord, SessionHistory};
+use crate::network::game::{HandshakeState, LoginIdentity};
+use crate::network::rate_limit::{SessionBandwidth, SharedBandwidth};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+/// The file which the session/affinity table of a listener is written to during
+/// graceful shutdown. `None` is the main listener.
+pub fn session_snapshot_path(listener: Option<&str>) -> PathBuf {
+    let file_name = match listener {
+        Some(name) => format!("sessions.{name}.yaml"),
+        None => "sessions.yaml".to_owned(),
+    };
+
+    DATA_PATH.join("state").join(file_name)
+}
+
+/// A random ID which names a session in the logs, the admin APIs, and the history.
+pub fn new_session_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// The span which every log line of a session is under, so the lines of both directions
+/// can be told apart from those of the other sessions.
+pub fn session_span(
+    id: &str,
+    client_address: SocketAddr,
+    upstream_address: SocketAddr,
+) -> tracing::Span {
+    tracing::info_span!(
+        "session",
+        id,
+        client = %client_address,
+        upstream = %upstream_address
+    )
+}
+
+/// Tracks live sessions and which upstream each client was routed to.
+pub struct SessionRegistry {
+    sessions: RwLock<HashMap<SocketAddr, Arc<Session>>>,
+
+    affinities: RwLock<HashMap<SocketAddr, Affinity>>,
+
+    /// The upstream each client IP was last routed to.
+    sticky: std::sync::Mutex<HashMap<IpAddr, Sticky>>,
+
+    /// How long a sticky upstream is kept after the last session of the IP ended.
+    sticky_ttl: Duration,
+
+    /// The number of live sessions on each upstream.
+    upstream_loads: std::sync::Mutex<HashMap<SocketAddr, usize>>,
+
+    /// How long an affinity is kept after its session ended.
+    affinity_ttl: Duration,
+
+    history: Option<SessionHistory>,
+
+    access_log: Option<AccessLog>,
+
+    geoip: Option<GeoIp>,
+
+    /// The byte budgets which every new session gets.
+    bandwidth: BandwidthConfig,
+
+    /// The byte budgets of the process, which every session also draws from.
+    shared_bandwidth: Arc<SharedBandwidth>,
+}
+
+#[derive(Debug)]
+pub struct Session {
+    pub id: String,
+
+    pub client_address: SocketAddr,
+
+    pub upstream_address: SocketAddr,
+
+    pub connected_at: SystemTime,
+
+    pub geo: GeoInfo,
+
+    pub bytes_c2s: AtomicU64,
+
+    pub bytes_s2c: AtomicU64,
+
+    /// Unix timestamp in seconds when a packet was last forwarded either way.
+    pub last_activity: AtomicU64,
+
+    pub bandwidth: SessionBandwidth,
+
+    pub handshake: std::sync::Mutex<HandshakeState>,
+
+    /// Cancelled when the session must be torn down, e.g. because it is replaced
+    /// by a new connection from the same endpoint.
+    pub terminate: CancellationToken,
+
+    /// Cancelled when the proxy ends the session, together with the `eviction`.
+    pub evicted: CancellationToken,
+
+    eviction: std::sync::Mutex<Option<Eviction>>,
+
+    /// Why the session ended, if it ended on its own rather than by an eviction.
+    end_reason: std::sync::Mutex<Option<&'static str>>,
+}
+
+/// How the proxy ends a session on its own.
+#[derive(Clone, Debug)]
+pub enum Eviction {
+    /// Show the message on the disconnect screen.
+    Kick(String),
+
+    /// Move the client to another server with the Transfer packet.
+    Transfer { host: String, port: u16 },
+}
+
+impl Session {
+    /// End the session, keeping the first eviction if it is evicted more than once.
+    pub fn evict(&self, eviction: Eviction) {
+        self.eviction.lock().unwrap().get_or_insert(eviction);
+        self.evicted.cancel();
+    }
+
+    pub fn eviction(&self) -> Option<Eviction> {
+        self.eviction.lock().unwrap().clone()
+    }
+
+    /// Record why the session ends for the access log, keeping the first reason as
+    /// closing one leg ends the other too.
+    pub fn set_end_reason(&self, reason: &'static str) {
+        self.end_reason.lock().unwrap().get_or_insert(reason);
+    }
+
+    fn end_reason(&self) -> &'static str {
+        if self.terminate.is_cancelled() {
+            return "replaced";
+        }
+
+        match self.eviction() {
+            Some(Eviction::Kick(_)) => "kicked",
+            Some(Eviction::Transfer { .. }) => "transferred",
+            None => self.end_reason.lock().unwrap().unwrap_or("closed"),
+        }
+    }
+
+    /// Count `len` bytes forwarded from the client to the upstream.
+    pub fn add_c2s(&self, len: usize) {
+        self.bytes_c2s.fetch_add(len as u64, Ordering::Relaxed);
+        self.last_activity.store(unix_now(), Ordering::Relaxed);
+    }
+
+    /// Count `len` bytes forwarded from the upstream to the client.
+    pub fn add_s2c(&self, len: usize) {
+        self.bytes_s2c.fetch_add(len as u64, Ordering::Relaxed);
+        self.last_activity.store(unix_now(), Ordering::Relaxed);
+    }
+
+    fn access_record(&self, end: &HistoryRecord) -> AccessRecord {
+        AccessRecord {
+            session_id: self.id.clone(),
+            start: self
+                .connected_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            end: end.timestamp,
+            client_ip: self.client_address.ip(),
+            client_port: self.client_address.port(),
+            geo: self.geo.clone(),
+            name: end.name.clone(),
+            xuid: end.xuid.clone(),
+            upstream_address: self.upstream_address,
+            bytes_in: end.bytes_c2s,
+            bytes_out: end.bytes_s2c,
+            reason: self.end_reason(),
+            kick_message: end.kick_message.clone(),
+        }
+    }
+
+    fn history_record(&self, event: HistoryEvent) -> HistoryRecord {
+        let handshake = self.handshake.lock().unwrap().clone();
+
+        HistoryRecord {
+            event,
+            session_id: self.id.clone(),
+            client_address: self.client_address,
+            upstream_address: self.upstream_address,
+            timestamp: unix_now(),
+            name: handshake
+                .identity
+                .as_ref()
+                .and_then(|i| i.display_name.clone()),
+            xuid: handshake.identity.as_ref().and_then(|i| i.xuid.clone()),
+            bytes_c2s: self.bytes_c2s.load(Ordering::Relaxed),
+            bytes_s2c: self.bytes_s2c.load(Ordering::Relaxed),
+            geo: self.geo.clone(),
+            protocol_version: handshake.protocol_version,
+            compression: handshake
+                .network_settings
+                .as_ref()
+                .map(|s| s.compression_algorithm.as_str().to_owned()),
+            compression_threshold: handshake.network_settings.map(|s| s.compression_threshold),
+            kick_message: match (event, self.eviction()) {
+                (HistoryEvent::End, Some(Eviction::Kick(message))) => Some(message),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// The upstream a client was last routed to.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Affinity {
+    pub client_address: SocketAddr,
+
+    pub upstream_address: SocketAddr,
+
+    /// Unix timestamp in seconds.
+    pub last_seen: u64,
+}
+
+struct Sticky {
+    upstream_address: SocketAddr,
+
+    /// The number of live sessions from the IP.
+    live: usize,
+
+    last_seen: Instant,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SessionSnapshot {
+    /// Unix timestamp in seconds.
+    saved_at: u64,
+
+    affinities: Vec<Affinity>,
+}
+
+impl SessionRegistry {
+    /// Create a registry. Session starts and ends are appended to `history` when given,
+    /// ended sessions to `access_log` when given, and sessions are annotated from `geoip`
+    /// when given. Every session is throttled to `bandwidth` and its fair share of
+    /// `shared_bandwidth`.
+    pub fn new(
+        affinity_ttl: Duration,
+        sticky_ttl: Duration,
+        history: Option<SessionHistory>,
+        access_log: Option<AccessLog>,
+        geoip: Option<GeoIp>,
+        bandwidth: BandwidthConfig,
+        shared_bandwidth: Arc<SharedBandwidth>,
+    ) -> Self {
+        Self {
+            sessions: Default::default(),
+            affinities: Default::default(),
+            sticky: Default::default(),
+            sticky_ttl,
+            upstream_loads: Default::default(),
+            affinity_ttl,
+            history,
+            access_log,
+            geoip,
+            bandwidth,
+            shared_bandwidth,
+        }
+    }
+
+    /// Look up where `ip` is located, if GeoIP is configured.
+    pub fn geo(&self, ip: IpAddr) -> GeoInfo {
+        self.geoip
+            .as_ref()
+            .map(|g| g.lookup(ip))
+            .unwrap_or_default()
+    }
+
+    pub async fn register(
+        &self,
+        id: String,
+        client_address: SocketAddr,
+        upstream_address: SocketAddr,
+    ) -> Arc<Session> {
+        let session = Arc::new(Session {
+            id,
+            client_address,
+            upstream_address,
+            connected_at: SystemTime::now(),
+            geo: self.geo(client_address.ip()),
+            bytes_c2s: AtomicU64::new(0),
+            bytes_s2c: AtomicU64::new(0),
+            last_activity: AtomicU64::new(unix_now()),
+            bandwidth: SessionBandwidth::new(&self.bandwidth, self.shared_bandwidth.clone()),
+            handshake: Default::default(),
+            terminate: CancellationToken::new(),
+            evicted: CancellationToken::new(),
+            eviction: Default::default(),
+            end_reason: Default::default(),
+        });
+
+        self.sessions
+            .write()
+            .await
+            .insert(client_address, session.clone());
+        *self
+            .upstream_loads
+            .lock()
+            .unwrap()
+            .entry(upstream_address)
+            .or_default() += 1;
+        if !self.sticky_ttl.is_zero() {
+            let mut sticky = self.sticky.lock().unwrap();
+            let entry = sticky.entry(client_address.ip()).or_insert(Sticky {
+                upstream_address,
+                live: 0,
+                last_seen: Instant::now(),
+            });
+            entry.upstream_address = upstream_address;
+            entry.live += 1;
+            entry.last_seen = Instant::now();
+        }
+
+        self.affinities.write().await.insert(
+            client_address,
+            Affinity {
+                client_address,
+                upstream_address,
+                last_seen: unix_now(),
+            },
+        );
+
+        self.append_history(session.history_record(HistoryEvent::Start))
+            .await;
+        EVENTS.push(
+            "session_start",
+            format!("{client_address} connected to {upstream_address}."),
+        );
+
+        session
+    }
+
+    pub async fn unregister(&self, session: &Session) {
+        {
+            // The endpoint may already be taken over by a newer session.
+            let mut sessions = self.sessions.write().await;
+            if sessions
+                .get(&session.client_address)
+                .is_some_and(|s| s.id == session.id)
+            {
+                sessions.remove(&session.client_address);
+            }
+        }
+        if let Some(load) = self
+            .upstream_loads
+            .lock()
+            .unwrap()
+            .get_mut(&session.upstream_address)
+        {
+            *load = load.saturating_sub(1);
+        }
+        if !self.sticky_ttl.is_zero() {
+            let mut sticky = self.sticky.lock().unwrap();
+            if let Some(entry) = sticky.get_mut(&session.client_address.ip()) {
+                entry.live = entry.live.saturating_sub(1);
+                entry.last_seen = Instant::now();
+            }
+            sticky.retain(|_, s| s.live > 0 || s.last_seen.elapsed() <= self.sticky_ttl);
+        }
+        let record = session.history_record(HistoryEvent::End);
+        let mut message = format!(
+            "{} ({}) disconnected after {} bytes in and {} bytes out.",
+            session.client_address,
+            record.name.as_deref().unwrap_or("-"),
+            record.bytes_c2s,
+            record.bytes_s2c
+        );
+        if let Some(kick_message) = &record.kick_message {
+            message.push_str(&format!(" Kicked: {kick_message}"));
+        }
+        EVENTS.push("session_end", message);
+        if let Some(access_log) = &self.access_log
+            && let Err(err) = access_log.append(&session.access_record(&record)).await
+        {
+            tracing::error!("Cannot write the access log: {err}");
+        }
+        self.append_history(record).await;
+
+        let now = unix_now();
+        let mut affinities = self.affinities.write().await;
+        if let Some(affinity) = affinities.get_mut(&session.client_address) {
+            affinity.last_seen = now;
+        }
+
+        // Forget expired affinities so the table does not grow unbounded.
+        affinities.retain(|_, a| now.saturating_sub(a.last_seen) <= self.affinity_ttl.as_secs());
+    }
+
+    /// Tear down the live session of `client_address`, if any, so a reconnecting client
+    /// does not leave a zombie upstream connection behind.
+    pub async fn terminate(&self, client_address: &SocketAddr) -> Option<Arc<Session>> {
+        let session = self.sessions.read().await.get(client_address).cloned()?;
+        session.terminate.cancel();
+
+        Some(session)
+    }
+
+    /// Another live session which logged in as the same player or from the same game
+    /// install as `identity`.
+    ///
+    /// Only an authenticated session counts, so a client which claims someone else's
+    /// XUID or ClientRandomId cannot hold that player out.
+    pub async fn find_duplicate(
+        &self,
+        session: &Session,
+        identity: &LoginIdentity,
+    ) -> Option<Arc<Session>> {
+        self.sessions
+            .read()
+            .await
+            .values()
+            .find(|other| {
+                other.id != session.id
+                    && other
+                        .handshake
+                        .lock()
+                        .unwrap()
+                        .identity
+                        .as_ref()
+                        .is_some_and(|i| i.authenticated && i.is_same(identity))
+            })
+            .cloned()
+    }
+
+    pub async fn sessions(&self) -> Vec<Arc<Session>> {
+        self.sessions.read().await.values().cloned().collect()
+    }
+
+    /// Get the number of live sessions on `upstream`.
+    pub fn upstream_load(&self, upstream: &SocketAddr) -> usize {
+        self.upstream_loads
+            .lock()
+            .unwrap()
+            .get(upstream)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Get the upstream the IP was routed to, if it still has a session or its last
+    /// session ended within the sticky TTL.
+    pub fn sticky(&self, ip: IpAddr) -> Option<SocketAddr> {
+        self.sticky
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .filter(|s| s.live > 0 || s.last_seen.elapsed() <= self.sticky_ttl)
+            .map(|s| s.upstream_address)
+    }
+
+    /// Get the upstream the client was routed to before, if any.
+    pub async fn affinity(&self, client_address: &SocketAddr) -> Option<SocketAddr> {
+        self.affinities
+            .read()
+            .await
+            .get(client_address)
+            .filter(|a| unix_now().saturating_sub(a.last_seen) <= self.affinity_ttl.as_secs())
+            .map(|a| a.upstream_address)
+    }
+
+    /// Write the affinity table to `path`, marking every live session as seen now.
+    pub async fn save_snapshot(&self, path: &Path) -> CCProxyResult<usize> {
+        let now = unix_now();
+
+        let mut affinities = self.affinities.write().await;
+        for session in self.sessions.read().await.values() {
+            if let Some(affinity) = affinities.get_mut(&session.client_address) {
+                affinity.last_seen = now;
+            }
+        }
+        affinities.retain(|_, a| now.saturating_sub(a.last_seen) <= self.affinity_ttl.as_secs());
+
+        let snapshot = SessionSnapshot {
+            saved_at: now,
+            affinities: affinities.values().cloned().collect(),
+        };
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::write(path, serde_yaml::to_string(&snapshot)?).await?;
+
+        Ok(snapshot.affinities.len())
+    }
+
+    /// Restore the affinity table from `path`, skipping expired entries.
+    ///
+    /// The snapshot is removed after loading so a stale table is never applied twice.
+    pub async fn load_snapshot(&self, path: &Path) -> CCProxyResult<usize> {
+        if !path.exists() {
+            return Ok(0);
+        }
+
+        let snapshot: SessionSnapshot =
+            serde_yaml::from_str(&tokio::fs::read_to_string(path).await?)?;
+        tokio::fs::remove_file(path).await?;
+
+        let now = unix_now();
+        let mut affinities = self.affinities.write().await;
+        for affinity in snapshot.affinities {
+            if now.saturating_sub(affinity.last_seen) <= self.affinity_ttl.as_secs() {
+                affinities.insert(affinity.client_address, affinity);
+            }
+        }
+
+        Ok(affinities.len())
+    }
+
+    async fn append_history(&self, record: HistoryRecord) {
+        if let Some(history) = &self.history
+            && let Err(err) = history.append(&record).await
+        {
+            tracing::error!("Cannot write the session history: {err}");
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,317 @@
+use crate::config::{MtuConfig, TproxyConfig};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::network::admission::Admission;
+use crate::network::proxy_protocol::InboundProxyProtocol;
+use crate::network::session::SessionRegistry;
+use std::sync::Arc;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// Forward Bedrock traffic intercepted by the iptables TPROXY target to its original
+/// destination, keeping the client address as the source so backends see real IPs.
+///
+/// The proxy must run as the default gateway of the backends with `CAP_NET_ADMIN` and
+/// policy routing which delivers both directions to the transparent sockets, e.g.:
+///
+/// ```text
+/// iptables -t mangle -A PREROUTING -p udp -m socket --transparent -j MARK --set-mark 1
+/// iptables -t mangle -A PREROUTING -p udp --dport 19132 -j TPROXY --on-port 19140 --tproxy-mark 1
+/// ip rule add fwmark 1 lookup 100
+/// ip route add local 0.0.0.0/0 dev lo table 100
+/// ```
+///
+/// Datagrams are relayed without terminating RakNet, and a flow is only opened by a
+/// RakNet offline message from a client which `admission` lets in. The MTU of each
+/// client is capped by `mtu`, by its GeoIP info from `sessions`, and `inbound` takes the
+/// client address from the header of a load balancer. Only IPv4 is supported.
+#[cfg(target_os = "linux")]
+pub async fn run_tproxy_gateway(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: TproxyConfig,
+    mtu: Option<MtuConfig>,
+    inbound: Arc<InboundProxyProtocol>,
+    sessions: Arc<SessionRegistry>,
+    admission: Arc<Admission>,
+) -> CCProxyResult<()> {
+    linux::run(sub_sys, config, mtu, inbound, sessions, admission).await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn run_tproxy_gateway(
+    _sub_sys: SubsystemHandle<CCProxyError>,
+    _config: TproxyConfig,
+    _mtu: Option<MtuConfig>,
+    _inbound: Arc<InboundProxyProtocol>,
+    _sessions: Arc<SessionRegistry>,
+    _admission: Arc<Admission>,
+) -> CCProxyResult<()> {
+    Err(CCProxyError::TproxyUnsupported)
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use crate::config::{MtuConfig, TproxyConfig};
+    use crate::error::{CCProxyError, CCProxyResult};
+    use crate::metrics::{
+        CounterHandle, DATAGRAMS_DROPPED_TOTAL, METRICS, SESSIONS_REFUSED_TOTAL,
+        TPROXY_BYTES_TOTAL, TPROXY_FLOWS_ACTIVE,
+    };
+    use crate::network::admission::{Admission, AdmissionSlot};
+    use crate::network::proxy_protocol::InboundProxyProtocol;
+    use crate::network::raknet::{clamp_open_connection_reply_1, is_valid_client_datagram};
+    use crate::network::session::SessionRegistry;
+    use nix::sys::socket::{
+        AddressFamily, ControlMessageOwned, MsgFlags, SockFlag, SockType, SockaddrIn, bind,
+        recvmsg, setsockopt, socket, sockopt,
+    };
+    use std::collections::HashMap;
+    use std::io::IoSliceMut;
+    use std::net::{SocketAddr, SocketAddrV4};
+    use std::os::fd::AsRawFd;
+    use std::sync::{Arc, LazyLock, Mutex};
+    use std::time::Duration;
+    use tokio::io::Interest;
+    use tokio::net::UdpSocket;
+    use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+
+    // The counters of every relayed datagram.
+    static TPROXY_BYTES_C2S: LazyLock<CounterHandle> =
+        LazyLock::new(|| METRICS.counter_handle(&TPROXY_BYTES_TOTAL, &[("direction", "c2s")]));
+    static TPROXY_BYTES_S2C: LazyLock<CounterHandle> =
+        LazyLock::new(|| METRICS.counter_handle(&TPROXY_BYTES_TOTAL, &[("direction", "s2c")]));
+
+    /// The upstream legs of the open flows by the client and the original destination.
+    type Flows = Arc<Mutex<HashMap<(SocketAddr, SocketAddr), Arc<UdpSocket>>>>;
+
+    pub async fn run(
+        sub_sys: SubsystemHandle<CCProxyError>,
+        config: TproxyConfig,
+        mtu: Option<MtuConfig>,
+        inbound: Arc<InboundProxyProtocol>,
+        sessions: Arc<SessionRegistry>,
+        admission: Arc<Admission>,
+    ) -> CCProxyResult<()> {
+        let socket = bind_transparent(config.address, true)?;
+        tracing::info!("The TPROXY gateway is started on {}.", config.address);
+
+        let flows = Flows::default();
+        let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+
+        let mut buf = vec![0u8; 2048];
+        loop {
+            tokio::select! {
+                received = recv_with_orig_dst(&socket, &mut buf) => {
+                    let (len, peer, destination) = match received {
+                        Ok(received) => received,
+                        Err(err) => {
+                            tracing::debug!("Cannot receive a datagram on the TPROXY gateway: {err}");
+                            continue;
+                        }
+                    };
+                    // A trusted load balancer names the client in a PROXY protocol header.
+                    let Some((client, packet)) = inbound.strip(peer, &buf[..len]) else {
+                        continue;
+                    };
+                    let len = packet.len();
+
+                    let upstream = flows.lock().unwrap().get(&(client, destination)).cloned();
+                    // Only an offline message opens a flow.
+                    if !is_valid_client_datagram(packet, upstream.is_some()) {
+                        METRICS.counter_add(&DATAGRAMS_DROPPED_TOTAL, &[("reason", "invalid")], 1.0);
+                        continue;
+                    }
+                    let upstream = match upstream {
+                        Some(upstream) => upstream,
+                        None => {
+                            let slot = match admission.check(&client) {
+                                Ok(slot) => slot,
+                                Err(refusal) => {
+                                    METRICS.counter_add(&SESSIONS_REFUSED_TOTAL, &[("reason", refusal.reason())], 1.0);
+                                    continue;
+                                }
+                            };
+
+                            let max_mtu = mtu.as_ref().and_then(|m| m.max_for(&sessions.geo(client.ip())));
+                            match open_flow(&sub_sys, &flows, peer, client, destination, idle_timeout, max_mtu, slot).await {
+                                Ok(upstream) => upstream,
+                                Err(err) => {
+                                    tracing::error!("Cannot open the TPROXY flow from ({client}) to ({destination}): {err}");
+                                    continue;
+                                }
+                            }
+                        }
+                    };
+
+                    if let Err(err) = upstream.send(packet).await {
+                        tracing::debug!("Cannot forward a datagram from ({client}) to ({destination}): {err}");
+                        continue;
+                    }
+                    TPROXY_BYTES_C2S.add(len as u64);
+                },
+                // Shutdown handler
+                _ = sub_sys.on_shutdown_requested() => {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Open the legs of a new flow and relay the replies to `peer`, which is the client or
+    /// its load balancer, until it is idle. The client holds `slot` for as long as the
+    /// flow is open.
+    #[allow(clippy::too_many_arguments)]
+    async fn open_flow(
+        sub_sys: &SubsystemHandle<CCProxyError>,
+        flows: &Flows,
+        peer: SocketAddr,
+        client: SocketAddr,
+        destination: SocketAddr,
+        idle_timeout: Duration,
+        max_mtu: Option<u16>,
+        slot: AdmissionSlot,
+    ) -> CCProxyResult<Arc<UdpSocket>> {
+        // Sent from the client address so the backend sees the real source.
+        let upstream = bind_transparent(client, false)?;
+        upstream.connect(destination).await?;
+        let upstream = Arc::new(upstream);
+        let std_upstream = upstream.clone();
+        // Replies are sent from the original destination, as the client expects.
+        let reply = bind_transparent(destination, false)?;
+
+        flows
+            .lock()
+            .unwrap()
+            .insert((client, destination), upstream.clone());
+
+        let flows = flows.clone();
+        sub_sys.start(SubsystemBuilder::new(
+            format!("Tproxy_{client}_{destination}"),
+            move |sub| async move {
+                let _slot = slot;
+                METRICS.gauge_add(&TPROXY_FLOWS_ACTIVE, &[], 1.0);
+                let result = relay(
+                    &sub,
+                    &std_upstream,
+                    &reply,
+                    peer,
+                    client,
+                    destination,
+                    max_mtu,
+                    idle_timeout,
+                )
+                .await;
+                METRICS.gauge_add(&TPROXY_FLOWS_ACTIVE, &[], -1.0);
+
+                flows.lock().unwrap().remove(&(client, destination));
+
+                // A broken flow only ends itself, not the gateway.
+                if let Err(err) = result {
+                    tracing::debug!(
+                        "The TPROXY flow from ({client}) to ({destination}) is closed: {err}"
+                    );
+                }
+
+                Ok::<_, CCProxyError>(())
+            },
+        ));
+
+        Ok(upstream)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn relay(
+        sub_sys: &SubsystemHandle<CCProxyError>,
+        upstream: &UdpSocket,
+        reply: &UdpSocket,
+        peer: SocketAddr,
+        client: SocketAddr,
+        destination: SocketAddr,
+        max_mtu: Option<u16>,
+        idle_timeout: Duration,
+    ) -> CCProxyResult<()> {
+        let mut buf = vec![0u8; 2048];
+        loop {
+            tokio::select! {
+                received = tokio::time::timeout(idle_timeout, upstream.recv(&mut buf)) => {
+                    let Ok(received) = received else {
+                        tracing::debug!("The TPROXY flow from ({client}) to ({destination}) is idle.");
+                        break;
+                    };
+
+                    let len = received?;
+                    let clamped = max_mtu.and_then(|m| clamp_open_connection_reply_1(&buf[..len], m));
+                    reply.send_to(clamped.as_deref().unwrap_or(&buf[..len]), peer).await?;
+                    TPROXY_BYTES_S2C.add(len as u64);
+                },
+                // Shutdown handler
+                _ = sub_sys.on_shutdown_requested() => {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bind a UDP socket which may use a non-local address and receive packets which
+    /// were redirected by TPROXY.
+    fn bind_transparent(address: SocketAddr, recv_orig_dst: bool) -> CCProxyResult<UdpSocket> {
+        let SocketAddr::V4(address) = address else {
+            return Err(CCProxyError::TproxyUnsupported);
+        };
+
+        let fd = socket(
+            AddressFamily::Inet,
+            SockType::Datagram,
+            SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+            None,
+        )
+        .map_err(std::io::Error::from)?;
+        setsockopt(&fd, sockopt::IpTransparent, &true).map_err(std::io::Error::from)?;
+        // Every flow to the same destination binds its reply socket to the same address.
+        setsockopt(&fd, sockopt::ReuseAddr, &true).map_err(std::io::Error::from)?;
+        if recv_orig_dst {
+            setsockopt(&fd, sockopt::Ipv4OrigDstAddr, &true).map_err(std::io::Error::from)?;
+        }
+        bind(fd.as_raw_fd(), &SockaddrIn::from(address)).map_err(std::io::Error::from)?;
+
+        Ok(UdpSocket::from_std(std::net::UdpSocket::from(fd))?)
+    }
+
+    /// Receive a datagram with its source and the destination it was sent to before
+    /// TPROXY redirected it.
+    async fn recv_with_orig_dst(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> std::io::Result<(usize, SocketAddr, SocketAddr)> {
+        socket
+            .async_io(Interest::READABLE, || {
+                let mut iov = [IoSliceMut::new(buf)];
+                let mut cmsg = nix::cmsg_space!(nix::libc::sockaddr_in);
+                let msg = recvmsg::<SockaddrIn>(
+                    socket.as_raw_fd(),
+                    &mut iov,
+                    Some(cmsg.as_mut_slice()),
+                    MsgFlags::empty(),
+                )?;
+
+                let client = msg
+                    .address
+                    .map(|a| SocketAddr::V4(SocketAddrV4::from(a)))
+                    .ok_or(std::io::ErrorKind::InvalidData)?;
+                let destination = msg
+                    .cmsgs()?
+                    .find_map(|c| match c {
+                        ControlMessageOwned::Ipv4OrigDstAddr(addr) => {
+                            Some(SocketAddr::V4(SockaddrIn::from(addr).into()))
+                        }
+                        _ => None,
+                    })
+                    .ok_or(std::io::ErrorKind::InvalidData)?;
+
+                Ok((msg.bytes, client, destination))
+            })
+            .await
+    }
+}
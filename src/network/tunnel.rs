@@ -0,0 +1,982 @@
+use crate::config::{MtuConfig, ProxyProtocolVersion, TunnelConfig};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{
+    CounterHandle, DATAGRAMS_DROPPED_TOTAL, METRICS, SESSIONS_TOTAL, TUNNEL_BYTES_TOTAL,
+    TUNNEL_FLOWS_ACTIVE,
+};
+use crate::network::admission::AdmissionSlot;
+use crate::network::datagram_filter::{DatagramFilter, SequenceTracker};
+use crate::network::outbound::Outbound;
+use crate::network::ping_guard::{PingGuard, PingVerdict};
+use crate::network::proxy_protocol::{
+    InboundProxyProtocol, RaknetTlvs, encode_flow_header, encode_udp_header,
+};
+use crate::network::raknet::{clamp_open_connection_reply_1, is_unconnected_ping};
+use crate::network::rate_limit::Direction;
+use crate::network::session::{Session, SessionRegistry, new_session_id, session_span};
+use quinn::rustls;
+use quinn::rustls::pki_types::pem::PemObject;
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+/// The name in the generated certificate, which the edge verifies the origin by.
+const SERVER_NAME: &str = "ccproxy-tunnel";
+
+const ALPN: &[u8] = b"ccproxy-tunnel";
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The tunnel is lost after this many milliseconds without packets.
+const MAX_IDLE_TIMEOUT_MS: u32 = 30_000;
+
+/// How often the edge closes the flows of evicted sessions.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+const CONTROL_OPEN: u8 = 0;
+
+const CONTROL_CLOSE: u8 = 1;
+
+// The counters of every relayed datagram.
+static EDGE_BYTES_C2S: LazyLock<CounterHandle> = LazyLock::new(|| {
+    METRICS.counter_handle(
+        &TUNNEL_BYTES_TOTAL,
+        &[("role", "edge"), ("direction", "c2s")],
+    )
+});
+static EDGE_BYTES_S2C: LazyLock<CounterHandle> = LazyLock::new(|| {
+    METRICS.counter_handle(
+        &TUNNEL_BYTES_TOTAL,
+        &[("role", "edge"), ("direction", "s2c")],
+    )
+});
+static ORIGIN_BYTES_C2S: LazyLock<CounterHandle> = LazyLock::new(|| {
+    METRICS.counter_handle(
+        &TUNNEL_BYTES_TOTAL,
+        &[("role", "origin"), ("direction", "c2s")],
+    )
+});
+static ORIGIN_BYTES_S2C: LazyLock<CounterHandle> = LazyLock::new(|| {
+    METRICS.counter_handle(
+        &TUNNEL_BYTES_TOTAL,
+        &[("role", "origin"), ("direction", "s2c")],
+    )
+});
+
+/// A message on the control stream, which the edge opens at the start of the tunnel.
+/// The datagrams of a flow are QUIC datagrams prefixed with its ID.
+#[derive(Debug)]
+enum ControlMessage {
+    /// A client is accepted by the edge (edge to origin).
+    Open { id: u32, client: SocketAddr },
+
+    /// A flow is ended (both ways).
+    Close { id: u32 },
+}
+
+impl ControlMessage {
+    fn encode(&self) -> Vec<u8> {
+        let (kind, id, client) = match self {
+            Self::Open { id, client } => (CONTROL_OPEN, *id, client.to_string()),
+            Self::Close { id } => (CONTROL_CLOSE, *id, String::new()),
+        };
+
+        let mut message = vec![kind];
+        message.extend_from_slice(&id.to_be_bytes());
+        message.push(client.len() as u8);
+        message.extend_from_slice(client.as_bytes());
+
+        message
+    }
+
+    async fn read(stream: &mut quinn::RecvStream) -> CCProxyResult<Self> {
+        let mut header = [0u8; 6];
+        stream.read_exact(&mut header).await.map_err(tunnel_error)?;
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+
+        let mut client = vec![0u8; header[5] as usize];
+        stream.read_exact(&mut client).await.map_err(tunnel_error)?;
+
+        match header[0] {
+            CONTROL_OPEN => {
+                let client = String::from_utf8_lossy(&client)
+                    .parse()
+                    .map_err(tunnel_error)?;
+                Ok(Self::Open { id, client })
+            }
+            CONTROL_CLOSE => Ok(Self::Close { id }),
+            kind => Err(tunnel_error(format!("unknown control message ({kind})"))),
+        }
+    }
+}
+
+async fn send_control(
+    stream: &mut quinn::SendStream,
+    message: ControlMessage,
+) -> CCProxyResult<()> {
+    stream
+        .write_all(&message.encode())
+        .await
+        .map_err(tunnel_error)
+}
+
+/// Read the control messages of `stream` in the background, as reading is not
+/// cancellation safe in `tokio::select!`. The channel is closed with the stream.
+fn start_control_reader(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    mut stream: quinn::RecvStream,
+) -> mpsc::UnboundedReceiver<ControlMessage> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    sub_sys.start(SubsystemBuilder::new(
+        "TunnelControl",
+        move |sub| async move {
+            loop {
+                tokio::select! {
+                    message = ControlMessage::read(&mut stream) => {
+                        match message {
+                            Ok(message) => if sender.send(message).is_err() {
+                                break;
+                            },
+                            Err(err) => {
+                                tracing::debug!("The tunnel control stream is closed: {err}");
+                                break;
+                            }
+                        }
+                    },
+                    // Shutdown handler
+                    _ = sub.on_shutdown_requested() => {
+                        break;
+                    }
+                }
+            }
+
+            Ok::<_, CCProxyError>(())
+        },
+    ));
+
+    receiver
+}
+
+/// Prefix `payload` with the ID of its flow.
+fn encode_datagram(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = id.to_be_bytes().to_vec();
+    datagram.extend_from_slice(payload);
+
+    datagram
+}
+
+fn decode_datagram(datagram: &[u8]) -> Option<(u32, &[u8])> {
+    let (id, payload) = datagram.split_first_chunk::<4>()?;
+
+    Some((u32::from_be_bytes(*id), payload))
+}
+
+/// Forward the clients on `address` to the origin over one QUIC connection, which is
+/// reconnected when it is lost. A flow is only opened by a RakNet offline message which
+/// `admit` accepts, and ends when the origin closes it or the session is evicted.
+///
+/// Datagrams are checked against `filter`, and unconnected pings against `pings`. `admit`
+/// returns `None` to refuse the client, and records the reason itself. The MTU of each
+/// client is capped by `mtu`, and `inbound` takes the client address from the header of
+/// a load balancer.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_tunnel_edge<F>(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: TunnelConfig,
+    address: SocketAddr,
+    sessions: Arc<SessionRegistry>,
+    filter: Arc<DatagramFilter>,
+    pings: Arc<PingGuard>,
+    mtu: Option<MtuConfig>,
+    inbound: Arc<InboundProxyProtocol>,
+    admit: F,
+) -> CCProxyResult<()>
+where
+    F: Fn(SocketAddr) -> Option<AdmissionSlot>,
+{
+    let (certificates, key) = load_identity(&config)?;
+    let socket = UdpSocket::bind(address).await?;
+    let local_address = if config.address.is_ipv4() {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)
+    } else {
+        SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0)
+    };
+    let mut endpoint = quinn::Endpoint::client(local_address)?;
+    endpoint.set_default_client_config(client_config(certificates, key)?);
+    tracing::info!(
+        "The tunnel edge is started on {address} for the origin ({}).",
+        config.address
+    );
+
+    loop {
+        let connecting = endpoint
+            .connect(config.address, SERVER_NAME)
+            .map_err(tunnel_error)?;
+        let connection = tokio::select! {
+            connection = connecting => connection,
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        };
+
+        match connection {
+            Ok(connection) => {
+                tracing::info!(
+                    "The tunnel to the origin ({}) is established.",
+                    config.address
+                );
+                let mut flows = EdgeFlows::default();
+                let result = relay_edge(
+                    &sub_sys,
+                    &socket,
+                    &connection,
+                    &sessions,
+                    &filter,
+                    &pings,
+                    mtu.as_ref(),
+                    &inbound,
+                    &admit,
+                    &mut flows,
+                )
+                .await;
+                for flow in flows.by_client.into_values() {
+                    close_edge_flow(&sessions, flow).await;
+                }
+                connection.close(0u32.into(), b"");
+
+                if let Err(err) = result {
+                    tracing::error!(
+                        "The tunnel to the origin ({}) is lost: {err}",
+                        config.address
+                    );
+                }
+            }
+            Err(err) => {
+                tracing::error!("Cannot connect to the origin ({}): {err}", config.address);
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {},
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    endpoint.close(0u32.into(), b"");
+
+    Ok(())
+}
+
+/// A client of the edge, which the origin knows by `id`.
+struct EdgeFlow {
+    id: u32,
+
+    /// The client or its load balancer, which the replies are sent to.
+    peer: SocketAddr,
+
+    session: Arc<Session>,
+
+    sequence: SequenceTracker,
+
+    max_mtu: Option<u16>,
+
+    /// Given back when the flow is closed.
+    _slot: AdmissionSlot,
+}
+
+#[derive(Default)]
+struct EdgeFlows {
+    by_client: HashMap<SocketAddr, EdgeFlow>,
+
+    by_id: HashMap<u32, SocketAddr>,
+
+    next_id: u32,
+}
+
+impl EdgeFlows {
+    fn remove(&mut self, id: u32) -> Option<EdgeFlow> {
+        let client = self.by_id.remove(&id)?;
+        self.by_client.remove(&client)
+    }
+}
+
+async fn close_edge_flow(sessions: &SessionRegistry, flow: EdgeFlow) {
+    METRICS.gauge_add(&TUNNEL_FLOWS_ACTIVE, &[("role", "edge")], -1.0);
+    sessions.unregister(&flow.session).await;
+    tracing::info!(
+        "The client ({}) is disconnected.",
+        flow.session.client_address
+    );
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn relay_edge<F>(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    socket: &UdpSocket,
+    connection: &quinn::Connection,
+    sessions: &Arc<SessionRegistry>,
+    filter: &DatagramFilter,
+    pings: &PingGuard,
+    mtu: Option<&MtuConfig>,
+    inbound: &InboundProxyProtocol,
+    admit: &F,
+    flows: &mut EdgeFlows,
+) -> CCProxyResult<()>
+where
+    F: Fn(SocketAddr) -> Option<AdmissionSlot>,
+{
+    let (mut control, control_stream) = connection.open_bi().await.map_err(tunnel_error)?;
+    let mut messages = start_control_reader(sub_sys, control_stream);
+
+    let mut sweep = tokio::time::interval(SWEEP_INTERVAL);
+    let mut buf = vec![0u8; 2048];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, peer) = match received {
+                    Ok(received) => received,
+                    // e.g. ICMP port unreachable of a previous reply on some platforms.
+                    Err(err) => {
+                        tracing::debug!("Cannot receive a datagram for the tunnel: {err}");
+                        continue;
+                    }
+                };
+                // A trusted load balancer names the client in a PROXY protocol header.
+                let Some((client, packet)) = inbound.strip(peer, &buf[..len]) else {
+                    continue;
+                };
+                let len = packet.len();
+
+                // Only an offline message opens a flow.
+                let violation = filter.check(
+                    packet,
+                    flows.by_client.get_mut(&client).map(|f| &mut f.sequence),
+                );
+                if let Some(violation) = violation
+                    && !filter.apply(client, violation).await
+                {
+                    continue;
+                }
+                if is_unconnected_ping(packet) {
+                    match pings.check(client.ip(), packet) {
+                        PingVerdict::Relay => (),
+                        PingVerdict::Drop => continue,
+                        PingVerdict::Answer(pong) => {
+                            if let Err(err) = socket.send_to(&pong, peer).await {
+                                tracing::debug!("Cannot answer the ping of ({client}): {err}");
+                            }
+                            continue;
+                        }
+                    }
+                }
+
+                let id = match flows.by_client.get(&client) {
+                    Some(flow) => flow.id,
+                    None => {
+                        let Some(slot) = admit(client) else {
+                            continue;
+                        };
+
+                        let id = flows.next_id;
+                        flows.next_id = flows.next_id.wrapping_add(1);
+                        send_control(&mut control, ControlMessage::Open { id, client }).await?;
+
+                        let session = sessions.register(new_session_id(), client, connection.remote_address()).await;
+                        METRICS.counter_add(&SESSIONS_TOTAL, &[], 1.0);
+                        METRICS.gauge_add(&TUNNEL_FLOWS_ACTIVE, &[("role", "edge")], 1.0);
+                        tracing::info!("The client ({client}) is forwarded to the origin.");
+
+                        flows.by_id.insert(id, client);
+                        flows.by_client.insert(
+                            client,
+                            EdgeFlow {
+                                id,
+                                peer,
+                                max_mtu: mtu.and_then(|m| m.max_for(&session.geo)),
+                                session,
+                                sequence: Default::default(),
+                                _slot: slot,
+                            },
+                        );
+                        id
+                    }
+                };
+
+                let Some(session) = flows.by_client.get(&client).map(|f| &f.session) else {
+                    continue;
+                };
+                if !session.bandwidth.try_send(Direction::C2s, len) {
+                    METRICS.counter_add(&DATAGRAMS_DROPPED_TOTAL, &[("reason", "bandwidth")], 1.0);
+                    continue;
+                }
+
+                match connection.send_datagram(encode_datagram(id, packet).into()) {
+                    Ok(()) => {
+                        session.add_c2s(len);
+                        EDGE_BYTES_C2S.add(len as u64);
+                    }
+                    Err(quinn::SendDatagramError::ConnectionLost(err)) => {
+                        return Err(tunnel_error(err));
+                    }
+                    // e.g. larger than the path MTU, which RakNet retries smaller.
+                    Err(err) => {
+                        tracing::debug!("Cannot forward a datagram from ({client}) to the origin: {err}");
+                    }
+                }
+            },
+            datagram = connection.read_datagram() => {
+                let datagram = datagram.map_err(tunnel_error)?;
+                let Some((id, payload)) = decode_datagram(&datagram) else {
+                    continue;
+                };
+                let Some(client) = flows.by_id.get(&id) else {
+                    continue;
+                };
+                pings.observe_reply(payload);
+                let Some(flow) = flows.by_client.get(client) else {
+                    continue;
+                };
+                let session = &flow.session;
+                if !session.bandwidth.try_send(Direction::S2c, payload.len()) {
+                    METRICS.counter_add(&DATAGRAMS_DROPPED_TOTAL, &[("reason", "bandwidth")], 1.0);
+                    continue;
+                }
+
+                let rewritten = pings
+                    .randomize_reply(payload)
+                    .or_else(|| flow.max_mtu.and_then(|m| clamp_open_connection_reply_1(payload, m)));
+                if let Err(err) = socket.send_to(rewritten.as_deref().unwrap_or(payload), flow.peer).await {
+                    tracing::debug!("Cannot forward a datagram from the origin to ({client}): {err}");
+                    continue;
+                }
+                session.add_s2c(payload.len());
+                EDGE_BYTES_S2C.add(payload.len() as u64);
+            },
+            message = messages.recv() => {
+                match message {
+                    Some(ControlMessage::Close { id }) => {
+                        if let Some(flow) = flows.remove(id) {
+                            close_edge_flow(sessions, flow).await;
+                        }
+                    }
+                    Some(message) => {
+                        tracing::debug!("The unexpected tunnel control message is ignored: {message:?}");
+                    }
+                    None => return Err(tunnel_error("the control stream is closed")),
+                }
+            },
+            _ = sweep.tick() => {
+                // Nothing can be injected into a forwarded flow, so the client just times out.
+                let ended = flows
+                    .by_client
+                    .values()
+                    .filter(|f| f.session.evicted.is_cancelled() || f.session.terminate.is_cancelled())
+                    .map(|f| f.id)
+                    .collect::<Vec<_>>();
+                for id in ended {
+                    send_control(&mut control, ControlMessage::Close { id }).await?;
+                    if let Some(flow) = flows.remove(id) {
+                        close_edge_flow(sessions, flow).await;
+                    }
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Accept the edges on `config.address` and relay each of their clients to the upstream
+/// which `select` picks for its real address, like the passthrough mode. A PROXY
+/// protocol header of the version which `proxy_protocol` selects for the upstream is sent
+/// first. The slot which `select` admits a client with is held until its flow ends.
+///
+/// The certificate is generated on the first start if it does not exist.
+pub async fn run_tunnel_origin<F, Fut, P>(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: TunnelConfig,
+    sessions: Arc<SessionRegistry>,
+    outbound: Arc<Outbound>,
+    select: F,
+    proxy_protocol: P,
+) -> CCProxyResult<()>
+where
+    F: Fn(SocketAddr) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Option<(SocketAddr, AdmissionSlot)>> + Send + 'static,
+    P: Fn(&SocketAddr) -> ProxyProtocolVersion + Clone + Send + Sync + 'static,
+{
+    generate_identity(&config)?;
+    let (certificates, key) = load_identity(&config)?;
+    let endpoint = quinn::Endpoint::server(server_config(certificates, key)?, config.address)?;
+    tracing::info!("The tunnel origin is started on {}.", config.address);
+
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    loop {
+        tokio::select! {
+            incoming = endpoint.accept() => {
+                let Some(incoming) = incoming else {
+                    break;
+                };
+                let edge = incoming.remote_address();
+
+                let edge_sessions = sessions.clone();
+                let edge_outbound = outbound.clone();
+                let edge_select = select.clone();
+                let edge_proxy_protocol = proxy_protocol.clone();
+                sub_sys.start(SubsystemBuilder::new(format!("TunnelEdge_{edge}"), move |sub| async move {
+                    let result = match incoming.await {
+                        Ok(connection) => {
+                            tracing::info!("The edge ({edge}) is connected.");
+                            serve_edge(&sub, &connection, &edge_sessions, &edge_outbound, idle_timeout, edge_select, edge_proxy_protocol).await
+                        }
+                        Err(err) => Err(tunnel_error(err)),
+                    };
+
+                    // A broken tunnel must not stop the origin.
+                    match result {
+                        Ok(()) => tracing::info!("The edge ({edge}) is disconnected."),
+                        Err(err) => tracing::warn!("The tunnel from the edge ({edge}) is lost: {err}"),
+                    }
+
+                    Ok::<_, CCProxyError>(())
+                }));
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    endpoint.close(0u32.into(), b"");
+
+    Ok(())
+}
+
+/// The upstream leg of a flow of an edge.
+struct OriginFlow {
+    upstream: Arc<UdpSocket>,
+
+    session: Arc<Session>,
+
+    /// The PROXY protocol header which the upstream is sent.
+    proxy_protocol: ProxyProtocolVersion,
+
+    /// Cancelled when the edge closes the flow.
+    closed: CancellationToken,
+}
+
+async fn serve_edge<F, Fut, P>(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    connection: &quinn::Connection,
+    sessions: &Arc<SessionRegistry>,
+    outbound: &Outbound,
+    idle_timeout: Duration,
+    select: F,
+    proxy_protocol: P,
+) -> CCProxyResult<()>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = Option<(SocketAddr, AdmissionSlot)>>,
+    P: Fn(&SocketAddr) -> ProxyProtocolVersion,
+{
+    let (mut control, control_stream) = tokio::select! {
+        accepted = connection.accept_bi() => accepted.map_err(tunnel_error)?,
+        // Shutdown handler
+        _ = sub_sys.on_shutdown_requested() => return Ok(()),
+    };
+    let mut messages = start_control_reader(sub_sys, control_stream);
+
+    let mut flows = HashMap::<u32, OriginFlow>::new();
+    let (ended_sender, mut ended) = mpsc::unbounded_channel();
+    let result = loop {
+        tokio::select! {
+            message = messages.recv() => {
+                match message {
+                    Some(ControlMessage::Open { id, client }) => {
+                        let Some((upstream_address, slot)) = select(client).await else {
+                            if let Err(err) = send_control(&mut control, ControlMessage::Close { id }).await {
+                                break Err(err);
+                            }
+                            continue;
+                        };
+
+                        match open_origin_flow(
+                            sub_sys,
+                            connection,
+                            sessions,
+                            outbound,
+                            id,
+                            client,
+                            upstream_address,
+                            slot,
+                            proxy_protocol(&upstream_address),
+                            idle_timeout,
+                            ended_sender.clone(),
+                        )
+                        .await
+                        {
+                            Ok(flow) => {
+                                flows.insert(id, flow);
+                            }
+                            Err(err) => {
+                                tracing::error!("Cannot open the tunnel flow from ({client}) to ({upstream_address}): {err}");
+                                if let Err(err) = send_control(&mut control, ControlMessage::Close { id }).await {
+                                    break Err(err);
+                                }
+                            }
+                        }
+                    }
+                    Some(ControlMessage::Close { id }) => {
+                        if let Some(flow) = flows.remove(&id) {
+                            flow.closed.cancel();
+                        }
+                    }
+                    None => break Err(tunnel_error("the control stream is closed")),
+                }
+            },
+            datagram = connection.read_datagram() => {
+                let datagram = match datagram {
+                    Ok(datagram) => datagram,
+                    Err(err) => break Err(tunnel_error(err)),
+                };
+                let Some((id, payload)) = decode_datagram(&datagram) else {
+                    continue;
+                };
+                // The datagrams may overtake the opening of their flow, and RakNet retries.
+                let Some(flow) = flows.get(&id) else {
+                    continue;
+                };
+                if !flow.session.bandwidth.try_send(Direction::C2s, payload.len()) {
+                    METRICS.counter_add(&DATAGRAMS_DROPPED_TOTAL, &[("reason", "bandwidth")], 1.0);
+                    continue;
+                }
+
+                // Each OpenConnectionRequest is preceded by a v2 header with the RakNet
+                // metadata which it carries.
+                let client = flow.session.client_address;
+                if flow.proxy_protocol == ProxyProtocolVersion::V2
+                    && let Some(tlvs) = RaknetTlvs::from_request(payload, client)
+                    && let Err(err) = flow.upstream.send(&encode_udp_header(client, connection.remote_address(), &tlvs)).await
+                {
+                    tracing::debug!("Cannot send the PROXY protocol header of ({client}) to ({}): {err}", flow.session.upstream_address);
+                    continue;
+                }
+                if let Err(err) = flow.upstream.send(payload).await {
+                    tracing::debug!("Cannot forward a datagram from ({}) to ({}): {err}", flow.session.client_address, flow.session.upstream_address);
+                    continue;
+                }
+                flow.session.add_c2s(payload.len());
+                ORIGIN_BYTES_C2S.add(payload.len() as u64);
+            },
+            Some(id) = ended.recv() => {
+                // The flow is still open on the edge unless the edge closed it.
+                if flows.remove(&id).is_some()
+                    && let Err(err) = send_control(&mut control, ControlMessage::Close { id }).await
+                {
+                    break Err(err);
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break Ok(());
+            }
+        }
+    };
+
+    for flow in flows.into_values() {
+        flow.closed.cancel();
+    }
+
+    result
+}
+
+/// Open the upstream leg of a new flow and relay the replies until it ends, when `ended`
+/// is sent its ID.
+#[allow(clippy::too_many_arguments)]
+async fn open_origin_flow(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    connection: &quinn::Connection,
+    sessions: &Arc<SessionRegistry>,
+    outbound: &Outbound,
+    id: u32,
+    client: SocketAddr,
+    upstream_address: SocketAddr,
+    slot: AdmissionSlot,
+    proxy_protocol: ProxyProtocolVersion,
+    idle_timeout: Duration,
+    ended: mpsc::UnboundedSender<u32>,
+) -> CCProxyResult<OriginFlow> {
+    let upstream = Arc::new(outbound.connect_udp(upstream_address).await?);
+    // The destination is the edge, which the client connected to. The metadata follows
+    // with the OpenConnectionRequests.
+    if proxy_protocol.is_enabled() {
+        upstream
+            .send(&encode_flow_header(
+                proxy_protocol,
+                client,
+                connection.remote_address(),
+                &RaknetTlvs::default(),
+            ))
+            .await?;
+    }
+
+    let session = sessions
+        .register(new_session_id(), client, upstream_address)
+        .await;
+    let closed = CancellationToken::new();
+    tracing::info!(
+        "The client ({client}) is relayed to the upstream ({upstream_address}) through the tunnel."
+    );
+
+    let relay_upstream = upstream.clone();
+    let relay_session = session.clone();
+    let relay_closed = closed.clone();
+    let connection = connection.clone();
+    let sessions = sessions.clone();
+    let span = session_span(&session.id, client, upstream_address);
+    sub_sys.start(SubsystemBuilder::new(
+        format!("TunnelFlow_{client}"),
+        move |sub| {
+            async move {
+                METRICS.counter_add(&SESSIONS_TOTAL, &[], 1.0);
+                METRICS.gauge_add(&TUNNEL_FLOWS_ACTIVE, &[("role", "origin")], 1.0);
+                let result = relay_origin(
+                    &sub,
+                    &relay_upstream,
+                    &connection,
+                    id,
+                    &relay_session,
+                    &relay_closed,
+                    idle_timeout,
+                )
+                .await;
+                METRICS.gauge_add(&TUNNEL_FLOWS_ACTIVE, &[("role", "origin")], -1.0);
+                drop(slot);
+
+                sessions.unregister(&relay_session).await;
+                ended.send(id).ok();
+                tracing::info!("The client ({client}) is disconnected.");
+
+                // A broken flow must not stop the tunnel.
+                if let Err(err) = result {
+                    tracing::debug!("The tunnel flow of the client ({client}) is broken: {err}");
+                }
+
+                Ok::<_, CCProxyError>(())
+            }
+            .instrument(span)
+        },
+    ));
+
+    Ok(OriginFlow {
+        upstream,
+        session,
+        proxy_protocol,
+        closed,
+    })
+}
+
+async fn relay_origin(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    upstream: &UdpSocket,
+    connection: &quinn::Connection,
+    id: u32,
+    session: &Session,
+    closed: &CancellationToken,
+    idle_timeout: Duration,
+) -> CCProxyResult<()> {
+    let client = session.client_address;
+
+    let mut buf = vec![0u8; 2048];
+    loop {
+        tokio::select! {
+            received = tokio::time::timeout(idle_timeout, upstream.recv(&mut buf)) => {
+                let Ok(received) = received else {
+                    tracing::debug!("The tunnel flow of the client ({client}) is idle.");
+                    session.set_end_reason("idle");
+                    break;
+                };
+
+                let len = received?;
+                session.bandwidth.throttle(Direction::S2c, len).await;
+                match connection.send_datagram(encode_datagram(id, &buf[..len]).into()) {
+                    Ok(()) => {
+                        session.add_s2c(len);
+                        ORIGIN_BYTES_S2C.add(len as u64);
+                    }
+                    Err(quinn::SendDatagramError::ConnectionLost(_)) => {
+                        session.set_end_reason("tunnel_lost");
+                        break;
+                    }
+                    Err(err) => {
+                        tracing::debug!("Cannot forward a datagram to the edge for ({client}): {err}");
+                    }
+                }
+            },
+            _ = closed.cancelled() => {
+                session.set_end_reason("edge_closed");
+                break;
+            },
+            _ = session.evicted.cancelled() => {
+                break;
+            },
+            _ = session.terminate.cancelled() => {
+                break;
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                session.set_end_reason("shutdown");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Generate the shared certificate of the tunnel unless it exists. It is copied to the
+/// edges by hand.
+fn generate_identity(config: &TunnelConfig) -> CCProxyResult<()> {
+    if config.certificate.exists() || config.key.exists() {
+        return Ok(());
+    }
+
+    let certified =
+        rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_owned()]).map_err(tunnel_error)?;
+    for path in [&config.certificate, &config.key] {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+    std::fs::write(&config.certificate, certified.cert.pem())?;
+    std::fs::write(&config.key, certified.key_pair.serialize_pem())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&config.key, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    tracing::info!(
+        "The tunnel certificate is generated at {} and {}. Copy both to the edges.",
+        config.certificate.display(),
+        config.key.display()
+    );
+
+    Ok(())
+}
+
+fn load_identity(
+    config: &TunnelConfig,
+) -> CCProxyResult<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+    let certificates = CertificateDer::pem_file_iter(&config.certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| {
+            tunnel_error(format!(
+                "cannot read the certificate ({}): {err}",
+                config.certificate.display()
+            ))
+        })?;
+    if certificates.is_empty() {
+        return Err(tunnel_error(format!(
+            "the certificate ({}) is empty",
+            config.certificate.display()
+        )));
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key).map_err(|err| {
+        tunnel_error(format!(
+            "cannot read the key ({}): {err}",
+            config.key.display()
+        ))
+    })?;
+
+    Ok((certificates, key))
+}
+
+/// Both sides trust the shared certificate only, so no other peer can join the tunnel.
+fn root_store(certificates: &[CertificateDer<'static>]) -> CCProxyResult<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for certificate in certificates {
+        roots.add(certificate.clone()).map_err(tunnel_error)?;
+    }
+
+    Ok(roots)
+}
+
+fn transport_config() -> Arc<quinn::TransportConfig> {
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    transport.max_idle_timeout(Some(quinn::VarInt::from_u32(MAX_IDLE_TIMEOUT_MS).into()));
+
+    Arc::new(transport)
+}
+
+fn server_config(
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> CCProxyResult<quinn::ServerConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+        Arc::new(root_store(&certificates)?),
+        provider.clone(),
+    )
+    .build()
+    .map_err(tunnel_error)?;
+
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(tunnel_error)?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certificates, key)
+        .map_err(tunnel_error)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls).map_err(tunnel_error)?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(transport_config());
+
+    Ok(config)
+}
+
+fn client_config(
+    certificates: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> CCProxyResult<quinn::ClientConfig> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = root_store(&certificates)?;
+
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(tunnel_error)?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certificates, key)
+        .map_err(tunnel_error)?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+
+    let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls).map_err(tunnel_error)?;
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(transport_config());
+
+    Ok(config)
+}
+
+fn tunnel_error(reason: impl ToString) -> CCProxyError {
+    CCProxyError::Tunnel {
+        reason: reason.to_string(),
+    }
+}
@@ -0,0 +1,169 @@
+use crate::admin::events::EVENTS;
+use crate::config::UnderAttackConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::metrics::{METRICS, UNDER_ATTACK};
+use crate::state::ProxyState;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio_graceful_shutdown::SubsystemHandle;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The connection attempts and drops of a listener since they were last measured.
+#[derive(Default)]
+pub struct AttackCounters {
+    attempts: AtomicU64,
+
+    drops: AtomicU64,
+}
+
+impl AttackCounters {
+    pub fn record_attempt(&self) {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a refused connection attempt or a dropped datagram.
+    pub fn record_drop(&self) {
+        self.drops.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> (u64, u64) {
+        (
+            self.attempts.swap(0, Ordering::Relaxed),
+            self.drops.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum AttackStatus {
+    Started,
+
+    Ended,
+}
+
+/// The body posted to the webhook.
+#[derive(Serialize)]
+struct AttackEvent<'a> {
+    listener: Option<&'a str>,
+
+    status: AttackStatus,
+
+    attempts_per_sec: f64,
+
+    drops_per_sec: f64,
+}
+
+/// Measure the rates of `counters` on an interval, and switch the under-attack mode of
+/// the listener on when one exceeds its threshold, or off once the cooldown passed.
+pub async fn run_under_attack_monitor(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: UnderAttackConfig,
+    counters: Arc<AttackCounters>,
+    state: Arc<ProxyState>,
+    listener: Option<String>,
+) -> CCProxyResult<()> {
+    let client = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()?;
+
+    let mut last_attack_at = None::<Instant>;
+    let mut measured_at = Instant::now();
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                let (attempts, drops) = counters.take();
+                let elapsed = measured_at.elapsed().as_secs_f64().max(f64::EPSILON);
+                measured_at = Instant::now();
+
+                let event = AttackEvent {
+                    listener: listener.as_deref(),
+                    status: AttackStatus::Started,
+                    attempts_per_sec: attempts as f64 / elapsed,
+                    drops_per_sec: drops as f64 / elapsed,
+                };
+                let attacked = config
+                    .max_attempts_per_sec
+                    .is_some_and(|max| event.attempts_per_sec > max)
+                    || config
+                        .max_drops_per_sec
+                        .is_some_and(|max| event.drops_per_sec > max);
+
+                if attacked {
+                    last_attack_at = Some(Instant::now());
+                    if !state.set_under_attack(true) {
+                        tracing::warn!(
+                            "The proxy server is under attack with {:.1} connection attempts and {:.1} drops per second, so the stricter posture is in effect.",
+                            event.attempts_per_sec,
+                            event.drops_per_sec
+                        );
+                        METRICS.gauge_add(&UNDER_ATTACK, &[], 1.0);
+                        EVENTS.push(
+                            "under_attack",
+                            format!(
+                                "Under attack with {:.1} connection attempts per second.",
+                                event.attempts_per_sec
+                            ),
+                        );
+                        notify(&client, &config, &event).await;
+                    }
+                } else if last_attack_at
+                    .is_some_and(|t| t.elapsed() >= Duration::from_secs(config.cooldown_secs))
+                {
+                    last_attack_at = None;
+                    if state.set_under_attack(false) {
+                        tracing::info!("The attack is over, so the usual posture is back.");
+                        METRICS.gauge_add(&UNDER_ATTACK, &[], -1.0);
+                        EVENTS.push("attack_over", "The attack is over.");
+                        let event = AttackEvent {
+                            status: AttackStatus::Ended,
+                            ..event
+                        };
+                        notify(&client, &config, &event).await;
+                    }
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    if state.set_under_attack(false) {
+        METRICS.gauge_add(&UNDER_ATTACK, &[], -1.0);
+    }
+
+    Ok(())
+}
+
+async fn notify(client: &reqwest::Client, config: &UnderAttackConfig, event: &AttackEvent<'_>) {
+    let Some(webhook) = &config.webhook else {
+        return;
+    };
+
+    let result = async {
+        let status = client
+            .post(webhook)
+            .header(CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(event)?)
+            .send()
+            .await?
+            .status();
+        if !status.is_success() {
+            tracing::error!("The under-attack webhook {webhook} responded with {status}.");
+        }
+
+        CCProxyResult::Ok(())
+    }
+    .await;
+
+    if let Err(err) = result {
+        tracing::error!("Cannot post the under-attack event to {webhook}: {err}");
+    }
+}
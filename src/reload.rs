@@ -0,0 +1,287 @@
+use crate::admin::ListenerCommand;
+use crate::config::{CCProxyConfig, UpstreamAddress};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::maintenance;
+use crate::metrics::{METRICS, SESSIONS_EVICTED_TOTAL};
+use crate::network::dns::UpstreamAddresses;
+use crate::network::session::{Eviction, SessionRegistry};
+use crate::state::ProxyState;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tokio_graceful_shutdown::{SubsystemBuilder, SubsystemHandle};
+
+/// How often a drained upstream is checked for remaining sessions.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reload the config of every listener whenever the process receives SIGHUP.
+#[cfg(unix)]
+pub async fn run_hangup_handler(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    admin: Arc<crate::admin::Admin>,
+) -> CCProxyResult<()> {
+    use crate::audit::Actor;
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                // Every listener logs the result of its own reload.
+                let result = admin.reload().await;
+                let actor = Actor::new("signal", None);
+                admin
+                    .audit(&actor, "reload", serde_json::json!({}), result.err().as_ref())
+                    .await;
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply the commands of the admin to a listener: reload its config, or enter or leave
+/// its maintenance mode. `None` is the main listener.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_config_reloader(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    profile: Option<String>,
+    listener: Option<String>,
+    config: Arc<CCProxyConfig>,
+    state: Arc<ProxyState>,
+    sessions: Arc<SessionRegistry>,
+    upstream_addresses: Arc<UpstreamAddresses>,
+    mut commands: mpsc::UnboundedReceiver<ListenerCommand>,
+) -> CCProxyResult<()> {
+    // The config which the next reload is compared with.
+    let mut previous = (*config).clone();
+
+    loop {
+        tokio::select! {
+            Some(command) = commands.recv() => match command {
+                ListenerCommand::Reload(reply) => {
+                    tracing::info!("The config is reloading...");
+
+                    let result = match load(profile.as_deref(), listener.as_deref()) {
+                        Ok(Some(reloaded)) => {
+                            reload(&sub_sys, &config, &previous, &reloaded, &state, &sessions, &upstream_addresses);
+                            previous = reloaded;
+                            Ok(())
+                        }
+                        Ok(None) => {
+                            tracing::warn!(
+                                "The listener ({}) is removed, which takes effect after a restart.",
+                                listener.as_deref().unwrap_or_default()
+                            );
+                            Ok(())
+                        }
+                        Err(err) => {
+                            tracing::error!("Cannot reload the config, so the current one is kept: {err}");
+                            Err(err)
+                        }
+                    };
+                    reply.send(result).ok();
+                }
+                ListenerCommand::Maintenance(maintenance) => {
+                    maintenance::set_maintenance(&sub_sys, &previous.proxy, &state, &sessions, maintenance);
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Load the config of a listener again, or `None` if it is not configured anymore.
+pub fn load(profile: Option<&str>, listener: Option<&str>) -> CCProxyResult<Option<CCProxyConfig>> {
+    let config = CCProxyConfig::init(profile)?;
+    config.validate()?;
+
+    Ok(match listener {
+        Some(name) => config
+            .all_listeners()
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, l)| config.with_listener(&l)),
+        None => Some(config),
+    })
+}
+
+/// Apply the changes of the upstream pool and the maintenance mode from `reloaded`.
+///
+/// An upstream which is removed from the pool of `config`, which the proxy server runs
+/// with, takes no new sessions. Its sessions are drained for `upstream.drain_timeout_secs`
+/// before they are transferred to the backup or kicked. An upstream which is added back
+/// is used again. The maintenance mode follows `proxy.maintenance.enabled` when it
+/// differs from the `previous` config. Other changes take effect after a restart.
+pub fn reload(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    config: &CCProxyConfig,
+    previous: &CCProxyConfig,
+    reloaded: &CCProxyConfig,
+    state: &Arc<ProxyState>,
+    sessions: &Arc<SessionRegistry>,
+    upstream_addresses: &Arc<UpstreamAddresses>,
+) {
+    let maintenance = reloaded.proxy.maintenance.enabled;
+    if maintenance != previous.proxy.maintenance.enabled {
+        maintenance::set_maintenance(sub_sys, &reloaded.proxy, state, sessions, maintenance);
+    }
+
+    drain_removed_upstreams(
+        sub_sys,
+        config,
+        reloaded,
+        state,
+        sessions,
+        upstream_addresses,
+    );
+}
+
+/// Drain the upstreams of `config` which `reloaded` removes, and stop draining the ones
+/// which it adds back.
+fn drain_removed_upstreams(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    config: &CCProxyConfig,
+    reloaded: &CCProxyConfig,
+    state: &Arc<ProxyState>,
+    sessions: &Arc<SessionRegistry>,
+    upstream_addresses: &Arc<UpstreamAddresses>,
+) {
+    let current = config
+        .upstream
+        .pool()
+        .into_iter()
+        .map(|s| s.address)
+        .collect::<Vec<_>>();
+    let upstreams = reloaded
+        .upstream
+        .pool()
+        .into_iter()
+        .map(|s| s.address)
+        .collect::<Vec<_>>();
+
+    if !current.iter().any(|u| upstreams.contains(u)) {
+        tracing::error!(
+            "The reloaded config removes every upstream, so none is drained. Restart the proxy server to switch to the new upstreams."
+        );
+        return;
+    }
+
+    for upstream in upstreams.iter().filter(|u| !current.contains(u)) {
+        tracing::warn!("The upstream ({upstream}) is added, which takes effect after a restart.");
+    }
+
+    let eviction = match &reloaded.upstream.backup {
+        Some(backup) => Eviction::Transfer {
+            host: backup.transfer_host.clone(),
+            port: backup.transfer_port,
+        },
+        None => Eviction::Kick(reloaded.proxy.disconnect_messages.upstream_drained.clone()),
+    };
+    let timeout = Duration::from_secs(reloaded.upstream.drain_timeout_secs);
+
+    for upstream in current {
+        if upstreams.contains(&upstream) {
+            if state.undrain_upstream(&upstream) {
+                tracing::info!("The upstream ({upstream}) is added back and takes new sessions.");
+            }
+            continue;
+        }
+        if !state.drain_upstream(upstream.clone()) {
+            continue;
+        }
+
+        tracing::info!(
+            "The upstream ({upstream}) is removed, so it is drained for {}s.",
+            timeout.as_secs()
+        );
+
+        let drain_eviction = eviction.clone();
+        let drain_state = state.clone();
+        let drain_sessions = sessions.clone();
+        let drain_upstream_addresses = upstream_addresses.clone();
+        sub_sys.start(SubsystemBuilder::new(
+            format!("UpstreamDrain_{upstream}"),
+            move |sub| {
+                run_upstream_drain(
+                    sub,
+                    upstream,
+                    timeout,
+                    drain_eviction,
+                    drain_state,
+                    drain_sessions,
+                    drain_upstream_addresses,
+                )
+            },
+        ));
+    }
+}
+
+/// Wait for the sessions of a drained upstream to end, evicting the remaining ones
+/// after `timeout`. The drain stops if the upstream is added back meanwhile.
+async fn run_upstream_drain(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    upstream: UpstreamAddress,
+    timeout: Duration,
+    eviction: Eviction,
+    state: Arc<ProxyState>,
+    sessions: Arc<SessionRegistry>,
+    upstream_addresses: Arc<UpstreamAddresses>,
+) -> CCProxyResult<()> {
+    let deadline = Instant::now() + timeout;
+
+    let mut interval = tokio::time::interval(DRAIN_CHECK_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                if !state.is_upstream_drained(&upstream) {
+                    break;
+                }
+
+                let targets = upstream_addresses.targets(&upstream);
+                let remaining = sessions
+                    .sessions()
+                    .await
+                    .into_iter()
+                    .filter(|s| targets.contains(&s.upstream_address))
+                    .collect::<Vec<_>>();
+                if remaining.is_empty() {
+                    tracing::info!("The upstream ({upstream}) is drained.");
+                    break;
+                }
+
+                if Instant::now() >= deadline {
+                    tracing::warn!(
+                        "The upstream ({upstream}) is not drained in time, so its {} sessions are evicted.",
+                        remaining.len()
+                    );
+                    METRICS.counter_add(
+                        &SESSIONS_EVICTED_TOTAL,
+                        &[("reason", "upstream_drained")],
+                        remaining.len() as f64,
+                    );
+                    for session in remaining {
+                        session.evict(eviction.clone());
+                    }
+                    break;
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
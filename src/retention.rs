@@ -0,0 +1,114 @@
+use crate::access_log::ACCESS_LOG_PATH;
+use crate::config::{LOG_PATH, RetentionConfig, RetentionPolicy};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::history::HISTORY_PATH;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// Periodically remove old files from every store under the data directory.
+pub async fn run_retention(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: RetentionConfig,
+) -> CCProxyResult<()> {
+    let stores = [
+        ("logs", LOG_PATH.clone(), config.logs),
+        ("history", HISTORY_PATH.clone(), config.history),
+        ("access log", ACCESS_LOG_PATH.clone(), config.access_log),
+    ];
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                for (name, path, policy) in &stores {
+                    match enforce_policy(path, policy).await {
+                        Ok(0) => (),
+                        Ok(removed) => tracing::info!("{removed} files are removed from the {name} store by the retention policy."),
+                        Err(err) => tracing::error!("Cannot apply the retention policy to the {name} store: {err}"),
+                    }
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove the files in `path` which violate `policy`, returning how many are removed.
+///
+/// The newest file is never removed by the size cap because it is usually still being written.
+pub async fn enforce_policy(path: &Path, policy: &RetentionPolicy) -> CCProxyResult<usize> {
+    if !path.is_dir() {
+        return Ok(0);
+    }
+
+    let mut files: Vec<(PathBuf, SystemTime, u64)> = vec![];
+    let mut dir = tokio::fs::read_dir(path).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            files.push((entry.path(), metadata.modified()?, metadata.len()));
+        }
+    }
+    // Oldest first.
+    files.sort_by_key(|(_, modified, _)| *modified);
+
+    let mut expired = vec![];
+
+    // An age or a size too large to represent removes nothing.
+    if let Some(cutoff) = policy
+        .max_age_days
+        .and_then(|days| days.checked_mul(24 * 60 * 60))
+        .and_then(|secs| SystemTime::now().checked_sub(Duration::from_secs(secs)))
+    {
+        let kept = files.split_off(files.partition_point(|(_, modified, _)| *modified < cutoff));
+        expired.append(&mut files);
+        files = kept;
+    }
+
+    if let Some(max_size) = policy
+        .max_size_mb
+        .and_then(|mb| mb.checked_mul(1024 * 1024))
+    {
+        let mut total = files.iter().map(|(_, _, len)| len).sum::<u64>();
+        while total > max_size && files.len() > 1 {
+            let oldest = files.remove(0);
+            total -= oldest.2;
+            expired.push(oldest);
+        }
+    }
+
+    for (path, _, _) in &expired {
+        tokio::fs::remove_file(path).await?;
+        tracing::debug!("{} is removed by the retention policy.", path.display());
+    }
+
+    Ok(expired.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn huge_policy_removes_nothing() {
+        let path = std::env::temp_dir().join(format!("ccproxy-retention-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("a.log"), b"a").unwrap();
+        std::fs::write(path.join("b.log"), b"b").unwrap();
+
+        let policy = RetentionPolicy {
+            max_age_days: Some(u64::MAX),
+            max_size_mb: Some(u64::MAX),
+        };
+        let removed = enforce_policy(&path, &policy).await;
+        std::fs::remove_dir_all(&path).unwrap();
+
+        assert_eq!(removed.unwrap(), 0);
+    }
+}
@@ -0,0 +1,151 @@
+use crate::config::{ProxyConfig, ScheduleConfig, TransferConfig};
+use crate::error::{CCProxyError, CCProxyResult};
+use crate::maintenance;
+use crate::metrics::{METRICS, SESSIONS_EVICTED_TOTAL};
+use crate::network::session::{Eviction, SessionRegistry};
+use crate::state::ProxyState;
+use chrono::Utc;
+use chrono_tz::Tz;
+use cron::Schedule;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// Run the configured schedules, applying their actions when each cron expression fires.
+///
+/// Cron expressions are evaluated in `timezone`, so "0 0 4 * * *" means 04:00 there.
+pub async fn run_scheduler(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    schedules: Vec<ScheduleConfig>,
+    proxy: ProxyConfig,
+    timezone: Tz,
+    state: Arc<ProxyState>,
+    sessions: Arc<SessionRegistry>,
+) -> CCProxyResult<()> {
+    for schedule in &schedules {
+        if let Some(profile) = &schedule.motd_profile
+            && !profile.is_empty()
+            && !proxy.motd_profiles.contains_key(profile)
+        {
+            tracing::warn!(
+                "The schedule ({}) refers to the unknown MOTD profile ({profile}).",
+                schedule.name
+            );
+        }
+    }
+
+    let schedules = schedules
+        .into_iter()
+        .map(|s| Ok((Schedule::from_str(&s.cron)?, s)))
+        .collect::<CCProxyResult<Vec<_>>>()?;
+
+    loop {
+        let now = Utc::now().with_timezone(&timezone);
+        let Some(next) = schedules
+            .iter()
+            .filter_map(|(schedule, _)| schedule.after(&now).next())
+            .min()
+        else {
+            // No schedule fires anymore.
+            sub_sys.on_shutdown_requested().await;
+            break;
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep((next - now).to_std().unwrap_or_default()) => {
+                for (_, config) in schedules
+                    .iter()
+                    .filter(|(schedule, _)| schedule.after(&now).next() == Some(next))
+                {
+                    apply_schedule(&sub_sys, config, &proxy, &state, &sessions).await;
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn apply_schedule(
+    sub_sys: &SubsystemHandle<CCProxyError>,
+    config: &ScheduleConfig,
+    proxy: &ProxyConfig,
+    state: &Arc<ProxyState>,
+    sessions: &Arc<SessionRegistry>,
+) {
+    tracing::info!("The schedule ({}) is fired.", config.name);
+
+    if let Some(draining) = config.drain {
+        state.set_draining(draining);
+
+        if draining {
+            tracing::info!("The proxy server is draining. New sessions are refused.");
+        } else {
+            tracing::info!("The proxy server stopped draining. New sessions are accepted.");
+        }
+    }
+
+    if let Some(profile) = &config.motd_profile {
+        // An empty profile name goes back to the upstream MOTD.
+        let profile = (!profile.is_empty()).then(|| profile.clone());
+        tracing::info!(
+            "The MOTD profile is switched to {}.",
+            profile.as_deref().unwrap_or("the upstream MOTD")
+        );
+        state.set_motd_profile(profile);
+    }
+
+    if let Some(maintenance) = config.maintenance {
+        maintenance::set_maintenance(sub_sys, proxy, state, sessions, maintenance);
+    }
+
+    if let Some(transfer) = &config.transfer {
+        transfer_sessions(sessions, transfer).await;
+    }
+
+    if config.exit {
+        tracing::info!("The proxy server is exiting by the schedule.");
+        sub_sys.request_shutdown();
+    }
+}
+
+/// Move every session which can still be injected into to the transfer target, and
+/// return how many are moved.
+pub async fn transfer_sessions(sessions: &SessionRegistry, transfer: &TransferConfig) -> usize {
+    let (movable, encrypted): (Vec<_>, Vec<_>) = sessions
+        .sessions()
+        .await
+        .into_iter()
+        .partition(|s| s.handshake.lock().unwrap().can_inject());
+
+    tracing::info!(
+        "{} sessions are transferred to {}:{}.",
+        movable.len(),
+        transfer.host,
+        transfer.port
+    );
+    if !encrypted.is_empty() {
+        tracing::warn!(
+            "{} sessions are encrypted, so they cannot be transferred and stay.",
+            encrypted.len()
+        );
+    }
+
+    METRICS.counter_add(
+        &SESSIONS_EVICTED_TOTAL,
+        &[("reason", "transfer")],
+        movable.len() as f64,
+    );
+    for session in &movable {
+        session.evict(Eviction::Transfer {
+            host: transfer.host.clone(),
+            port: transfer.port,
+        });
+    }
+
+    movable.len()
+}
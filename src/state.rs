@@ -0,0 +1,124 @@
+use crate::config::UpstreamAddress;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use tokio_util::sync::CancellationToken;
+
+/// Runtime toggles shared between the listener and the subsystems which control it.
+#[derive(Default)]
+pub struct ProxyState {
+    draining: AtomicBool,
+
+    maintenance: AtomicBool,
+
+    /// Cancelled when the maintenance mode is entered or left, which stops the kick
+    /// countdown of the previous one.
+    maintenance_countdown: Mutex<CancellationToken>,
+
+    under_attack: AtomicBool,
+
+    motd_profile: RwLock<Option<String>>,
+
+    drained_upstreams: RwLock<HashSet<UpstreamAddress>>,
+}
+
+impl ProxyState {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Whether new sessions are refused while existing ones continue.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.draining.store(draining, Ordering::Relaxed);
+    }
+
+    /// Whether new players are refused and the maintenance MOTD is served.
+    pub fn is_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    /// Returns the previous value.
+    pub fn set_maintenance(&self, maintenance: bool) -> bool {
+        let mut countdown = self.maintenance_countdown.lock().unwrap();
+        let previous = self.maintenance.swap(maintenance, Ordering::Relaxed);
+        if previous != maintenance {
+            countdown.cancel();
+            *countdown = CancellationToken::new();
+        }
+
+        previous
+    }
+
+    /// Cancelled once the current maintenance mode is left.
+    pub fn maintenance_countdown(&self) -> CancellationToken {
+        self.maintenance_countdown.lock().unwrap().clone()
+    }
+
+    /// Whether the stricter posture of `under_attack` is in effect.
+    pub fn is_under_attack(&self) -> bool {
+        self.under_attack.load(Ordering::Relaxed)
+    }
+
+    /// Returns the previous value.
+    pub fn set_under_attack(&self, under_attack: bool) -> bool {
+        self.under_attack.swap(under_attack, Ordering::Relaxed)
+    }
+
+    /// The name of the MOTD profile served instead of the upstream MOTD, if any.
+    pub fn motd_profile(&self) -> Option<String> {
+        self.motd_profile.read().unwrap().clone()
+    }
+
+    pub fn set_motd_profile(&self, profile: Option<String>) {
+        *self.motd_profile.write().unwrap() = profile;
+    }
+
+    /// The upstreams which are removed from the config and take no new sessions.
+    pub fn drained_upstreams(&self) -> Vec<UpstreamAddress> {
+        self.drained_upstreams
+            .read()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_upstream_drained(&self, upstream: &UpstreamAddress) -> bool {
+        self.drained_upstreams.read().unwrap().contains(upstream)
+    }
+
+    /// Returns whether the upstream was not drained yet.
+    pub fn drain_upstream(&self, upstream: UpstreamAddress) -> bool {
+        self.drained_upstreams.write().unwrap().insert(upstream)
+    }
+
+    /// Returns whether the upstream was drained.
+    pub fn undrain_upstream(&self, upstream: &UpstreamAddress) -> bool {
+        self.drained_upstreams.write().unwrap().remove(upstream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaving_maintenance_cancels_countdown() {
+        let state = ProxyState::new();
+        state.set_maintenance(true);
+        let first = state.maintenance_countdown();
+
+        state.set_maintenance(true);
+        assert!(!first.is_cancelled());
+
+        state.set_maintenance(false);
+        assert!(first.is_cancelled());
+
+        state.set_maintenance(true);
+        assert!(!state.maintenance_countdown().is_cancelled());
+    }
+}
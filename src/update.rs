@@ -0,0 +1,91 @@
+use crate::built_info;
+use crate::config::UpdateCheckConfig;
+use crate::error::{CCProxyError, CCProxyResult};
+use reqwest::header::{ACCEPT, USER_AGENT};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio_graceful_shutdown::SubsystemHandle;
+
+/// A release in the project's release feed.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Release {
+    pub tag_name: String,
+
+    /// The release page, which includes the changelog.
+    pub html_url: String,
+
+    #[serde(default)]
+    pub assets: Vec<ReleaseAsset>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ReleaseAsset {
+    pub name: String,
+
+    pub browser_download_url: String,
+}
+
+impl Release {
+    pub fn version(&self) -> CCProxyResult<semver::Version> {
+        Ok(semver::Version::parse(
+            self.tag_name.trim_start_matches('v'),
+        )?)
+    }
+
+    /// Whether this release is newer than the running binary.
+    pub fn is_newer(&self) -> CCProxyResult<bool> {
+        Ok(self.version()? > semver::Version::parse(built_info::PKG_VERSION)?)
+    }
+}
+
+/// Get the latest release from the release feed at `url`.
+pub async fn fetch_latest_release(url: &str) -> CCProxyResult<Release> {
+    let response = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?
+        .get(url)
+        .header(USER_AGENT, format!("ccproxy/{}", built_info::PKG_VERSION))
+        .header(ACCEPT, "application/vnd.github+json")
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(serde_json::from_slice(&response.bytes().await?)?)
+}
+
+/// Check the release feed at startup and on every interval, logging when a newer
+/// version is available.
+pub async fn run_update_checker(
+    sub_sys: SubsystemHandle<CCProxyError>,
+    config: UpdateCheckConfig,
+) -> CCProxyResult<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                match check(&config.url).await {
+                    Ok(Some(release)) => tracing::warn!(
+                        "A new version ({}) of ccproxy is available. The current version is v{}. See the changelog: {}",
+                        release.tag_name,
+                        built_info::PKG_VERSION,
+                        release.html_url
+                    ),
+                    Ok(None) => tracing::debug!("ccproxy v{} is up to date.", built_info::PKG_VERSION),
+                    Err(err) => tracing::error!("Cannot check for updates from {}: {err}", config.url),
+                }
+            },
+            // Shutdown handler
+            _ = sub_sys.on_shutdown_requested() => {
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn check(url: &str) -> CCProxyResult<Option<Release>> {
+    let release = fetch_latest_release(url).await?;
+
+    Ok(release.is_newer()?.then_some(release))
+}